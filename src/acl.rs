@@ -0,0 +1,189 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Serialize};
+
+/// `10.0.0.0/8`、`::1/128`，省略前缀长度时为单个地址；加载配置时解析
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped IPv6 peers from dual-stack listeners
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask(u32::from(net).into(), 32, self.prefix)
+                    == mask(u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask(net.into(), 128, self.prefix) == mask(ip.into(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask(bits: u128, width: u8, prefix: u8) -> u128 {
+    match width - prefix {
+        0 => bits,
+        shift if shift >= 128 => 0,
+        shift => bits >> shift,
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.trim().parse::<IpAddr>()?, Some(prefix.trim().parse()?)),
+            None => (s.trim().parse::<IpAddr>()?, None),
+        };
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(width);
+        if prefix > width {
+            return Err(anyhow!("invalid prefix length in `{s}`"));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(value: Cidr) -> Self {
+        value.to_string()
+    }
+}
+
+/// 拒绝优先；允许列表为空时允许所有未被拒绝的地址
+pub fn is_allowed(allow: &[Cidr], deny: &[Cidr], ip: IpAddr) -> bool {
+    let matches = |list: &[Cidr]| list.iter().any(|cidr| cidr.contains(ip));
+    !matches(deny) && (allow.is_empty() || matches(allow))
+}
+
+/// `443` 或 `8000-8999`；加载配置时解析
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (start.trim().parse()?, end.trim().parse()?),
+            None => {
+                let port = s.trim().parse()?;
+                (port, port)
+            }
+        };
+        if start > end {
+            return Err(anyhow!("invalid port range `{s}`"));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl From<u16> for PortRange {
+    fn from(port: u16) -> Self {
+        Self {
+            start: port,
+            end: port,
+        }
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(value: PortRange) -> Self {
+        value.to_string()
+    }
+}
+
+/// 同 `is_allowed`，用于 CONNECT 的目标端口
+pub fn is_port_allowed(allow: &[PortRange], deny: &[PortRange], port: u16) -> bool {
+    let matches = |list: &[PortRange]| list.iter().any(|range| range.contains(port));
+    !matches(deny) && (allow.is_empty() || matches(allow))
+}
+
+#[test]
+fn should_match_cidr() {
+    let allow = ["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()];
+    let deny = ["10.1.0.0/16".parse().unwrap()];
+    let ip = |s: &str| s.parse().unwrap();
+    assert!(is_allowed(&allow, &deny, ip("10.2.3.4")));
+    assert!(is_allowed(&allow, &deny, ip("::ffff:10.2.3.4")));
+    assert!(is_allowed(&allow, &deny, ip("::1")));
+    assert!(!is_allowed(&allow, &deny, ip("10.1.3.4")));
+    assert!(!is_allowed(&allow, &deny, ip("192.168.1.1")));
+    assert!(is_allowed(&[], &[], ip("192.168.1.1")));
+    assert!(is_allowed(
+        &["0.0.0.0/0".parse().unwrap()],
+        &[],
+        ip("8.8.8.8")
+    ));
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert_eq!(allow[1].to_string(), "::1/128");
+}
+
+#[test]
+fn should_match_port() {
+    let allow = ["443".parse().unwrap(), "8000-8999".parse().unwrap()];
+    let deny = ["8080".parse().unwrap()];
+    assert!(is_port_allowed(&allow, &deny, 443));
+    assert!(is_port_allowed(&allow, &deny, 8443));
+    assert!(!is_port_allowed(&allow, &deny, 8080));
+    assert!(!is_port_allowed(&allow, &deny, 22));
+    assert!(is_port_allowed(&[], &deny, 22));
+    assert!("9000-8000".parse::<PortRange>().is_err());
+    assert_eq!(allow[1].to_string(), "8000-8999");
+}
@@ -0,0 +1,314 @@
+use std::net::IpAddr;
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, HOST, ORIGIN, TRANSFER_ENCODING};
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::util;
+
+/// 序列化配置时凭据替换为此值，PUT / PATCH 带回时换回当前值
+pub const MASK: &str = "[REDACTED]";
+
+/// 值为凭据的字段，任意层级
+const SECRET_FIELDS: &[&str] = &["password", "client_secret", "bearer", "admin_token"];
+
+/// 会执行外部程序、或读取本地文件发往上游的字段，只能在磁盘上的配置文件里设置
+const COMMAND_FIELDS: &[&str] = &["token_command", "token_file"];
+
+/// 不需要 token 的路径：探针与不含数据的 dashboard 页面
+const PUBLIC_PATHS: &[&str] = &["/", "/healthz", "/readyz"];
+
+/// 拦截跨站请求（浏览器带 Origin）、DNS rebinding、缺少 `admin_token` 与非 JSON 的 body，
+/// 返回拒绝的响应
+pub fn check<B>(
+    config: &Config,
+    req: &Request<B>,
+) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    if let Some(origin) = req.headers().get(ORIGIN) {
+        let same = origin
+            .to_str()
+            .ok()
+            .and_then(|origin| origin.parse::<Uri>().ok())
+            .and_then(|origin| {
+                origin
+                    .authority()
+                    .map(|a| a.as_str().eq_ignore_ascii_case(host))
+            })
+            .unwrap_or(false);
+        if !same {
+            return Some(reject(
+                StatusCode::FORBIDDEN,
+                "cross-origin requests are not allowed",
+            ));
+        }
+    }
+    match &config.admin_token {
+        Some(token) if !PUBLIC_PATHS.contains(&req.uri().path()) && !has_token(req, token) => {
+            return Some(reject(StatusCode::UNAUTHORIZED, "admin token required"));
+        }
+        Some(_) => {}
+        // a rebound DNS name would pass the Origin check, only literal hosts are trusted
+        None if !is_local_host(host) => {
+            return Some(reject(
+                StatusCode::FORBIDDEN,
+                "set admin_token to access the admin API by host name",
+            ));
+        }
+        None => {}
+    }
+    if has_body(req) && !is_json(req) {
+        return Some(reject(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "request body must be application/json",
+        ));
+    }
+    None
+}
+
+fn has_token<B>(req: &Request<B>, token: &str) -> bool {
+    let bearer = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_owned);
+    // EventSource and WebSocket can't set headers
+    let query = || {
+        form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+            .find(|(key, _)| key == "token")
+            .map(|(_, value)| value.into_owned())
+    };
+    bearer.or_else(query).is_some_and(|given| {
+        given.len() == token.len() && openssl::memcmp::eq(given.as_bytes(), token.as_bytes())
+    })
+}
+
+/// IP 字面量或 localhost，可带端口
+fn is_local_host(host: &str) -> bool {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().is_ok()
+}
+
+fn has_body<B>(req: &Request<B>) -> bool {
+    if matches!(*req.method(), Method::GET | Method::HEAD) {
+        return false;
+    }
+    let length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    length.is_some_and(|len| len > 0) || req.headers().contains_key(TRANSFER_ENCODING)
+}
+
+fn is_json<B>(req: &Request<B>) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("application/json"))
+}
+
+fn reject(status: StatusCode, message: &str) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(util::full(format!("{message}\n")));
+    *resp.status_mut() = status;
+    resp
+}
+
+/// 把凭据字段替换为 [`MASK`]
+pub fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_FIELDS.contains(&key.as_str()) && !value.is_null() {
+                    *value = Value::String(MASK.to_owned());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// 带回的 [`MASK`] 换成 `current` 中同一位置的值
+pub fn restore(value: &mut Value, current: &Value) {
+    match (value, current) {
+        (Value::Object(map), Value::Object(current)) => {
+            for (key, value) in map.iter_mut() {
+                let Some(current) = current.get(key) else {
+                    continue;
+                };
+                if SECRET_FIELDS.contains(&key.as_str()) && value.as_str() == Some(MASK) {
+                    *value = current.clone();
+                } else {
+                    restore(value, current);
+                }
+            }
+        }
+        (Value::Array(items), Value::Array(current)) => {
+            for (value, current) in items.iter_mut().zip(current) {
+                restore(value, current);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// 拒绝 `value` 中当前配置没有的命令，保留或删除已有命令不受限制
+pub fn forbid_new_commands(value: &Value, current: &Value) -> anyhow::Result<()> {
+    let mut existing = Vec::new();
+    commands(current, &mut existing);
+    let mut given = Vec::new();
+    commands(value, &mut given);
+    match given
+        .into_iter()
+        .find(|command| !existing.contains(command))
+    {
+        Some((field, value)) => Err(anyhow::anyhow!(
+            "`{field}` {value} can only be set in the config file"
+        )),
+        None => Ok(()),
+    }
+}
+
+fn commands<'a>(value: &'a Value, found: &mut Vec<(&'a str, &'a Value)>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                if COMMAND_FIELDS.contains(&key.as_str()) {
+                    if !value.is_null() && value.as_array().is_none_or(|args| !args.is_empty()) {
+                        found.push((key.as_str(), value));
+                    }
+                } else {
+                    commands(value, found);
+                }
+            }
+        }
+        Value::Array(items) => items.iter().for_each(|item| commands(item, found)),
+        _ => {}
+    }
+}
+
+#[test]
+fn should_guard_admin_requests() {
+    let get = |host: &str, path: &str| Request::get(path).header(HOST, host).body(()).unwrap();
+    let config = Config::default();
+    assert!(check(&config, &get("127.0.0.1:8081", "/api/config")).is_none());
+    assert!(check(&config, &get("[::1]:8081", "/api/config")).is_none());
+    assert!(check(&config, &get("evil.example:8081", "/api/config")).is_some());
+
+    let cross_site = Request::post("/api/replay")
+        .header(HOST, "127.0.0.1:8081")
+        .header(ORIGIN, "https://evil.example")
+        .header(CONTENT_TYPE, "application/json")
+        .header(CONTENT_LENGTH, "2")
+        .body(())
+        .unwrap();
+    assert_eq!(
+        check(&config, &cross_site).unwrap().status(),
+        StatusCode::FORBIDDEN
+    );
+    let form = Request::post("/api/replay")
+        .header(HOST, "127.0.0.1:8081")
+        .header(ORIGIN, "http://127.0.0.1:8081")
+        .header(CONTENT_TYPE, "text/plain")
+        .header(CONTENT_LENGTH, "2")
+        .body(())
+        .unwrap();
+    assert_eq!(
+        check(&config, &form).unwrap().status(),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+
+    let config = Config {
+        admin_token: Some("s3cret".to_owned()),
+        ..Default::default()
+    };
+    assert!(check(&config, &get("proxy.lan:8081", "/healthz")).is_none());
+    assert_eq!(
+        check(&config, &get("proxy.lan:8081", "/api/config"))
+            .unwrap()
+            .status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert!(check(&config, &get("proxy.lan:8081", "/api/events?token=s3cret")).is_none());
+    let bearer = Request::get("/api/config")
+        .header(HOST, "proxy.lan:8081")
+        .header(AUTHORIZATION, "Bearer s3cret")
+        .body(())
+        .unwrap();
+    assert!(check(&config, &bearer).is_none());
+}
+
+#[test]
+fn should_redact_config_secrets() {
+    let mut config = serde_json::json!({
+        "password": "hunter2",
+        "username": "alice",
+        "auth": [{"bearer": "t0ken", "oauth2": {"client_secret": "shh"}}],
+        "admin_token": null,
+    });
+    let current = config.clone();
+    redact(&mut config);
+    assert_eq!(config["password"], MASK);
+    assert_eq!(config["username"], "alice");
+    assert_eq!(config["auth"][0]["bearer"], MASK);
+    assert_eq!(config["auth"][0]["oauth2"]["client_secret"], MASK);
+    assert!(config["admin_token"].is_null());
+
+    config["username"] = "bob".into();
+    restore(&mut config, &current);
+    assert_eq!(config["password"], "hunter2");
+    assert_eq!(config["auth"][0]["oauth2"]["client_secret"], "shh");
+    assert_eq!(config["username"], "bob");
+}
+
+#[test]
+fn should_forbid_new_commands() {
+    let current = serde_json::json!({
+        "auth_rules": [{"host": "a.example", "token_command": ["gh", "auth", "token"]}],
+    });
+    let moved = serde_json::json!({
+        "auth_rules": [
+            {"host": "b.example", "token_command": []},
+            {"host": "a.example", "token_command": ["gh", "auth", "token"]},
+        ],
+    });
+    assert!(forbid_new_commands(&moved, &current).is_ok());
+    assert!(forbid_new_commands(&serde_json::json!({"auth_rules": []}), &current).is_ok());
+    let added = serde_json::json!({
+        "profiles": {"work": {"auth_rules": [{"token_command": ["sh", "-c", "id"]}]}},
+    });
+    assert!(forbid_new_commands(&added, &current).is_err());
+
+    // the file is read and sent upstream as a token
+    let current = serde_json::json!({
+        "auth_rules": [{"host": "a.example", "token_file": "/run/secrets/a"}],
+    });
+    assert!(forbid_new_commands(&current, &current).is_ok());
+    let changed = serde_json::json!({
+        "auth_rules": [{"host": "a.example", "token_file": "/etc/shadow"}],
+    });
+    assert!(forbid_new_commands(&changed, &current).is_err());
+    let added = serde_json::json!({
+        "auth_rules": [
+            {"host": "a.example", "token_file": "/run/secrets/a"},
+            {"host": "b.example", "token_file": "/root/.ssh/id_ed25519"},
+        ],
+    });
+    assert!(forbid_new_commands(&added, &current).is_err());
+}
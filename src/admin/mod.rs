@@ -0,0 +1,490 @@
+use std::convert::Infallible;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Frame, Incoming as IncomingBody};
+use hyper::header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE};
+use hyper::server::conn::http1::Builder as ServerBuilder;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::StreamExt;
+use tracing::{error, info};
+use ulid::Ulid;
+
+use crate::breakpoint::{Decision, Edit};
+use crate::config::Config;
+use crate::cookie;
+use crate::diff;
+use crate::export::{curl, openapi, postman};
+use crate::filter::{self, Filter};
+use crate::flow::Flow;
+use crate::loadtest;
+use crate::replay;
+use crate::state::State;
+use crate::util;
+
+mod guard;
+mod tail;
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// 管理端口：dashboard 与 JSON 接口
+pub async fn serve(state: State, addr: String) -> Result<()> {
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Admin listening on http://{}", listener.local_addr()?);
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept admin connection: {e}");
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            let service = service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(route(state, req).await) }
+            });
+            if let Err(e) = ServerBuilder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+            {
+                error!("Failed to serve admin connection: {e}");
+            }
+        });
+    }
+}
+
+async fn route(state: State, req: Request<IncomingBody>) -> Response<BoxBody<Bytes, hyper::Error>> {
+    if let Some(resp) = guard::check(&state.config(), &req) {
+        return resp;
+    }
+    let path = req.uri().path().to_owned();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let result = match (req.method().clone(), segments.as_slice()) {
+        (Method::GET, [""]) => Ok(html(DASHBOARD)),
+        (Method::GET, ["api", "flows"]) => list_flows(&state, req.uri().query()),
+        (Method::GET, ["api", "flows", id]) => {
+            match id.parse().ok().and_then(|id| state.flows().get(id)) {
+                Some(flow) => json(&flow),
+                None => Ok(not_found()),
+            }
+        }
+        (Method::GET, ["api", "flows", id, "curl"]) => {
+            match id.parse().ok().and_then(|id| state.flows().get(id)) {
+                Some(flow) => Ok(text(curl::command(&flow))),
+                None => Ok(not_found()),
+            }
+        }
+        (Method::GET, ["api", "flows", id, "body", part]) => {
+            match id.parse().ok().and_then(|id| state.flows().get(id)) {
+                Some(flow) => flow_body(&flow, part),
+                None => Ok(not_found()),
+            }
+        }
+        (Method::GET, ["api", "flows", id, "diff"]) => {
+            match id.parse().ok().and_then(|id| state.flows().get(id)) {
+                Some(flow) => match flow.origin {
+                    Some(origin) => diff_flows(&state, origin, flow.id, req.uri().query()),
+                    None => Err(anyhow!("{} is not a mirror or replay", flow.id)),
+                },
+                None => Ok(not_found()),
+            }
+        }
+        (Method::GET, ["api", "flows", left, "diff", right]) => {
+            match (left.parse(), right.parse()) {
+                (Ok(left), Ok(right)) => diff_flows(&state, left, right, req.uri().query()),
+                _ => Ok(not_found()),
+            }
+        }
+        (Method::POST, ["api", "flows", id, "replay"]) => {
+            match id.parse().ok().and_then(|id| state.flows().get(id)) {
+                Some(flow) => replay::replay(&state, &flow)
+                    .await
+                    .and_then(|replayed| json(&replayed)),
+                None => Ok(not_found()),
+            }
+        }
+        (Method::POST, ["api", "replay"]) => match read_json(req).await {
+            Ok(batch) => json(&replay::run_batch(&state, batch).await),
+            Err(e) => Err(e),
+        },
+        (Method::POST, ["api", "loadtest"]) => match read_json(req).await {
+            Ok(spec) => loadtest::run(&state, spec)
+                .await
+                .and_then(|summary| json(&summary)),
+            Err(e) => Err(e),
+        },
+        (Method::GET, ["api", "export", "postman"]) => {
+            json(&postman::collection(&state.flows().list()))
+        }
+        (Method::GET, ["api", "export", "postman", "environment"]) => {
+            json(&postman::environment(&state.flows().list()))
+        }
+        (Method::GET, ["api", "export", "openapi", host]) => {
+            json(&openapi::document(host, &state.flows().list()))
+        }
+        (Method::GET, ["api", "breakpoints"]) => json(&state.breakpoints().list()),
+        (Method::POST, ["api", "breakpoints", id, "resume"]) => resume(&state, id, req).await,
+        (Method::POST, ["api", "breakpoints", id, "drop"]) => decide(&state, id, Decision::Drop),
+        (Method::GET, ["api", "events"]) => {
+            query_filter(req.uri().query()).map(|filter| events(&state, filter))
+        }
+        (Method::GET, ["api", "tail"]) => tail::upgrade(&state, req),
+        (Method::GET, ["healthz"]) => Ok(text("ok\n".to_owned())),
+        (Method::GET, ["readyz"]) => readyz(&state),
+        (Method::GET, ["version"]) => json(&version()),
+        (Method::GET, ["api", "health"]) => json(&state.health().snapshot()),
+        (Method::GET, ["api", "config"]) => config_json(&state.base_config()),
+        (Method::GET, ["api", "config", "effective"]) => config_json(&state.config()),
+        (Method::GET, ["api", "profiles"]) => json(&profiles(&state)),
+        (Method::PUT, ["api", "profiles", "active"]) => switch_profile(&state, req).await,
+        (Method::PUT, ["api", "config"]) => put_config(&state, req).await,
+        (Method::PATCH, ["api", "config"]) => patch_config(&state, req).await,
+        (Method::GET, ["api", "ca"]) => root_ca(&state),
+        (Method::GET, ["api", "ca", "rotation"]) => json(&state.rotation_status()),
+        (Method::POST, ["api", "ca", "rotation"]) => rotate_ca(&state, req).await,
+        (Method::DELETE, ["api", "ca", "rotation"]) => state
+            .cancel_rotation()
+            .await
+            .and_then(|cancelled| json(&cancelled)),
+        (Method::GET, ["api", "upstream-certs"]) => json(&state.upstream_certs().snapshot()),
+        (Method::GET, ["api", "upstream-certs", host]) => match state.upstream_certs().get(host) {
+            Some(cert) => json(&cert),
+            None => Ok(not_found()),
+        },
+        (Method::GET, ["api", "upstream-certs", host, "pem"]) => {
+            match state.upstream_certs().chain_pem(host) {
+                Some(chain) => pem(chain, &format!("{host}.chain.pem")),
+                None => Ok(not_found()),
+            }
+        }
+        (Method::GET, ["api", "cookies"]) => json(&state.cookies().snapshot()),
+        (Method::GET, ["api", "cookies", "export"]) => cookies_txt(&state),
+        (Method::GET, ["api", "cookies", host]) => json(&state.cookies().get(host)),
+        (Method::DELETE, ["api", "cookies"]) => json(&state.cookies().clear()),
+        (Method::GET, ["api", "expect"]) => json(&state.expectations().report()),
+        (Method::GET, ["api", "quotas"]) => json(&state.quotas().snapshot()),
+        (Method::DELETE, ["api", "quotas"]) => json(&state.quotas().reset()),
+        (Method::GET, ["api", "certs"]) => json(&state.signed_hosts()),
+        (Method::DELETE, ["api", "certs"]) => json(&state.purge_signed(None)),
+        (Method::DELETE, ["api", "certs", host]) => json(&state.purge_signed(Some(host))),
+        (Method::GET, ["api", "connections"]) => json(&state.connections().list()),
+        (Method::GET, ["api", "stats"]) => json(&state.metrics().snapshot()),
+        (Method::GET, ["api", "stats", "protocols"]) => json(&state.protocols().snapshot()),
+        (Method::GET, ["api", "stats", "accepts"]) => json(&state.accepts().snapshot()),
+        (Method::GET, ["api", "stats", "hosts"]) => json(&state.traffic().snapshot()),
+        _ => Ok(not_found()),
+    };
+    result.unwrap_or_else(|e| {
+        let mut resp = Response::new(util::full(e.to_string()));
+        *resp.status_mut() = StatusCode::BAD_REQUEST;
+        resp
+    })
+}
+
+/// 未就绪时返回 503，body 同样是检查结果
+fn readyz(state: &State) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let readiness = state.readiness();
+    let mut resp = json(&readiness)?;
+    if !readiness.ready {
+        *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    Ok(resp)
+}
+
+fn version() -> serde_json::Value {
+    let features: Vec<&str> = [
+        ("http3", cfg!(feature = "http3")),
+        ("splice", cfg!(feature = "splice")),
+        ("tray", cfg!(feature = "tray")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect();
+    serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "debug": cfg!(debug_assertions),
+        "features": features,
+    })
+}
+
+/// `?filter=~d example.com`
+fn query_filter(query: Option<&str>) -> Result<Option<Filter>> {
+    form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .find(|(key, value)| key == "filter" && !value.is_empty())
+        .map(|(_, value)| value.parse())
+        .transpose()
+}
+
+fn list_flows(
+    state: &State,
+    query: Option<&str>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let filter = query_filter(query)?;
+    let mut flows = state.flows().list();
+    flows.retain(|flow| filter::allows(filter.as_ref(), flow));
+    json(&flows)
+}
+
+/// `request` 或 `response` 的完整 body，被截断且没有写入临时文件时返回 409
+fn flow_body(flow: &Flow, part: &str) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let (body, headers) = match part {
+        "request" => (flow.full_request_body(), &flow.request_headers),
+        "response" => (flow.full_response_body(), &flow.response_headers),
+        _ => return Ok(not_found()),
+    };
+    let Some(body) = body else {
+        let mut resp = text(format!(
+            "{part} body of {} was truncated by flow_body_limit",
+            flow.id
+        ));
+        *resp.status_mut() = StatusCode::CONFLICT;
+        return Ok(resp);
+    };
+    let content_type = headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .map_or("application/octet-stream", |(_, value)| value.as_str());
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .body(body)?)
+}
+
+/// `?ignore=etag&ignore=/timestamp` 忽略指定 header 与 JSON 字段
+fn diff_flows(
+    state: &State,
+    left: Ulid,
+    right: Ulid,
+    query: Option<&str>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let flows = state.flows();
+    let (Some(left), Some(right)) = (flows.get(left), flows.get(right)) else {
+        return Ok(not_found());
+    };
+    if left.is_tcp() || right.is_tcp() {
+        return Err(anyhow!("TCP tunnels have no response to diff"));
+    }
+    let ignore: Vec<String> = form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+        .filter(|(key, value)| key == "ignore" && !value.is_empty())
+        .map(|(_, value)| value.into_owned())
+        .collect();
+    json(&diff::compare(&left, &right, &ignore))
+}
+
+/// body 为空时原样放行，否则按 `Edit` 修改后放行
+async fn resume(
+    state: &State,
+    id: &str,
+    req: Request<IncomingBody>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let body = req.into_body().collect().await?.to_bytes();
+    let edit: Edit = if body.is_empty() {
+        Edit::default()
+    } else {
+        serde_json::from_slice(&body)?
+    };
+    edit.check()?;
+    decide(state, id, Decision::Resume(edit))
+}
+
+fn decide(
+    state: &State,
+    id: &str,
+    decision: Decision,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    match id.parse() {
+        Ok(id) if state.breakpoints().decide(id, decision) => Ok(Response::new(util::empty())),
+        _ => Ok(not_found()),
+    }
+}
+
+/// 当前的 root CA 证书，轮换中时两个都有
+fn root_ca(state: &State) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    pem(state.root_ca_pem()?, "proxy.ca.cert.crt")
+}
+
+fn pem(body: Vec<u8>, filename: &str) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/x-pem-file")
+        .header(
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{filename}\""),
+        )
+        .body(util::full(body))?)
+}
+
+/// Netscape 格式，可直接给 `curl -b` 使用；值按 `redact` 处理过
+fn cookies_txt(state: &State) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(CONTENT_DISPOSITION, "attachment; filename=\"cookies.txt\"")
+        .body(util::full(cookie::netscape(&state.cookies().snapshot())))?)
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RotateCa {
+    /// 新旧 CA 并存的时长
+    overlap_secs: u64,
+}
+
+impl Default for RotateCa {
+    fn default() -> Self {
+        Self {
+            overlap_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+/// body 可省略，默认并存 7 天
+async fn rotate_ca(
+    state: &State,
+    req: Request<IncomingBody>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let body = req.into_body().collect().await?.to_bytes();
+    let spec: RotateCa = if body.is_empty() {
+        RotateCa::default()
+    } else {
+        serde_json::from_slice(&body)?
+    };
+    json(
+        &state
+            .rotate_ca(Duration::from_secs(spec.overlap_secs))
+            .await?,
+    )
+}
+
+fn profiles(state: &State) -> serde_json::Value {
+    let config = state.base_config();
+    let mut names: Vec<&String> = config.profiles.keys().collect();
+    names.sort();
+    serde_json::json!({ "active": config.profile, "profiles": names })
+}
+
+/// body 为 profile 名，`null` 切回基础配置；生效并保存
+async fn switch_profile(
+    state: &State,
+    req: Request<IncomingBody>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let profile: Option<String> = read_json(req).await?;
+    let config = Config {
+        profile,
+        ..(*state.base_config()).clone()
+    };
+    config.resolve()?;
+    config.save().await?;
+    state.set_config(config)?;
+    info!(profile = ?state.base_config().profile, "profile switched");
+    json(&profiles(state))
+}
+
+/// 整体替换配置，生效并保存
+async fn put_config(
+    state: &State,
+    req: Request<IncomingBody>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let mut config: serde_json::Value = read_json(req).await?;
+    let current = serde_json::to_value(&*state.base_config())?;
+    guard::restore(&mut config, &current);
+    guard::forbid_new_commands(&config, &current)?;
+    let config: Config = serde_json::from_value(config)?;
+    config.resolve()?;
+    config.save().await?;
+    state.set_config(config)?;
+    config_json(&state.base_config())
+}
+
+/// 合并部分字段到当前配置，生效并保存
+async fn patch_config(
+    state: &State,
+    req: Request<IncomingBody>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let patch: serde_json::Value = read_json(req).await?;
+    let patch = patch
+        .as_object()
+        .ok_or(anyhow!("config patch must be an object"))?;
+    let current = serde_json::to_value(&*state.base_config())?;
+    let mut config = current.clone();
+    let fields = config
+        .as_object_mut()
+        .ok_or(anyhow!("config is not an object"))?;
+    for (key, value) in patch {
+        if !fields.contains_key(key) {
+            return Err(anyhow!("unknown config field `{key}`"));
+        }
+        fields.insert(key.clone(), value.clone());
+    }
+    guard::restore(&mut config, &current);
+    guard::forbid_new_commands(&config, &current)?;
+    let config: Config = serde_json::from_value(config)?;
+    config.resolve()?;
+    config.save().await?;
+    state.set_config(config)?;
+    config_json(&state.base_config())
+}
+
+/// 凭据字段替换为 `[REDACTED]`
+fn config_json(config: &Config) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let mut value = serde_json::to_value(config)?;
+    guard::redact(&mut value);
+    json(&value)
+}
+
+/// Server-Sent Events，每次 flow 变更推送一条
+fn events(state: &State, filter: Option<Filter>) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let stream = BroadcastStream::new(state.flows().subscribe())
+        .filter_map(|flow| flow.ok())
+        .filter(move |flow| filter::allows(filter.as_ref(), flow))
+        .filter_map(|flow| serde_json::to_string(&flow).ok())
+        .map(|flow| Ok(Frame::data(Bytes::from(format!("data: {flow}\n\n")))));
+    Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(StreamBody::new(stream).boxed())
+        .unwrap_or_else(|_| not_found())
+}
+
+async fn read_json<T: DeserializeOwned>(req: Request<IncomingBody>) -> Result<T> {
+    let body = req.into_body().collect().await?.to_bytes();
+    Ok(serde_json::from_slice(&body)?)
+}
+
+pub fn json<T: Serialize + ?Sized>(value: &T) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(util::full(serde_json::to_vec(value)?))?)
+}
+
+fn text(body: String) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(util::full(body));
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, "text/plain; charset=utf-8".parse().unwrap());
+    resp
+}
+
+fn html(page: &'static str) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(util::full(page));
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, "text/html; charset=utf-8".parse().unwrap());
+    resp
+}
+
+pub fn not_found() -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(util::full("not found"));
+    *resp.status_mut() = StatusCode::NOT_FOUND;
+    resp
+}
@@ -0,0 +1,335 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::body::Incoming as IncomingBody;
+use hyper::header::{CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE};
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use openssl::base64;
+use openssl::sha::sha1;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tracing::{debug, Level};
+
+use crate::filter::{self, Filter};
+use crate::flow::{Flow, Summary};
+use crate::logging::{self, LogEvent};
+use crate::state::State;
+use crate::util;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// 客户端只发送过滤条件，更大的帧视为错误
+const MAX_PAYLOAD: u64 = 64 * 1024;
+
+const TEXT: u8 = 0x1;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// tail 的过滤条件，来自 query 或客户端发送的 JSON 文本消息（整体替换）
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+struct Options {
+    /// flow 过滤表达式，同 `/api/flows?filter=`
+    filter: Option<Filter>,
+    /// 日志的最低级别
+    level: Option<String>,
+    /// 日志 target 前缀
+    target: Option<String>,
+    /// 匹配日志 message 的正则，不区分大小写
+    grep: Option<String>,
+    logs: bool,
+    flows: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            filter: None,
+            level: None,
+            target: None,
+            grep: None,
+            logs: true,
+            flows: true,
+        }
+    }
+}
+
+/// 编译后的 [`Options`]
+struct Tail {
+    filter: Option<Filter>,
+    level: Level,
+    target: Option<String>,
+    grep: Option<Regex>,
+    logs: bool,
+    flows: bool,
+}
+
+impl Tail {
+    fn new(options: Options) -> Result<Self> {
+        let level = match options.level.as_deref() {
+            Some(level) => Level::from_str(level).map_err(|_| anyhow!("invalid level {level}"))?,
+            None => Level::TRACE,
+        };
+        let grep = options
+            .grep
+            .as_deref()
+            .map(|re| RegexBuilder::new(re).case_insensitive(true).build())
+            .transpose()?;
+        Ok(Self {
+            filter: options.filter,
+            level,
+            target: options.target,
+            grep,
+            logs: options.logs,
+            flows: options.flows,
+        })
+    }
+
+    fn log(&self, event: &LogEvent) -> bool {
+        // more verbose levels compare greater
+        self.logs
+            && event.level <= self.level
+            && self
+                .target
+                .as_deref()
+                .is_none_or(|target| event.target.starts_with(target))
+            && self
+                .grep
+                .as_ref()
+                .is_none_or(|re| re.is_match(&event.message))
+    }
+
+    /// 只推送结束（完成或出错）的 flow
+    fn flow(&self, flow: &Flow) -> bool {
+        self.flows
+            && (flow.complete || flow.error.is_some())
+            && filter::allows(self.filter.as_ref(), flow)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Message<'a> {
+    Log(&'a LogEvent),
+    Flow(Summary),
+    /// 客户端太慢，broadcast 丢弃的条数
+    Lagged {
+        skipped: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// `GET /api/tail` 升级为 WebSocket，推送日志与 flow 摘要，每条一个 JSON 文本帧
+pub fn upgrade(
+    state: &State,
+    req: Request<IncomingBody>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let is_websocket = req
+        .headers()
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let Some(key) = req
+        .headers()
+        .get(SEC_WEBSOCKET_KEY)
+        .filter(|_| is_websocket)
+    else {
+        bail!("expected a WebSocket upgrade");
+    };
+    let accept = accept_key(key.as_bytes());
+    let options = query_options(req.uri().query())?;
+    let tail = Tail::new(options)?;
+
+    let state = state.clone();
+    tokio::task::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                if let Err(e) = run(&state, TokioIo::new(upgraded), tail).await {
+                    debug!("tail closed: {e}");
+                }
+            }
+            Err(e) => debug!("tail upgrade failed: {e}"),
+        }
+    });
+
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .body(util::empty())?)
+}
+
+fn query_options(query: Option<&str>) -> Result<Options> {
+    let fields: serde_json::Map<String, serde_json::Value> =
+        form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(key, value)| {
+                let value = match value.as_ref() {
+                    "true" => serde_json::Value::Bool(true),
+                    "false" => serde_json::Value::Bool(false),
+                    _ => serde_json::Value::String(value.into_owned()),
+                };
+                (key.into_owned(), value)
+            })
+            .collect();
+    Ok(serde_json::from_value(serde_json::Value::Object(fields))?)
+}
+
+async fn run<S>(state: &State, io: S, mut tail: Tail) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(io);
+    let mut logs = logging::subscribe();
+    let mut flows = state.flows().subscribe();
+
+    // reads are not cancel safe, so frames come through a channel
+    let (tx, mut incoming) = mpsc::channel(8);
+    tokio::task::spawn(async move {
+        let mut reader = reader;
+        loop {
+            let frame = read_frame(&mut reader).await;
+            let done = !matches!(frame, Ok((opcode, _)) if opcode != CLOSE);
+            if tx.send(frame).await.is_err() || done {
+                break;
+            }
+        }
+    });
+
+    loop {
+        let message = tokio::select! {
+            frame = incoming.recv() => {
+                let Some(frame) = frame else {
+                    return Ok(());
+                };
+                let (opcode, payload) = frame?;
+                match opcode {
+                    CLOSE => {
+                        writer.write_all(&encode(CLOSE, &[])).await?;
+                        return Ok(());
+                    }
+                    PING => {
+                        writer.write_all(&encode(PONG, &payload)).await?;
+                        continue;
+                    }
+                    TEXT => match serde_json::from_slice(&payload)
+                        .map_err(anyhow::Error::from)
+                        .and_then(Tail::new)
+                    {
+                        Ok(updated) => {
+                            tail = updated;
+                            continue;
+                        }
+                        Err(e) => serde_json::to_vec(&Message::Error { message: e.to_string() })?,
+                    },
+                    _ => continue,
+                }
+            }
+            event = logs.recv() => match event {
+                Ok(event) if tail.log(&event) => serde_json::to_vec(&Message::Log(&event))?,
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => serde_json::to_vec(&Message::Lagged { skipped })?,
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            flow = flows.recv() => match flow {
+                Ok(flow) if tail.flow(&flow) => {
+                    serde_json::to_vec(&Message::Flow(Summary::from(&flow)))?
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => serde_json::to_vec(&Message::Lagged { skipped })?,
+                Err(RecvError::Closed) => return Ok(()),
+            },
+        };
+        writer.write_all(&encode(TEXT, &message)).await?;
+    }
+}
+
+fn accept_key(key: &[u8]) -> String {
+    base64::encode_block(&sha1(&[key, GUID.as_bytes()].concat()))
+}
+
+/// 服务端的帧不加掩码
+fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// 读取一帧并去掉掩码；分片的消息按帧各自返回
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(u8, Vec<u8>)> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head).await?;
+    let opcode = head[0] & 0x0f;
+    let masked = head[1] & 0x80 != 0;
+    let len = match head[1] & 0x7f {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        len => len as u64,
+    };
+    if len > MAX_PAYLOAD {
+        bail!("frame of {len} bytes is too large");
+    }
+    let mut mask = [0; 4];
+    if masked {
+        reader.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    if masked {
+        payload
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b ^= mask[i % 4]);
+    }
+    Ok((opcode, payload))
+}
+
+#[tokio::test]
+async fn should_frame_websocket_messages() {
+    // RFC 6455 section 1.3
+    assert_eq!(
+        accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+    let frame = encode(TEXT, &[b'x'; 300]);
+    assert_eq!(&frame[..4], &[0x81, 126, 0x01, 0x2c]);
+    assert_eq!(
+        read_frame(&mut &frame[..]).await.unwrap(),
+        (TEXT, vec![b'x'; 300])
+    );
+
+    // masked "Hello" from section 5.7
+    let masked = [
+        0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+    ];
+    assert_eq!(
+        read_frame(&mut &masked[..]).await.unwrap(),
+        (TEXT, b"Hello".to_vec())
+    );
+
+    let options = query_options(Some("filter=~m+POST&level=warn&flows=false")).unwrap();
+    let tail = Tail::new(options).unwrap();
+    assert!(!tail.flows);
+    assert_eq!(tail.level, Level::WARN);
+    assert!(Tail::new(query_options(Some("level=loud")).unwrap()).is_err());
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use openssl::ex_data::Index;
+use openssl::ssl::{select_next_proto, AlpnError, Ssl, SslRef};
+
+/// 探测源站时提供的协议
+pub const OFFER: &[u8] = b"\x02h2\x08http/1.1";
+
+/// 解析请求时两端都是 HTTP/1.1
+pub const HTTP1: &[u8] = b"\x08http/1.1";
+
+/// 源站在 ALPN 中选择的协议，按 `host:port` 缓存；源站不支持 ALPN 时为 None
+#[derive(Clone, Default)]
+pub struct AlpnCache {
+    inner: Arc<Mutex<HashMap<String, Option<Vec<u8>>>>>,
+}
+
+impl AlpnCache {
+    pub fn get(&self, addr: &str) -> Option<Option<Vec<u8>>> {
+        self.inner.lock().ok()?.get(addr).cloned()
+    }
+
+    pub fn record(&self, addr: &str, selected: Option<&[u8]>) {
+        if let Ok(mut map) = self.inner.lock() {
+            map.insert(addr.to_owned(), selected.map(<[u8]>::to_vec));
+        }
+    }
+}
+
+/// 单个协议的 wire 格式
+pub fn wire(protocol: &[u8]) -> Vec<u8> {
+    let mut wire = Vec::with_capacity(protocol.len() + 1);
+    wire.push(protocol.len() as u8);
+    wire.extend_from_slice(protocol);
+    wire
+}
+
+/// 连接上要向客户端通告的协议（wire 格式），MITM 的 context 是共用的
+pub fn index() -> Index<Ssl, Vec<u8>> {
+    static INDEX: OnceLock<Index<Ssl, Vec<u8>>> = OnceLock::new();
+    *INDEX.get_or_init(|| Ssl::new_ex_index().expect("allocate ALPN ex data index"))
+}
+
+/// 没有设置协议的连接不协商 ALPN
+pub fn select<'a>(ssl: &mut SslRef, client: &'a [u8]) -> Result<&'a [u8], AlpnError> {
+    let protocols = ssl.ex_data(index()).ok_or(AlpnError::NOACK)?;
+    select_next_proto(protocols, client).ok_or(AlpnError::NOACK)
+}
+
+#[tokio::test]
+async fn should_advertise_origin_protocol() {
+    use openssl::ssl::{SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
+    use std::pin::Pin;
+    use tokio_openssl::SslStream;
+
+    let root = crate::ca::CA::generate().await.unwrap();
+    let leaf = root.sign("localhost".to_owned()).unwrap();
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    builder.set_alpn_select_callback(select);
+    let acceptor = builder.build();
+    let connector = SslConnector::builder(SslMethod::tls()).unwrap().build();
+
+    for (advertised, expected) in [
+        (Some(wire(b"h2")), Some(&b"h2"[..])),
+        (Some(HTTP1.to_vec()), Some(b"http/1.1")),
+        (None, None),
+    ] {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let mut ssl = Ssl::new(acceptor.context()).unwrap();
+        ssl.set_certificate(&leaf.cert).unwrap();
+        ssl.set_private_key(&leaf.key).unwrap();
+        if let Some(advertised) = advertised {
+            ssl.set_ex_data(index(), advertised);
+        }
+        let mut server = SslStream::new(ssl, server).unwrap();
+        let server = tokio::spawn(async move {
+            Pin::new(&mut server).accept().await.unwrap();
+            server
+        });
+
+        let mut ssl = connector
+            .configure()
+            .unwrap()
+            .into_ssl("localhost")
+            .unwrap();
+        ssl.set_verify(SslVerifyMode::NONE);
+        ssl.set_alpn_protos(OFFER).unwrap();
+        let mut client = SslStream::new(ssl, client).unwrap();
+        Pin::new(&mut client).connect().await.unwrap();
+        assert_eq!(client.ssl().selected_alpn_protocol(), expected);
+        server.await.unwrap();
+    }
+}
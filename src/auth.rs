@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::header::{HeaderValue, ACCEPT, AUTHORIZATION, CONTENT_TYPE, HOST};
+use hyper::{Request, Uri};
+use motore::Service;
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::client::HttpClient;
+use crate::config::{AuthRule, OAuth2Config};
+use crate::flow;
+use crate::reverse::Target;
+use crate::state::{ClientState, State};
+use crate::util;
+
+/// `token_file`、`token_command`、`oauth2` 得到的 token，按来源缓存到过期
+#[derive(Clone, Default)]
+pub struct Tokens {
+    inner: Arc<Mutex<HashMap<String, (String, Instant)>>>,
+    /// 同一时间只获取一次，并发的请求等待后直接用缓存
+    fetching: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Tokens {
+    /// 规则对应的 `Authorization` 值，已标记为 sensitive
+    pub async fn authorization(&self, shared: &State, rule: &AuthRule) -> Result<HeaderValue> {
+        let ttl = Duration::from_secs(rule.token_ttl_secs);
+        let key = key(rule);
+        let value = if let Some(token) = &rule.bearer {
+            format!("{} {token}", rule.scheme)
+        } else if let Some(username) = &rule.username {
+            format!(
+                "Basic {}",
+                basic(username, rule.password.as_deref().unwrap_or_default())
+            )
+        } else if let (Some(path), Some(key)) = (&rule.token_file, key.clone()) {
+            let token = self
+                .cached(key, Duration::ZERO, async {
+                    Ok((tokio::fs::read_to_string(path).await?, ttl))
+                })
+                .await?;
+            format!("{} {token}", rule.scheme)
+        } else if let (Some((program, args)), Some(key)) =
+            (rule.token_command.split_first(), key.clone())
+        {
+            let token = self
+                .cached(key, Duration::ZERO, async {
+                    let output = tokio::process::Command::new(program)
+                        .args(args)
+                        .kill_on_drop(true)
+                        .output()
+                        .await?;
+                    if !output.status.success() {
+                        bail!(
+                            "{program} exited with {}: {}",
+                            output.status,
+                            String::from_utf8_lossy(&output.stderr).trim()
+                        );
+                    }
+                    Ok((String::from_utf8(output.stdout)?, ttl))
+                })
+                .await?;
+            format!("{} {token}", rule.scheme)
+        } else if let (Some(oauth2), Some(key)) = (&rule.oauth2, key) {
+            let refresh_before = Duration::from_secs(oauth2.refresh_before_secs);
+            let token = self
+                .cached(key, refresh_before, async {
+                    let (token, expires_in) = client_credentials(shared, oauth2).await?;
+                    debug!(
+                        token_url = oauth2.token_url,
+                        expires_in, "oauth2 token fetched"
+                    );
+                    Ok((token, expires_in.map_or(ttl, Duration::from_secs)))
+                })
+                .await?;
+            format!("{} {token}", rule.scheme)
+        } else {
+            bail!("auth rule for {:?} has no credential", rule.hosts);
+        };
+        let mut value = HeaderValue::from_str(&value)
+            .map_err(|_| anyhow!("credential for {:?} is not a valid header", rule.hosts))?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+
+    /// 上游拒绝了缓存的 token，下个请求重新获取
+    pub fn invalidate(&self, rule: &AuthRule) {
+        if let (Some(key), Ok(mut map)) = (key(rule), self.inner.lock()) {
+            map.remove(&key);
+        }
+    }
+
+    /// 离过期不到 `refresh_before` 时重新获取，失败时未过期的旧 token 继续使用
+    async fn cached(
+        &self,
+        key: String,
+        refresh_before: Duration,
+        fetch: impl Future<Output = Result<(String, Duration)>>,
+    ) -> Result<String> {
+        let lookup = || {
+            let map = self.inner.lock().ok()?;
+            let (token, expires) = map.get(&key)?;
+            let now = Instant::now();
+            (now < *expires).then(|| (token.clone(), now + refresh_before < *expires))
+        };
+        if let Some((token, true)) = lookup() {
+            return Ok(token);
+        }
+        let _fetching = self.fetching.lock().await;
+        let stale = match lookup() {
+            Some((token, true)) => return Ok(token),
+            Some((token, false)) => Some(token),
+            None => None,
+        };
+        let fetched = fetch.await.and_then(|(token, lifetime)| {
+            let token = token.trim().to_owned();
+            if token.is_empty() {
+                bail!("{key} produced an empty token");
+            }
+            Ok((token, lifetime))
+        });
+        match (fetched, stale) {
+            (Ok((token, lifetime)), _) => {
+                if let Ok(mut map) = self.inner.lock() {
+                    map.insert(key, (token.clone(), Instant::now() + lifetime));
+                }
+                Ok(token)
+            }
+            (Err(e), Some(token)) => {
+                warn!("refresh {key} failed, keep the current token: {e:#}");
+                Ok(token)
+            }
+            (Err(e), None) => Err(e),
+        }
+    }
+}
+
+/// 需要缓存的凭据来源
+fn key(rule: &AuthRule) -> Option<String> {
+    if rule.bearer.is_some() || rule.username.is_some() {
+        None
+    } else if let Some(path) = &rule.token_file {
+        Some(format!("file:{}", path.display()))
+    } else if !rule.token_command.is_empty() {
+        Some(format!("command:{}", rule.token_command.join(" ")))
+    } else {
+        rule.oauth2.as_ref().map(|oauth2| {
+            format!(
+                "oauth2:{} {} {}",
+                oauth2.token_url,
+                oauth2.client_id,
+                oauth2.scope.as_deref().unwrap_or_default()
+            )
+        })
+    }
+}
+
+fn basic(username: &str, password: &str) -> String {
+    openssl::base64::encode_block(format!("{username}:{password}").as_bytes())
+}
+
+/// 默认用 `Basic` 认证 client，RFC 6749 2.3.1 要求 id 与 secret 先做表单编码
+fn token_request(
+    oauth2: &OAuth2Config,
+    target: &Target,
+) -> Result<Request<BoxBody<Bytes, hyper::Error>>> {
+    let uri: Uri = oauth2.token_url.parse()?;
+    let mut form = form_urlencoded::Serializer::new(String::new());
+    form.append_pair("grant_type", "client_credentials");
+    if let Some(scope) = &oauth2.scope {
+        form.append_pair("scope", scope);
+    }
+    if let Some(audience) = &oauth2.audience {
+        form.append_pair("audience", audience);
+    }
+    let mut req = Request::post(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(HOST, &target.authority)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(ACCEPT, "application/json");
+    if oauth2.credentials_in_body {
+        form.append_pair("client_id", &oauth2.client_id);
+        form.append_pair("client_secret", &oauth2.client_secret);
+    } else {
+        let encode = |s: &str| form_urlencoded::byte_serialize(s.as_bytes()).collect::<String>();
+        req = req.header(
+            AUTHORIZATION,
+            format!(
+                "Basic {}",
+                basic(&encode(&oauth2.client_id), &encode(&oauth2.client_secret))
+            ),
+        );
+    }
+    Ok(req.body(util::full(form.finish()))?)
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// 经由上游连接池向 `token_url` 请求 token，返回 token 与有效秒数
+async fn client_credentials(
+    shared: &State,
+    oauth2: &OAuth2Config,
+) -> Result<(String, Option<u64>)> {
+    let target = Target::parse(&oauth2.token_url, false)?;
+    let req = token_request(oauth2, &target)?;
+    let mut state = ClientState {
+        id: flow::next_id(),
+        addr: target.addr,
+        sni: target.host,
+        is_secure: target.secure,
+        parse: false,
+        transcript: None,
+        timings: Default::default(),
+        shared: shared.clone(),
+    };
+    let resp = HttpClient.call(&mut state, req).await?;
+    let status = resp.status();
+    let body = resp.into_body().collect().await?.to_bytes();
+    if !status.is_success() {
+        bail!(
+            "{} returned {status}: {}",
+            oauth2.token_url,
+            String::from_utf8_lossy(&body)
+        );
+    }
+    let token: TokenResponse = serde_json::from_slice(&body)?;
+    Ok((token.access_token, token.expires_in))
+}
+
+#[tokio::test]
+async fn should_build_authorization() {
+    let shared = State::new(crate::config::Config::default()).await.unwrap();
+    let tokens = Tokens::default();
+    let rule = AuthRule {
+        username: Some("user".to_owned()),
+        password: Some("pass".to_owned()),
+        ..Default::default()
+    };
+    let value = tokens.authorization(&shared, &rule).await.unwrap();
+    assert_eq!(value, "Basic dXNlcjpwYXNz");
+    assert!(value.is_sensitive());
+
+    let path = std::env::temp_dir().join(format!("auth-token-{}", ulid::Ulid::new()));
+    std::fs::write(&path, "abc\n").unwrap();
+    let rule = AuthRule {
+        scheme: "token".to_owned(),
+        token_file: Some(path.clone()),
+        ..Default::default()
+    };
+    assert_eq!(
+        tokens.authorization(&shared, &rule).await.unwrap(),
+        "token abc"
+    );
+    // cached until the ttl expires
+    std::fs::write(&path, "def").unwrap();
+    assert_eq!(
+        tokens.authorization(&shared, &rule).await.unwrap(),
+        "token abc"
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    let rule = AuthRule {
+        token_command: ["echo".to_owned(), "xyz".to_owned()].to_vec(),
+        token_ttl_secs: 0,
+        ..Default::default()
+    };
+    assert_eq!(
+        tokens.authorization(&shared, &rule).await.unwrap(),
+        "Bearer xyz"
+    );
+    assert!(tokens
+        .authorization(&shared, &AuthRule::default())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn should_refresh_oauth2_tokens() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::server::conn::http1::Builder as ServerBuilder;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use hyper_util::rt::TokioIo;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let issued = Arc::new(AtomicUsize::new(0));
+    let counter = issued.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let counter = counter.clone();
+            let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                let counter = counter.clone();
+                async move {
+                    assert_eq!(req.uri().path(), "/oauth/token");
+                    assert_eq!(req.headers()[AUTHORIZATION], "Basic aWQ6cyUyRmM=");
+                    let form = req.into_body().collect().await?.to_bytes();
+                    assert_eq!(&form[..], b"grant_type=client_credentials&scope=read");
+                    let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                    let body = format!(r#"{{"access_token":"t{n}","expires_in":90}}"#);
+                    Ok::<_, hyper::Error>(Response::new(util::full(body)))
+                }
+            });
+            tokio::spawn(ServerBuilder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+
+    let shared = State::new(crate::config::Config::default()).await.unwrap();
+    let tokens = Tokens::default();
+    let mut rule = AuthRule {
+        oauth2: Some(OAuth2Config {
+            token_url: format!("http://{addr}/oauth/token"),
+            client_id: "id".to_owned(),
+            client_secret: "s/c".to_owned(),
+            scope: Some("read".to_owned()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    assert_eq!(
+        tokens.authorization(&shared, &rule).await.unwrap(),
+        "Bearer t1"
+    );
+    assert_eq!(
+        tokens.authorization(&shared, &rule).await.unwrap(),
+        "Bearer t1"
+    );
+    tokens.invalidate(&rule);
+    assert_eq!(
+        tokens.authorization(&shared, &rule).await.unwrap(),
+        "Bearer t2"
+    );
+    // within refresh_before_secs of expiry
+    rule.oauth2.as_mut().unwrap().refresh_before_secs = 120;
+    assert_eq!(
+        tokens.authorization(&shared, &rule).await.unwrap(),
+        "Bearer t3"
+    );
+    assert_eq!(issued.load(Ordering::SeqCst), 3);
+}
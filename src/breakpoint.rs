@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use bytes::Bytes;
+use hyper::ext::ReasonPhrase;
+use hyper::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use hyper::{http, HeaderMap, Method, StatusCode, Uri};
+use serde::{Deserialize, Serialize, Serializer};
+use tokio::sync::oneshot;
+use tracing::warn;
+use ulid::Ulid;
+
+use crate::replay;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Request,
+    Response,
+}
+
+/// 暂停中的请求或响应，管理接口编辑的就是这些字段
+#[derive(Serialize, Debug, Clone)]
+pub struct Paused {
+    /// 同 flow id
+    pub id: Ulid,
+    pub phase: Phase,
+    pub host: String,
+    pub method: String,
+    pub uri: String,
+    pub status: Option<u16>,
+    pub headers: Vec<(String, String)>,
+    #[serde(serialize_with = "lossy")]
+    pub body: Bytes,
+}
+
+/// 为空的字段保持原样
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Edit {
+    pub method: Option<String>,
+    pub uri: Option<String>,
+    pub status: Option<u16>,
+    pub headers: Option<Vec<(String, String)>>,
+    pub body: Option<String>,
+}
+
+impl Edit {
+    /// 管理接口收到时检查，之后应用时不会失败
+    pub fn check(&self) -> Result<()> {
+        if let Some(method) = &self.method {
+            Method::from_bytes(method.as_bytes())?;
+        }
+        if let Some(uri) = &self.uri {
+            uri.parse::<Uri>()?;
+        }
+        if let Some(status) = self.status {
+            StatusCode::from_u16(status)?;
+        }
+        if let Some(headers) = &self.headers {
+            replay::headers(headers)?;
+        }
+        Ok(())
+    }
+
+    pub fn apply_request(self, parts: &mut http::request::Parts, body: &mut Bytes) {
+        if let Some(method) = self.method.as_ref().and_then(|m| m.parse().ok()) {
+            parts.method = method;
+        }
+        if let Some(uri) = self.uri.as_ref().and_then(|u| u.parse().ok()) {
+            parts.uri = uri;
+        }
+        self.apply(&mut parts.headers, body);
+    }
+
+    pub fn apply_response(self, parts: &mut http::response::Parts, body: &mut Bytes) {
+        if let Some(status) = self.status.and_then(|s| StatusCode::from_u16(s).ok()) {
+            parts.status = status;
+            parts.extensions.remove::<ReasonPhrase>();
+        }
+        self.apply(&mut parts.headers, body);
+    }
+
+    fn apply(self, headers: &mut HeaderMap, body: &mut Bytes) {
+        if let Some(edited) = self.headers.and_then(|h| replay::headers(&h).ok()) {
+            *headers = edited;
+        }
+        if let Some(edited) = self.body {
+            *body = edited.into();
+            headers.remove(TRANSFER_ENCODING);
+            headers.insert(CONTENT_LENGTH, body.len().into());
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Decision {
+    Resume(Edit),
+    /// 请求不再转发，响应不再返回，客户端收到 502
+    Drop,
+}
+
+struct Pending {
+    paused: Paused,
+    tx: oneshot::Sender<Decision>,
+}
+
+/// 等待处理的断点
+#[derive(Clone, Default)]
+pub struct Breakpoints {
+    pending: Arc<Mutex<HashMap<Ulid, Pending>>>,
+}
+
+/// 客户端断开、请求被取消时移除断点
+struct Guard<'a> {
+    breakpoints: &'a Breakpoints,
+    id: Ulid,
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut pending) = self.breakpoints.pending.lock() {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+impl Breakpoints {
+    /// 等待管理接口的决定，超时后原样放行
+    pub async fn pause(&self, paused: Paused, timeout: Duration) -> Decision {
+        let id = paused.id;
+        let (tx, rx) = oneshot::channel();
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.insert(id, Pending { paused, tx });
+        }
+        let _guard = Guard {
+            breakpoints: self,
+            id,
+        };
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(decision)) => decision,
+            Ok(Err(_)) => Decision::Resume(Edit::default()),
+            Err(_) => {
+                warn!(%id, "breakpoint timed out, resuming");
+                Decision::Resume(Edit::default())
+            }
+        }
+    }
+
+    /// 返回 false 表示没有这个断点
+    pub fn decide(&self, id: Ulid, decision: Decision) -> bool {
+        let pending = self
+            .pending
+            .lock()
+            .ok()
+            .and_then(|mut pending| pending.remove(&id));
+        match pending {
+            Some(pending) => pending.tx.send(decision).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> Vec<Paused> {
+        let mut list: Vec<_> = self
+            .pending
+            .lock()
+            .map(|pending| pending.values().map(|p| p.paused.clone()).collect())
+            .unwrap_or_default();
+        list.sort_by_key(|paused| paused.id);
+        list
+    }
+}
+
+fn lossy<S: Serializer>(body: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(body))
+}
+
+#[tokio::test]
+async fn should_resume_with_edit() {
+    let breakpoints = Breakpoints::default();
+    let id = Ulid::new();
+    let paused = Paused {
+        id,
+        phase: Phase::Request,
+        host: "example.com".to_owned(),
+        method: "GET".to_owned(),
+        uri: "/".to_owned(),
+        status: None,
+        headers: vec![],
+        body: Bytes::new(),
+    };
+    let waiting = {
+        let breakpoints = breakpoints.clone();
+        tokio::spawn(async move { breakpoints.pause(paused, Duration::from_secs(5)).await })
+    };
+    while breakpoints.list().is_empty() {
+        tokio::task::yield_now().await;
+    }
+    let edit = Edit {
+        method: Some("POST".to_owned()),
+        ..Default::default()
+    };
+    assert!(breakpoints.decide(id, Decision::Resume(edit)));
+    match waiting.await.unwrap() {
+        Decision::Resume(edit) => assert_eq!(edit.method.as_deref(), Some("POST")),
+        Decision::Drop => panic!("dropped"),
+    }
+    assert!(breakpoints.list().is_empty());
+    assert!(!breakpoints.decide(id, Decision::Drop));
+}
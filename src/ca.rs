@@ -1,12 +1,14 @@
-use std::io::Error;
+use std::io::{Error, IsTerminal};
 use std::path::Path;
 
 use openssl::asn1::Asn1Time;
 use openssl::bn::{BigNum, MsbOption};
 use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
+use openssl::pkcs12::Pkcs12;
 use openssl::pkey::{PKey, Private};
 use openssl::rsa::Rsa;
+use openssl::symm::Cipher;
 use openssl::x509::extension::{
     AuthorityKeyIdentifier, BasicConstraints, KeyUsage, SubjectAlternativeName,
     SubjectKeyIdentifier,
@@ -14,7 +16,8 @@ use openssl::x509::extension::{
 use openssl::x509::{X509NameBuilder, X509Req, X509ReqBuilder, X509};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::task::{self, JoinHandle};
+use tokio::task;
+use tracing::info;
 
 #[derive(Debug, Clone)]
 pub struct CA {
@@ -22,36 +25,126 @@ pub struct CA {
     pub key: PKey<Private>,
 }
 
+/// 加密私钥的口令所在的环境变量
+pub const PASSPHRASE_ENV: &str = "HTTP_PROXY_CA_PASSPHRASE";
+
 impl CA {
-    pub async fn load_or_create(cert_path: &Path, key_path: &Path) -> Result<Self, Error> {
+    /// 有 `passphrase` 时私钥加密保存，已有的明文私钥会改写为加密的；
+    /// 证书与私钥可以是 PEM 或 DER，两个路径也可以指向同一个 PKCS#12 或 PEM 文件
+    pub async fn load_or_create(
+        cert_path: &Path,
+        key_path: &Path,
+        passphrase: Option<&[u8]>,
+    ) -> Result<Self, Error> {
         let open_result = tokio::try_join!(File::open(cert_path), File::open(key_path));
-        if let Ok((mut cert_file, mut key_file)) = open_result {
+        if let Ok((cert_file, key_file)) = open_result {
             // 已存在
-            let mut cert_pem = vec![];
-            let mut key_pem = vec![];
-            tokio::try_join!(
-                cert_file.read_to_end(&mut cert_pem),
-                key_file.read_to_end(&mut key_pem)
-            )?;
-
-            let cert_future = task::spawn_blocking(move || X509::from_pem(&cert_pem));
-            let key_future = task::spawn_blocking(move || PKey::private_key_from_pem(&key_pem));
-            let (cert, key) = tokio::try_join!(flatten(cert_future), flatten(key_future))?;
-
-            Ok(Self { cert, key })
+            let (ca, plain) = Self::read(cert_file, key_file, passphrase).await?;
+            if passphrase.is_some() && plain {
+                write_key(key_path, &ca.key, passphrase).await?;
+                info!("Encrypted root CA key {}", key_path.display());
+            }
+            Ok(ca)
         } else {
             // 重新生成
-            let ca = task::spawn_blocking(mk_ca_cert).await?;
-            if let Ok(ref ca) = ca {
-                let cert_pem = ca.cert.to_pem()?;
-                let key_pem = ca.key.private_key_to_pem_pkcs8()?;
-
-                let (mut cert_file, mut key_file) =
-                    tokio::try_join!(File::create(cert_path), File::create(key_path))?;
-                tokio::try_join!(cert_file.write(&cert_pem), key_file.write(&key_pem))?;
-            }
-            ca
+            let ca = Self::generate().await?;
+            ca.save(cert_path, key_path, passphrase).await?;
+            Ok(ca)
+        }
+    }
+
+    /// 新的自签名 root CA，有效期 20 年
+    pub async fn generate() -> Result<Self, Error> {
+        task::spawn_blocking(mk_ca_cert).await?
+    }
+
+    /// 只读取已有的文件，不存在时报错
+    pub async fn load(
+        cert_path: &Path,
+        key_path: &Path,
+        passphrase: Option<&[u8]>,
+    ) -> Result<Self, Error> {
+        let (cert_file, key_file) = tokio::try_join!(File::open(cert_path), File::open(key_path))?;
+        Ok(Self::read(cert_file, key_file, passphrase).await?.0)
+    }
+
+    /// 从一个同时含证书与私钥的文件导入已有的 CA：mitmproxy 的 `mitmproxy-ca.pem`、
+    /// Charles 等导出的 PKCS#12（口令为 `passphrase`，没有时为空）
+    pub async fn import(source: &Path, passphrase: Option<&[u8]>) -> Result<Self, Error> {
+        let bundle = tokio::fs::read(source).await?;
+        let passphrase = passphrase.map(<[u8]>::to_vec);
+        let (ca, _) =
+            task::spawn_blocking(move || decode(&bundle, &bundle, passphrase.as_deref())).await??;
+        Ok(ca)
+    }
+
+    /// 证书写为 PEM，私钥见 [`write_key`]
+    pub async fn save(
+        &self,
+        cert_path: &Path,
+        key_path: &Path,
+        passphrase: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        let cert_pem = self.cert.to_pem()?;
+        let mut cert_file = File::create(cert_path).await?;
+        cert_file.write_all(&cert_pem).await?;
+        cert_file.flush().await?;
+        write_key(key_path, &self.key, passphrase).await
+    }
+
+    /// 同时返回私钥文件是否为可以改写加密的明文 PEM
+    async fn read(
+        mut cert_file: File,
+        mut key_file: File,
+        passphrase: Option<&[u8]>,
+    ) -> Result<(Self, bool), Error> {
+        let mut cert = vec![];
+        let mut key = vec![];
+        tokio::try_join!(
+            cert_file.read_to_end(&mut cert),
+            key_file.read_to_end(&mut key)
+        )?;
+        let passphrase = passphrase.map(<[u8]>::to_vec);
+        task::spawn_blocking(move || decode(&cert, &key, passphrase.as_deref())).await?
+    }
+
+    /// 证书在有效期内且与私钥匹配
+    pub fn verify(&self) -> anyhow::Result<()> {
+        let now = Asn1Time::days_from_now(0)?;
+        if self.cert.not_before() > now {
+            return Err(anyhow::anyhow!("root CA is not valid yet"));
+        }
+        if self.cert.not_after() < now {
+            return Err(anyhow::anyhow!(
+                "root CA expired at {}",
+                self.cert.not_after()
+            ));
+        }
+        if !self.cert.public_key()?.public_eq(&self.key) {
+            return Err(anyhow::anyhow!(
+                "root CA key does not match the certificate"
+            ));
         }
+        Ok(())
+    }
+
+    /// 已过期或将在 `days` 天内过期
+    pub fn expires_within(&self, days: u32) -> bool {
+        Asn1Time::days_from_now(days).map_or(true, |limit| self.cert.not_after() <= limit)
+    }
+
+    /// 证书 DER 的 SHA-256，冒号分隔的十六进制
+    pub fn fingerprint(&self) -> String {
+        self.cert
+            .digest(MessageDigest::sha256())
+            .map(|digest| {
+                digest
+                    .iter()
+                    .map(|b| format!("{b:02X}"))
+                    .collect::<Vec<_>>()
+                    .join(":")
+            })
+            .unwrap_or_default()
     }
 
     /// 签发
@@ -60,12 +153,120 @@ impl CA {
     }
 }
 
-async fn flatten<T>(handle: JoinHandle<Result<T, ErrorStack>>) -> Result<T, Error> {
-    match handle.await {
-        Ok(Ok(result)) => Ok(result),
-        Ok(Err(err)) => Err(err.into()),
-        Err(err) => Err(err.into()),
+/// 口令取自 `HTTP_PROXY_CA_PASSPHRASE`，未设置时在终端输入
+pub fn passphrase() -> anyhow::Result<Vec<u8>> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        if passphrase.is_empty() {
+            return Err(anyhow::anyhow!("{PASSPHRASE_ENV} is empty"));
+        }
+        return Ok(passphrase.into_bytes());
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow::anyhow!(
+            "root CA key is encrypted: set {PASSPHRASE_ENV} or run from a terminal"
+        ));
+    }
+    eprint!("Root CA key passphrase: ");
+    // no echo while typing
+    #[cfg(unix)]
+    let hidden = std::process::Command::new("stty")
+        .arg("-echo")
+        .status()
+        .is_ok_and(|status| status.success());
+    let mut line = String::new();
+    let read = std::io::stdin().read_line(&mut line);
+    #[cfg(unix)]
+    if hidden {
+        let _ = std::process::Command::new("stty").arg("echo").status();
+        eprintln!();
     }
+    read?;
+    let passphrase = line.trim_end_matches(['\r', '\n']);
+    if passphrase.is_empty() {
+        return Err(anyhow::anyhow!("empty passphrase"));
+    }
+    Ok(passphrase.as_bytes().to_vec())
+}
+
+/// 有口令时以 PKCS#8 + AES-256-CBC 加密；unix 下只有所有者可读写
+pub async fn write_key(
+    path: &Path,
+    key: &PKey<Private>,
+    passphrase: Option<&[u8]>,
+) -> Result<(), Error> {
+    let pem = match passphrase {
+        Some(passphrase) => {
+            key.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase)?
+        }
+        None => key.private_key_to_pem_pkcs8()?,
+    };
+    let mut file = File::create(path).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+    }
+    file.write_all(&pem).await?;
+    // tokio 的 File 在后台写入，flush 后才算写完
+    file.flush().await
+}
+
+/// 按内容识别格式：PEM、DER 或 PKCS#12（证书文件不是证书时当作 PKCS#12，忽略私钥文件）
+fn decode(cert: &[u8], key: &[u8], passphrase: Option<&[u8]>) -> Result<(CA, bool), Error> {
+    if !is_pem(cert) && X509::from_der(cert).is_err() {
+        let password = std::str::from_utf8(passphrase.unwrap_or_default())
+            .map_err(|_| Error::other("PKCS#12 passphrase is not UTF-8"))?;
+        let bundle = Pkcs12::from_der(cert)?
+            .parse2(password)
+            .map_err(|e| match passphrase {
+                None => Error::other(format!(
+                    "PKCS#12 bundle is password protected, set {PASSPHRASE_ENV}"
+                )),
+                Some(_) => Error::other(format!("PKCS#12 bundle: {e}")),
+            })?;
+        return match (bundle.cert, bundle.pkey) {
+            (Some(cert), Some(key)) => Ok((CA { cert, key }, false)),
+            _ => Err(Error::other(
+                "PKCS#12 bundle has no certificate or private key",
+            )),
+        };
+    }
+
+    let cert = if is_pem(cert) {
+        X509::from_pem(cert)?
+    } else {
+        X509::from_der(cert)?
+    };
+    if !is_pem(key) {
+        let key = match passphrase {
+            Some(passphrase) => PKey::private_key_from_pkcs8_passphrase(key, passphrase)
+                .or_else(|_| PKey::private_key_from_der(key))?,
+            None => PKey::private_key_from_der(key)?,
+        };
+        return Ok((CA { cert, key }, false));
+    }
+    let encrypted = contains(key, b"ENCRYPTED");
+    // 与证书在同一个文件（如 mitmproxy-ca.pem）时不改写
+    let plain = !encrypted && !contains(key, b"CERTIFICATE");
+    let key = match (encrypted, passphrase) {
+        (true, None) => {
+            return Err(Error::other(
+                "root CA key is encrypted, enable root_ca_key_encrypted",
+            ))
+        }
+        (_, Some(passphrase)) => PKey::private_key_from_pem_passphrase(key, passphrase)?,
+        (false, None) => PKey::private_key_from_pem(key)?,
+    };
+    Ok((CA { cert, key }, plain))
+}
+
+fn is_pem(data: &[u8]) -> bool {
+    contains(data, b"-----BEGIN ")
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|w| w == needle)
 }
 
 fn mk_ca_cert() -> Result<CA, Error> {
@@ -189,7 +390,9 @@ async fn signed_and_verified() {
     let cert_path = std::path::PathBuf::from("cert.crt");
     let key_path = std::path::PathBuf::from("key.pem");
 
-    let ca = CA::load_or_create(&cert_path, &key_path).await.unwrap();
+    let ca = CA::load_or_create(&cert_path, &key_path, None)
+        .await
+        .unwrap();
     let ca_cert = ca.cert.clone();
     let signed_ca = ca.sign("localhost".to_string()).unwrap();
     assert_eq!(
@@ -197,3 +400,78 @@ async fn signed_and_verified() {
         openssl::x509::X509VerifyResult::OK
     )
 }
+
+#[tokio::test]
+async fn should_encrypt_key() {
+    let dir = std::env::temp_dir().join(format!("proxy-ca-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("ca.crt"), dir.join("ca.key.pem"));
+
+    let plain = CA::load_or_create(&cert_path, &key_path, None)
+        .await
+        .unwrap();
+    // migrated on the next start
+    CA::load_or_create(&cert_path, &key_path, Some(b"secret"))
+        .await
+        .unwrap();
+    let pem = std::fs::read_to_string(&key_path).unwrap();
+    assert!(pem.contains("ENCRYPTED"));
+    assert!(CA::load(&cert_path, &key_path, None).await.is_err());
+    assert!(CA::load(&cert_path, &key_path, Some(b"wrong"))
+        .await
+        .is_err());
+    let loaded = CA::load(&cert_path, &key_path, Some(b"secret"))
+        .await
+        .unwrap();
+    assert!(loaded.key.public_eq(&plain.key));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn should_import_existing_ca() {
+    let dir = std::env::temp_dir().join(format!("proxy-ca-import-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let ca = mk_ca_cert().unwrap();
+
+    // mitmproxy keeps key and certificate in one file
+    let mitmproxy = dir.join("mitmproxy-ca.pem");
+    let mut pem = ca.key.private_key_to_pem_pkcs8().unwrap();
+    pem.extend(ca.cert.to_pem().unwrap());
+    std::fs::write(&mitmproxy, pem).unwrap();
+    let pkcs12 = dir.join("charles.p12");
+    let bundle = Pkcs12::builder()
+        .name("ca")
+        .pkey(&ca.key)
+        .cert(&ca.cert)
+        .build2("secret")
+        .unwrap();
+    std::fs::write(&pkcs12, bundle.to_der().unwrap()).unwrap();
+    let (der_cert, der_key) = (dir.join("ca.cer"), dir.join("ca.key"));
+    std::fs::write(&der_cert, ca.cert.to_der().unwrap()).unwrap();
+    std::fs::write(&der_key, ca.key.private_key_to_der().unwrap()).unwrap();
+
+    for imported in [
+        CA::import(&mitmproxy, None).await.unwrap(),
+        CA::import(&pkcs12, Some(b"secret")).await.unwrap(),
+        CA::load(&pkcs12, &pkcs12, Some(b"secret")).await.unwrap(),
+        CA::load(&der_cert, &der_key, None).await.unwrap(),
+    ] {
+        assert_eq!(imported.cert, ca.cert);
+        assert!(imported.key.public_eq(&ca.key));
+    }
+    assert!(CA::import(&pkcs12, None).await.is_err());
+    assert!(CA::import(&der_cert, None).await.is_err());
+    // the combined file is not rewritten as a bare key
+    CA::load_or_create(&mitmproxy, &mitmproxy, Some(b"secret"))
+        .await
+        .unwrap();
+    assert!(CA::import(&mitmproxy, None).await.is_ok());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn should_detect_expiring_leaf() {
+    let leaf = mk_ca_cert().unwrap().sign("localhost".to_owned()).unwrap();
+    assert!(!leaf.expires_within(30));
+    assert!(leaf.expires_within(365));
+}
@@ -0,0 +1,642 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use hyper::header::{
+    HeaderName, AGE, AUTHORIZATION, CACHE_CONTROL, CONNECTION, CONTENT_LENGTH, DATE, ETAG, EXPIRES,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, PRAGMA, TRANSFER_ENCODING, VARY,
+};
+use hyper::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, File};
+use tokio::io::AsyncReadExt;
+use tracing::error;
+
+use crate::config::{CacheConfig, RedactConfig};
+use crate::flow;
+use crate::replay;
+
+/// 没有显式过期时间时可按 `Last-Modified` 推算新鲜度的状态码（RFC 9110 15.1）
+const HEURISTIC_STATUSES: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// 启发式新鲜度的上限
+const MAX_HEURISTIC_SECS: u64 = 24 * 60 * 60;
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// 请求或响应的 `Cache-Control` 指令
+#[derive(Debug, Default)]
+pub struct CacheControl {
+    pub no_store: bool,
+    pub no_cache: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub only_if_cached: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    pub min_fresh: Option<u64>,
+    /// 不带值时不限
+    pub max_stale: Option<u64>,
+}
+
+impl CacheControl {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = Self::default();
+        let directives = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let secs = value.and_then(|v| v.parse().ok());
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "public" => cc.public = true,
+                "must-revalidate" | "proxy-revalidate" => cc.must_revalidate = true,
+                "only-if-cached" => cc.only_if_cached = true,
+                "max-age" => cc.max_age = secs,
+                "s-maxage" => cc.s_maxage = secs,
+                "min-fresh" => cc.min_fresh = secs,
+                "max-stale" => cc.max_stale = Some(secs.unwrap_or(u64::MAX)),
+                _ => {}
+            }
+        }
+        cc
+    }
+
+    /// 没有 `Cache-Control` 时 `Pragma: no-cache` 视同 `no-cache`
+    pub fn request(headers: &HeaderMap) -> Self {
+        let mut cc = Self::parse(headers);
+        if !headers.contains_key(CACHE_CONTROL) {
+            cc.no_cache = headers
+                .get_all(PRAGMA)
+                .iter()
+                .any(|value| value.as_bytes().eq_ignore_ascii_case(b"no-cache"));
+        }
+        cc
+    }
+}
+
+/// 缓存条目的元数据，时间均为 unix 秒
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Meta {
+    pub key: String,
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// `Vary` 列出的请求头及存储时的值
+    pub vary: Vec<(String, Option<String>)>,
+    pub stored_at: u64,
+    /// 收到时已经过的时间
+    pub initial_age: u64,
+    pub freshness_secs: u64,
+    /// 每次使用前都要向上游验证
+    pub no_cache: bool,
+    /// 过期后不允许按 `max-stale` 使用
+    pub must_revalidate: bool,
+}
+
+impl Meta {
+    /// 不可存储时返回 None
+    pub fn new(
+        key: String,
+        req_headers: &HeaderMap,
+        status: StatusCode,
+        headers: &HeaderMap,
+        now: u64,
+    ) -> Option<Self> {
+        let req_cc = CacheControl::request(req_headers);
+        let cc = CacheControl::parse(headers);
+        if req_cc.no_store
+            || cc.no_store
+            || cc.private
+            || status == StatusCode::PARTIAL_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+        {
+            return None;
+        }
+        if req_headers.contains_key(AUTHORIZATION)
+            && !(cc.public || cc.s_maxage.is_some() || cc.must_revalidate)
+        {
+            return None;
+        }
+        let vary = vary(headers, req_headers)?;
+        let freshness_secs = match lifetime(status, headers, &cc) {
+            Some(secs) => secs,
+            None if HEURISTIC_STATUSES.contains(&status.as_u16()) => 0,
+            None => return None,
+        };
+        let validators = headers.contains_key(ETAG) || headers.contains_key(LAST_MODIFIED);
+        // neither fresh nor revalidatable, useless to keep
+        if freshness_secs == 0 && !validators {
+            return None;
+        }
+        Some(Self {
+            key,
+            status: status.as_u16(),
+            headers: stored_headers(headers),
+            vary,
+            stored_at: now,
+            initial_age: initial_age(headers, now),
+            freshness_secs,
+            no_cache: cc.no_cache,
+            must_revalidate: cc.must_revalidate,
+        })
+    }
+
+    pub fn age(&self, now: u64) -> u64 {
+        self.initial_age + now.saturating_sub(self.stored_at)
+    }
+
+    /// 按请求的指令判断能否不经验证直接返回
+    pub fn is_fresh(&self, now: u64, req_cc: &CacheControl) -> bool {
+        if self.no_cache || req_cc.no_cache {
+            return false;
+        }
+        let age = self.age(now);
+        if req_cc.max_age.is_some_and(|max_age| age > max_age) {
+            return false;
+        }
+        let stale = if self.must_revalidate {
+            0
+        } else {
+            req_cc.max_stale.unwrap_or(0)
+        };
+        age.saturating_add(req_cc.min_fresh.unwrap_or(0))
+            <= self.freshness_secs.saturating_add(stale)
+    }
+
+    /// `Vary` 列出的请求头与存储时一致
+    pub fn matches(&self, req_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| header_value(req_headers, name) == *value)
+    }
+
+    pub fn header_map(&self) -> HeaderMap {
+        replay::headers(&self.headers).unwrap_or_default()
+    }
+
+    /// 设置向上游验证的条件头，没有验证器时返回 false
+    pub fn conditional(&self, req_headers: &mut HeaderMap) -> bool {
+        let headers = self.header_map();
+        let mut conditional = false;
+        if let Some(etag) = headers.get(ETAG) {
+            req_headers.insert(IF_NONE_MATCH, etag.clone());
+            conditional = true;
+        }
+        if let Some(last_modified) = headers.get(LAST_MODIFIED) {
+            req_headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+            conditional = true;
+        }
+        conditional
+    }
+
+    /// 用 304 的头更新存储的头并重新计算新鲜度
+    pub fn refresh(&mut self, not_modified: &HeaderMap, now: u64) {
+        let mut headers = self.header_map();
+        for name in not_modified.keys() {
+            if *name == CONTENT_LENGTH {
+                continue;
+            }
+            headers.remove(name);
+            for value in not_modified.get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+        let cc = CacheControl::parse(&headers);
+        self.freshness_secs = lifetime(status, &headers, &cc).unwrap_or(0);
+        self.no_cache = cc.no_cache;
+        self.must_revalidate = cc.must_revalidate;
+        self.initial_age = initial_age(not_modified, now);
+        self.stored_at = now;
+        self.headers = stored_headers(&headers);
+    }
+}
+
+/// 显式过期时间优先，其次按 `Last-Modified` 推算
+fn lifetime(status: StatusCode, headers: &HeaderMap, cc: &CacheControl) -> Option<u64> {
+    let date = header_time(headers, &DATE).unwrap_or_else(now);
+    // invalid dates such as `0` mean already expired
+    let expires = || {
+        headers
+            .contains_key(EXPIRES)
+            .then(|| header_time(headers, &EXPIRES).map_or(0, |e| e.saturating_sub(date)))
+    };
+    let heuristic = || {
+        if !cc.public && !HEURISTIC_STATUSES.contains(&status.as_u16()) {
+            return None;
+        }
+        let last_modified = header_time(headers, &LAST_MODIFIED)?;
+        Some((date.saturating_sub(last_modified) / 10).min(MAX_HEURISTIC_SECS))
+    };
+    cc.s_maxage
+        .or(cc.max_age)
+        .or_else(expires)
+        .or_else(heuristic)
+}
+
+fn initial_age(headers: &HeaderMap, now: u64) -> u64 {
+    let age = header_value(headers, AGE.as_str())
+        .and_then(|age| age.parse().ok())
+        .unwrap_or(0);
+    let apparent = header_time(headers, &DATE).map_or(0, |date| now.saturating_sub(date));
+    age.max(apparent)
+}
+
+/// `Vary: *` 时不可缓存
+fn vary(headers: &HeaderMap, req_headers: &HeaderMap) -> Option<Vec<(String, Option<String>)>> {
+    let mut vary = Vec::new();
+    for name in headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+    {
+        if name == "*" {
+            return None;
+        }
+        let value = header_value(req_headers, &name);
+        vary.push((name, value));
+    }
+    Some(vary)
+}
+
+/// 去掉逐跳头，body 已完整缓冲
+fn stored_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    let mut headers = headers.clone();
+    for name in [
+        CONNECTION,
+        TRANSFER_ENCODING,
+        HeaderName::from_static("keep-alive"),
+    ] {
+        headers.remove(name);
+    }
+    flow::headers(&headers, &RedactConfig::default())
+}
+
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<_> = headers
+        .get_all(name)
+        .iter()
+        .map(|value| String::from_utf8_lossy(value.as_bytes()))
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+pub fn header_time(headers: &HeaderMap, name: &HeaderName) -> Option<u64> {
+    let value = headers.get(name)?.to_str().ok()?;
+    let time = httpdate::parse_http_date(value).ok()?;
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub meta: Meta,
+    pub body: Bytes,
+}
+
+impl Entry {
+    pub fn new(mut meta: Meta, body: Bytes) -> Self {
+        meta.headers
+            .retain(|(name, _)| name != CONTENT_LENGTH.as_str());
+        meta.headers
+            .push((CONTENT_LENGTH.to_string(), body.len().to_string()));
+        Self { meta, body }
+    }
+
+    fn size(&self) -> u64 {
+        let headers: usize = self
+            .meta
+            .headers
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum();
+        (self.body.len() + headers + self.meta.key.len()) as u64
+    }
+}
+
+struct Slot<V> {
+    value: V,
+    size: u64,
+    used: u64,
+}
+
+/// 按字节数限制的 LRU
+struct Tier<V> {
+    slots: HashMap<String, Slot<V>>,
+    bytes: u64,
+    capacity: u64,
+    tick: u64,
+}
+
+impl<V> Tier<V> {
+    fn new(capacity: u64) -> Self {
+        Self {
+            slots: HashMap::new(),
+            bytes: 0,
+            capacity,
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&V> {
+        self.tick += 1;
+        let slot = self.slots.get_mut(key)?;
+        slot.used = self.tick;
+        Some(&slot.value)
+    }
+
+    /// 返回被淘汰的条目，放不下的条目本身也算被淘汰
+    fn insert(&mut self, key: String, value: V, size: u64) -> Vec<(String, V)> {
+        self.remove(&key);
+        self.tick += 1;
+        self.bytes += size;
+        self.slots.insert(
+            key,
+            Slot {
+                value,
+                size,
+                used: self.tick,
+            },
+        );
+        let mut evicted = Vec::new();
+        while self.bytes > self.capacity {
+            let Some(oldest) = self
+                .slots
+                .iter()
+                .min_by_key(|(_, slot)| slot.used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(value) = self.remove(&oldest) {
+                evicted.push((oldest, value));
+            }
+        }
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let slot = self.slots.remove(key)?;
+        self.bytes -= slot.size;
+        Some(slot.value)
+    }
+}
+
+/// 内存淘汰的条目落到磁盘，再次命中时移回内存
+struct Disk {
+    dir: PathBuf,
+    index: Mutex<Tier<()>>,
+}
+
+impl Disk {
+    fn path(&self, key: &str) -> PathBuf {
+        let digest = openssl::sha::sha256(key.as_bytes());
+        let name: String = digest.iter().map(|b| format!("{b:02x}")).collect();
+        self.dir.join(name)
+    }
+
+    /// 返回需要删除文件的 key
+    fn store_index(&self, key: String, size: u64) -> Vec<String> {
+        let evicted = match self.index.lock() {
+            Ok(mut index) => index.insert(key, (), size),
+            Err(_) => return Vec::new(),
+        };
+        evicted.into_iter().map(|(key, _)| key).collect()
+    }
+}
+
+/// 两级响应缓存
+#[derive(Clone)]
+pub struct Cache {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    memory: Mutex<Tier<Entry>>,
+    disk: Option<Disk>,
+}
+
+impl Cache {
+    /// 扫描磁盘目录恢复索引
+    pub async fn new(config: &CacheConfig) -> Result<Self> {
+        let disk = match &config.disk_dir {
+            Some(dir) => {
+                fs::create_dir_all(dir).await?;
+                let disk = Disk {
+                    dir: dir.clone(),
+                    index: Mutex::new(Tier::new(config.disk_bytes)),
+                };
+                let mut entries = fs::read_dir(dir).await?;
+                while let Some(file) = entries.next_entry().await? {
+                    let size = file.metadata().await?.len();
+                    let evicted = match read_key(file.path()).await {
+                        Ok(key) => disk.store_index(key, size),
+                        // not a cache entry, or written partially
+                        Err(_) => [].to_vec(),
+                    };
+                    for key in evicted {
+                        let _ = fs::remove_file(disk.path(&key)).await;
+                    }
+                }
+                Some(disk)
+            }
+            None => None,
+        };
+        Ok(Self {
+            inner: Arc::new(Inner {
+                memory: Mutex::new(Tier::new(config.memory_bytes)),
+                disk,
+            }),
+        })
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Entry> {
+        let cached = self
+            .inner
+            .memory
+            .lock()
+            .ok()
+            .and_then(|mut memory| memory.get(key).cloned());
+        if cached.is_some() {
+            return cached;
+        }
+        let disk = self.inner.disk.as_ref()?;
+        disk.index.lock().ok()?.remove(key)?;
+        let path = disk.path(key);
+        let entry = read_entry(&path).await;
+        let _ = fs::remove_file(&path).await;
+        match entry {
+            Ok(entry) if entry.meta.key == key => {
+                self.insert(entry.clone());
+                Some(entry)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                error!("read cache {} failed: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, entry: Entry) {
+        let key = entry.meta.key.clone();
+        let size = entry.size();
+        let evicted = match self.inner.memory.lock() {
+            Ok(mut memory) => memory.insert(key.clone(), entry, size),
+            Err(_) => return,
+        };
+        if let Some(disk) = &self.inner.disk {
+            if let Ok(mut index) = disk.index.lock() {
+                index.remove(&key);
+            }
+            for (_, entry) in evicted {
+                self.spill(entry);
+            }
+        }
+    }
+
+    /// 不安全方法成功后使对应 URL 失效
+    pub fn remove(&self, key: &str) {
+        if let Ok(mut memory) = self.inner.memory.lock() {
+            memory.remove(key);
+        }
+        if let Some(disk) = &self.inner.disk {
+            let removed = disk
+                .index
+                .lock()
+                .ok()
+                .and_then(|mut index| index.remove(key));
+            if removed.is_some() {
+                let path = disk.path(key);
+                tokio::task::spawn(async move { fs::remove_file(path).await });
+            }
+        }
+    }
+
+    fn spill(&self, entry: Entry) {
+        let cache = self.clone();
+        tokio::task::spawn(async move {
+            let Some(disk) = &cache.inner.disk else {
+                return;
+            };
+            let path = disk.path(&entry.meta.key);
+            let encoded = match encode(&entry) {
+                Ok(encoded) => encoded,
+                Err(e) => return error!("encode cache entry failed: {e}"),
+            };
+            let size = encoded.len() as u64;
+            if let Err(e) = fs::write(&path, encoded).await {
+                return error!("write cache {} failed: {e}", path.display());
+            }
+            for key in disk.store_index(entry.meta.key, size) {
+                let _ = fs::remove_file(disk.path(&key)).await;
+            }
+        });
+    }
+}
+
+/// 4 字节元数据长度 + JSON 元数据 + body
+fn encode(entry: &Entry) -> Result<Bytes> {
+    let meta = serde_json::to_vec(&entry.meta)?;
+    let mut buf = BytesMut::with_capacity(4 + meta.len() + entry.body.len());
+    buf.put_u32(meta.len() as u32);
+    buf.put_slice(&meta);
+    buf.put_slice(&entry.body);
+    Ok(buf.freeze())
+}
+
+async fn read_entry(path: &PathBuf) -> Result<Entry> {
+    let mut buf = Bytes::from(fs::read(path).await?);
+    let len = u32::from_be_bytes(buf.get(..4).unwrap_or_default().try_into()?) as usize;
+    let meta = serde_json::from_slice(buf.get(4..4 + len).unwrap_or_default())?;
+    Ok(Entry {
+        meta,
+        body: buf.split_off(4 + len),
+    })
+}
+
+async fn read_key(path: PathBuf) -> Result<String> {
+    let mut file = File::open(path).await?;
+    let len = file.read_u32().await? as usize;
+    let mut meta = vec![0; len];
+    file.read_exact(&mut meta).await?;
+    let meta: Meta = serde_json::from_slice(&meta)?;
+    Ok(meta.key)
+}
+
+#[test]
+fn should_compute_freshness() {
+    let headers = |list: &[(&str, &str)]| {
+        let list: Vec<_> = list
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        replay::headers(&list).unwrap()
+    };
+    let now = now();
+    let request = headers(&[("accept-encoding", "gzip")]);
+    let meta = |response: &[(&str, &str)]| {
+        Meta::new(
+            "http://example.com/".to_owned(),
+            &request,
+            StatusCode::OK,
+            &headers(response),
+            now,
+        )
+    };
+
+    let fresh = meta(&[("cache-control", "max-age=60"), ("vary", "Accept-Encoding")]).unwrap();
+    let plain = CacheControl::default();
+    assert!(fresh.is_fresh(now + 60, &plain));
+    assert!(!fresh.is_fresh(now + 61, &plain));
+    assert!(fresh.is_fresh(
+        now + 61,
+        &CacheControl::request(&headers(&[("cache-control", "max-stale")]))
+    ));
+    assert!(!fresh.is_fresh(
+        now,
+        &CacheControl::request(&headers(&[("pragma", "no-cache")]))
+    ));
+    assert!(fresh.matches(&request));
+    assert!(!fresh.matches(&HeaderMap::new()));
+
+    let mut revalidate = meta(&[("cache-control", "no-cache"), ("etag", "\"v1\"")]).unwrap();
+    assert!(!revalidate.is_fresh(now, &plain));
+    revalidate.refresh(&headers(&[("cache-control", "max-age=10")]), now);
+    assert!(revalidate.is_fresh(now, &plain));
+    assert!(revalidate.header_map().contains_key(ETAG));
+
+    assert!(meta(&[("cache-control", "no-store, max-age=60")]).is_none());
+    assert!(meta(&[("cache-control", "max-age=60"), ("vary", "*")]).is_none());
+    // nothing to revalidate with
+    assert!(meta(&[]).is_none());
+
+    let mut tier = Tier::new(10);
+    tier.insert("a".to_owned(), (), 4);
+    tier.insert("b".to_owned(), (), 4);
+    tier.get("a");
+    let evicted = tier.insert("c".to_owned(), (), 4);
+    assert_eq!(
+        evicted.into_iter().map(|(k, _)| k).collect::<Vec<_>>(),
+        ["b"]
+    );
+}
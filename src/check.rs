@@ -0,0 +1,192 @@
+use std::cell::OnceCell;
+use std::path::Path;
+
+use crate::ca::{self, CA};
+use crate::config::Config;
+use crate::listener;
+
+/// `--check <config>`：解析配置并编译其中的过滤表达式与正则，检查字段取值与证书文件，
+/// 不启动监听；问题逐行写到 stderr，没有问题时返回 true
+pub fn run(path: &Path) -> bool {
+    let name = path.display();
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("{name}: {e}");
+            return false;
+        }
+    };
+    let config: Config = match serde_json::from_str(&text) {
+        Ok(config) => config,
+        Err(e) => {
+            let (line, column) = (e.line(), e.column());
+            let message = e.to_string();
+            let message = message
+                .strip_suffix(&format!(" at line {line} column {column}"))
+                .unwrap_or(&message);
+            match field_at(&text, line, column) {
+                Some(field) => eprintln!("{name}:{line}:{column}: {field}: {message}"),
+                None => eprintln!("{name}:{line}:{column}: {message}"),
+            }
+            return false;
+        }
+    };
+
+    let mut problems = config.validate();
+    problems.extend(check_files(&config));
+    for problem in &problems {
+        eprintln!("{name}: {problem}");
+    }
+    if problems.is_empty() {
+        println!("{name}: ok");
+    }
+    problems.is_empty()
+}
+
+fn check_files(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    // asked once, only when some CA exists
+    let passphrase = OnceCell::new();
+    let passphrase = || {
+        passphrase.get_or_init(|| match config.root_ca_key_encrypted {
+            true => ca::passphrase().map(Some).map_err(|e| e.to_string()),
+            false => Ok(None),
+        })
+    };
+    let (cert, key) = (&config.root_ca_cert_path, &config.root_ca_key_path);
+    match (cert.exists(), key.exists()) {
+        // generated on first start
+        (false, false) => {}
+        (true, true) => {
+            if let Err(e) = load_ca(cert, key, passphrase()) {
+                problems.push(format!("root_ca_cert_path: {e}"));
+            }
+        }
+        (true, false) => problems.push(format!(
+            "root_ca_key_path: {} does not exist, the CA would be regenerated",
+            key.display()
+        )),
+        (false, true) => problems.push(format!(
+            "root_ca_cert_path: {} does not exist, the CA would be regenerated",
+            cert.display()
+        )),
+    }
+    for (i, root) in config.root_cas.iter().enumerate() {
+        if let Err(e) = load_ca(&root.cert_path, &root.key_path, passphrase()) {
+            problems.push(format!("root_cas[{i}].cert_path: {e}"));
+        }
+    }
+    if let Err(e) = listener::tls_acceptor(config) {
+        problems.push(format!("tls_cert_path: {e}"));
+    }
+    problems
+}
+
+fn load_ca(
+    cert: &Path,
+    key: &Path,
+    passphrase: &Result<Option<Vec<u8>>, String>,
+) -> anyhow::Result<()> {
+    let passphrase = passphrase.as_ref().map_err(|e| anyhow::anyhow!("{e}"))?;
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    runtime
+        .block_on(CA::load(cert, key, passphrase.as_deref()))?
+        .verify()
+}
+
+enum Frame {
+    Object {
+        key: Option<String>,
+        expect_key: bool,
+    },
+    Array {
+        index: usize,
+    },
+}
+
+/// serde_json 报错位置所在的字段，如 `splits[0].filter`
+fn field_at(text: &str, line: usize, column: usize) -> Option<String> {
+    let offset = text
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum::<usize>()
+        + column;
+    let mut bytes = text.as_bytes()[..offset.min(text.len())].iter();
+    let mut stack = Vec::new();
+    // errors raised by `try_from` get the position where the enclosing object or array ends
+    let mut closed = None;
+    while let Some(&b) = bytes.next() {
+        if !b.is_ascii_whitespace() {
+            closed = None;
+        }
+        match b {
+            b'{' => stack.push(Frame::Object {
+                key: None,
+                expect_key: true,
+            }),
+            b'[' => stack.push(Frame::Array { index: 0 }),
+            b'}' | b']' => closed = stack.pop(),
+            b',' => match stack.last_mut() {
+                Some(Frame::Array { index }) => *index += 1,
+                Some(Frame::Object { expect_key, .. }) => *expect_key = true,
+                None => {}
+            },
+            b':' => {
+                if let Some(Frame::Object { expect_key, .. }) = stack.last_mut() {
+                    *expect_key = false;
+                }
+            }
+            b'"' => {
+                let mut string = Vec::new();
+                while let Some(&b) = bytes.next() {
+                    match b {
+                        b'"' => break,
+                        b'\\' => string.extend(bytes.next()),
+                        _ => string.push(b),
+                    }
+                }
+                if let Some(Frame::Object {
+                    key,
+                    expect_key: true,
+                }) = stack.last_mut()
+                {
+                    *key = Some(String::from_utf8_lossy(&string).into_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    stack.extend(closed);
+    let mut field = String::new();
+    for frame in &stack {
+        match frame {
+            Frame::Object { key: Some(key), .. } => {
+                if !field.is_empty() {
+                    field.push('.');
+                }
+                field.push_str(key);
+            }
+            Frame::Object { key: None, .. } => break,
+            Frame::Array { index } => field.push_str(&format!("[{index}]")),
+        }
+    }
+    (!field.is_empty()).then_some(field)
+}
+
+#[test]
+fn should_locate_error_field() {
+    let text = "{\n  \"bind_port\": 1,\n  \"splits\": [{}, {\"filter\": \"~q (\"}]\n}";
+    let e = serde_json::from_str::<Config>(text).err().unwrap();
+    assert_eq!(
+        field_at(text, e.line(), e.column()).as_deref(),
+        Some("splits[1].filter")
+    );
+    let text = r#"{"dns": {"protocol": "tcp"}}"#;
+    let e = serde_json::from_str::<Config>(text).err().unwrap();
+    assert_eq!(
+        field_at(text, e.line(), e.column()).as_deref(),
+        Some("dns.protocol")
+    );
+}
@@ -0,0 +1,53 @@
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use ulid::Ulid;
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    /// Install, uninstall or run as a system service (Windows service / systemd unit)
+    #[arg(long, value_enum)]
+    pub service: Option<ServiceCommand>,
+    /// Start with this profile from `profiles`, instead of the `profile` in the config
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+    /// Validate a config file (rules, addresses, certificates) and exit without listening
+    #[arg(long, value_name = "CONFIG")]
+    pub check: Option<PathBuf>,
+    /// Import an existing root CA (mitmproxy-ca.pem, PKCS#12 from Charles etc.) into
+    /// root_ca_cert_path / root_ca_key_path, keeping the replaced files as *.bak
+    #[arg(long, value_name = "FILE")]
+    pub import_ca: Option<PathBuf>,
+    /// Print a stored flow of the running instance as a curl command (needs admin_addr)
+    #[arg(long, value_name = "FLOW_ID")]
+    pub curl: Option<Ulid>,
+    /// Print the stored flows of the running instance as a Postman 2.1 collection
+    #[arg(long, conflicts_with = "curl")]
+    pub postman: bool,
+    /// Print an OpenAPI 3.0 document inferred from the running instance's flows to HOST
+    #[arg(long, value_name = "HOST", conflicts_with_all = ["curl", "postman"])]
+    pub openapi: Option<String>,
+    /// Save the certificate chain the running instance last saw from HOST as PEM files:
+    /// HOST.chain.pem and HOST.0.pem (the leaf), HOST.1.pem …
+    #[arg(long, value_name = "HOST")]
+    pub export_chain: Option<String>,
+    /// Directory for --export-chain
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = ".",
+        requires = "export_chain"
+    )]
+    pub out: PathBuf,
+    /// Replay stored flows of the running instance as a load test described by a JSON FILE
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["curl", "postman", "openapi"])]
+    pub load_test: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceCommand {
+    Install,
+    Uninstall,
+    Run,
+}
@@ -1,63 +1,773 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
 use bytes::Bytes;
 use http_body_util::combinators::BoxBody;
 use http_body_util::BodyExt;
-use hyper::StatusCode;
-use hyper::{body::Incoming as IncomingBody, Request, Response};
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{EXPECT, UPGRADE};
+use hyper::{Method, StatusCode};
+use hyper::{Request, Response};
 use hyper_util::rt::TokioIo;
+use motore::builder::ServiceBuilder;
 use motore::{service, Service};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::TcpStream;
+use tokio::sync::Notify;
 use tracing::{debug, error};
 
+use crate::config::RetryConfig;
+use crate::dialer::Dialer;
+use crate::flow::Timings;
+use crate::layer::audit::AuditLayer;
+use crate::layer::auth::AuthLayer;
+use crate::layer::breakpoint::BreakpointLayer;
+use crate::layer::cache::CacheLayer;
+use crate::layer::cookie::CookieLayer;
+use crate::layer::cors::CorsLayer;
+use crate::layer::decode::DecodeLayer;
+use crate::layer::echo::EchoLayer;
+use crate::layer::expect::ExpectLayer;
+use crate::layer::flow::FlowLayer;
+use crate::layer::forwarded::ForwardedLayer;
+use crate::layer::grpc::GrpcLayer;
+use crate::layer::inject::InjectLayer;
+use crate::layer::log::LogLayer;
+use crate::layer::mirror::MirrorLayer;
+use crate::layer::offline::OfflineLayer;
+use crate::layer::privacy::PrivacyLayer;
+use crate::layer::quota::QuotaLayer;
+use crate::layer::split::SplitLayer;
+use crate::layer::user_agent::UserAgentLayer;
+use crate::metrics::Metrics;
+use crate::pool::{PoolKey, Sender};
 use crate::state::ClientState;
-use crate::util::{self, create_ssl_connection};
+use crate::transcript;
+use crate::upstream_cert::CertInfo;
+use crate::util::{self, create_ssl_connection_timed};
 
 #[derive(Clone)]
 pub struct HttpClient;
 
+/// 完整的客户端处理链
+pub fn service() -> impl Service<
+    ClientState,
+    Request<BoxBody<Bytes, hyper::Error>>,
+    Response = Response<BoxBody<Bytes, hyper::Error>>,
+    Error = hyper::Error,
+> + Clone
+       + Sync
+       + Send
+       + Unpin
+       + 'static {
+    ServiceBuilder::new()
+        .layer(LogLayer)
+        .layer(ExpectLayer)
+        .layer(QuotaLayer)
+        .layer(FlowLayer)
+        .layer(GrpcLayer)
+        .layer(CookieLayer)
+        .layer(MirrorLayer)
+        .layer(SplitLayer)
+        .layer(BreakpointLayer)
+        .layer(AuditLayer)
+        .layer(CorsLayer)
+        .layer(ForwardedLayer)
+        .layer(PrivacyLayer)
+        .layer(UserAgentLayer)
+        .layer(AuthLayer)
+        .layer(InjectLayer)
+        .layer(DecodeLayer)
+        .layer(OfflineLayer)
+        .layer(CacheLayer)
+        .layer(EchoLayer)
+        .service(HttpClient)
+}
+
 #[service]
-impl Service<ClientState, Request<IncomingBody>> for HttpClient {
+impl Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for HttpClient {
     async fn call(
         &self,
         state: &mut ClientState,
-        req: Request<IncomingBody>,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-        if state.is_secure {
-            if let Ok(stream) = create_ssl_connection(&state.addr, &state.sni)
-                .await
-                .inspect_err(|e| error!("create ssl stream failed: {e}"))
+        let config = state.shared.config();
+        #[cfg(feature = "http3")]
+        if state.is_secure && config.is_h3(&state.sni) {
+            return Ok(match crate::h3::send(state, req).await {
+                Ok(resp) => resp,
+                Err(e) => {
+                    error!("h3 request to {} failed: {e}", state.addr);
+                    status(StatusCode::BAD_GATEWAY, e.to_string())
+                }
+            });
+        }
+        // transcripts belong to one client connection, upgrades never return
+        let pooled = config.pool.enabled
+            && state.transcript.is_none()
+            && !req.headers().contains_key(UPGRADE);
+        let key = PoolKey {
+            addr: state.addr.clone(),
+            sni: state.sni.clone(),
+            secure: state.is_secure,
+        };
+
+        // GET/HEAD without a body can be rebuilt for another attempt
+        let replayable =
+            matches!(*req.method(), Method::GET | Method::HEAD) && req.body().is_end_stream();
+        let mut req = Some(req);
+        // handed back by hyper without anything written, already gated on 100-continue
+        let mut unsent = None;
+        let mut attempt = 0;
+        loop {
+            let retry = attempt < config.retry.max_retries;
+            state.timings = Timings::default();
+            // an idle connection may be closed by the upstream while the request is written,
+            // only requests that can be sent again risk that
+            let sender = match (pooled && replayable)
+                .then(|| state.shared.pool().take(&key, &config.pool))
+                .flatten()
             {
-                return http_request(req, stream).await;
+                Some(sender) => {
+                    Metrics::incr(&state.shared.metrics().pooled_requests);
+                    if state.is_secure {
+                        let cert = state.shared.upstream_certs().get(&state.sni);
+                        record_cert(state, cert);
+                    }
+                    Ok(sender)
+                }
+                None => connect(state).await?,
+            };
+            let mut sender = match sender {
+                Ok(sender) => sender,
+                // nothing was sent, safe to retry for any method
+                Err(e) if retry && is_transient(&e) => {
+                    backoff(state, &config.retry, &mut attempt).await;
+                    continue;
+                }
+                Err(e) if util::is_timeout(&e) => {
+                    return Ok(status(StatusCode::GATEWAY_TIMEOUT, e.to_string()))
+                }
+                Err(e) => return Ok(status(StatusCode::BAD_GATEWAY, e.to_string())),
+            };
+
+            let outgoing = match unsent.take() {
+                Some(outgoing) => outgoing,
+                None => {
+                    let mut outgoing = match req.as_ref() {
+                        Some(req) if replayable => rebuild(req),
+                        _ => req
+                            .take()
+                            .expect("request is only sent once unless replayable"),
+                    };
+                    await_continue(&mut outgoing, config.timeouts.continue_secs);
+                    outgoing
+                }
+            };
+            let response_secs = config.timeouts.response_secs;
+            let start = Instant::now();
+            let resp = util::timeout(response_secs, sender.try_send_request(outgoing)).await;
+            state.timings.ttfb_ms = Timings::since(start);
+            let Ok(resp) = resp else {
+                error!(
+                    "upstream {} did not respond in {response_secs}s",
+                    state.addr
+                );
+                return Ok(status(StatusCode::GATEWAY_TIMEOUT, "upstream timed out"));
+            };
+            let resp = match resp {
+                Ok(resp) => Ok(resp),
+                Err(mut e) => match e.take_message() {
+                    Some(outgoing) if retry => {
+                        debug!("retry {}, the request was not sent", state.addr);
+                        unsent = Some(outgoing);
+                        backoff(state, &config.retry, &mut attempt).await;
+                        continue;
+                    }
+                    _ => Err(e.into_error()),
+                },
+            };
+            let resp = match resp {
+                Ok(resp) => resp,
+                Err(e) if replayable && retry && is_transient_hyper(&e) => {
+                    debug!("retry {} after {e}", state.addr);
+                    backoff(state, &config.retry, &mut attempt).await;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if pooled {
+                state
+                    .shared
+                    .pool()
+                    .put_when_ready(key, sender, &config.pool);
             }
-        } else if let Ok(stream) = TcpStream::connect(&state.addr)
+            return Ok(resp.map(|b| b.boxed()));
+        }
+    }
+}
+
+/// 带 `Expect: 100-continue` 时，上游回应 100 或等满 `secs` 后才读取客户端的 body；
+/// hyper 在第一次读取 body 时才向客户端发出 100，客户端因此与上游同步
+fn await_continue(req: &mut Request<BoxBody<Bytes, hyper::Error>>, secs: u64) {
+    let expects = req
+        .headers()
+        .get(EXPECT)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"100-continue"));
+    if !expects || secs == 0 || req.body().is_end_stream() {
+        return;
+    }
+    let notify = Arc::new(Notify::new());
+    let on_continue = notify.clone();
+    hyper::ext::on_informational(req, move |resp| {
+        if resp.status() == StatusCode::CONTINUE {
+            on_continue.notify_one();
+        }
+    });
+    // a final response without 100 also ends up here, the body then meets a closed connection
+    let ready = async move {
+        let _ = tokio::time::timeout(Duration::from_secs(secs), notify.notified()).await;
+    };
+    let inner = std::mem::replace(req.body_mut(), util::empty());
+    *req.body_mut() = Gated {
+        inner,
+        ready: Some(Box::pin(ready)),
+    }
+    .boxed();
+}
+
+/// `ready` 完成前不读取 `inner`
+struct Gated {
+    inner: BoxBody<Bytes, hyper::Error>,
+    ready: Option<Pin<Box<dyn Future<Output = ()> + Send + Sync>>>,
+}
+
+impl Body for Gated {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(ready) = self.ready.as_mut() {
+            ready!(ready.as_mut().poll(cx));
+            self.ready = None;
+        }
+        Pin::new(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// 指数退避：`backoff_ms`、`2 * backoff_ms`……
+async fn backoff(state: &ClientState, retry: &RetryConfig, attempt: &mut u32) {
+    Metrics::incr(&state.shared.metrics().upstream_retries);
+    let delay = retry.backoff_ms.saturating_mul(1 << (*attempt).min(16));
+    *attempt += 1;
+    tokio::time::sleep(Duration::from_millis(delay)).await;
+}
+
+/// 空 body 的 GET/HEAD 的副本
+fn rebuild(req: &Request<BoxBody<Bytes, hyper::Error>>) -> Request<BoxBody<Bytes, hyper::Error>> {
+    let mut copy = Request::new(util::empty());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    copy
+}
+
+fn is_transient_kind(kind: io::ErrorKind) -> bool {
+    matches!(
+        kind,
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// 连接被拒绝、重置等；DNS 失败不重试
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| is_transient_kind(e.kind()))
+}
+
+/// 连接在响应前被关闭，常见于复用已被上游关闭的空闲连接
+fn is_transient_hyper(e: &hyper::Error) -> bool {
+    if e.is_closed() || e.is_incomplete_message() || e.is_canceled() {
+        return true;
+    }
+    let mut source = std::error::Error::source(e);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            return is_transient_kind(e.kind());
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// 内层为连接失败，外层为 http 握手失败
+async fn connect(state: &mut ClientState) -> Result<Result<Sender>, hyper::Error> {
+    let dialer = state.shared.dialer();
+    if state.is_secure {
+        match create_ssl_connection_timed(&dialer, &state.addr, &state.sni, &mut state.timings)
             .await
-            .inspect_err(|e| error!("create stream failed: {e}"))
         {
-            return http_request(req, stream).await;
+            Ok(stream) => {
+                record_geo(state, &dialer);
+                let warn_days = state.shared.config().upstream_cert_warn_days;
+                let cert =
+                    state
+                        .shared
+                        .upstream_certs()
+                        .record(&state.sni, stream.ssl(), warn_days);
+                record_cert(state, cert);
+                handshake(stream, state).await.map(Ok)
+            }
+            Err(e) => {
+                error!("create ssl stream failed: {e}");
+                Ok(Err(e))
+            }
+        }
+    } else {
+        match dialer.connect_timed(&state.addr, &mut state.timings).await {
+            Ok(stream) => {
+                record_geo(state, &dialer);
+                handshake(stream, state).await.map(Ok)
+            }
+            Err(e) => {
+                error!("create stream failed: {e}");
+                Ok(Err(e.into()))
+            }
         }
+    }
+}
+
+fn record_cert(state: &ClientState, cert: Option<Arc<CertInfo>>) {
+    if cert.is_some() {
+        state
+            .shared
+            .flows()
+            .update(state.id, |flow| flow.upstream_cert = cert);
+    }
+}
 
-        let mut resp = Response::new(util::full("connect http failed"));
-        *resp.status_mut() = StatusCode::NOT_ACCEPTABLE;
-        Ok(resp)
+fn record_geo(state: &ClientState, dialer: &Dialer) {
+    if let Some(geo) = state.timings.remote_ip.and_then(|ip| dialer.geo(ip)) {
+        state
+            .shared
+            .flows()
+            .update(state.id, |flow| flow.geo = Some(geo));
     }
 }
 
-async fn http_request<T>(
-    req: Request<IncomingBody>,
-    stream: T,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error>
+fn status(status: StatusCode, body: impl Into<Bytes>) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(util::full(body));
+    *resp.status_mut() = status;
+    resp
+}
+
+async fn handshake<T>(stream: T, state: &ClientState) -> Result<Sender, hyper::Error>
 where
     T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
     debug!("connect success");
 
+    let stream = transcript::record(state.transcript.as_ref(), stream, "upstream").await;
     let io = TokioIo::new(stream);
-    let (mut sender, conn) = hyper::client::conn::http1::handshake(io).await?;
+    let (sender, conn) = hyper::client::conn::http1::handshake(io).await?;
     tokio::task::spawn(async move { conn.await.inspect_err(|e| error!("Connection failed: {e}")) });
+    Ok(sender)
+}
+
+/// 解析模式下大响应应逐帧转发，只保留 `flow_body_limit` 字节
+#[tokio::test]
+async fn should_stream_large_body() {
+    use http_body_util::StreamBody;
+    use hyper::body::Frame;
+    use hyper::server::conn::http1::Builder as ServerBuilder;
+    use hyper::service::service_fn;
+
+    const CHUNK: usize = 64 * 1024;
+    const CHUNKS: usize = 32 * 1024; // 2 GiB
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let service = service_fn(|_| async {
+            let chunk = Bytes::from(vec![b'x'; CHUNK]);
+            let frames = tokio_stream::iter(
+                (0..CHUNKS).map(move |_| Ok::<_, hyper::Error>(Frame::data(chunk.clone()))),
+            );
+            Ok::<_, hyper::Error>(Response::new(StreamBody::new(frames)))
+        });
+        let _ = ServerBuilder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    let config = crate::config::Config::default();
+    let limit = config.flow_body_limit;
+    let shared = crate::state::State::new(config).await.unwrap();
+    let mut state = ClientState {
+        id: crate::flow::next_id(),
+        addr: addr.clone(),
+        sni: "127.0.0.1".to_owned(),
+        is_secure: false,
+        parse: true,
+        transcript: None,
+        timings: Default::default(),
+        shared: shared.clone(),
+    };
+    let req = Request::get(format!("http://{addr}/"))
+        .body(util::empty())
+        .unwrap();
+    let resp = service().call(&mut state, req).await.unwrap();
+
+    let mut body = resp.into_body();
+    let mut received = 0;
+    while let Some(frame) = body.frame().await {
+        received += frame
+            .unwrap()
+            .into_data()
+            .map(|d| d.len())
+            .unwrap_or_default();
+    }
+    drop(body);
+    assert_eq!(received, CHUNK * CHUNKS);
+
+    let flow = shared.flows().get(state.id).unwrap();
+    assert_eq!(flow.response_size, (CHUNK * CHUNKS) as u64);
+    assert_eq!(flow.response_body.len(), limit);
+
+    // nothing close to the body size was held in memory
+    #[cfg(target_os = "linux")]
+    if let Ok(status) = std::fs::read_to_string("/proc/self/status") {
+        let peak_kb: u64 = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))
+            .and_then(|v| v.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap_or_default();
+        assert!(peak_kb < 1024 * 1024, "peak rss {peak_kb} kB");
+    }
+}
 
-    let resp = sender.send_request(req).await?;
-    let resp = resp.map(|b| b.boxed());
+#[tokio::test]
+async fn should_hold_body_until_continue() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-    Ok(resp)
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let upstream = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        let mut body = [0; 5];
+        let early = tokio::time::timeout(Duration::from_millis(300), stream.read(&mut body)).await;
+        assert!(early.is_err(), "body sent before 100");
+        stream
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await
+            .unwrap();
+        stream.read_exact(&mut body).await.unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .await
+            .unwrap();
+        body
+    });
+
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+    let mut req = Request::post("/")
+        .header(EXPECT, "100-continue")
+        .header("content-length", "5")
+        .body(util::full("hello"))
+        .unwrap();
+    await_continue(&mut req, 10);
+    let start = Instant::now();
+    let resp = sender.send_request(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(&upstream.await.unwrap(), b"hello");
+}
+
+/// 空闲连接在 `idle_timeout_secs` 内复用，过期后重新连接
+#[tokio::test]
+async fn should_reuse_pooled_connections_until_idle() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hyper::server::conn::http1::Builder as ServerBuilder;
+    use hyper::service::service_fn;
+
+    use crate::config::{Config, PoolConfig};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::Relaxed);
+            let service =
+                service_fn(|_| async { Ok::<_, hyper::Error>(Response::new(util::full("ok"))) });
+            tokio::spawn(ServerBuilder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+
+    for (idle_timeout_secs, connections) in [(90, 1), (0, 3)] {
+        accepted.store(0, Ordering::Relaxed);
+        let shared = crate::state::State::new(Config {
+            pool: PoolConfig {
+                idle_timeout_secs,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        for _ in 0..3 {
+            let mut state = ClientState {
+                id: crate::flow::next_id(),
+                addr: addr.clone(),
+                sni: "127.0.0.1".to_owned(),
+                is_secure: false,
+                parse: true,
+                transcript: None,
+                timings: Default::default(),
+                shared: shared.clone(),
+            };
+            let req = Request::get(format!("http://{addr}/"))
+                .body(util::empty())
+                .unwrap();
+            let resp = service().call(&mut state, req).await.unwrap();
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, "ok");
+            // the sender goes back to the pool once the connection is ready again
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(accepted.load(Ordering::Relaxed), connections);
+        assert_eq!(
+            shared.metrics().pooled_requests.load(Ordering::Relaxed),
+            3 - connections as u64
+        );
+    }
+}
+
+/// 上游在响应前关闭连接时重试 GET，不重试带 body 的 POST
+#[tokio::test]
+async fn should_retry_only_replayable_requests() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    // every other connection is closed before responding
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let n = counter.fetch_add(1, Ordering::Relaxed);
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                head.push(stream.read_u8().await.unwrap());
+            }
+            if n % 2 == 1 {
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+
+    for (method, body, retried) in [(Method::GET, "", true), (Method::POST, "data", false)] {
+        let mut config = crate::config::Config::default();
+        config.retry.backoff_ms = 10;
+        let shared = crate::state::State::new(config).await.unwrap();
+        let mut state = ClientState {
+            id: crate::flow::next_id(),
+            addr: addr.clone(),
+            sni: "127.0.0.1".to_owned(),
+            is_secure: false,
+            parse: true,
+            transcript: None,
+            timings: Default::default(),
+            shared: shared.clone(),
+        };
+        let before = accepted.load(Ordering::Relaxed);
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("http://{addr}/"))
+            .body(if body.is_empty() {
+                util::empty()
+            } else {
+                util::full(body)
+            })
+            .unwrap();
+        let resp = service().call(&mut state, req).await;
+        let retries = shared.metrics().upstream_retries.load(Ordering::Relaxed);
+        if retried {
+            assert_eq!(resp.unwrap().status(), StatusCode::OK);
+            assert_eq!(accepted.load(Ordering::Relaxed) - before, 2);
+            assert_eq!(retries, 1);
+        } else {
+            assert!(resp.is_err());
+            assert_eq!(accepted.load(Ordering::Relaxed) - before, 1);
+            assert_eq!(retries, 0);
+        }
+    }
+}
+
+/// 新建连接记录 dns / connect 耗时，复用的连接只有 ttfb
+#[tokio::test]
+async fn should_record_timings() {
+    use hyper::server::conn::http1::Builder as ServerBuilder;
+    use hyper::service::service_fn;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let service = service_fn(|_| async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok::<_, hyper::Error>(Response::new(util::full("ok")))
+        });
+        let _ = ServerBuilder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    let shared = crate::state::State::new(crate::config::Config::default())
+        .await
+        .unwrap();
+    let mut timings = Vec::new();
+    for _ in 0..2 {
+        let mut state = ClientState {
+            id: crate::flow::next_id(),
+            addr: addr.clone(),
+            sni: "127.0.0.1".to_owned(),
+            is_secure: false,
+            parse: true,
+            transcript: None,
+            timings: Default::default(),
+            shared: shared.clone(),
+        };
+        let req = Request::get(format!("http://{addr}/"))
+            .body(util::empty())
+            .unwrap();
+        let resp = service().call(&mut state, req).await.unwrap();
+        resp.into_body().collect().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let flow = shared.flows().get(state.id).unwrap();
+        timings.push(flow.timings);
+    }
+
+    let (fresh, pooled) = (timings[0], timings[1]);
+    assert!(fresh.dns_ms.is_some() && fresh.connect_ms.is_some());
+    assert_eq!(fresh.tls_ms, None);
+    assert_eq!(fresh.remote_ip, Some("127.0.0.1".parse().unwrap()));
+    assert!(fresh.ttfb_ms.unwrap() >= 100);
+    assert_eq!((pooled.dns_ms, pooled.connect_ms), (None, None));
+    assert!(pooled.ttfb_ms.unwrap() >= 100);
+}
+
+/// 上游关闭了空闲连接：POST 不走连接池，GET 复用失败后在新连接上重试
+#[tokio::test]
+async fn should_not_lose_post_to_closed_pooled_connection() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    // answers the first request on a connection, then times it out on the next one
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, Ordering::Relaxed);
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                let head = String::from_utf8(head).unwrap();
+                if let Some(len) = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                {
+                    let mut body = vec![0; len.parse().unwrap()];
+                    stream.read_exact(&mut body).await.unwrap();
+                }
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                    .await
+                    .unwrap();
+                let _ = stream.read_u8().await;
+            });
+        }
+    });
+
+    let shared = crate::state::State::new(crate::config::Config::default())
+        .await
+        .unwrap();
+    for method in [Method::GET, Method::POST, Method::GET] {
+        let mut state = ClientState {
+            id: crate::flow::next_id(),
+            addr: addr.clone(),
+            sni: "127.0.0.1".to_owned(),
+            is_secure: false,
+            parse: true,
+            transcript: None,
+            timings: Default::default(),
+            shared: shared.clone(),
+        };
+        let body = if method == Method::POST {
+            util::full("data")
+        } else {
+            util::empty()
+        };
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("http://{addr}/"))
+            .body(body)
+            .unwrap();
+        let resp = service().call(&mut state, req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "ok");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    // the POST opened its own connection, the second GET tried both dead idle ones first
+    let metrics = shared.metrics();
+    assert_eq!(metrics.pooled_requests.load(Ordering::Relaxed), 2);
+    assert_eq!(metrics.upstream_retries.load(Ordering::Relaxed), 2);
+    assert_eq!(accepted.load(Ordering::Relaxed), 3);
 }
@@ -1,24 +1,1276 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use tokio::{
     fs::File,
     io::{AsyncReadExt, AsyncWriteExt},
 };
+use tracing_subscriber::EnvFilter;
+
+use crate::acl;
+use crate::filter::{self, Filter};
+use crate::flow::Flow;
+use crate::redact::Pattern;
+use crate::resolver;
+use crate::reverse::Target;
+use crate::sniff::Detected;
 
 const CONFIG_FILE: &str = "proxy_config.json";
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// JSON lines, one event per line
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum IpFamily {
+    /// resolver order
+    #[default]
+    Auto,
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+/// `X-Forwarded-For` / `Via` 等转发头的处理方式
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardedPolicy {
+    /// 追加客户端地址与本代理
+    Append,
+    #[default]
+    Passthrough,
+    /// 删除，不暴露客户端与代理链
+    Strip,
+}
+
+/// 写入日志与 flow store 前的脱敏规则，replay 会使用脱敏后的值
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RedactConfig {
+    /// 不区分大小写，如 `Authorization`、`Cookie`
+    pub headers: Vec<String>,
+    /// JSON body 中任意层级的字段名
+    pub json_fields: Vec<String>,
+    /// 匹配到的内容替换为 `[REDACTED]`，作用于 URI、header 值与 body
+    pub patterns: Vec<Pattern>,
+}
+
+/// 过滤表达式，如 `~d example.com & ~m POST`，为空则不过滤
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct FilterConfig {
+    /// 只记录匹配的请求日志，按当时已知的请求与响应头求值
+    pub log: Option<Filter>,
+    /// 只保留匹配的 flow，在请求完成时求值
+    pub store: Option<Filter>,
+    /// 只解密匹配的 CONNECT，按 `CONNECT host` 求值
+    pub intercept: Option<Filter>,
+}
+
+/// 隐私模式：去掉发往上游的请求中的跟踪参数、跨源 `Referer` 与 client hints
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PrivacyConfig {
+    pub enabled: bool,
+    /// host 按后缀匹配，为空时对所有 host 生效
+    pub hosts: Vec<String>,
+    /// 去掉的 query 参数，不区分大小写，`*` 结尾时按前缀匹配
+    pub params: Vec<String>,
+    pub strip_referer: bool,
+    /// `Sec-CH-*` 与 `DPR`、`Viewport-Width` 等旧式 client hints
+    pub strip_client_hints: bool,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            hosts: [].to_vec(),
+            params: [
+                "utm_*", "gclid", "dclid", "gbraid", "wbraid", "fbclid", "msclkid", "yclid",
+                "mc_eid", "igshid", "_hsenc", "_hsmi",
+            ]
+            .map(str::to_owned)
+            .to_vec(),
+            strip_referer: true,
+            strip_client_hints: true,
+        }
+    }
+}
+
+impl PrivacyConfig {
+    pub fn matches(&self, domain: &str) -> bool {
+        self.enabled && (self.hosts.is_empty() || self.hosts.iter().any(|i| domain.ends_with(i)))
+    }
+}
+
+/// 解析模式下压缩 body 的处理方式
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentDecoding {
+    #[default]
+    Off,
+    /// 解压响应后以明文转发给客户端
+    Decode,
+    /// 请求改为 `Accept-Encoding: identity`，上游直接返回明文
+    Identity,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeKind {
+    #[default]
+    Tcp,
+    Tls,
+    /// `HEAD /`, over TLS when port is 443
+    Http,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ProbeConfig {
+    /// `host:port`
+    pub hosts: Vec<String>,
+    pub kind: ProbeKind,
+    pub interval_secs: u64,
+    pub timeout_secs: u64,
+    /// 不可达时直接拒绝经由代理访问该源站
+    pub circuit_breaker: bool,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            hosts: [].to_vec(),
+            kind: ProbeKind::Tcp,
+            interval_secs: 30,
+            timeout_secs: 5,
+            circuit_breaker: true,
+        }
+    }
+}
+
+/// body 的临时文件在 flow 被移出 flow store 后删除；
+/// 配置了 `redact` 时不写临时文件，脱敏需要完整的 body
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SpillConfig {
+    /// 为空时使用系统临时目录
+    pub dir: Option<PathBuf>,
+    /// 单个 body 写入的上限，超出部分丢弃
+    pub max_body_mb: u64,
+}
+
+impl Default for SpillConfig {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            max_body_mb: 1024,
+        }
+    }
+}
+
+impl SpillConfig {
+    /// 每个进程一个子目录，退出时整个删除
+    pub fn dir(&self) -> PathBuf {
+        self.dir
+            .clone()
+            .unwrap_or_else(std::env::temp_dir)
+            .join(format!("http-proxy-server-{}", std::process::id()))
+    }
+}
+
+/// 发往 `hosts` 的请求头 / 收到的响应头超出白名单时报告，`strip` 时同时移除
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AuditRule {
+    pub hosts: Vec<String>,
+    /// 为空则不审计请求头
+    pub request_headers: Option<Vec<String>>,
+    /// 为空则不审计响应头
+    pub response_headers: Option<Vec<String>>,
+    pub strip: bool,
+}
+
+impl AuditRule {
+    pub fn matches(&self, domain: &str) -> bool {
+        self.hosts.iter().any(|i| domain.ends_with(i))
+    }
+}
+
+/// 为发往 `hosts` 的请求附加 `Authorization`，
+/// 按 `bearer`、`username`、`token_file`、`token_command`、`oauth2` 的顺序取第一个设置的凭据
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AuthRule {
+    pub hosts: Vec<String>,
+    /// `bearer`、`token_file`、`token_command` 的 token 前缀，如 GitHub 的 `token`
+    pub scheme: String,
+    pub bearer: Option<String>,
+    /// 与 `password` 组成 `Basic` 认证
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// 文件内容（去掉首尾空白）作为 token
+    pub token_file: Option<PathBuf>,
+    /// 程序与参数，stdout 作为 token，如 `["gcloud", "auth", "print-access-token"]`
+    pub token_command: Vec<String>,
+    pub oauth2: Option<OAuth2Config>,
+    /// `token_file`、`token_command` 得到的 token 缓存多久，0 为每个请求重新获取；
+    /// 也是 `oauth2` 响应没有 `expires_in` 时的有效期
+    pub token_ttl_secs: u64,
+    /// 覆盖客户端自带的 `Authorization`，否则保留
+    pub overwrite: bool,
+}
+
+impl Default for AuthRule {
+    fn default() -> Self {
+        Self {
+            hosts: [].to_vec(),
+            scheme: "Bearer".to_owned(),
+            bearer: None,
+            username: None,
+            password: None,
+            token_file: None,
+            token_command: [].to_vec(),
+            oauth2: None,
+            token_ttl_secs: 300,
+            overwrite: false,
+        }
+    }
+}
+
+/// OAuth2 client credentials，同一 issuer、client 与 scope 的 token 在规则间共用，
+/// 过期前 `refresh_before_secs` 刷新，上游返回 401 时丢弃
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OAuth2Config {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scope: Option<String>,
+    pub audience: Option<String>,
+    /// 把 client 凭据放在表单里，否则用 `Basic` 认证
+    pub credentials_in_body: bool,
+    pub refresh_before_secs: u64,
+}
+
+impl Default for OAuth2Config {
+    fn default() -> Self {
+        Self {
+            token_url: "".to_owned(),
+            client_id: "".to_owned(),
+            client_secret: "".to_owned(),
+            scope: None,
+            audience: None,
+            credentials_in_body: false,
+            refresh_before_secs: 60,
+        }
+    }
+}
+
+impl AuthRule {
+    pub fn matches(&self, domain: &str) -> bool {
+        self.hosts.iter().any(|i| domain.ends_with(i))
+    }
+}
+
+/// 向上游代理认证的方式
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyAuthScheme {
+    /// 第一个 CONNECT 就带上凭据
+    #[default]
+    Basic,
+    /// NTLMv2 challenge-response，在同一连接上完成
+    Ntlm,
+    /// 以 NTLM 令牌应答 `Negotiate` challenge，不支持 Kerberos
+    Negotiate,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamKind {
+    /// CONNECT 隧道，DNS 总是由上游代理解析
+    #[default]
+    Http,
+    /// 如 `ssh -D` 或 Tor
+    Socks5,
+}
+
+/// 经上游代理连接 `hosts`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct UpstreamProxy {
+    /// `host:port`
+    pub addr: String,
+    pub kind: UpstreamKind,
+    /// 为空时对所有 host 生效
+    pub hosts: Vec<String>,
+    /// ISO 3166 国家代码，设置后只在目标解析到这些国家时使用此代理，需要 `geoip.database`
+    pub countries: Vec<String>,
+    /// SOCKS5 时把域名交给上游解析（即 `socks5h`），否则在本地解析后只发送 IP
+    pub remote_dns: bool,
+    /// 设置后认证，SOCKS5 使用用户名/密码认证（RFC 1929）
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// 仅 HTTP
+    pub auth: ProxyAuthScheme,
+    /// NTLM 的域，也可以写成 `DOMAIN\user`
+    pub domain: Option<String>,
+    /// NTLM 的工作站名
+    pub workstation: Option<String>,
+}
+
+impl UpstreamProxy {
+    pub fn matches(&self, domain: &str) -> bool {
+        self.hosts.is_empty() || self.hosts.iter().any(|i| domain.ends_with(i))
+    }
+}
+
+/// MaxMind 格式的 GeoIP 数据库，查询结果附加到 flow
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct GeoIpConfig {
+    /// `.mmdb`，如 GeoLite2-Country、GeoLite2-City 或 GeoLite2-ASN
+    pub database: Option<PathBuf>,
+    /// ISO 3166 国家代码，不连接解析到这些国家的地址；由上游代理解析 DNS 时不检查
+    pub block_countries: Vec<String>,
+}
+
+/// 常见浏览器与爬虫的 User-Agent
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UserAgentPreset {
+    Chrome,
+    Firefox,
+    Safari,
+    Edge,
+    Iphone,
+    Ipad,
+    Android,
+    Googlebot,
+}
+
+impl UserAgentPreset {
+    pub fn value(self) -> &'static str {
+        match self {
+            Self::Chrome => "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Safari/537.36",
+            Self::Firefox => "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:132.0) Gecko/20100101 Firefox/132.0",
+            Self::Safari => "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.0 Safari/605.1.15",
+            Self::Edge => "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Safari/537.36 Edg/130.0.0.0",
+            Self::Iphone => "Mozilla/5.0 (iPhone; CPU iPhone OS 18_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.0 Mobile/15E148 Safari/604.1",
+            Self::Ipad => "Mozilla/5.0 (iPad; CPU OS 18_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.0 Mobile/15E148 Safari/604.1",
+            Self::Android => "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/130.0.0.0 Mobile Safari/537.36",
+            Self::Googlebot => "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+        }
+    }
+}
+
+/// 替换发往 `hosts` 的请求的 User-Agent，`user_agent` 优先于 `preset`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct UserAgentRule {
+    /// 为空时对所有 host 生效
+    pub hosts: Vec<String>,
+    pub user_agent: Option<String>,
+    pub preset: Option<UserAgentPreset>,
+}
+
+impl UserAgentRule {
+    pub fn matches(&self, domain: &str) -> bool {
+        self.hosts.is_empty() || self.hosts.iter().any(|i| domain.ends_with(i))
+    }
+
+    pub fn value(&self) -> Option<&str> {
+        self.user_agent
+            .as_deref()
+            .or(self.preset.map(UserAgentPreset::value))
+    }
+}
+
+/// 为 `hosts` 的响应补充 CORS 头，并在本地应答预检请求
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CorsRule {
+    pub hosts: Vec<String>,
+    /// 允许的 Origin，为空则回显请求的 Origin
+    pub allow_origins: Vec<String>,
+    pub allow_credentials: bool,
+    /// 预检允许的方法，为空则回显 `Access-Control-Request-Method`
+    pub allow_methods: Vec<String>,
+    /// 预检允许的请求头，为空则回显 `Access-Control-Request-Headers`
+    pub allow_headers: Vec<String>,
+    /// 暴露给页面的响应头，为空则暴露响应的全部头
+    pub expose_headers: Vec<String>,
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsRule {
+    fn default() -> Self {
+        Self {
+            hosts: [].to_vec(),
+            allow_origins: [].to_vec(),
+            allow_credentials: true,
+            allow_methods: [].to_vec(),
+            allow_headers: [].to_vec(),
+            expose_headers: [].to_vec(),
+            max_age_secs: 86400,
+        }
+    }
+}
+
+impl CorsRule {
+    pub fn matches(&self, domain: &str) -> bool {
+        self.hosts.iter().any(|i| domain.ends_with(i))
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        self.allow_origins.is_empty() || self.allow_origins.iter().any(|o| o == origin)
+    }
+}
+
+/// 片段插入的位置，找不到对应标签时退到另一处，都没有时追加到末尾
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InjectPosition {
+    /// `</head>` 之前
+    #[default]
+    Head,
+    /// `</body>` 之前
+    Body,
+}
+
+/// 向 `hosts` 的 `text/html` 响应插入片段，如 eruda / vConsole 的 script 标签；
+/// 压缩的响应先解压，插入后以明文转发并重新计算长度
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct InjectRule {
+    pub hosts: Vec<String>,
+    pub snippet: String,
+    pub position: InjectPosition,
+    /// 移除 `Content-Security-Policy`，以免内联脚本被拦截
+    pub strip_csp: bool,
+}
+
+impl InjectRule {
+    pub fn matches(&self, domain: &str) -> bool {
+        self.hosts.iter().any(|i| domain.ends_with(i))
+    }
+}
+
+/// 匹配的请求/响应在转发前暂停，等待管理接口编辑、放行或丢弃
+/// 按请求头与响应头求值，body 不参与匹配
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BreakpointRule {
+    pub filter: Filter,
+    pub request: bool,
+    pub response: bool,
+}
+
+impl Default for BreakpointRule {
+    fn default() -> Self {
+        Self {
+            filter: Filter::default(),
+            request: true,
+            response: false,
+        }
+    }
+}
+
+/// 匹配的请求复制一份异步发往 `upstream`，客户端只收到主上游的响应
+/// 按请求头求值，镜像的请求 body 会先完整读入内存
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MirrorRule {
+    pub filter: Filter,
+    /// 如 `http://127.0.0.1:8081`，path 作为前缀
+    pub upstream: String,
+    /// 镜像的请求与响应也记录为 flow（`origin` 为原 flow），否则丢弃
+    pub store: bool,
+}
+
+/// 按权重把匹配的请求分到多个上游，用于从本机验证灰度发布
+/// 按请求头求值，仅解析模式下生效
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SplitRule {
+    pub filter: Filter,
+    pub targets: Vec<SplitTarget>,
+    /// 设置后用这个 cookie 记住选中的上游
+    pub sticky_cookie: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SplitTarget {
+    /// sticky cookie 的值，为空时用下标
+    pub name: String,
+    /// 如 `http://127.0.0.1:8081`，path 作为前缀
+    pub upstream: String,
+    pub weight: u32,
+    /// 保留客户端的 Host，否则改为上游地址
+    pub preserve_host: bool,
+}
+
+impl Default for SplitTarget {
+    fn default() -> Self {
+        Self {
+            name: "".to_owned(),
+            upstream: "".to_owned(),
+            weight: 1,
+            preserve_host: false,
+        }
+    }
+}
+
+/// 上游 http1 连接复用
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PoolConfig {
+    pub enabled: bool,
+    pub idle_timeout_secs: u64,
+    /// 每个上游最多保留的空闲连接
+    pub max_idle_per_host: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            idle_timeout_secs: 90,
+            max_idle_per_host: 8,
+        }
+    }
+}
+
+/// 按 RFC 9111 缓存上游响应，容量修改后重启生效
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub memory_bytes: u64,
+    /// 内存淘汰的条目写入此目录，为空则只用内存
+    pub disk_dir: Option<PathBuf>,
+    pub disk_bytes: u64,
+    /// 超过的响应不缓存
+    pub max_entry_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            memory_bytes: 64 * 1024 * 1024,
+            disk_dir: None,
+            disk_bytes: 1024 * 1024 * 1024,
+            max_entry_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProtocol {
+    /// UDP，截断时 TCP
+    #[default]
+    Udp,
+    /// DNS-over-TLS，默认端口 853
+    Tls,
+    /// DNS-over-HTTPS，默认端口 443
+    Https,
+}
+
+/// 出站连接使用的 DNS 解析，修改后重启生效
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct DnsConfig {
+    /// 如 `1.1.1.1` 或 `[2606:4700::1111]:53`，为空则使用系统配置
+    pub nameservers: Vec<String>,
+    pub protocol: DnsProtocol,
+    /// DoT/DoH 校验证书的服务器名，如 `cloudflare-dns.com`
+    pub tls_name: Option<String>,
+    /// 正缓存 TTL 上限
+    pub max_ttl_secs: u64,
+    /// 解析失败的缓存时间
+    pub negative_ttl_secs: u64,
+}
+
+impl DnsConfig {
+    /// DoT/DoH 没有 `tls_name` 无法校验证书
+    pub fn check(&self) -> Result<()> {
+        if self.protocol != DnsProtocol::Udp && self.tls_name.is_none() {
+            return Err(anyhow!("required by {:?}", self.protocol));
+        }
+        Ok(())
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            nameservers: [].to_vec(),
+            protocol: DnsProtocol::Udp,
+            tls_name: None,
+            max_ttl_secs: 300,
+            negative_ttl_secs: 30,
+        }
+    }
+}
+
+/// 各阶段超时，0 表示不限制
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// 建立上游 TCP 连接（含所有地址的尝试）
+    pub connect_secs: u64,
+    /// 与客户端或上游的 TLS 握手
+    pub tls_handshake_secs: u64,
+    /// 发出请求到收到上游响应头
+    pub response_secs: u64,
+    /// 隧道双向都没有数据时关闭
+    pub tunnel_idle_secs: u64,
+    /// 带 `Expect: 100-continue` 的请求等待上游 100 的时间，之后照常发送 body；为 0 时立即发送
+    pub continue_secs: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            connect_secs: 10,
+            tls_handshake_secs: 10,
+            response_secs: 60,
+            tunnel_idle_secs: 300,
+            continue_secs: 1,
+        }
+    }
+}
+
+/// 上游连接失败，或 GET/HEAD 请求遇到连接被重置等错误时重试
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    pub max_retries: u32,
+    /// 第一次重试前等待，之后每次翻倍
+    pub backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            backoff_ms: 100,
+        }
+    }
+}
+
+/// 并发上限，0 表示不限制，修改后重启生效
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LimitConfig {
+    /// 同时服务的客户端连接
+    pub max_connections: usize,
+    /// 同时进行的隧道与请求
+    pub max_requests: usize,
+    /// 每个目标 host 同时进行的隧道与请求
+    pub max_per_host: usize,
+    /// 达到上限时最多排队等待的数量，超出则返回 503
+    pub max_queue: usize,
+    /// 活动连接超过该数量时减载
+    pub shed_connections: usize,
+    /// 进程常驻内存超过该值（MB）时减载，仅 Linux
+    pub shed_memory_mb: u64,
+    pub shed: ShedMode,
+}
+
+impl Default for LimitConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 0,
+            max_requests: 0,
+            max_per_host: 0,
+            max_queue: 1000,
+            shed_connections: 0,
+            shed_memory_mb: 0,
+            shed: ShedMode::default(),
+        }
+    }
+}
+
+/// 过载时的减载方式
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ShedMode {
+    /// 暂停 accept，新连接留在内核的 backlog 中
+    #[default]
+    Pause,
+    /// 照常 accept，新的明文 HTTP 请求直接返回 503
+    Reject,
+}
+
+/// 按客户端 IP 与目标 host 累计流量（上下行合计），额度为 0 时只计数不限制；
+/// 计数在请求或隧道结束时累加，超出后拒绝新的请求与隧道，进行中的不中断
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QuotaConfig {
+    pub enabled: bool,
+    /// 计数持久化的 JSON 文件，为空时只在内存中计数
+    pub path: Option<PathBuf>,
+    pub save_interval_secs: u64,
+    /// 每个客户端 IP 的字节额度
+    pub client_bytes: u64,
+    /// 客户端 IP -> 额度，优先于 `client_bytes`
+    pub clients: HashMap<String, u64>,
+    /// 每个 host 的字节额度
+    pub host_bytes: u64,
+    /// host 后缀 -> 额度，优先于 `host_bytes`；匹配的 host 各自计数
+    pub hosts: HashMap<String, u64>,
+    /// 超出额度时返回的 HTML 页面，`{{subject}}`、`{{used}}`、`{{limit}}` 会被替换；为空时使用内置页面
+    pub page: Option<PathBuf>,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            save_interval_secs: 60,
+            client_bytes: 0,
+            clients: HashMap::new(),
+            host_bytes: 0,
+            hosts: HashMap::new(),
+            page: None,
+        }
+    }
+}
+
+impl QuotaConfig {
+    pub fn client_limit(&self, ip: &str) -> u64 {
+        self.clients.get(ip).copied().unwrap_or(self.client_bytes)
+    }
+
+    pub fn host_limit(&self, host: &str) -> u64 {
+        // the longest suffix wins, map order is arbitrary
+        self.hosts
+            .iter()
+            .filter(|(suffix, _)| host.ends_with(suffix.as_str()))
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map_or(self.host_bytes, |(_, limit)| *limit)
+    }
+}
+
+/// 定期把按 host 的请求数、错误数与流量的增量追加到 CSV，退出时再写一次
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StatsSnapshotConfig {
+    pub path: PathBuf,
+    pub interval_secs: u64,
+}
+
+impl Default for StatsSnapshotConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("stats.csv"),
+            interval_secs: 3600,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    /// 本地时间跨天时
+    #[default]
+    Daily,
+    /// 超过 `max_size_mb` 时
+    Size,
+}
+
+/// release 构建写入的日志文件，轮转后的文件名带上日期或时间
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    pub rotation: LogRotation,
+    pub max_size_mb: u64,
+    /// 保留的轮转文件数，0 为不删除
+    pub keep: usize,
+}
+
+impl Default for LogFileConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("proxy.log"),
+            rotation: LogRotation::Daily,
+            max_size_mb: 100,
+            keep: 7,
+        }
+    }
+}
+
+/// CI 断言模式：记录所有请求，退出时有不在允许列表中的请求则输出报告并以非零状态退出
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ExpectConfig {
+    /// `[METHOD ]URL`，`*` 匹配任意字符，如 `GET https://api.example.com/v1/*`；
+    /// 不解析的隧道按 `CONNECT host:port`、UDP 隧道按 `CONNECT-UDP host:port` 匹配
+    pub allow: Vec<String>,
+    /// 每行一条，`#` 开头为注释，与 `allow` 合并
+    pub allow_file: Option<PathBuf>,
+    /// 退出时写入 JSON 报告
+    pub report: Option<PathBuf>,
+    /// 以 403 拒绝不在允许列表中的请求，而不只是记录
+    pub block: bool,
+}
+
+/// 规则触发时向 `url` POST JSON，带 `text` 字段，可直接用作 Slack incoming webhook
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// 结束（完成或出错）的 flow 匹配时通知，如 `~d example.com & ~c 5xx`
+    pub filter: Option<Filter>,
+    /// 根 CA 在这么多天内过期时通知，每天最多一次
+    pub ca_expiry_days: Option<u32>,
+    /// flow 通知的最小间隔，期间的匹配只计数，在下一次通知中带上
+    pub min_interval_secs: u64,
+    /// 连接失败、5xx 与 429 时的重试次数，间隔依次加倍
+    pub retries: u32,
+    pub timeout_secs: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            filter: None,
+            ca_expiry_days: None,
+            min_interval_secs: 60,
+            retries: 3,
+            timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogTransport {
+    #[default]
+    Udp,
+    /// RFC 6587 octet counting
+    Tcp,
+    /// 本机的 `/dev/log`，仅 Unix
+    Unix,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    User,
+    #[default]
+    Daemon,
+    Auth,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    pub fn code(self) -> u8 {
+        match self {
+            Self::User => 1,
+            Self::Daemon => 3,
+            Self::Auth => 4,
+            Self::Local0 => 16,
+            Self::Local1 => 17,
+            Self::Local2 => 18,
+            Self::Local3 => 19,
+            Self::Local4 => 20,
+            Self::Local5 => 21,
+            Self::Local6 => 22,
+            Self::Local7 => 23,
+        }
+    }
+}
+
+/// 以 RFC 5424 格式把日志发往 syslog，与控制台 / `proxy.log` 同时输出
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SyslogConfig {
+    pub transport: SyslogTransport,
+    /// `host:port` 或 UNIX socket 路径，为空时为 `127.0.0.1:514` / `/dev/log`
+    pub addr: String,
+    pub facility: SyslogFacility,
+    pub app_name: String,
+    /// 为空时取系统主机名
+    pub hostname: Option<String>,
+}
+
+impl Default for SyslogConfig {
+    fn default() -> Self {
+        Self {
+            transport: SyslogTransport::Udp,
+            addr: String::new(),
+            facility: SyslogFacility::Daemon,
+            app_name: "http-proxy-server".to_owned(),
+            hostname: None,
+        }
+    }
+}
+
+impl SyslogConfig {
+    pub fn addr(&self) -> &str {
+        match (self.addr.as_str(), self.transport) {
+            ("", SyslogTransport::Unix) => "/dev/log",
+            ("", _) => "127.0.0.1:514",
+            (addr, _) => addr,
+        }
+    }
+}
+
+/// 写入 Windows 事件日志（应用程序），需要 `eventlog` feature
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct EventLogConfig {
+    /// 事件来源名称
+    pub source: String,
+}
+
+impl Default for EventLogConfig {
+    fn default() -> Self {
+        Self {
+            source: "http-proxy-server".to_owned(),
+        }
+    }
+}
+
+/// 客户端与上游 TCP 连接的 socket 参数，为空则使用系统默认值
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SocketConfig {
+    pub nodelay: bool,
+    /// 开始发送 keepalive 探测前的空闲时间
+    pub keepalive_secs: Option<u64>,
+    pub keepalive_interval_secs: Option<u64>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    /// 隧道每个方向的拷贝缓冲区
+    pub copy_buffer_size: usize,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: false,
+            keepalive_secs: None,
+            keepalive_interval_secs: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            copy_buffer_size: 8 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerMode {
+    /// HTTP 代理，接受绝对 URI 与 CONNECT
+    #[default]
+    Forward,
+    /// 接受普通的 HTTP/TLS 请求，按 SNI 或 Host 转发到 `backends`
+    Reverse,
+    /// 接受 iptables/nftables REDIRECT 或 TPROXY 重定向来的连接
+    Transparent,
+}
+
+/// 额外的 TCP 监听端，与主监听端共享状态
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ListenerConfig {
+    /// 如 `0.0.0.0:3128`
+    pub addr: String,
+    /// 配置了用户名时是否要求认证
+    pub auth: bool,
+    /// 为空则跟随 `parse`
+    pub parse: Option<bool>,
+    pub mode: ListenerMode,
+    /// 反向代理时以 TLS 接受连接，用 root CA 按 SNI 签发证书
+    pub tls: bool,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            addr: "".to_owned(),
+            auth: true,
+            parse: None,
+            mode: ListenerMode::default(),
+            tls: false,
+        }
+    }
+}
+
+/// 单个 host 的拦截深度
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Depth {
+    /// 不解密，原样转发
+    Tunnel,
+    /// 解密后与上游 TLS 对转，不解析 HTTP
+    Terminate,
+    /// 解密并解析 HTTP
+    Parse,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelPolicy {
+    /// 原样转发
+    #[default]
+    Tunnel,
+    /// 关闭连接
+    Reject,
+    /// TLS 按 `proxy_hosts` 解密，明文 HTTP 按 `parse` 解析，其他协议作为 TCP flow 记录
+    Intercept,
+}
+
+/// 发往上游的 ClientHello 模仿的客户端；OpenSSL 无法生成 GREASE 值，也不能调整扩展顺序，
+/// 只能对齐密码套件及其顺序、曲线、签名算法、ALPN 与部分扩展
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsProfile {
+    /// OpenSSL 默认
+    #[default]
+    Openssl,
+    Chrome,
+    Firefox,
+}
+
+/// CONNECT 隧道内按开头字节识别的协议及其处理方式
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SniffConfig {
+    pub tls: TunnelPolicy,
+    pub http: TunnelPolicy,
+    pub ssh: TunnelPolicy,
+    pub other: TunnelPolicy,
+}
+
+impl Default for SniffConfig {
+    fn default() -> Self {
+        Self {
+            tls: TunnelPolicy::Intercept,
+            http: TunnelPolicy::Tunnel,
+            ssh: TunnelPolicy::Tunnel,
+            other: TunnelPolicy::Tunnel,
+        }
+    }
+}
+
+impl SniffConfig {
+    pub fn policy(&self, detected: &Detected) -> TunnelPolicy {
+        match detected {
+            Detected::Tls(_) => self.tls,
+            Detected::Http => self.http,
+            Detected::Ssh => self.ssh,
+            Detected::Other => self.other,
+        }
+    }
+
+    /// 所有协议都会原样转发时不必等待客户端的数据
+    pub fn is_needed(&self, is_proxy: bool) -> bool {
+        let tls = match self.tls {
+            TunnelPolicy::Intercept if !is_proxy => TunnelPolicy::Tunnel,
+            policy => policy,
+        };
+        [tls, self.http, self.ssh, self.other]
+            .iter()
+            .any(|policy| *policy != TunnelPolicy::Tunnel)
+    }
+}
+
+/// 反向代理的虚拟主机
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Backend {
+    /// SNI / Host 后缀，为空匹配所有；多个匹配时取最长的后缀
+    pub hosts: Vec<String>,
+    /// 如 `http://127.0.0.1:3000` 或 `https://api.internal/v1`，path 作为前缀
+    pub upstream: String,
+    /// 保留客户端的 Host，否则改为上游地址
+    pub preserve_host: bool,
+}
+
+/// 为部分 host 签发证书的其他 CA，如内部域名用公司的 CA
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct RootCa {
+    /// 唯一的名字，日志与 `/readyz` 中使用
+    pub name: String,
+    /// 证书与私钥文件必须已存在，格式同 `root_ca_cert_path`
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// host 后缀，为空匹配所有；多个匹配时取最长的后缀，都不匹配时用默认的 root CA
+    pub hosts: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(default)]
 pub struct Config {
     pub bind_ip: String,
     pub bind_port: u16,
     pub proxy_hosts: Vec<String>,
+    /// host 后缀 -> 拦截深度，优先于 `proxy_hosts`、`filters.intercept` 与 `parse`
+    pub depth_hosts: HashMap<String, Depth>,
     pub sni: String,
     pub root_ca_cert_path: PathBuf,
     pub root_ca_key_path: PathBuf,
+    /// root CA 私钥以口令加密保存，口令取自 `HTTP_PROXY_CA_PASSPHRASE` 或启动时在终端输入；
+    /// 已有的明文私钥在启动时改写为加密的
+    pub root_ca_key_encrypted: bool,
+    /// 按 host 选择的其他 CA，启动时加载；私钥加密时与默认的 root CA 使用同一口令
+    pub root_cas: Vec<RootCa>,
+    /// 上游证书在这些天内过期时告警
+    pub upstream_cert_warn_days: Option<u32>,
+    pub upstream_tls_profile: TlsProfile,
     pub parse: bool,
+    /// 关闭后所有 CONNECT 直接转发，不做 MITM
+    pub intercept: bool,
+    /// 记录这些 host 的连接原始字节（pre-TLS 与 post-TLS）
+    pub transcript_hosts: Vec<String>,
+    pub transcript_dir: PathBuf,
+    /// 把解密后的流量写成合成 TCP 连接的 pcapng 文件
+    pub pcap_path: Option<PathBuf>,
+    pub log_format: LogFormat,
+    /// tracing 过滤指令，如 `info` 或 `http_proxy_server::proxy=debug,error`
+    pub log_filter: Option<String>,
+    pub log_file: LogFileConfig,
+    pub syslog: Option<SyslogConfig>,
+    pub event_log: Option<EventLogConfig>,
+    pub ip_family: IpFamily,
+    /// host 后缀 -> 地址族，优先于 `ip_family`
+    pub ip_family_hosts: HashMap<String, IpFamily>,
+    pub probe: Option<ProbeConfig>,
+    /// 保留最近多少条 flow
+    pub flow_capacity: usize,
+    /// 每个 body 最多保留的字节数
+    pub flow_body_limit: usize,
+    /// 超出 `flow_body_limit` 的 body 完整写入临时文件
+    pub flow_spill: Option<SpillConfig>,
+    /// 记录为 TCP flow 的隧道同时保存原始字节，总量受 `flow_body_limit` 限制
+    pub tcp_capture: bool,
+    /// 向上游发送 `X-Request-Id: <flow id>`，已有时保留客户端的值
+    pub inject_request_id: bool,
+    /// 解析模式下日志中 body 预览的字节数，0 只记录大小
+    pub log_body_limit: usize,
+    /// 解析模式下逐条记录 `text/event-stream` 的事件，data 预览同样受 `log_body_limit` 限制
+    pub log_sse_events: bool,
+    /// gzip / deflate / br / zstd
+    pub content_decoding: ContentDecoding,
+    /// `protoc --include_imports --descriptor_set_out` 生成的 FileDescriptorSet，
+    /// 设置后按方法的输入输出类型解码 gRPC 消息
+    pub grpc_descriptors: Option<PathBuf>,
+    /// GraphQL 端点，如 `api.example.com/graphql`，host 按后缀匹配；只写路径时匹配所有 host
+    pub graphql_endpoints: Vec<String>,
+    /// 解析模式下按 domain 记录 `Set-Cookie` 与 `Cookie`，在管理接口 `/api/cookies` 查看与导出
+    pub cookie_jar: bool,
+    pub redact: RedactConfig,
+    pub filters: FilterConfig,
+    /// 管理端口，如 `127.0.0.1:31182`，为空则不启用
+    pub admin_addr: Option<String>,
+    /// 设置后管理接口要求 `Authorization: Bearer <token>` 或 `?token=`；
+    /// 未设置时只接受以 IP 或 localhost 访问
+    pub admin_token: Option<String>,
+    pub audit_rules: Vec<AuditRule>,
+    pub cors_rules: Vec<CorsRule>,
+    /// 按顺序取第一个匹配的规则；HTTPS 仅在解密时生效
+    pub auth_rules: Vec<AuthRule>,
+    /// 按顺序取第一个匹配的规则；HTTPS 仅在解密时生效
+    pub inject_rules: Vec<InjectRule>,
+    /// 按顺序取第一个匹配的规则；HTTPS 仅在解密时生效
+    pub user_agent_rules: Vec<UserAgentRule>,
+    /// 仅解析模式下生效
+    pub breakpoints: Vec<BreakpointRule>,
+    /// 断点无人处理时多久后原样放行
+    pub breakpoint_timeout_secs: u64,
+    /// 仅解析模式下生效，按顺序取第一个匹配的规则
+    pub mirrors: Vec<MirrorRule>,
+    /// 按顺序取第一个匹配的规则
+    pub splits: Vec<SplitRule>,
+    pub forwarded: ForwardedPolicy,
+    /// HTTPS 仅在解密时生效
+    pub privacy: PrivacyConfig,
+    /// 启动时把系统代理指向本服务，退出时恢复
+    pub system_proxy: bool,
+    /// 设置后要求客户端通过 `Proxy-Authorization: Basic` 认证
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// 允许连接的客户端地址（CIDR），为空则不限制
+    pub allow_clients: Vec<acl::Cidr>,
+    /// 拒绝连接的客户端地址（CIDR），优先于 `allow_clients`
+    pub deny_clients: Vec<acl::Cidr>,
+    /// 允许 CONNECT 的目标端口，如 `443`、`8000-8999`，为空时允许所有
+    pub allow_connect_ports: Vec<acl::PortRange>,
+    /// 拒绝 CONNECT 的目标端口，优先于 `allow_connect_ports`
+    pub deny_connect_ports: Vec<acl::PortRange>,
+    /// 监听端证书链（PEM），与 `tls_key_path` 同时设置时以 TLS 接受客户端连接
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    /// 额外监听的 unix domain socket 路径（unix）
+    pub unix_path: Option<PathBuf>,
+    /// 额外监听的命名管道，如 `\\.\pipe\http-proxy-server`（windows）
+    pub pipe_name: Option<String>,
+    pub listeners: Vec<ListenerConfig>,
+    /// QUIC 监听地址（UDP），如 `0.0.0.0:443`；以 root CA 按 SNI 签发的证书解密客户端的 HTTP/3 请求，
+    /// 需要 `http3` feature
+    pub h3_addr: Option<String>,
+    /// 这些 host 的上游请求走 HTTP/3，需要 `http3` feature
+    pub h3_hosts: Vec<String>,
+    pub backends: Vec<Backend>,
+    pub sniff: SniffConfig,
+    /// 主监听端的 accept 循环数，大于 1 时以 SO_REUSEPORT 绑定（unix）
+    pub accept_workers: usize,
+    pub pool: PoolConfig,
+    pub cache: CacheConfig,
+    /// 不连接上游，只返回缓存或 flow store 中的响应，其余返回 504
+    pub offline: bool,
+    /// 内置测试源站的主机名（`/echo`、`/status/{code}`、`/delay/{ms}`、`/bytes/{n}`），为空则不启用
+    pub echo_host: String,
+    pub dns: DnsConfig,
+    /// host -> IP，先于 DNS 查询，SNI 与 Host 不变
+    pub host_overrides: HashMap<String, IpAddr>,
+    pub geoip: GeoIpConfig,
+    /// 上一个地址未连上时，等待多久并行尝试下一个地址
+    pub happy_eyeballs_delay_ms: u64,
+    /// 按顺序取第一个匹配的上游代理，都不匹配时直连
+    pub upstream_proxies: Vec<UpstreamProxy>,
+    pub timeouts: TimeoutConfig,
+    pub retry: RetryConfig,
+    pub limits: LimitConfig,
+    pub quota: QuotaConfig,
+    pub stats_snapshot: Option<StatsSnapshotConfig>,
+    pub webhooks: Vec<WebhookConfig>,
+    pub expect: Option<ExpectConfig>,
+    pub socket: SocketConfig,
+    /// 生效的 profile，为空时使用基础配置
+    pub profile: Option<String>,
+    /// profile 名 -> 覆盖的顶层字段，如 `{"capture-all": {"parse": true, "proxy_hosts": []}}`
+    pub profiles: HashMap<String, serde_json::Map<String, serde_json::Value>>,
 }
 
 impl Default for Config {
@@ -27,10 +1279,89 @@ impl Default for Config {
             bind_ip: "127.0.0.1".to_owned(),
             bind_port: 31181,
             proxy_hosts: [].to_vec(),
+            depth_hosts: HashMap::new(),
             sni: "".to_owned(),
             root_ca_cert_path: "proxy.ca.cert.crt".into(),
             root_ca_key_path: "proxy.ca.key.pem".into(),
+            root_ca_key_encrypted: false,
+            root_cas: [].to_vec(),
+            upstream_cert_warn_days: None,
+            upstream_tls_profile: TlsProfile::Openssl,
             parse: false,
+            intercept: true,
+            transcript_hosts: [].to_vec(),
+            transcript_dir: "transcripts".into(),
+            pcap_path: None,
+            log_format: LogFormat::Text,
+            log_filter: None,
+            log_file: LogFileConfig::default(),
+            syslog: None,
+            event_log: None,
+            ip_family: IpFamily::Auto,
+            ip_family_hosts: HashMap::new(),
+            probe: None,
+            flow_capacity: 1000,
+            flow_body_limit: 64 * 1024,
+            flow_spill: None,
+            tcp_capture: false,
+            inject_request_id: false,
+            log_body_limit: 0,
+            log_sse_events: false,
+            content_decoding: ContentDecoding::default(),
+            grpc_descriptors: None,
+            graphql_endpoints: [].to_vec(),
+            cookie_jar: false,
+            redact: RedactConfig::default(),
+            filters: FilterConfig::default(),
+            admin_addr: None,
+            admin_token: None,
+            audit_rules: [].to_vec(),
+            cors_rules: [].to_vec(),
+            auth_rules: [].to_vec(),
+            inject_rules: [].to_vec(),
+            user_agent_rules: [].to_vec(),
+            breakpoints: [].to_vec(),
+            breakpoint_timeout_secs: 300,
+            mirrors: [].to_vec(),
+            splits: [].to_vec(),
+            forwarded: ForwardedPolicy::default(),
+            privacy: PrivacyConfig::default(),
+            system_proxy: false,
+            username: None,
+            password: None,
+            allow_clients: [].to_vec(),
+            deny_clients: [].to_vec(),
+            allow_connect_ports: [443.into()].to_vec(),
+            deny_connect_ports: [].to_vec(),
+            tls_cert_path: None,
+            tls_key_path: None,
+            unix_path: None,
+            pipe_name: None,
+            listeners: [].to_vec(),
+            h3_addr: None,
+            h3_hosts: [].to_vec(),
+            backends: [].to_vec(),
+            sniff: SniffConfig::default(),
+            accept_workers: 1,
+            pool: PoolConfig::default(),
+            cache: CacheConfig::default(),
+            offline: false,
+            echo_host: "proxy.test".to_owned(),
+            dns: DnsConfig::default(),
+            host_overrides: HashMap::new(),
+            geoip: GeoIpConfig::default(),
+            happy_eyeballs_delay_ms: 250,
+            upstream_proxies: [].to_vec(),
+            timeouts: TimeoutConfig::default(),
+            retry: RetryConfig::default(),
+            limits: LimitConfig::default(),
+            quota: QuotaConfig::default(),
+            stats_snapshot: None,
+            webhooks: Vec::new(),
+            expect: None,
+            socket: SocketConfig::default(),
+            profile: None,
+            profiles: HashMap::new(),
         }
     }
 }
@@ -64,16 +1395,233 @@ impl Config {
         Ok(())
     }
 
+    /// 把 `profile` 覆盖的字段整体替换到基础配置上，`profile` 为空时原样返回
+    pub fn resolve(&self) -> Result<Config> {
+        let Some(name) = &self.profile else {
+            return Ok(self.clone());
+        };
+        let overrides = self
+            .profiles
+            .get(name)
+            .ok_or(anyhow!("unknown profile `{name}`"))?;
+        let mut config = serde_json::to_value(self)?;
+        let fields = config
+            .as_object_mut()
+            .ok_or(anyhow!("config is not an object"))?;
+        for (key, value) in overrides {
+            if key == "profile" || key == "profiles" || !fields.contains_key(key) {
+                return Err(anyhow!(
+                    "profile `{name}` sets unknown config field `{key}`"
+                ));
+            }
+            fields.insert(key.clone(), value.clone());
+        }
+        Ok(serde_json::from_value(config)?)
+    }
+
+    /// 运行时才会用到、反序列化时未检查的字段，返回 `字段: 原因`
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Err(e) = self.local_addr() {
+            problems.push(format!("bind_ip: {e}"));
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if let Err(e) = listener.addr.parse::<SocketAddr>() {
+                problems.push(format!("listeners[{i}].addr: {e}"));
+            }
+        }
+        if let Some(Err(e)) = self.h3_addr.as_ref().map(|addr| addr.parse::<SocketAddr>()) {
+            problems.push(format!("h3_addr: {e}"));
+        }
+        for (i, ca) in self.root_cas.iter().enumerate() {
+            if ca.name.is_empty() {
+                problems.push(format!("root_cas[{i}].name: must not be empty"));
+            } else if self.root_cas[..i].iter().any(|other| other.name == ca.name) {
+                problems.push(format!("root_cas[{i}].name: duplicate `{}`", ca.name));
+            }
+        }
+        let mut upstreams = Vec::new();
+        for (i, backend) in self.backends.iter().enumerate() {
+            upstreams.push((format!("backends[{i}].upstream"), &backend.upstream));
+        }
+        for (i, mirror) in self.mirrors.iter().enumerate() {
+            upstreams.push((format!("mirrors[{i}].upstream"), &mirror.upstream));
+        }
+        for (i, split) in self.splits.iter().enumerate() {
+            for (j, target) in split.targets.iter().enumerate() {
+                upstreams.push((
+                    format!("splits[{i}].targets[{j}].upstream"),
+                    &target.upstream,
+                ));
+            }
+        }
+        for (i, webhook) in self.webhooks.iter().enumerate() {
+            upstreams.push((format!("webhooks[{i}].url"), &webhook.url));
+        }
+        for (field, upstream) in upstreams {
+            if let Err(e) = Target::parse(upstream, false) {
+                problems.push(format!("{field}: {e}"));
+            }
+        }
+        if let Some(directives) = &self.log_filter {
+            if let Err(e) = EnvFilter::try_new(directives) {
+                problems.push(format!("log_filter: {e}"));
+            }
+        }
+        for (i, nameserver) in self.dns.nameservers.iter().enumerate() {
+            if resolver::parse_nameserver(nameserver, 53).is_none() {
+                problems.push(format!(
+                    "dns.nameservers[{i}]: invalid address `{nameserver}`"
+                ));
+            }
+        }
+        if let Err(e) = self.dns.check() {
+            problems.push(format!("dns.tls_name: {e}"));
+        }
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            problems.push("tls_cert_path: must be set together with tls_key_path".to_owned());
+        }
+        if let Some(name) = &self.profile {
+            if !self.profiles.contains_key(name) {
+                problems.push(format!("profile: unknown profile `{name}`"));
+            }
+        }
+        // only what a profile breaks on top of the base config
+        let base = problems.clone();
+        let mut names: Vec<&String> = self.profiles.keys().collect();
+        names.sort();
+        for name in names {
+            let config = Config {
+                profile: Some(name.clone()),
+                ..self.clone()
+            };
+            match config.resolve() {
+                Ok(resolved) => problems.extend(
+                    Config {
+                        profile: None,
+                        profiles: HashMap::new(),
+                        ..resolved
+                    }
+                    .validate()
+                    .into_iter()
+                    .filter(|problem| !base.contains(problem))
+                    .map(|problem| format!("profiles.{name}.{problem}")),
+                ),
+                Err(e) => problems.push(format!("profiles.{name}: {e}")),
+            }
+        }
+        problems
+    }
+
     pub fn local_addr(&self) -> Result<SocketAddr> {
         Ok(format!("{}:{}", self.bind_ip, self.bind_port).parse()?)
     }
 
     pub fn is_proxy(&self, domain: &str) -> bool {
-        if self.proxy_hosts.is_empty() {
-            true
-        } else {
-            self.proxy_hosts.iter().any(|i| domain.ends_with(i))
+        // the test origin only exists behind MITM
+        if self.is_echo(domain) {
+            return true;
+        }
+        if !self.intercept {
+            return false;
         }
+        if let Some(depth) = self.depth(domain) {
+            return depth != Depth::Tunnel;
+        }
+        let listed =
+            self.proxy_hosts.is_empty() || self.proxy_hosts.iter().any(|i| domain.ends_with(i));
+        let connect = Flow {
+            addr: domain.to_owned(),
+            host: domain.to_owned(),
+            secure: true,
+            method: "CONNECT".to_owned(),
+            uri: domain.to_owned(),
+            ..Default::default()
+        };
+        listed && filter::allows(self.filters.intercept.as_ref(), &connect)
+    }
+
+    pub fn is_echo(&self, host: &str) -> bool {
+        !self.echo_host.is_empty() && self.echo_host.eq_ignore_ascii_case(host)
+    }
+
+    /// 最长的匹配后缀
+    pub fn depth(&self, domain: &str) -> Option<Depth> {
+        self.depth_hosts
+            .iter()
+            .filter(|(suffix, _)| domain.ends_with(suffix.as_str()))
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map(|(_, depth)| *depth)
+    }
+
+    /// `root_cas` 中匹配的 CA，None 为默认的 root CA
+    pub fn root_ca(&self, host: &str) -> Option<&RootCa> {
+        self.root_cas
+            .iter()
+            .filter_map(|ca| {
+                if ca.hosts.is_empty() {
+                    return Some((0, ca));
+                }
+                ca.hosts
+                    .iter()
+                    .filter(|suffix| host.ends_with(suffix.as_str()))
+                    .map(|suffix| (suffix.len(), ca))
+                    .max_by_key(|(len, _)| *len)
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, ca)| ca)
+    }
+
+    pub fn backend(&self, host: &str) -> Option<&Backend> {
+        self.backends
+            .iter()
+            .filter_map(|backend| {
+                if backend.hosts.is_empty() {
+                    return Some((0, backend));
+                }
+                backend
+                    .hosts
+                    .iter()
+                    .filter(|suffix| host.ends_with(suffix.as_str()))
+                    .map(|suffix| (suffix.len(), backend))
+                    .max_by_key(|(len, _)| *len)
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, backend)| backend)
+    }
+
+    pub fn ip_family(&self, domain: &str) -> IpFamily {
+        self.ip_family_hosts
+            .iter()
+            .filter(|(suffix, _)| domain.ends_with(suffix.as_str()))
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map(|(_, family)| *family)
+            .unwrap_or(self.ip_family)
+    }
+
+    pub fn is_client_allowed(&self, ip: IpAddr) -> bool {
+        acl::is_allowed(&self.allow_clients, &self.deny_clients, ip)
+    }
+
+    pub fn is_connect_port_allowed(&self, port: u16) -> bool {
+        acl::is_port_allowed(&self.allow_connect_ports, &self.deny_connect_ports, port)
+    }
+
+    #[cfg_attr(not(feature = "http3"), allow(dead_code))]
+    pub fn is_h3(&self, domain: &str) -> bool {
+        self.h3_hosts.iter().any(|i| domain.ends_with(i))
+    }
+
+    pub fn is_graphql(&self, domain: &str, path: &str) -> bool {
+        self.graphql_endpoints.iter().any(|endpoint| {
+            let (host, endpoint_path) =
+                endpoint.split_at(endpoint.find('/').unwrap_or(endpoint.len()));
+            domain.ends_with(host) && endpoint_path == path
+        })
+    }
+
+    pub fn is_transcript(&self, domain: &str) -> bool {
+        self.transcript_hosts.iter().any(|i| domain.ends_with(i))
     }
 }
 
@@ -82,3 +1630,135 @@ async fn should_proxy() {
     let config = Config::load().await.unwrap();
     assert!(config.is_proxy("alive.github.com"))
 }
+
+#[test]
+fn should_resolve_depth() {
+    let config = Config {
+        proxy_hosts: vec!["example.com".to_owned()],
+        depth_hosts: HashMap::from([
+            ("example.com".to_owned(), Depth::Terminate),
+            ("api.example.com".to_owned(), Depth::Parse),
+            ("cdn.example.com".to_owned(), Depth::Tunnel),
+            ("other.test".to_owned(), Depth::Parse),
+        ]),
+        ..Default::default()
+    };
+    assert_eq!(config.depth("v1.api.example.com"), Some(Depth::Parse));
+    assert_eq!(config.depth("www.example.com"), Some(Depth::Terminate));
+    assert!(!config.is_proxy("cdn.example.com"));
+    assert!(config.is_proxy("other.test"));
+    assert_eq!(config.depth("github.com"), None);
+}
+
+#[test]
+fn should_always_intercept_echo_host() {
+    let mut config = Config {
+        intercept: false,
+        ..Default::default()
+    };
+    assert!(config.is_proxy("Proxy.Test"));
+    assert!(!config.is_proxy("example.com"));
+    config.echo_host.clear();
+    assert!(!config.is_proxy("proxy.test"));
+}
+
+#[test]
+fn should_require_dns_tls_name() {
+    let config = |dns| Config {
+        dns: serde_json::from_value(dns).unwrap(),
+        ..Default::default()
+    };
+    let problems =
+        config(serde_json::json!({"nameservers": ["1.1.1.1"], "protocol": "tls"})).validate();
+    assert_eq!(problems, ["dns.tls_name: required by Tls"]);
+    let dns = serde_json::json!({
+        "nameservers": ["1.1.1.1"],
+        "protocol": "https",
+        "tls_name": "cloudflare-dns.com",
+    });
+    assert!(config(dns).validate().is_empty());
+}
+
+#[test]
+fn should_validate_config() {
+    assert!(Config::default().validate().is_empty());
+    let config = Config {
+        bind_ip: "localhost".to_owned(),
+        h3_addr: Some("localhost:443".to_owned()),
+        ..Default::default()
+    };
+    let problems = config.validate();
+    let fields: Vec<&str> = problems
+        .iter()
+        .filter_map(|problem| problem.split(':').next())
+        .collect();
+    assert_eq!(fields, ["bind_ip", "h3_addr"]);
+
+    // ACL entries are parsed once when loading
+    let parse = |json| serde_json::from_value::<Config>(json);
+    assert!(parse(serde_json::json!({"deny_clients": ["10.0.0.0/99"]})).is_err());
+    assert!(parse(serde_json::json!({"allow_connect_ports": ["443-80"]})).is_err());
+    let config = parse(serde_json::json!({
+        "deny_clients": ["10.0.0.0/8"],
+        "allow_connect_ports": ["8000-8999"],
+    }))
+    .unwrap();
+    assert!(!config.is_client_allowed("10.1.2.3".parse().unwrap()));
+    assert!(config.is_connect_port_allowed(8080));
+    assert!(!config.is_connect_port_allowed(443));
+}
+
+#[test]
+fn should_select_root_ca() {
+    let root = |name: &str, hosts: &[&str]| RootCa {
+        name: name.to_owned(),
+        hosts: hosts.iter().map(|h| h.to_string()).collect(),
+        ..Default::default()
+    };
+    let config = Config {
+        root_cas: vec![root("corp", &[".corp"]), root("lab", &[".lab.corp"])],
+        ..Default::default()
+    };
+    let name = |host| config.root_ca(host).map(|ca| ca.name.as_str());
+    assert_eq!(name("git.corp"), Some("corp"));
+    assert_eq!(name("ci.lab.corp"), Some("lab"));
+    assert_eq!(name("example.com"), None);
+
+    let config = Config {
+        root_cas: vec![root("", &[]), root("lab", &[]), root("lab", &[])],
+        ..Default::default()
+    };
+    assert_eq!(
+        config.validate(),
+        [
+            "root_cas[0].name: must not be empty",
+            "root_cas[2].name: duplicate `lab`"
+        ]
+    );
+}
+
+#[test]
+fn should_resolve_profile() {
+    let overrides = |value: serde_json::Value| value.as_object().cloned().unwrap_or_default();
+    let mut config = Config {
+        profiles: HashMap::from([
+            (
+                "capture".to_owned(),
+                overrides(serde_json::json!({ "parse": true, "bind_port": 8080 })),
+            ),
+            (
+                "broken".to_owned(),
+                overrides(serde_json::json!({ "nope": 1 })),
+            ),
+        ]),
+        ..Default::default()
+    };
+    assert!(!config.resolve().unwrap().parse);
+    config.profile = Some("capture".to_owned());
+    let resolved = config.resolve().unwrap();
+    assert!(resolved.parse && resolved.bind_port == 8080);
+    assert_eq!(resolved.profiles.len(), 2);
+    config.profile = Some("broken".to_owned());
+    assert!(config.resolve().is_err());
+    assert_eq!(config.validate().len(), 1);
+}
@@ -0,0 +1,315 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderMap, COOKIE, SET_COOKIE};
+use serde::Serialize;
+use ulid::Ulid;
+
+use crate::config::RedactConfig;
+
+/// 按 domain 记录的 cookie
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub name: String,
+    /// 按 `redact` 处理过
+    pub value: String,
+    /// `Domain` 属性，没有时为设置它的 host
+    pub domain: String,
+    /// 没有 `Domain` 属性，只发往设置它的 host
+    pub host_only: bool,
+    pub path: String,
+    /// unix 秒，会话 cookie 为空
+    pub expires: Option<u64>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<String>,
+    /// 最近一次设置它的 flow，只见过客户端发送时为空
+    pub set_by: Option<Ulid>,
+    /// 最近一次由客户端发送的 flow
+    pub sent_by: Option<Ulid>,
+}
+
+impl Cookie {
+    /// `request_path` 用于推出默认的 `Path`（RFC 6265 5.1.4）
+    fn parse(header: &str, host: &str, request_path: &str, now: u64) -> Option<Self> {
+        let mut parts = header.split(';');
+        let (name, value) = parts.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let mut cookie = Cookie {
+            name: name.to_owned(),
+            value: value.trim().to_owned(),
+            domain: host.to_owned(),
+            host_only: true,
+            path: default_path(request_path),
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+            set_by: None,
+            sent_by: None,
+        };
+        let mut max_age = None;
+        for attribute in parts {
+            let (key, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "expires" => cookie.expires = expires(value).or(cookie.expires),
+                "max-age" => max_age = value.parse::<i64>().ok(),
+                "domain" if !value.is_empty() => {
+                    cookie.domain = value.trim_start_matches('.').to_ascii_lowercase();
+                    cookie.host_only = false;
+                }
+                "path" if value.starts_with('/') => cookie.path = value.to_owned(),
+                "secure" => cookie.secure = true,
+                "httponly" => cookie.http_only = true,
+                "samesite" => cookie.same_site = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+        // Max-Age wins over Expires
+        if let Some(max_age) = max_age {
+            cookie.expires = Some(now.saturating_add_signed(max_age));
+        }
+        Some(cookie)
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        if self.host_only {
+            self.domain == host
+        } else {
+            host == self.domain || host.ends_with(&format!(".{}", self.domain))
+        }
+    }
+}
+
+/// 请求路径最后一个 `/` 之前的部分
+fn default_path(path: &str) -> String {
+    match path.rfind('/') {
+        Some(0) | None => "/".to_owned(),
+        Some(i) => path[..i].to_owned(),
+    }
+}
+
+/// 兼容 `Wed, 21-Oct-2015 07:28:00 GMT` 这种带 `-` 的写法
+fn expires(value: &str) -> Option<u64> {
+    let time = httpdate::parse_http_date(value)
+        .or_else(|_| httpdate::parse_http_date(&value.replace('-', " ")))
+        .ok()?;
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+/// 按 domain 记录经过的 `Set-Cookie` 与 `Cookie`，过期或被删除的 cookie 移除
+#[derive(Clone, Default)]
+pub struct CookieJar {
+    inner: Arc<Mutex<HashMap<String, Vec<Cookie>>>>,
+}
+
+impl CookieJar {
+    /// 客户端发送的 cookie 记在已有的同名 cookie 上，没有时按 host-only 记录
+    pub fn record_sent(&self, id: Ulid, host: &str, headers: &HeaderMap, redact: &RedactConfig) {
+        let Ok(mut map) = self.inner.lock() else {
+            return;
+        };
+        let sent = headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|pair| pair.split_once('='));
+        for (name, value) in sent {
+            let name = name.trim();
+            let value = redact.header(COOKIE.as_str(), value.trim()).into_owned();
+            let known = map
+                .values_mut()
+                .flatten()
+                .find(|cookie| cookie.name == name && cookie.matches(host));
+            match known {
+                Some(cookie) => {
+                    cookie.value = value;
+                    cookie.sent_by = Some(id);
+                }
+                None => map.entry(host.to_owned()).or_default().push(Cookie {
+                    name: name.to_owned(),
+                    value,
+                    domain: host.to_owned(),
+                    host_only: true,
+                    path: "/".to_owned(),
+                    expires: None,
+                    secure: false,
+                    http_only: false,
+                    same_site: None,
+                    set_by: None,
+                    sent_by: Some(id),
+                }),
+            }
+        }
+    }
+
+    pub fn record_set(
+        &self,
+        id: Ulid,
+        host: &str,
+        path: &str,
+        headers: &HeaderMap,
+        redact: &RedactConfig,
+    ) {
+        let now = now();
+        let Ok(mut map) = self.inner.lock() else {
+            return;
+        };
+        let set = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(|v| Cookie::parse(v, host, path, now));
+        for mut cookie in set {
+            cookie.value = redact
+                .header(SET_COOKIE.as_str(), &cookie.value)
+                .into_owned();
+            cookie.set_by = Some(id);
+            let cookies = map.entry(cookie.domain.clone()).or_default();
+            let existing = cookies
+                .iter()
+                .position(|c| c.name == cookie.name && c.path == cookie.path);
+            if let Some(i) = existing {
+                cookie.sent_by = cookies.remove(i).sent_by;
+            }
+            if !cookie.is_expired(now) {
+                cookies.push(cookie);
+            }
+        }
+        map.retain(|_, cookies| !cookies.is_empty());
+    }
+
+    /// 按 domain 排序，去掉已过期的
+    pub fn snapshot(&self) -> BTreeMap<String, Vec<Cookie>> {
+        let now = now();
+        let Ok(mut map) = self.inner.lock() else {
+            return BTreeMap::new();
+        };
+        map.values_mut()
+            .for_each(|cookies| cookies.retain(|cookie| !cookie.is_expired(now)));
+        map.retain(|_, cookies| !cookies.is_empty());
+        map.iter()
+            .map(|(domain, cookies)| (domain.clone(), cookies.clone()))
+            .collect()
+    }
+
+    /// 会发往 `host` 的 cookie
+    pub fn get(&self, host: &str) -> Vec<Cookie> {
+        self.snapshot()
+            .into_values()
+            .flatten()
+            .filter(|cookie| cookie.matches(host))
+            .collect()
+    }
+
+    /// 返回移除的数量
+    pub fn clear(&self) -> usize {
+        let Ok(mut map) = self.inner.lock() else {
+            return 0;
+        };
+        map.drain().map(|(_, cookies)| cookies.len()).sum()
+    }
+}
+
+/// Netscape `cookies.txt`，curl `-b` 与 wget `--load-cookies` 可读
+pub fn netscape(jar: &BTreeMap<String, Vec<Cookie>>) -> String {
+    let mut out = String::from("# Netscape HTTP Cookie File\n");
+    for cookie in jar.values().flatten() {
+        let (domain, subdomains) = if cookie.host_only {
+            (cookie.domain.clone(), "FALSE")
+        } else {
+            (format!(".{}", cookie.domain), "TRUE")
+        };
+        let domain = if cookie.http_only {
+            format!("#HttpOnly_{domain}")
+        } else {
+            domain
+        };
+        out.push_str(&format!(
+            "{domain}\t{subdomains}\t{}\t{}\t{}\t{}\t{}\n",
+            cookie.path,
+            if cookie.secure { "TRUE" } else { "FALSE" },
+            cookie.expires.unwrap_or_default(),
+            cookie.name,
+            cookie.value,
+        ));
+    }
+    out
+}
+
+#[test]
+fn should_track_cookies() {
+    use hyper::header::HeaderValue;
+
+    let redact = RedactConfig::default();
+    let jar = CookieJar::default();
+    let (set, sent) = (Ulid::new(), Ulid::new());
+    let mut headers = HeaderMap::new();
+    headers.append(
+        SET_COOKIE,
+        HeaderValue::from_static(
+            "sid=abc; Domain=.example.com; Path=/; Secure; HttpOnly; Max-Age=3600",
+        ),
+    );
+    headers.append(
+        SET_COOKIE,
+        HeaderValue::from_static("theme=dark; Expires=Wed, 21-Oct-2015 07:28:00 GMT"),
+    );
+    headers.append(
+        SET_COOKIE,
+        HeaderValue::from_static("lang=en; SameSite=Lax"),
+    );
+    jar.record_set(set, "www.example.com", "/app/login", &headers, &redact);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(COOKIE, HeaderValue::from_static("sid=abc; other=1"));
+    jar.record_sent(sent, "api.example.com", &headers, &redact);
+
+    let snapshot = jar.snapshot();
+    let sid = &snapshot["example.com"][0];
+    assert_eq!((sid.set_by, sid.sent_by), (Some(set), Some(sent)));
+    assert!(sid.secure && sid.http_only && !sid.host_only);
+    assert!(sid.expires.unwrap() > now());
+    // expired on arrival
+    assert!(jar.get("www.example.com").iter().all(|c| c.name != "theme"));
+    let lang = &snapshot["www.example.com"][0];
+    assert_eq!(
+        (lang.path.as_str(), lang.same_site.as_deref()),
+        ("/app", Some("Lax"))
+    );
+    assert_eq!(snapshot["api.example.com"][0].name, "other");
+    assert_eq!(jar.get("api.example.com").len(), 2);
+
+    let txt = netscape(&snapshot);
+    assert!(txt.contains(&format!(
+        "#HttpOnly_.example.com\tTRUE\t/\tTRUE\t{}\tsid\tabc\n",
+        sid.expires.unwrap()
+    )));
+    assert!(txt.contains("www.example.com\tFALSE\t/app\tFALSE\t0\tlang\ten\n"));
+
+    // deleted by the server
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        SET_COOKIE,
+        HeaderValue::from_static("sid=; Domain=example.com; Path=/; Max-Age=0"),
+    );
+    jar.record_set(set, "www.example.com", "/", &headers, &redact);
+    assert!(!jar.snapshot().contains_key("example.com"));
+}
@@ -0,0 +1,388 @@
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tracing::debug;
+
+use crate::config::{Config, IpFamily, UpstreamKind, UpstreamProxy};
+use crate::flow::Timings;
+use crate::geoip::{Geo, GeoIp};
+use crate::resolver::Resolver;
+use crate::upstream_proxy;
+use crate::util;
+
+/// 所有出站 TCP 连接的入口
+#[derive(Clone)]
+pub struct Dialer {
+    config: Arc<Config>,
+    resolver: Resolver,
+    geoip: GeoIp,
+}
+
+impl Dialer {
+    pub fn new(config: Arc<Config>, resolver: Resolver, geoip: GeoIp) -> Self {
+        Self {
+            config,
+            resolver,
+            geoip,
+        }
+    }
+
+    /// `addr` 为 `host:port`，按地址族策略依次尝试解析出的地址
+    pub async fn connect(&self, addr: &str) -> Result<TcpStream, Error> {
+        self.connect_timed(addr, &mut Timings::default()).await
+    }
+
+    /// 匹配 `addr` 的 `upstream_proxies`；UDP 无法经上游代理转发，用于拒绝
+    pub fn upstream_proxy(&self, host: &str, addr: SocketAddr) -> Option<&UpstreamProxy> {
+        let country = self.geo(addr.ip()).and_then(|geo| geo.country);
+        self.config.upstream_proxies.iter().find(|proxy| {
+            proxy.matches(host)
+                && (proxy.countries.is_empty()
+                    || country
+                        .as_ref()
+                        .is_some_and(|c| proxy.countries.iter().any(|i| i.eq_ignore_ascii_case(c))))
+        })
+    }
+
+    /// 同 `connect`，记录解析与建连耗时；匹配 `upstream_proxies` 时经上游代理的隧道连接
+    pub async fn connect_timed(
+        &self,
+        addr: &str,
+        timings: &mut Timings,
+    ) -> Result<TcpStream, Error> {
+        let host = split_host(addr);
+        // resolved only when a rule needs it, so remote DNS stays remote
+        let mut resolved = None;
+        let mut route = None;
+        for proxy in self.config.upstream_proxies.iter() {
+            if !proxy.matches(host) {
+                continue;
+            }
+            if proxy.countries.is_empty() {
+                route = Some(proxy);
+                break;
+            }
+            let addrs = match &resolved {
+                Some(addrs) => addrs,
+                None => resolved.insert(self.resolve(addr, timings).await?),
+            };
+            let country = addrs
+                .first()
+                .and_then(|a| self.geo(a.ip()))
+                .and_then(|geo| geo.country);
+            if country.is_some_and(|c| proxy.countries.iter().any(|i| i.eq_ignore_ascii_case(&c))) {
+                route = Some(proxy);
+                break;
+            }
+        }
+        let Some(proxy) = route else {
+            let addrs = match resolved {
+                Some(addrs) => addrs,
+                None => self.resolve(addr, timings).await?,
+            };
+            return self.connect_addrs(addr, addrs, timings).await;
+        };
+        if proxy.kind == UpstreamKind::Socks5 && !proxy.remote_dns && resolved.is_none() {
+            resolved = Some(self.resolve(addr, timings).await?);
+        }
+        let remote = match resolved {
+            Some(addrs) => Some(*addrs.first().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::AddrNotAvailable,
+                    format!("no address for {addr}"),
+                )
+            })?),
+            None => None,
+        };
+        // keep the target's DNS time when it was resolved here
+        let dns_ms = timings.dns_ms;
+        let proxy_addrs = self.resolve(&proxy.addr, timings).await?;
+        if remote.is_some() {
+            timings.dns_ms = dns_ms;
+        }
+        let mut stream = self
+            .connect_addrs(&proxy.addr, proxy_addrs, timings)
+            .await?;
+        timings.remote_ip = remote.map(|a| a.ip());
+        let target = match remote {
+            Some(remote) if proxy.kind == UpstreamKind::Socks5 && !proxy.remote_dns => {
+                remote.to_string()
+            }
+            _ => addr.to_owned(),
+        };
+        let start = Instant::now();
+        let handshake = async {
+            match proxy.kind {
+                UpstreamKind::Http => upstream_proxy::tunnel(&mut stream, proxy, &target).await,
+                UpstreamKind::Socks5 => upstream_proxy::socks5(&mut stream, proxy, &target).await,
+            }
+        };
+        util::timeout(self.config.timeouts.connect_secs, handshake).await??;
+        // the tunnel setup counts as connecting
+        timings.connect_ms = timings
+            .connect_ms
+            .zip(Timings::since(start))
+            .map(|(connect, tunnel)| connect + tunnel);
+        Ok(stream)
+    }
+
+    async fn connect_addrs(
+        &self,
+        addr: &str,
+        addrs: Vec<SocketAddr>,
+        timings: &mut Timings,
+    ) -> Result<TcpStream, Error> {
+        let delay = Duration::from_millis(self.config.happy_eyeballs_delay_ms);
+        let start = Instant::now();
+        let stream = util::timeout(self.config.timeouts.connect_secs, race(addrs, delay))
+            .await?
+            .map_err(|e| Error::new(e.kind(), format!("connect {addr} failed: {e}")))?;
+        timings.connect_ms = Timings::since(start);
+        timings.remote_ip = stream.peer_addr().ok().map(|a| a.ip());
+        util::tune_socket(&stream, &self.config.socket);
+        Ok(stream)
+    }
+
+    /// 按 `geoip.database` 查询，未配置时为 None
+    pub fn geo(&self, ip: IpAddr) -> Option<Geo> {
+        let path = self.config.geoip.database.as_deref()?;
+        self.geoip.lookup(path, ip)
+    }
+
+    /// 解析 `host:port`，按地址族策略排序
+    pub async fn resolve(
+        &self,
+        addr: &str,
+        timings: &mut Timings,
+    ) -> Result<Vec<SocketAddr>, Error> {
+        let host = split_host(addr);
+        let port = addr
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("no port in {addr}")))?;
+        let family = self.config.ip_family(host);
+        let start = Instant::now();
+        let ips = match self.config.host_overrides.get(host) {
+            Some(ip) => {
+                debug!("{host} overridden to {ip}");
+                vec![*ip]
+            }
+            None => self.resolver.resolve(host).await?,
+        };
+        timings.dns_ms = Timings::since(start);
+        let blocked = &self.config.geoip.block_countries;
+        let mut ips = ips;
+        if !blocked.is_empty() && !ips.is_empty() {
+            ips.retain(|ip| {
+                let country = self.geo(*ip).and_then(|geo| geo.country);
+                !country.is_some_and(|c| blocked.iter().any(|i| i.eq_ignore_ascii_case(&c)))
+            });
+            if ips.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("{host} resolves only to blocked countries"),
+                ));
+            }
+        }
+        let addrs = ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect();
+        Ok(interleave(order_addrs(addrs, family)))
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+}
+
+/// Happy Eyeballs（RFC 8305）：依次发起连接，前一个在 `delay` 内未成功就并行发起下一个，
+/// 失败时立即发起下一个，取最先成功的连接
+async fn race(addrs: Vec<SocketAddr>, delay: Duration) -> Result<TcpStream, Error> {
+    let mut last_err = Error::new(ErrorKind::AddrNotAvailable, "no address to connect");
+    let mut addrs = addrs.into_iter();
+    let mut attempts = JoinSet::new();
+    loop {
+        if let Some(addr) = addrs.next() {
+            attempts.spawn(async move { (addr, TcpStream::connect(addr).await) });
+        } else if attempts.is_empty() {
+            return Err(last_err);
+        }
+        let more = addrs.len() > 0;
+        tokio::select! {
+            Some(joined) = attempts.join_next() => match joined {
+                // the remaining attempts are aborted with the JoinSet
+                Ok((_, Ok(stream))) => return Ok(stream),
+                Ok((addr, Err(e))) => {
+                    debug!("connect {addr} failed: {e}");
+                    last_err = e;
+                }
+                Err(e) => last_err = Error::other(e),
+            },
+            _ = tokio::time::sleep(delay), if more => {}
+        }
+    }
+}
+
+pub fn split_host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// 从第一个地址的地址族开始，两个地址族交替
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let first_v6 = first.is_ipv6();
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+    let mut addrs = Vec::with_capacity(preferred.len() + other.len());
+    while let Some(addr) = preferred.pop_front() {
+        addrs.push(addr);
+        addrs.extend(other.pop_front());
+    }
+    addrs.extend(other);
+    addrs
+}
+
+fn order_addrs(mut addrs: Vec<SocketAddr>, family: IpFamily) -> Vec<SocketAddr> {
+    match family {
+        IpFamily::Auto => {}
+        IpFamily::PreferIpv4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+        IpFamily::PreferIpv6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+        IpFamily::Ipv4Only => addrs.retain(|a| a.is_ipv4()),
+        IpFamily::Ipv6Only => addrs.retain(|a| a.is_ipv6()),
+    }
+    addrs
+}
+
+#[test]
+fn should_order_by_family() {
+    let addrs: Vec<SocketAddr> = vec![
+        "[::1]:443".parse().unwrap(),
+        "127.0.0.1:443".parse().unwrap(),
+    ];
+    assert!(order_addrs(addrs.clone(), IpFamily::PreferIpv4)[0].is_ipv4());
+    assert!(order_addrs(addrs.clone(), IpFamily::Auto)[0].is_ipv6());
+    assert_eq!(order_addrs(addrs, IpFamily::Ipv6Only).len(), 1);
+    let addrs: Vec<SocketAddr> = vec![
+        "[::1]:443".parse().unwrap(),
+        "[::2]:443".parse().unwrap(),
+        "127.0.0.1:443".parse().unwrap(),
+    ];
+    let addrs = interleave(order_addrs(addrs, IpFamily::PreferIpv4));
+    assert!(addrs[0].is_ipv4() && addrs[1].is_ipv6() && addrs[2].is_ipv6());
+    assert_eq!(split_host("[::1]:443"), "::1");
+    assert_eq!(split_host("example.com:80"), "example.com");
+}
+
+#[tokio::test]
+async fn should_override_host_address() {
+    use std::pin::Pin;
+
+    use hyper::header::HOST;
+    use hyper::server::conn::http1::Builder as ServerBuilder;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response};
+    use hyper_util::rt::TokioIo;
+    use motore::Service;
+    use openssl::ssl::{NameType, Ssl, SslAcceptor, SslMethod};
+    use tokio_openssl::SslStream;
+
+    // `.invalid` never resolves, only the override can reach the origin
+    const HOST_NAME: &str = "origin.invalid";
+
+    let root = crate::ca::CA::generate().await.unwrap();
+    let leaf = root.sign(HOST_NAME.to_owned()).unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        builder.set_certificate(&leaf.cert).unwrap();
+        builder.set_private_key(&leaf.key).unwrap();
+        let acceptor = builder.build();
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = SslStream::new(Ssl::new(acceptor.context()).unwrap(), stream).unwrap();
+        Pin::new(&mut stream).accept().await.unwrap();
+        let sni = stream
+            .ssl()
+            .servername(NameType::HOST_NAME)
+            .map(str::to_owned);
+        let tx = std::sync::Mutex::new(Some(tx));
+        let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+            let host = req.headers().get(HOST).cloned();
+            if let Some(tx) = tx.lock().unwrap().take() {
+                let _ = tx.send((sni.clone(), host));
+            }
+            async { Ok::<_, hyper::Error>(Response::new(util::empty())) }
+        });
+        let _ = ServerBuilder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    let config = Config {
+        host_overrides: [(HOST_NAME.to_owned(), [127, 0, 0, 1].into())].into(),
+        ..Default::default()
+    };
+    let shared = crate::state::State::new(config).await.unwrap();
+    let addr = format!("{HOST_NAME}:{port}");
+    let resolved = shared
+        .dialer()
+        .resolve(&addr, &mut Timings::default())
+        .await
+        .unwrap();
+    assert_eq!(resolved, [SocketAddr::from(([127, 0, 0, 1], port))]);
+
+    let mut state = crate::state::ClientState {
+        id: crate::flow::next_id(),
+        addr: addr.clone(),
+        sni: HOST_NAME.to_owned(),
+        is_secure: true,
+        parse: false,
+        transcript: None,
+        timings: Default::default(),
+        shared,
+    };
+    let req = Request::get(format!("https://{addr}/"))
+        .header(HOST, &addr)
+        .body(util::empty())
+        .unwrap();
+    let resp = crate::client::service()
+        .call(&mut state, req)
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+    let (sni, host) = rx.await.unwrap();
+    assert_eq!(sni.as_deref(), Some(HOST_NAME));
+    assert_eq!(host.unwrap(), addr.as_str());
+}
+
+/// IPv6 不可达（立即失败或无响应）时应在 `delay` 后连上 IPv4
+#[tokio::test]
+async fn should_fall_back_when_ipv6_unreachable() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    // discard-only prefix, either unroutable or silently dropped
+    let addrs: Vec<SocketAddr> = vec![
+        format!("[100::1]:{port}").parse().unwrap(),
+        format!("127.0.0.1:{port}").parse().unwrap(),
+    ];
+    let start = std::time::Instant::now();
+    let stream = race(interleave(addrs), Duration::from_millis(250))
+        .await
+        .unwrap();
+    assert!(stream.peer_addr().unwrap().is_ipv4());
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    drop(listener);
+    let closed: Vec<SocketAddr> = vec![format!("127.0.0.1:{port}").parse().unwrap()];
+    assert!(race(closed, Duration::from_millis(250)).await.is_err());
+}
@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json::Value;
+use ulid::Ulid;
+
+use crate::flow::Flow;
+
+/// 每次请求都会变化的响应头，总是忽略
+const VOLATILE_HEADERS: [&str; 2] = ["date", "age"];
+
+/// 文本 body 最多报告的不同行数
+const MAX_LINE_CHANGES: usize = 100;
+
+/// 两个 flow 的响应差异，`left` 通常是原始 flow，`right` 是镜像或重放
+#[derive(Serialize, Debug)]
+pub struct Diff {
+    pub left: Ulid,
+    pub right: Ulid,
+    pub identical: bool,
+    pub status: Option<Change>,
+    pub headers: Vec<Change>,
+    pub body: Vec<Change>,
+    /// 任一 body 被 `flow_body_limit` 截断，body 差异可能不完整
+    pub truncated: bool,
+}
+
+/// `path` 为 header 名、JSON Pointer 或 `line N`，缺失的一侧为 None
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Change {
+    pub path: String,
+    pub left: Option<Value>,
+    pub right: Option<Value>,
+}
+
+impl Change {
+    fn new(path: impl Into<String>, left: Option<Value>, right: Option<Value>) -> Self {
+        Self {
+            path: path.into(),
+            left,
+            right,
+        }
+    }
+}
+
+/// `ignore` 中以 `/` 开头的是 JSON Pointer（连同其下的字段），其余是 header 名
+pub fn compare(left: &Flow, right: &Flow, ignore: &[String]) -> Diff {
+    let status = (left.status != right.status).then(|| {
+        Change::new(
+            "status",
+            left.status.map(Value::from),
+            right.status.map(Value::from),
+        )
+    });
+    let headers = headers(left, right, ignore);
+    let mut body = Vec::new();
+    match (
+        serde_json::from_slice::<Value>(&left.response_body),
+        serde_json::from_slice::<Value>(&right.response_body),
+    ) {
+        (Ok(l), Ok(r)) => json(String::new(), Some(&l), Some(&r), &mut body),
+        _ => text(&left.response_body, &right.response_body, &mut body),
+    }
+    body.retain(|change| !is_ignored_path(&change.path, ignore));
+
+    Diff {
+        left: left.id,
+        right: right.id,
+        identical: status.is_none() && headers.is_empty() && body.is_empty(),
+        status,
+        headers,
+        body,
+        truncated: left.is_response_truncated() || right.is_response_truncated(),
+    }
+}
+
+fn headers(left: &Flow, right: &Flow, ignore: &[String]) -> Vec<Change> {
+    let (left, right) = (
+        header_map(&left.response_headers),
+        header_map(&right.response_headers),
+    );
+    let mut names: Vec<&String> = left.keys().chain(right.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter(|name| {
+            !VOLATILE_HEADERS.contains(&name.as_str())
+                && !ignore.iter().any(|i| i.eq_ignore_ascii_case(name))
+        })
+        .filter_map(|name| {
+            let (l, r) = (left.get(name), right.get(name));
+            (l != r).then(|| {
+                Change::new(
+                    name.as_str(),
+                    l.map(|v| Value::from(v.as_str())),
+                    r.map(|v| Value::from(v.as_str())),
+                )
+            })
+        })
+        .collect()
+}
+
+/// 名字转小写，同名的值按出现顺序用 `, ` 连接
+fn header_map(headers: &[(String, String)]) -> BTreeMap<String, String> {
+    let mut map = BTreeMap::<String, String>::new();
+    for (name, value) in headers {
+        map.entry(name.to_ascii_lowercase())
+            .and_modify(|joined| {
+                joined.push_str(", ");
+                joined.push_str(value);
+            })
+            .or_insert_with(|| value.clone());
+    }
+    map
+}
+
+/// 对象按 key、数组按下标递归比较
+fn json(path: String, left: Option<&Value>, right: Option<&Value>, changes: &mut Vec<Change>) {
+    match (left, right) {
+        (Some(Value::Object(l)), Some(Value::Object(r))) => {
+            let mut keys: Vec<&String> = l.keys().chain(r.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let escaped = key.replace('~', "~0").replace('/', "~1");
+                json(format!("{path}/{escaped}"), l.get(key), r.get(key), changes);
+            }
+        }
+        (Some(Value::Array(l)), Some(Value::Array(r))) => {
+            for i in 0..l.len().max(r.len()) {
+                json(format!("{path}/{i}"), l.get(i), r.get(i), changes);
+            }
+        }
+        (l, r) if l != r => changes.push(Change::new(path, l.cloned(), r.cloned())),
+        _ => {}
+    }
+}
+
+/// 非 JSON 按行逐一比较
+fn text(left: &[u8], right: &[u8], changes: &mut Vec<Change>) {
+    if left == right {
+        return;
+    }
+    let (left, right) = (
+        String::from_utf8_lossy(left),
+        String::from_utf8_lossy(right),
+    );
+    let (mut l, mut r) = (left.lines(), right.lines());
+    let mut line = 1;
+    while changes.len() < MAX_LINE_CHANGES {
+        match (l.next(), r.next()) {
+            (None, None) => break,
+            (l, r) if l != r => changes.push(Change::new(
+                format!("line {line}"),
+                l.map(Value::from),
+                r.map(Value::from),
+            )),
+            _ => {}
+        }
+        line += 1;
+    }
+}
+
+fn is_ignored_path(path: &str, ignore: &[String]) -> bool {
+    ignore.iter().filter(|i| i.starts_with('/')).any(|i| {
+        path == i
+            || path
+                .strip_prefix(i.as_str())
+                .is_some_and(|rest| rest.starts_with('/'))
+    })
+}
+
+#[test]
+fn should_diff_json_responses() {
+    let flow = |status, etag: &str, body: &'static str| Flow {
+        status: Some(status),
+        response_headers: vec![
+            ("Date".to_owned(), etag.to_owned()),
+            ("ETag".to_owned(), etag.to_owned()),
+        ],
+        response_size: body.len() as u64,
+        response_body: body.into(),
+        ..Default::default()
+    };
+    let left = flow(
+        200,
+        "a",
+        r#"{"id":1,"tags":["x","y"],"at":1,"user":{"n/a":true}}"#,
+    );
+    let right = flow(
+        200,
+        "b",
+        r#"{"id":2,"tags":["x"],"at":2,"user":{"n/a":false}}"#,
+    );
+
+    let diff = compare(&left, &right, &["/at".to_owned()]);
+    assert!(!diff.identical && diff.status.is_none() && !diff.truncated);
+    assert_eq!(
+        diff.headers,
+        [Change::new("etag", Some("a".into()), Some("b".into()))]
+    );
+    let paths: Vec<&str> = diff.body.iter().map(|c| c.path.as_str()).collect();
+    assert_eq!(paths, ["/id", "/tags/1", "/user/n~1a"]);
+    assert_eq!(diff.body[1].right, None);
+
+    assert!(compare(&left, &left, &[]).identical);
+    let diff = compare(&flow(200, "a", "a\nb"), &flow(500, "a", "a\nc\nd"), &[]);
+    assert_eq!(diff.status.unwrap().right, Some(500.into()));
+    assert_eq!(
+        diff.body,
+        [
+            Change::new("line 2", Some("b".into()), Some("c".into())),
+            Change::new("line 3", None, Some("d".into())),
+        ]
+    );
+}
@@ -0,0 +1,233 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::config::ExpectConfig;
+
+/// `[METHOD ]URL`，没有方法时匹配任意方法
+#[derive(Debug, Clone, PartialEq)]
+struct Pattern {
+    method: Option<String>,
+    url: String,
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Self {
+        match line.trim().split_once(char::is_whitespace) {
+            Some((method, url)) => Self {
+                method: Some(method.to_owned()).filter(|method| method != "*"),
+                url: url.trim().to_owned(),
+            },
+            None => Self {
+                method: None,
+                url: line.trim().to_owned(),
+            },
+        }
+    }
+
+    fn matches(&self, method: &str, url: &str) -> bool {
+        self.method
+            .as_deref()
+            .is_none_or(|m| m.eq_ignore_ascii_case(method))
+            && glob(&self.url, url)
+    }
+}
+
+/// `*` 匹配任意字符，不区分大小写
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let text = text.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // no `*`, the whole text must match
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// 同一方法与 URL 的请求合并计数
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Seen {
+    pub method: String,
+    pub url: String,
+    pub count: u64,
+    pub expected: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub total: u64,
+    pub unexpected: Vec<Seen>,
+    pub requests: Vec<Seen>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.unexpected.is_empty()
+    }
+}
+
+/// 加载的 `allow_file` 与其中的规则，读取失败时为空
+type Loaded = (PathBuf, Arc<Vec<Pattern>>);
+
+/// `expect` 模式记录的请求
+#[derive(Clone, Default)]
+pub struct Expectations {
+    seen: Arc<Mutex<BTreeMap<(String, String), Seen>>>,
+    file: Arc<Mutex<Option<Loaded>>>,
+}
+
+impl Expectations {
+    /// 记录请求，返回是否在允许列表中
+    pub fn check(&self, config: &ExpectConfig, method: &str, url: &str) -> bool {
+        let file = config
+            .allow_file
+            .as_deref()
+            .map(|path| self.load(path))
+            .unwrap_or_default();
+        let expected = config
+            .allow
+            .iter()
+            .map(|line| Pattern::parse(line))
+            .chain(file.iter().cloned())
+            .any(|pattern| pattern.matches(method, url));
+        if let Ok(mut seen) = self.seen.lock() {
+            seen.entry((method.to_owned(), url.to_owned()))
+                .or_insert_with(|| Seen {
+                    method: method.to_owned(),
+                    url: url.to_owned(),
+                    count: 0,
+                    expected,
+                })
+                .count += 1;
+        }
+        expected
+    }
+
+    fn load(&self, path: &Path) -> Arc<Vec<Pattern>> {
+        let Ok(mut cached) = self.file.lock() else {
+            return Arc::default();
+        };
+        match cached.as_ref() {
+            Some((loaded, patterns)) if loaded == path => patterns.clone(),
+            _ => {
+                let patterns = Arc::new(
+                    read_patterns(path)
+                        .inspect_err(
+                            |e| warn!(path = %path.display(), "load expect allow_file fail: {e}"),
+                        )
+                        .unwrap_or_default(),
+                );
+                *cached = Some((path.to_owned(), patterns.clone()));
+                patterns
+            }
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        let requests: Vec<Seen> = self
+            .seen
+            .lock()
+            .map(|seen| seen.values().cloned().collect())
+            .unwrap_or_default();
+        Report {
+            total: requests.iter().map(|seen| seen.count).sum(),
+            unexpected: requests
+                .iter()
+                .filter(|seen| !seen.expected)
+                .cloned()
+                .collect(),
+            requests,
+        }
+    }
+
+    /// 退出时调用：写报告并列出意外的请求，返回是否通过
+    pub async fn finish(&self, config: &ExpectConfig) -> bool {
+        let report = self.report();
+        if let Some(path) = &config.report {
+            let written = match serde_json::to_vec_pretty(&report) {
+                Ok(json) => tokio::fs::write(path, json).await.map_err(Into::into),
+                Err(e) => Err(anyhow::Error::from(e)),
+            };
+            if let Err(e) = written {
+                error!("write expect report to {} failed: {e}", path.display());
+            }
+        }
+        for seen in &report.unexpected {
+            error!(
+                method = seen.method,
+                url = seen.url,
+                count = seen.count,
+                "unexpected request"
+            );
+        }
+        // the summary goes to stderr as well, CI logs may not include proxy.log
+        if report.passed() {
+            eprintln!("expect: all {} requests were expected", report.total);
+        } else {
+            eprintln!(
+                "expect: {} of {} distinct requests were unexpected",
+                report.unexpected.len(),
+                report.requests.len()
+            );
+            for seen in &report.unexpected {
+                eprintln!("  {} {} (x{})", seen.method, seen.url, seen.count);
+            }
+        }
+        report.passed()
+    }
+}
+
+fn read_patterns(path: &Path) -> Result<Vec<Pattern>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(Pattern::parse)
+        .collect())
+}
+
+#[test]
+fn should_report_unexpected_requests() {
+    assert!(glob(
+        "https://*.example.com/*",
+        "https://api.example.com/v1"
+    ));
+    assert!(!glob("https://*.example.com/*", "https://example.org/"));
+    assert!(glob("api.example.com:443", "API.example.com:443"));
+    assert!(!glob("api.example.com", "api.example.com:443"));
+
+    let config = ExpectConfig {
+        allow: vec![
+            "GET https://api.example.com/v1/*".to_owned(),
+            "CONNECT *.github.com:443".to_owned(),
+        ],
+        ..Default::default()
+    };
+    let expectations = Expectations::default();
+    assert!(expectations.check(&config, "GET", "https://api.example.com/v1/users"));
+    assert!(expectations.check(&config, "CONNECT", "objects.github.com:443"));
+    assert!(!expectations.check(&config, "POST", "https://api.example.com/v1/users"));
+    assert!(!expectations.check(&config, "POST", "https://api.example.com/v1/users"));
+
+    let report = expectations.report();
+    assert!(!report.passed());
+    assert_eq!(report.total, 4);
+    assert_eq!(report.unexpected.len(), 1);
+    assert_eq!(report.unexpected[0].count, 2);
+}
@@ -0,0 +1,90 @@
+use crate::flow::Flow;
+
+/// curl 自行设置或与连接相关的头
+const SKIPPED_HEADERS: &[&str] = &[
+    "host",
+    "content-length",
+    "connection",
+    "proxy-connection",
+    "keep-alive",
+    "transfer-encoding",
+];
+
+/// 生成等价的 curl 命令，上游证书不校验时加 `--insecure`
+pub fn command(flow: &Flow) -> String {
+    let mut args = vec!["curl".to_owned()];
+    if flow.method != "GET" || !flow.request_body.is_empty() {
+        args.push(format!("-X {}", quote(&flow.method)));
+    }
+    args.push(quote(&flow.url()));
+    for (name, value) in &flow.request_headers {
+        if SKIPPED_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        // curl decodes the response itself with --compressed
+        if name == "accept-encoding" {
+            args.push("--compressed".to_owned());
+            continue;
+        }
+        args.push(format!("-H {}", quote(&format!("{name}: {value}"))));
+    }
+    if !flow.request_body.is_empty() {
+        let body = match std::str::from_utf8(&flow.request_body) {
+            Ok(body) => quote(body),
+            Err(_) => ansi_c_quote(&flow.request_body),
+        };
+        args.push(format!("--data-binary {body}"));
+    }
+    if flow.secure {
+        args.push("--insecure".to_owned());
+    }
+    let mut command = args.join(" \\\n  ");
+    if flow.is_request_truncated() {
+        command.insert_str(0, "# request body was truncated by flow_body_limit\n");
+    }
+    command.push('\n');
+    command
+}
+
+/// POSIX shell 单引号
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// bash `$'...'`，用于非 UTF-8 的 body
+fn ansi_c_quote(value: &[u8]) -> String {
+    let escaped: String = value
+        .iter()
+        .map(|&b| match b {
+            b'\'' | b'\\' => format!("\\{}", b as char),
+            b' '..=b'~' => (b as char).to_string(),
+            _ => format!("\\x{b:02x}"),
+        })
+        .collect();
+    format!("$'{escaped}'")
+}
+
+#[test]
+fn should_build_curl_command() {
+    let flow = Flow {
+        addr: "example.com:443".to_owned(),
+        host: "example.com".to_owned(),
+        secure: true,
+        method: "POST".to_owned(),
+        uri: "/api?q=1".to_owned(),
+        request_headers: vec![
+            ("host".to_owned(), "example.com".to_owned()),
+            ("accept-encoding".to_owned(), "gzip".to_owned()),
+            ("x-note".to_owned(), "it's".to_owned()),
+        ],
+        request_body: "{}".into(),
+        request_size: 2,
+        ..Default::default()
+    };
+    assert_eq!(
+        command(&flow),
+        "curl \\\n  -X 'POST' \\\n  'https://example.com/api?q=1' \\\n  --compressed \\\n  \
+         -H 'x-note: it'\\''s' \\\n  --data-binary '{}' \\\n  --insecure\n"
+    );
+    assert_eq!(ansi_c_quote(b"a'\x00"), r"$'a\'\x00'");
+}
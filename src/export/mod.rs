@@ -0,0 +1,59 @@
+use std::io::Write;
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, HOST};
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+
+use crate::config::Config;
+use crate::util;
+
+pub mod curl;
+pub mod openapi;
+pub mod postman;
+
+/// 命令行导出，从运行中实例的管理端口读取并写到 stdout；有 `body` 时以 JSON POST
+pub fn print(path: &str, body: Option<Bytes>) -> Result<()> {
+    std::io::stdout().write_all(&fetch(path, body)?)?;
+    Ok(())
+}
+
+/// 从运行中实例的管理端口读取
+pub fn fetch(path: &str, body: Option<Bytes>) -> Result<Bytes> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            let config = Config::load().await?;
+            let admin = config
+                .admin_addr
+                .ok_or(anyhow!("admin_addr is not configured"))?;
+            let stream = TcpStream::connect(&admin).await?;
+            let (mut sender, conn) =
+                hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+            tokio::task::spawn(conn);
+            let mut req = match body {
+                Some(body) => Request::post(path)
+                    .header(HOST, admin.as_str())
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(util::full(body))?,
+                None => Request::get(path)
+                    .header(HOST, admin.as_str())
+                    .body(util::empty())?,
+            };
+            if let Some(token) = &config.admin_token {
+                req.headers_mut()
+                    .insert(AUTHORIZATION, format!("Bearer {token}").parse()?);
+            }
+            let resp = sender.send_request(req).await?;
+            let status = resp.status();
+            let body = resp.into_body().collect().await?.to_bytes();
+            if !status.is_success() {
+                return Err(anyhow!("{status}: {}", String::from_utf8_lossy(&body)));
+            }
+            Ok(body)
+        })
+}
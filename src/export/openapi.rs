@@ -0,0 +1,308 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use hyper::Uri;
+use serde_json::{json, Map, Value};
+
+use crate::flow::Flow;
+
+/// 同一 path 模板 + method 的所有观测
+#[derive(Default)]
+struct Operation {
+    /// 参数名 -> 是否总是整数
+    path_params: Vec<(String, bool)>,
+    query: BTreeSet<String>,
+    request: Option<Value>,
+    responses: BTreeMap<u16, Option<Value>>,
+}
+
+/// 从 `host` 的 flow 推断 OpenAPI 3.0 文档：
+/// 数字、UUID 与长十六进制的 path 段视为参数，JSON body 合并出 schema
+pub fn document(host: &str, flows: &[Flow]) -> Value {
+    let mut servers = BTreeSet::new();
+    let mut operations: BTreeMap<(String, String), Operation> = BTreeMap::new();
+    for flow in flows
+        .iter()
+        .filter(|flow| flow.host == host && !flow.is_tcp())
+    {
+        let Ok(uri) = flow.url().parse::<Uri>() else {
+            continue;
+        };
+        if let (Some(scheme), Some(authority)) = (uri.scheme_str(), uri.authority()) {
+            servers.insert(format!("{scheme}://{authority}"));
+        }
+        let (template, params) = template(uri.path());
+        let operation = operations
+            .entry((template, flow.method.to_ascii_lowercase()))
+            .or_default();
+        merge_params(&mut operation.path_params, params);
+        operation.query.extend(
+            uri.query()
+                .unwrap_or_default()
+                .split('&')
+                .filter_map(|pair| pair.split('=').next())
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned),
+        );
+        if let Some(body) = json_body(&flow.request_headers, &flow.request_body) {
+            operation.request = Some(merge_option(operation.request.take(), infer(&body)));
+        }
+        if let Some(status) = flow.status {
+            let schema = json_body(&flow.response_headers, &flow.response_body).map(|b| infer(&b));
+            let entry = operation.responses.entry(status).or_default();
+            *entry = match (entry.take(), schema) {
+                (Some(a), Some(b)) => Some(merge(a, b)),
+                (a, b) => a.or(b),
+            };
+        }
+    }
+
+    let mut paths = Map::new();
+    for ((template, method), operation) in operations {
+        let item = paths
+            .entry(template)
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[method] = operation_object(operation);
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": host,
+            "version": "inferred",
+            "description": "Inferred from traffic captured by http-proxy-server",
+        },
+        "servers": servers.into_iter().map(|url| json!({ "url": url })).collect::<Vec<_>>(),
+        "paths": paths,
+    })
+}
+
+fn operation_object(operation: Operation) -> Value {
+    let mut parameters: Vec<_> = operation
+        .path_params
+        .iter()
+        .map(|(name, integer)| {
+            json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": if *integer { "integer" } else { "string" } },
+            })
+        })
+        .collect();
+    parameters.extend(operation.query.iter().map(|name| {
+        json!({
+            "name": name,
+            "in": "query",
+            "required": false,
+            "schema": { "type": "string" },
+        })
+    }));
+    let responses: Map<_, _> = operation
+        .responses
+        .into_iter()
+        .map(|(status, schema)| {
+            let mut response = json!({ "description": "observed" });
+            if let Some(schema) = schema {
+                response["content"] = json!({ "application/json": { "schema": schema } });
+            }
+            (status.to_string(), response)
+        })
+        .collect();
+    let mut object = json!({
+        "parameters": parameters,
+        "responses": responses,
+    });
+    if let Some(schema) = operation.request {
+        object["requestBody"] = json!({
+            "content": { "application/json": { "schema": schema } },
+        });
+    }
+    object
+}
+
+/// `/users/42/orders` -> `/users/{userId}/orders`
+fn template(path: &str) -> (String, Vec<(String, bool)>) {
+    let mut params = Vec::new();
+    let mut previous = "";
+    let segments: Vec<_> = path
+        .split('/')
+        .map(|segment| {
+            let template = if is_identifier(segment) {
+                let name = match previous.strip_suffix('s').unwrap_or(previous) {
+                    stem if !stem.is_empty() && stem.chars().all(|c| c.is_ascii_alphabetic()) => {
+                        format!("{stem}Id")
+                    }
+                    _ => format!("id{}", params.len() + 1),
+                };
+                let integer = segment.chars().all(|c| c.is_ascii_digit());
+                let template = format!("{{{name}}}");
+                params.push((name, integer));
+                template
+            } else {
+                segment.to_owned()
+            };
+            previous = segment;
+            template
+        })
+        .collect();
+    (segments.join("/"), params)
+}
+
+fn is_identifier(segment: &str) -> bool {
+    let digits = !segment.is_empty() && segment.chars().all(|c| c.is_ascii_digit());
+    let uuid = segment.len() == 36
+        && segment.chars().enumerate().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    let hex = segment.len() >= 16 && segment.chars().all(|c| c.is_ascii_hexdigit());
+    digits || uuid || hex
+}
+
+/// 同一模板下参数名相同，只有全部观测都是整数时才是整数
+fn merge_params(params: &mut Vec<(String, bool)>, observed: Vec<(String, bool)>) {
+    if params.is_empty() {
+        *params = observed;
+        return;
+    }
+    for ((_, integer), (_, observed)) in params.iter_mut().zip(observed) {
+        *integer &= observed;
+    }
+}
+
+fn json_body(headers: &[(String, String)], body: &[u8]) -> Option<Value> {
+    let json = headers
+        .iter()
+        .any(|(name, value)| name == "content-type" && value.contains("json"));
+    if !json || body.is_empty() {
+        return None;
+    }
+    // truncated bodies do not parse
+    serde_json::from_slice(body).ok()
+}
+
+fn infer(value: &Value) -> Value {
+    match value {
+        Value::Null => json!({ "nullable": true }),
+        Value::Bool(_) => json!({ "type": "boolean" }),
+        Value::Number(n) if n.is_f64() => json!({ "type": "number" }),
+        Value::Number(_) => json!({ "type": "integer" }),
+        Value::String(_) => json!({ "type": "string" }),
+        Value::Array(items) => {
+            let items = items
+                .iter()
+                .map(infer)
+                .reduce(merge)
+                .unwrap_or_else(|| json!({}));
+            json!({ "type": "array", "items": items })
+        }
+        Value::Object(fields) => {
+            let properties: Map<_, _> = fields.iter().map(|(k, v)| (k.clone(), infer(v))).collect();
+            let required: Vec<_> = fields.keys().cloned().collect();
+            json!({ "type": "object", "properties": properties, "required": required })
+        }
+    }
+}
+
+fn merge_option(a: Option<Value>, b: Value) -> Value {
+    match a {
+        Some(a) => merge(a, b),
+        None => b,
+    }
+}
+
+/// 两个观测的并集，字段只在每次都出现时才是 required，类型冲突时放开为任意类型
+fn merge(a: Value, b: Value) -> Value {
+    let kind = |v: &Value| v.get("type").and_then(Value::as_str).map(str::to_owned);
+    let nullable = |v: &Value| v.get("nullable").is_some_and(|n| n == true);
+    match (kind(&a), kind(&b)) {
+        (None, _) if nullable(&a) => with_nullable(b),
+        (_, None) if nullable(&b) => with_nullable(a),
+        (Some(x), Some(y)) if x == y && x == "object" => merge_objects(a, b),
+        (Some(x), Some(y)) if x == y && x == "array" => {
+            let nullable = nullable(&a) || nullable(&b);
+            let items = merge(a["items"].clone(), b["items"].clone());
+            let merged = json!({ "type": "array", "items": items });
+            if nullable {
+                with_nullable(merged)
+            } else {
+                merged
+            }
+        }
+        (Some(x), Some(y)) if x == y => {
+            if nullable(&b) {
+                with_nullable(a)
+            } else {
+                a
+            }
+        }
+        (Some(x), Some(y)) if [x.as_str(), y.as_str()] == ["integer", "number"] => b,
+        (Some(x), Some(y)) if [x.as_str(), y.as_str()] == ["number", "integer"] => a,
+        _ => json!({}),
+    }
+}
+
+fn merge_objects(mut a: Value, b: Value) -> Value {
+    let required = |v: &Value| -> BTreeSet<String> {
+        v["required"]
+            .as_array()
+            .map(|r| {
+                r.iter()
+                    .filter_map(|k| k.as_str().map(str::to_owned))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let required: Vec<_> = required(&a).intersection(&required(&b)).cloned().collect();
+    let nullable = b.get("nullable").is_some_and(|n| n == true);
+    if let (Some(properties), Some(other)) =
+        (a["properties"].as_object_mut(), b["properties"].as_object())
+    {
+        for (key, schema) in other {
+            let merged = match properties.remove(key) {
+                Some(existing) => merge(existing, schema.clone()),
+                None => schema.clone(),
+            };
+            properties.insert(key.clone(), merged);
+        }
+    }
+    a["required"] = json!(required);
+    if nullable {
+        a = with_nullable(a);
+    }
+    a
+}
+
+fn with_nullable(mut schema: Value) -> Value {
+    if schema.get("type").is_some() {
+        schema["nullable"] = json!(true);
+    }
+    schema
+}
+
+#[test]
+fn should_infer_openapi() {
+    let flow = |uri: &str, body: &str| Flow {
+        addr: "api.example.com:443".to_owned(),
+        host: "api.example.com".to_owned(),
+        secure: true,
+        method: "GET".to_owned(),
+        uri: uri.to_owned(),
+        status: Some(200),
+        response_headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+        response_body: body.to_owned().into(),
+        ..Default::default()
+    };
+    let flows = [
+        flow("/users/1?fields=name", r#"{"id":1,"name":"a","age":3}"#),
+        flow("/users/2", r#"{"id":2,"name":null,"score":1.5}"#),
+    ];
+    let doc = document("api.example.com", &flows);
+    assert_eq!(doc["servers"][0]["url"], "https://api.example.com");
+    let get = &doc["paths"]["/users/{userId}"]["get"];
+    assert_eq!(get["parameters"][0]["schema"]["type"], "integer");
+    assert_eq!(get["parameters"][1]["name"], "fields");
+    let schema = &get["responses"]["200"]["content"]["application/json"]["schema"];
+    assert_eq!(schema["required"], json!(["id", "name"]));
+    assert_eq!(schema["properties"]["name"]["nullable"], true);
+    assert_eq!(schema["properties"]["score"]["type"], "number");
+}
@@ -0,0 +1,146 @@
+use std::collections::BTreeMap;
+
+use hyper::Uri;
+use serde_json::{json, Value};
+
+use crate::flow::Flow;
+
+const SCHEMA: &str = "https://schema.getpostman.com/json/collection/v2.1.0/collection.json";
+
+/// 生成时不带的头，Postman 会自行设置
+const SKIPPED_HEADERS: &[&str] = &["host", "content-length", "connection", "proxy-connection"];
+
+/// Postman 2.1 collection：每个 host 一个目录，同一 method + path 只保留最近一次，
+/// URL 前缀为以 host 命名的变量，条目 id 为 flow id
+pub fn collection(flows: &[Flow]) -> Value {
+    let mut hosts: BTreeMap<&str, BTreeMap<(String, String), Value>> = BTreeMap::new();
+    for flow in flows.iter().filter(|flow| !flow.is_tcp()) {
+        let Ok(uri) = flow.url().parse::<Uri>() else {
+            continue;
+        };
+        let item = request(flow, &uri);
+        hosts
+            .entry(&flow.host)
+            .or_default()
+            .insert((uri.path().to_owned(), flow.method.clone()), item);
+    }
+    let item: Vec<_> = hosts
+        .into_iter()
+        .map(|(host, requests)| json!({ "name": host, "item": requests.into_values().collect::<Vec<_>>() }))
+        .collect();
+    json!({
+        "info": {
+            "name": "http-proxy-server capture",
+            "schema": SCHEMA,
+        },
+        "item": item,
+        "variable": variables(flows),
+    })
+}
+
+/// 与 collection 变量相同的环境，导入后可切换到测试环境的地址
+pub fn environment(flows: &[Flow]) -> Value {
+    let values: Vec<_> = variables(flows)
+        .into_iter()
+        .map(|mut variable| {
+            variable["enabled"] = json!(true);
+            variable
+        })
+        .collect();
+    json!({
+        "name": "http-proxy-server hosts",
+        "values": values,
+    })
+}
+
+fn variables(flows: &[Flow]) -> Vec<Value> {
+    let bases: BTreeMap<_, _> = flows
+        .iter()
+        .filter_map(|flow| Some((flow.host.as_str(), base(&flow.url().parse().ok()?)?)))
+        .collect();
+    bases
+        .into_iter()
+        .map(|(host, base)| json!({ "key": host, "value": base }))
+        .collect()
+}
+
+fn base(uri: &Uri) -> Option<String> {
+    Some(format!("{}://{}", uri.scheme_str()?, uri.authority()?))
+}
+
+fn request(flow: &Flow, uri: &Uri) -> Value {
+    let header: Vec<_> = flow
+        .request_headers
+        .iter()
+        .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.as_str()))
+        .map(|(name, value)| json!({ "key": name, "value": value }))
+        .collect();
+    let host = format!("{{{{{}}}}}", flow.host);
+    let path: Vec<_> = uri.path().split('/').filter(|s| !s.is_empty()).collect();
+    let query: Vec<_> = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            json!({ "key": key, "value": value })
+        })
+        .collect();
+    let raw = match uri.query() {
+        Some(query) => format!("{host}{}?{query}", uri.path()),
+        None => format!("{host}{}", uri.path()),
+    };
+    let mut request = json!({
+        "method": flow.method,
+        "header": header,
+        "url": {
+            "raw": raw,
+            "host": [host],
+            "path": path,
+            "query": query,
+        },
+    });
+    if !flow.request_body.is_empty() {
+        request["body"] = json!({
+            "mode": "raw",
+            "raw": String::from_utf8_lossy(&flow.request_body),
+        });
+    }
+    json!({
+        "id": flow.id.to_string(),
+        "name": format!("{} {}", flow.method, uri.path()),
+        "request": request,
+    })
+}
+
+#[test]
+fn should_group_by_host() {
+    let flow = |method: &str, uri: &str| Flow {
+        addr: "api.example.com:443".to_owned(),
+        host: "api.example.com".to_owned(),
+        secure: true,
+        method: method.to_owned(),
+        uri: uri.to_owned(),
+        id: crate::flow::next_id(),
+        ..Default::default()
+    };
+    let flows = [
+        flow("GET", "/users?page=1"),
+        flow("GET", "/users?page=2"),
+        flow("POST", "/users"),
+    ];
+    let collection = collection(&flows);
+    let folder = &collection["item"][0];
+    assert_eq!(folder["name"], "api.example.com");
+    assert_eq!(folder["item"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        folder["item"][0]["request"]["url"]["raw"],
+        "{{api.example.com}}/users?page=2"
+    );
+    assert_eq!(folder["item"][0]["id"], flows[1].id.to_string());
+    assert_eq!(
+        collection["variable"][0]["value"],
+        "https://api.example.com"
+    );
+}
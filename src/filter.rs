@@ -0,0 +1,318 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+use anyhow::{anyhow, Result};
+use regex::bytes::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::flow::Flow;
+
+/// mitmproxy 风格的过滤表达式，如 `~d example.com & ~m POST & !~c 200`。
+/// 解析一次得到 AST，按 flow 求值；正则不区分大小写，单独的字符串等同 `~u`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    All,
+    /// 还没有响应
+    Request,
+    Response,
+    Error,
+    Domain(Regex),
+    Method(Regex),
+    Code(u16),
+    /// `~c 5xx`，状态码的百位
+    CodeClass(u16),
+    Url(Regex),
+    Header(Part, Regex),
+    Body(Part, Regex),
+    ContentType(Part, Regex),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Part {
+    Request,
+    Response,
+    Either,
+}
+
+impl Filter {
+    pub fn matches(&self, flow: &Flow) -> bool {
+        self.expr.matches(flow)
+    }
+}
+
+/// 没有配置过滤时全部通过
+pub fn allows(filter: Option<&Filter>, flow: &Flow) -> bool {
+    filter.is_none_or(|filter| filter.matches(flow))
+}
+
+impl Expr {
+    fn matches(&self, flow: &Flow) -> bool {
+        match self {
+            Self::All => true,
+            Self::Request => flow.status.is_none() && flow.error.is_none(),
+            Self::Response => flow.status.is_some(),
+            Self::Error => flow.error.is_some(),
+            Self::Domain(re) => re.is_match(flow.host.as_bytes()),
+            Self::Method(re) => re.is_match(flow.method.as_bytes()),
+            Self::Code(code) => flow.status == Some(*code),
+            Self::CodeClass(class) => flow.status.is_some_and(|status| status / 100 == *class),
+            Self::Url(re) => re.is_match(flow.url().as_bytes()),
+            Self::Header(part, re) => part
+                .headers(flow)
+                .any(|(name, value)| re.is_match(format!("{name}: {value}").as_bytes())),
+            Self::Body(part, re) => part.bodies(flow).any(|body| re.is_match(body)),
+            Self::ContentType(part, re) => part
+                .headers(flow)
+                .any(|(name, value)| name == "content-type" && re.is_match(value.as_bytes())),
+            Self::Not(expr) => !expr.matches(flow),
+            Self::And(a, b) => a.matches(flow) && b.matches(flow),
+            Self::Or(a, b) => a.matches(flow) || b.matches(flow),
+        }
+    }
+}
+
+impl Part {
+    fn headers(self, flow: &Flow) -> impl Iterator<Item = &(String, String)> {
+        let (request, response): (&[_], &[_]) = match self {
+            Self::Request => (&flow.request_headers, &[]),
+            Self::Response => (&[], &flow.response_headers),
+            Self::Either => (&flow.request_headers, &flow.response_headers),
+        };
+        request.iter().chain(response)
+    }
+
+    fn bodies(self, flow: &Flow) -> impl Iterator<Item = &[u8]> {
+        let bodies: [Option<&[u8]>; 2] = match self {
+            Self::Request => [Some(&flow.request_body), None],
+            Self::Response => [None, Some(&flow.response_body)],
+            Self::Either => [Some(&flow.request_body), Some(&flow.response_body)],
+        };
+        bodies.into_iter().flatten()
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            chars: source.chars().peekable(),
+        };
+        let expr = parser.or()?;
+        parser.skip_whitespace();
+        if let Some(c) = parser.chars.peek() {
+            return Err(anyhow!("unexpected `{c}` in filter {source:?}"));
+        }
+        Ok(Self {
+            source: source.to_owned(),
+            expr,
+        })
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            source: "~all".to_owned(),
+            expr: Expr::All,
+        }
+    }
+}
+
+impl TryFrom<String> for Filter {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Filter> for String {
+    fn from(value: Filter) -> Self {
+        value.source
+    }
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// 递归下降：`|` 优先级最低，相邻的项之间省略 `&`
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        loop {
+            self.skip_whitespace();
+            if self.chars.next_if_eq(&'|').is_none() {
+                return Ok(expr);
+            }
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek() {
+                Some('&') => {
+                    self.chars.next();
+                }
+                Some('|' | ')') | None => return Ok(expr),
+                Some(_) => {}
+            }
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('!') => {
+                self.chars.next();
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some('(') => {
+                self.chars.next();
+                let expr = self.or()?;
+                self.skip_whitespace();
+                self.chars
+                    .next_if_eq(&')')
+                    .ok_or(anyhow!("missing `)` in filter"))?;
+                Ok(expr)
+            }
+            Some('~') => {
+                self.chars.next();
+                let mut name = String::new();
+                while let Some(c) = self.chars.next_if(|c| c.is_ascii_alphabetic()) {
+                    name.push(c);
+                }
+                self.operator(&name)
+            }
+            Some(_) => Ok(Expr::Url(regex(&self.argument()?)?)),
+            None => Err(anyhow!("unexpected end of filter")),
+        }
+    }
+
+    fn operator(&mut self, name: &str) -> Result<Expr> {
+        let expr = match name {
+            "all" | "http" => Expr::All,
+            "q" => Expr::Request,
+            "s" => Expr::Response,
+            "e" => Expr::Error,
+            "c" => {
+                let code = self.argument()?;
+                let invalid = || anyhow!("invalid status code `{code}`");
+                match code.to_ascii_lowercase().strip_suffix("xx") {
+                    Some(class) => Expr::CodeClass(
+                        class
+                            .parse()
+                            .ok()
+                            .filter(|class| (1..=5).contains(class))
+                            .ok_or_else(invalid)?,
+                    ),
+                    None => Expr::Code(code.parse().map_err(|_| invalid())?),
+                }
+            }
+            "d" => Expr::Domain(regex(&self.argument()?)?),
+            "m" => Expr::Method(regex(&self.argument()?)?),
+            "u" => Expr::Url(regex(&self.argument()?)?),
+            "h" => Expr::Header(Part::Either, regex(&self.argument()?)?),
+            "hq" => Expr::Header(Part::Request, regex(&self.argument()?)?),
+            "hs" => Expr::Header(Part::Response, regex(&self.argument()?)?),
+            "b" => Expr::Body(Part::Either, regex(&self.argument()?)?),
+            "bq" => Expr::Body(Part::Request, regex(&self.argument()?)?),
+            "bs" => Expr::Body(Part::Response, regex(&self.argument()?)?),
+            "t" => Expr::ContentType(Part::Either, regex(&self.argument()?)?),
+            "tq" => Expr::ContentType(Part::Request, regex(&self.argument()?)?),
+            "ts" => Expr::ContentType(Part::Response, regex(&self.argument()?)?),
+            _ => return Err(anyhow!("unknown filter operator `~{name}`")),
+        };
+        Ok(expr)
+    }
+
+    /// 引号内可包含空白与括号
+    fn argument(&mut self) -> Result<String> {
+        self.skip_whitespace();
+        let mut arg = String::new();
+        match self.chars.next_if(|c| *c == '"' || *c == '\'') {
+            Some(quote) => loop {
+                match self.chars.next() {
+                    Some('\\') => {
+                        if let Some(c) = self.chars.next() {
+                            if c != quote {
+                                arg.push('\\');
+                            }
+                            arg.push(c);
+                        }
+                    }
+                    Some(c) if c == quote => break,
+                    Some(c) => arg.push(c),
+                    None => return Err(anyhow!("unterminated {quote} in filter")),
+                }
+            },
+            None => {
+                while let Some(c) = self.chars.next_if(|c| !c.is_whitespace() && *c != ')') {
+                    arg.push(c);
+                }
+            }
+        }
+        if arg.is_empty() {
+            return Err(anyhow!("missing filter argument"));
+        }
+        Ok(arg)
+    }
+}
+
+fn regex(pattern: &str) -> Result<Regex> {
+    Ok(RegexBuilder::new(pattern).case_insensitive(true).build()?)
+}
+
+#[test]
+fn should_match_filter() {
+    let flow = Flow {
+        addr: "api.example.com:443".to_owned(),
+        host: "api.example.com".to_owned(),
+        secure: true,
+        method: "POST".to_owned(),
+        uri: "/login".to_owned(),
+        status: Some(500),
+        request_headers: vec![("content-type".to_owned(), "application/json".to_owned())],
+        request_body: r#"{"user":"a"}"#.into(),
+        ..Default::default()
+    };
+    let matches = |source: &str| source.parse::<Filter>().unwrap().matches(&flow);
+    assert!(matches("~d example.com & ~m POST & ~c 500"));
+    assert!(matches("~d example.com ~m post"));
+    assert!(!matches("~d example.com & !~c 500"));
+    assert!(matches("~c 5xx") && !matches("~c 4XX"));
+    assert!(matches("~c 200 | (~tq json & ~bq '\"user\"')"));
+    assert!(matches("login"));
+    assert!(matches("~hq 'content-type: application/json'"));
+    assert!(!matches("~q | ~e"));
+    assert!("~x foo".parse::<Filter>().is_err());
+    assert!("(~d a".parse::<Filter>().is_err());
+    assert!("~c abc".parse::<Filter>().is_err());
+    assert!("~c 9xx".parse::<Filter>().is_err());
+}
@@ -0,0 +1,188 @@
+use openssl::hash::{hash, MessageDigest};
+use openssl::sha::sha256;
+use serde::Serialize;
+
+use crate::sniff::ClientHello;
+
+/// 客户端 ClientHello 的 JA3 / JA4 指纹，用来区分本机上发出流量的应用
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    /// 未做 hash 的 JA3 字符串
+    pub ja3: String,
+    /// JA3 的 MD5
+    pub ja3_hash: String,
+    pub ja4: String,
+}
+
+impl Fingerprint {
+    pub fn of(hello: &ClientHello) -> Self {
+        let ja3 = ja3(hello);
+        let ja3_hash = hash(MessageDigest::md5(), ja3.as_bytes())
+            .map(|digest| hex(&digest))
+            .unwrap_or_default();
+        Self {
+            ja3,
+            ja3_hash,
+            ja4: ja4(hello),
+        }
+    }
+}
+
+/// GREASE（RFC 8701）值：0x0a0a、0x1a1a……
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// `版本,密码套件,扩展,椭圆曲线,点格式`，十进制，去掉 GREASE
+fn ja3(hello: &ClientHello) -> String {
+    let join = |values: &[u16]| {
+        values
+            .iter()
+            .filter(|value| !is_grease(**value))
+            .map(u16::to_string)
+            .collect::<Vec<_>>()
+            .join("-")
+    };
+    let point_formats: Vec<u16> = hello.point_formats.iter().map(|f| *f as u16).collect();
+    format!(
+        "{},{},{},{},{}",
+        hello.version,
+        join(&hello.ciphers),
+        join(&hello.extensions),
+        join(&hello.groups),
+        join(&point_formats),
+    )
+}
+
+/// `t13d1516h2_<密码套件 hash>_<扩展与签名算法 hash>`，只有 TCP 上的 TLS
+fn ja4(hello: &ClientHello) -> String {
+    let version = hello
+        .supported_versions
+        .iter()
+        .copied()
+        .filter(|version| !is_grease(*version))
+        .max()
+        .unwrap_or(hello.version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        _ => "00",
+    };
+    let sni = if hello.server_name.is_some() {
+        'd'
+    } else {
+        'i'
+    };
+
+    let ciphers: Vec<u16> = hello
+        .ciphers
+        .iter()
+        .copied()
+        .filter(|cipher| !is_grease(*cipher))
+        .collect();
+    let extensions: Vec<u16> = hello
+        .extensions
+        .iter()
+        .copied()
+        .filter(|extension| !is_grease(*extension))
+        .collect();
+
+    let mut sorted_ciphers = ciphers.clone();
+    sorted_ciphers.sort_unstable();
+    // SNI 与 ALPN 已体现在第一部分
+    let mut sorted_extensions: Vec<u16> = extensions
+        .iter()
+        .copied()
+        .filter(|extension| *extension != 0x0000 && *extension != 0x0010)
+        .collect();
+    sorted_extensions.sort_unstable();
+    let mut extension_part = hex_list(&sorted_extensions);
+    if !hello.signature_algorithms.is_empty() {
+        extension_part.push('_');
+        extension_part.push_str(&hex_list(&hello.signature_algorithms));
+    }
+
+    format!(
+        "t{version}{sni}{:02}{:02}{}_{}_{}",
+        ciphers.len().min(99),
+        extensions.len().min(99),
+        alpn(hello.alpn.first()),
+        truncated_hash(&sorted_ciphers, &hex_list(&sorted_ciphers)),
+        truncated_hash(&sorted_extensions, &extension_part),
+    )
+}
+
+/// 第一个 ALPN 的首尾字符，不是字母数字时取其十六进制表示的首尾
+fn alpn(first: Option<&String>) -> String {
+    let Some(first) = first.filter(|alpn| !alpn.is_empty()) else {
+        return "00".to_owned();
+    };
+    let bytes = first.as_bytes();
+    let (head, tail) = (bytes[0], bytes[bytes.len() - 1]);
+    if head.is_ascii_alphanumeric() && tail.is_ascii_alphanumeric() {
+        format!("{}{}", head as char, tail as char)
+    } else {
+        let hex = hex(bytes);
+        format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+    }
+}
+
+/// SHA-256 的前 12 位，列表为空时为 12 个 0
+fn truncated_hash(values: &[u16], text: &str) -> String {
+    if values.is_empty() {
+        return "000000000000".to_owned();
+    }
+    hex(&sha256(text.as_bytes()))[..12].to_owned()
+}
+
+fn hex_list(values: &[u16]) -> String {
+    values
+        .iter()
+        .map(|value| format!("{value:04x}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[test]
+fn should_fingerprint_client_hello() {
+    let hello = ClientHello {
+        server_name: Some("example.com".to_owned()),
+        alpn: vec!["h2".to_owned(), "http/1.1".to_owned()],
+        version: 0x0303,
+        ciphers: vec![0x2a2a, 0x1301, 0x1302, 0xc02b],
+        extensions: vec![0x3a3a, 0x0000, 0x0010, 0x000a, 0x000b, 0x000d, 0x002b],
+        groups: vec![0x4a4a, 0x001d, 0x0017],
+        point_formats: vec![0],
+        signature_algorithms: vec![0x0403, 0x0804],
+        supported_versions: vec![0x5a5a, 0x0304, 0x0303],
+    };
+    let fingerprint = Fingerprint::of(&hello);
+    assert_eq!(
+        fingerprint.ja3,
+        "771,4865-4866-49195,0-16-10-11-13-43,29-23,0"
+    );
+    assert_eq!(fingerprint.ja3_hash.len(), 32);
+
+    let ciphers = hex(&sha256(b"1301,1302,c02b"));
+    let extensions = hex(&sha256(b"000a,000b,000d,002b_0403,0804"));
+    assert_eq!(
+        fingerprint.ja4,
+        format!("t13d0306h2_{}_{}", &ciphers[..12], &extensions[..12])
+    );
+
+    let bare = ClientHello {
+        version: 0x0301,
+        ..Default::default()
+    };
+    assert_eq!(
+        Fingerprint::of(&bare).ja4,
+        "t10i000000_000000000000_000000000000"
+    );
+}
@@ -0,0 +1,643 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::HeaderMap;
+use serde::{Serialize, Serializer};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::StreamExt;
+use tokio_util::io::ReaderStream;
+use tracing::{error, warn};
+use ulid::{Generator, Ulid};
+
+use crate::config::{RedactConfig, SpillConfig};
+use crate::fingerprint::Fingerprint;
+use crate::geoip::Geo;
+use crate::graphql;
+use crate::grpc::GrpcCall;
+use crate::upstream_cert::CertInfo;
+use crate::util;
+
+/// 写入临时文件时的缓冲
+const SPILL_BUFFER: usize = 64 * 1024;
+
+static GENERATOR: Mutex<Generator> = Mutex::new(Generator::new());
+
+/// 单调递增的 ULID，日志、flow store 与管理接口共用
+pub fn next_id() -> Ulid {
+    let mut generator = match GENERATOR.lock() {
+        Ok(generator) => generator,
+        Err(e) => e.into_inner(),
+    };
+    generator.generate().unwrap_or_else(|_| Ulid::new())
+}
+
+/// 一次经过解析的请求/响应
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Flow {
+    pub id: Ulid,
+    /// `host:port`
+    pub addr: String,
+    pub host: String,
+    pub secure: bool,
+    pub method: String,
+    pub uri: String,
+    pub status: Option<u16>,
+    pub request_headers: Vec<(String, String)>,
+    pub response_headers: Vec<(String, String)>,
+    #[serde(serialize_with = "lossy")]
+    pub request_body: Bytes,
+    #[serde(serialize_with = "lossy")]
+    pub response_body: Bytes,
+    pub request_size: u64,
+    pub response_size: u64,
+    /// 超出 `flow_body_limit` 时写入临时文件的完整 body
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_spill: Option<Arc<Spill>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_spill: Option<Arc<Spill>>,
+    pub duration_ms: Option<u64>,
+    pub timings: Timings,
+    pub error: Option<String>,
+    pub complete: bool,
+    /// 镜像或重放时为原 flow
+    pub origin: Option<Ulid>,
+    /// HTTPS 上游的证书
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_cert: Option<Arc<CertInfo>>,
+    /// 按 `geoip.database` 查询的上游地址
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub geo: Option<Geo>,
+    /// 被解密的客户端的 TLS 指纹
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_fingerprint: Option<Arc<Fingerprint>>,
+    /// chunked body 末尾的 trailers
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub request_trailers: Vec<(String, String)>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub response_trailers: Vec<(String, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grpc: Option<GrpcCall>,
+    /// `graphql_endpoints` 上的请求中的操作
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub graphql: Vec<graphql::Operation>,
+    /// 非 HTTP 隧道（method 为 `TCP`）按读写记录的原始字节
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<Chunk>,
+}
+
+/// 写入临时文件的 body，最后一个引用释放时删除文件
+#[derive(Serialize, Debug)]
+pub struct Spill {
+    pub path: PathBuf,
+    /// 写入的字节数，超出 `max_body_mb` 时小于 body 的大小
+    pub size: u64,
+    /// 后台写完时为 `Some(是否成功)`
+    #[serde(skip)]
+    written: watch::Receiver<Option<bool>>,
+}
+
+impl Spill {
+    /// 等后台写完后逐块读取文件，读取期间保留文件
+    pub fn body(self: &Arc<Self>) -> BoxBody<Bytes, hyper::Error> {
+        let (tx, rx) = mpsc::channel(1);
+        let spill = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = spill.send(&tx).await {
+                error!("read spilled body {} failed: {e}", spill.path.display());
+            }
+        });
+        StreamBody::new(ReceiverStream::new(rx).map(|chunk| Ok(Frame::data(chunk)))).boxed()
+    }
+
+    async fn send(&self, tx: &mpsc::Sender<Bytes>) -> io::Result<()> {
+        let mut written = self.written.clone();
+        if !written
+            .wait_for(Option::is_some)
+            .await
+            .is_ok_and(|ok| *ok == Some(true))
+        {
+            return Err(io::Error::other("spill was not written"));
+        }
+        let mut chunks = ReaderStream::new(tokio::fs::File::open(&self.path).await?);
+        while let Some(chunk) = chunks.next().await {
+            if tx.send(chunk?).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// 推送用的 flow 摘要，不含 header 与 body
+#[derive(Serialize, Debug, Clone)]
+pub struct Summary {
+    pub id: Ulid,
+    pub method: String,
+    pub url: String,
+    pub status: Option<u16>,
+    pub duration_ms: Option<u64>,
+    pub request_size: u64,
+    pub response_size: u64,
+    pub error: Option<String>,
+}
+
+impl From<&Flow> for Summary {
+    fn from(flow: &Flow) -> Self {
+        Self {
+            id: flow.id,
+            method: flow.method.clone(),
+            url: flow.url(),
+            status: flow.status,
+            duration_ms: flow.duration_ms,
+            request_size: flow.request_size,
+            response_size: flow.response_size,
+            error: flow.error.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// 客户端发往上游
+    Up,
+    Down,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Chunk {
+    pub direction: Direction,
+    /// 距连接建立的毫秒数
+    pub offset_ms: u64,
+    #[serde(serialize_with = "lossy")]
+    pub data: Bytes,
+}
+
+/// 各阶段耗时，复用连接时没有 dns / connect / tls
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct Timings {
+    pub dns_ms: Option<u64>,
+    pub connect_ms: Option<u64>,
+    pub tls_ms: Option<u64>,
+    /// 发出请求到收到响应头
+    pub ttfb_ms: Option<u64>,
+    /// 新建连接的目标地址，由上游代理解析 DNS 时为空
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_ip: Option<IpAddr>,
+}
+
+impl Timings {
+    pub fn since(start: Instant) -> Option<u64> {
+        Some(start.elapsed().as_millis() as u64)
+    }
+}
+
+/// 最近的 flow，满了丢弃最旧的，变更通过 broadcast 推送
+#[derive(Clone)]
+pub struct FlowStore {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    flows: Mutex<VecDeque<Flow>>,
+    capacity: usize,
+    tx: broadcast::Sender<Flow>,
+}
+
+impl FlowStore {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(256);
+        Self {
+            inner: Arc::new(Inner {
+                flows: Mutex::new(VecDeque::with_capacity(capacity)),
+                capacity,
+                tx,
+            }),
+        }
+    }
+
+    /// `capacity` 为 0 时只推送不保留
+    pub fn insert(&self, flow: Flow) {
+        if self.inner.capacity == 0 {
+            let _ = self.inner.tx.send(flow);
+            return;
+        }
+        if let Ok(mut flows) = self.inner.flows.lock() {
+            if flows.len() >= self.inner.capacity {
+                flows.pop_front();
+            }
+            flows.push_back(flow.clone());
+        }
+        let _ = self.inner.tx.send(flow);
+    }
+
+    pub fn update(&self, id: Ulid, f: impl FnOnce(&mut Flow)) {
+        let updated = self.inner.flows.lock().ok().and_then(|mut flows| {
+            let flow = flows.iter_mut().rev().find(|flow| flow.id == id)?;
+            f(flow);
+            Some(flow.clone())
+        });
+        if let Some(flow) = updated {
+            let _ = self.inner.tx.send(flow);
+        }
+    }
+
+    pub fn remove(&self, id: Ulid) {
+        if let Ok(mut flows) = self.inner.flows.lock() {
+            flows.retain(|flow| flow.id != id);
+        }
+    }
+
+    pub fn get(&self, id: Ulid) -> Option<Flow> {
+        let flows = self.inner.flows.lock().ok()?;
+        flows.iter().find(|flow| flow.id == id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<Flow> {
+        self.inner
+            .flows
+            .lock()
+            .map(|flows| flows.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Flow> {
+        self.inner.tx.subscribe()
+    }
+}
+
+impl Flow {
+    /// 完整 URL，HTTPS 内解析出的请求只有 path
+    pub fn url(&self) -> String {
+        url(&self.addr, self.secure, &self.uri)
+    }
+
+    /// 记录的是非 HTTP 隧道，没有请求可以导出或重放
+    pub fn is_tcp(&self) -> bool {
+        self.method == "TCP"
+    }
+
+    /// body 超出 `flow_body_limit` 时只保留了前缀
+    pub fn is_request_truncated(&self) -> bool {
+        self.request_body.len() as u64 != self.request_size
+    }
+
+    pub fn is_response_truncated(&self) -> bool {
+        self.response_body.len() as u64 != self.response_size
+    }
+
+    /// 完整的 body，被截断且没有完整写入临时文件时为空
+    pub fn full_request_body(&self) -> Option<BoxBody<Bytes, hyper::Error>> {
+        full_body(
+            &self.request_body,
+            self.request_size,
+            self.request_spill.as_ref(),
+        )
+    }
+
+    pub fn full_response_body(&self) -> Option<BoxBody<Bytes, hyper::Error>> {
+        full_body(
+            &self.response_body,
+            self.response_size,
+            self.response_spill.as_ref(),
+        )
+    }
+}
+
+fn full_body(
+    body: &Bytes,
+    size: u64,
+    spill: Option<&Arc<Spill>>,
+) -> Option<BoxBody<Bytes, hyper::Error>> {
+    if body.len() as u64 == size {
+        return Some(util::full(body.clone()));
+    }
+    Some(spill.filter(|spill| spill.size == size)?.body())
+}
+
+/// 代理请求的 URI 已是完整 URL，其余只有 path，按连接地址补全
+pub fn url(addr: &str, secure: bool, uri: &str) -> String {
+    if !uri.starts_with('/') {
+        return uri.to_owned();
+    }
+    let (scheme, default_port) = if secure {
+        ("https", ":443")
+    } else {
+        ("http", ":80")
+    };
+    let authority = addr.strip_suffix(default_port).unwrap_or(addr);
+    format!("{scheme}://{authority}{uri}")
+}
+
+fn lossy<S: Serializer>(body: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(body))
+}
+
+pub fn headers(map: &HeaderMap, redact: &RedactConfig) -> Vec<(String, String)> {
+    map.iter()
+        .map(|(k, v)| {
+            let value = String::from_utf8_lossy(v.as_bytes());
+            (
+                k.to_string(),
+                redact.header(k.as_str(), &value).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// body 结束（或被丢弃）时交给回调
+pub struct Captured {
+    /// 前 `limit` 字节
+    pub body: Bytes,
+    pub size: u64,
+    pub trailers: Option<HeaderMap>,
+    /// 超出 `limit` 时写入临时文件的完整 body
+    pub spill: Option<Arc<Spill>>,
+}
+
+type OnDone = Box<dyn FnOnce(Captured) + Send + Sync>;
+
+/// 透传 body，同时保留前 `limit` 字节与 trailers，结束（或被丢弃）时回调
+pub struct CaptureBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    buf: BytesMut,
+    limit: usize,
+    size: u64,
+    trailers: Option<HeaderMap>,
+    spiller: Option<Spiller>,
+    on_done: Option<OnDone>,
+}
+
+impl CaptureBody {
+    pub fn new(
+        inner: BoxBody<Bytes, hyper::Error>,
+        limit: usize,
+        on_done: impl FnOnce(Captured) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner,
+            buf: BytesMut::new(),
+            limit,
+            size: 0,
+            trailers: None,
+            spiller: None,
+            on_done: Some(Box::new(on_done)),
+        }
+    }
+
+    /// 超出 `limit` 后把整个 body 写入临时文件
+    pub fn spill(mut self, config: Option<&SpillConfig>) -> Self {
+        self.spiller = config.map(|config| Spiller {
+            dir: config.dir(),
+            max: config.max_body_mb * 1024 * 1024,
+            writer: None,
+            written: 0,
+        });
+        self
+    }
+
+    fn done(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done(Captured {
+                body: self.buf.split().freeze(),
+                size: self.size,
+                trailers: self.trailers.take(),
+                spill: self.spiller.as_mut().and_then(Spiller::finish),
+            });
+        }
+    }
+}
+
+/// 第一次超出 `limit` 时启动后台写入，先写入内存中已保留的前缀；
+/// 文件操作都在 blocking 线程上，poll_frame 只转交数据
+struct Spiller {
+    dir: PathBuf,
+    max: u64,
+    writer: Option<Writer>,
+    written: u64,
+}
+
+struct Writer {
+    path: PathBuf,
+    chunks: std::sync::mpsc::Sender<Bytes>,
+    written: watch::Receiver<Option<bool>>,
+}
+
+impl Spiller {
+    fn write(&mut self, prefix: &[u8], data: &Bytes) {
+        if self.writer.is_none() {
+            let path = self.dir.join(format!("{}.body", next_id()));
+            let (chunks, rx) = std::sync::mpsc::channel();
+            let (tx, written) = watch::channel(None);
+            let (dir, file) = (self.dir.clone(), path.clone());
+            tokio::task::spawn_blocking(move || write_spill(&dir, &file, rx, tx));
+            self.writer = Some(Writer {
+                path,
+                chunks,
+                written,
+            });
+            self.append(Bytes::copy_from_slice(prefix));
+        }
+        self.append(data.clone());
+    }
+
+    fn append(&mut self, mut data: Bytes) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        data.truncate(self.max.saturating_sub(self.written).min(data.len() as u64) as usize);
+        if data.is_empty() {
+            return;
+        }
+        self.written += data.len() as u64;
+        // the writer stops on errors and reports them through `written`
+        let _ = writer.chunks.send(data);
+    }
+
+    fn finish(&mut self) -> Option<Arc<Spill>> {
+        let Writer { path, written, .. } = self.writer.take()?;
+        Some(Arc::new(Spill {
+            path,
+            size: self.written,
+            written,
+        }))
+    }
+}
+
+fn write_spill(
+    dir: &Path,
+    path: &Path,
+    chunks: std::sync::mpsc::Receiver<Bytes>,
+    written: watch::Sender<Option<bool>>,
+) {
+    let result = (|| {
+        create_private_dir(dir)?;
+        let mut file = BufWriter::with_capacity(SPILL_BUFFER, create_private_file(path)?);
+        for chunk in chunks {
+            file.write_all(&chunk)?;
+        }
+        file.flush()
+    })();
+    if let Err(e) = &result {
+        warn!("spill body to {} failed: {e}", path.display());
+    }
+    // the flow may already be evicted, nobody would remove the file
+    if result.is_err() || written.is_closed() {
+        let _ = std::fs::remove_file(path);
+    }
+    let _ = written.send(Some(result.is_ok()));
+}
+
+/// 只有当前用户可访问
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
+
+fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+impl Drop for CaptureBody {
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+impl Body for CaptureBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    let this = &mut *self;
+                    this.size += data.len() as u64;
+                    if let Some(spiller) = &mut this.spiller {
+                        if spiller.writer.is_some() || this.size > this.limit as u64 {
+                            spiller.write(&this.buf, data);
+                        }
+                    }
+                    let remain = this.limit.saturating_sub(this.buf.len());
+                    let take = remain.min(data.len());
+                    this.buf.extend_from_slice(&data[..take]);
+                } else if let Some(trailers) = frame.trailers_ref() {
+                    self.trailers = Some(trailers.clone());
+                }
+            }
+            Poll::Ready(None) | Poll::Ready(Some(Err(_))) => self.done(),
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[tokio::test]
+async fn should_spill_large_body() {
+    let frames = ["hello ", "spilled ", "world"].map(|chunk| Ok(Frame::data(Bytes::from(chunk))));
+    let body = StreamBody::new(tokio_stream::iter(frames)).boxed();
+    let captured = Arc::new(Mutex::new(None));
+    let config = SpillConfig {
+        dir: Some(std::env::temp_dir().join("should_spill_large_body")),
+        ..Default::default()
+    };
+    let capture = {
+        let captured = captured.clone();
+        CaptureBody::new(body, 8, move |c| *captured.lock().unwrap() = Some(c)).spill(Some(&config))
+    };
+    let sent = capture.collect().await.unwrap().to_bytes();
+    assert_eq!(sent, "hello spilled world");
+
+    let Captured {
+        body, size, spill, ..
+    } = captured.lock().unwrap().take().unwrap();
+    assert_eq!(body, "hello sp");
+    let flow = Flow {
+        response_body: body,
+        response_size: size,
+        response_spill: spill,
+        ..Default::default()
+    };
+    assert!(flow.is_response_truncated());
+    let full = flow.full_response_body().unwrap().collect().await.unwrap();
+    assert_eq!(full.to_bytes(), "hello spilled world");
+
+    let path = flow.response_spill.as_ref().unwrap().path.clone();
+    assert!(path.exists());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| path.metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(mode(&config.dir()), 0o700);
+    }
+    drop(flow);
+    assert!(!path.exists());
+    let _ = std::fs::remove_dir_all(config.dir());
+}
+
+#[test]
+fn should_generate_sortable_unique_ids() {
+    let threads: Vec<_> = (0..4)
+        .map(|_| std::thread::spawn(|| (0..1000).map(|_| next_id()).collect::<Vec<_>>()))
+        .collect();
+    let mut all = Vec::new();
+    for thread in threads {
+        let ids = thread.join().unwrap();
+        // monotonic within a thread, even inside the same millisecond
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        all.extend(ids);
+    }
+    let mut texts: Vec<String> = all.iter().map(Ulid::to_string).collect();
+    all.sort();
+    all.dedup();
+    assert_eq!(all.len(), 4000);
+    // the text form sorts the same way, as in exports and file names
+    texts.sort();
+    assert_eq!(texts, all.iter().map(Ulid::to_string).collect::<Vec<_>>());
+}
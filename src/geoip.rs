@@ -0,0 +1,324 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+use tracing::warn;
+
+/// 元数据段的起始标记
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// 搜索树与数据段之间的 16 字节分隔
+const DATA_SEPARATOR: usize = 16;
+
+/// 一个地址的查询结果，字段取决于数据库（Country / City / ASN）
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Geo {
+    pub ip: IpAddr,
+    /// ISO 3166 国家代码
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_org: Option<String>,
+}
+
+/// 加载的路径与结果，加载失败时为 None
+type Loaded = (PathBuf, Option<Arc<Reader>>);
+
+/// 加载过的数据库，路径变化时重新加载
+#[derive(Clone, Default)]
+pub struct GeoIp {
+    inner: Arc<Mutex<Option<Loaded>>>,
+}
+
+impl GeoIp {
+    /// 加载失败时告警一次，之后不再尝试
+    pub fn lookup(&self, path: &Path, ip: IpAddr) -> Option<Geo> {
+        let reader = {
+            let mut cached = self.inner.lock().ok()?;
+            if cached.as_ref().map(|(loaded, _)| loaded.as_path()) != Some(path) {
+                let reader = std::fs::read(path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(Reader::new)
+                    .map_err(|e| warn!(path = %path.display(), "load GeoIP database fail: {e}"))
+                    .ok()
+                    .map(Arc::new);
+                *cached = Some((path.to_owned(), reader));
+            }
+            cached.as_ref()?.1.clone()?
+        };
+        reader.lookup(ip)
+    }
+}
+
+/// MaxMind DB（`.mmdb`）的只读解析，格式见 https://maxmind.github.io/MaxMind-DB/
+pub struct Reader {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    /// 数据段的起始偏移
+    data_start: usize,
+    /// IPv6 树中 IPv4 地址（`::a.b.c.d`）的起始节点
+    ipv4_start: usize,
+    ip_version: u64,
+}
+
+/// 数据段中的值，只保留查询用到的类型
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Uint(u128),
+    Int(i32),
+    Double(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn path(&self, keys: &[&str]) -> Option<&Value> {
+        keys.iter().try_fold(self, |value, key| value.get(key))
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u64> {
+        match self {
+            Value::Uint(n) => u64::try_from(*n).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl Reader {
+    pub fn new(data: Vec<u8>) -> Result<Self> {
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| anyhow!("not a MaxMind DB"))?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = decode(&data[metadata_start..], 0)?;
+        let field = |key| {
+            metadata
+                .get(key)
+                .and_then(Value::as_uint)
+                .ok_or_else(|| anyhow!("metadata has no {key}"))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            bail!("unsupported record size {record_size}");
+        }
+        let data_start = node_count * record_size / 4 + DATA_SEPARATOR;
+        if data_start > marker {
+            bail!("search tree exceeds file");
+        }
+        let mut reader = Self {
+            data,
+            node_count,
+            record_size,
+            data_start,
+            ipv4_start: 0,
+            ip_version,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = reader.record(node, 0)?;
+            }
+            reader.ipv4_start = node;
+        }
+        Ok(reader)
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<Geo> {
+        let record = self.find(ip).ok()??;
+        let str_at = |keys: &[&str]| record.path(keys).and_then(Value::as_str).map(str::to_owned);
+        Some(Geo {
+            ip,
+            country: str_at(&["country", "iso_code"])
+                .or_else(|| str_at(&["registered_country", "iso_code"])),
+            continent: str_at(&["continent", "code"]),
+            asn: record
+                .get("autonomous_system_number")
+                .and_then(Value::as_uint),
+            as_org: str_at(&["autonomous_system_organization"]),
+        })
+    }
+
+    fn find(&self, ip: IpAddr) -> Result<Option<Value>> {
+        let (bits, mut node) = match ip {
+            IpAddr::V4(ip) => (ip.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(_) if self.ip_version == 4 => return Ok(None),
+            IpAddr::V6(ip) => (ip.octets().to_vec(), 0),
+        };
+        for i in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bits[i / 8] >> (7 - i % 8)) & 1;
+            node = self.record(node, bit)?;
+        }
+        if node <= self.node_count {
+            // equal to node_count means no data
+            return Ok(None);
+        }
+        let offset = node - self.node_count - DATA_SEPARATOR;
+        let section = self
+            .data
+            .get(self.data_start..)
+            .ok_or_else(|| anyhow!("no data section"))?;
+        Ok(Some(decode(section, offset)?.0))
+    }
+
+    fn record(&self, node: usize, bit: u8) -> Result<usize> {
+        let size = self.record_size / 4;
+        let at = node * size;
+        let b = self
+            .data
+            .get(at..at + size)
+            .ok_or_else(|| anyhow!("node {node} out of range"))?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |n, &b| n << 8 | b as usize);
+        Ok(match (self.record_size, bit) {
+            (24, 0) => be(&b[..3]),
+            (24, _) => be(&b[3..]),
+            (28, 0) => (b[3] as usize & 0xf0) << 20 | be(&b[..3]),
+            (28, _) => (b[3] as usize & 0x0f) << 24 | be(&b[4..]),
+            (_, 0) => be(&b[..4]),
+            _ => be(&b[4..]),
+        })
+    }
+}
+
+/// 解码 `section` 中 `offset` 处的值，返回值与其后的偏移；指针相对于 `section` 的起始
+fn decode(section: &[u8], offset: usize) -> Result<(Value, usize)> {
+    let eof = || anyhow!("truncated data at {offset}");
+    let bytes = |from: usize, len: usize| section.get(from..from + len).ok_or_else(eof);
+    let be = |bytes: &[u8]| bytes.iter().fold(0u128, |n, &b| n << 8 | b as u128);
+
+    let ctrl = *section.get(offset).ok_or_else(eof)?;
+    let mut at = offset + 1;
+    let mut kind = ctrl >> 5;
+    if kind == 1 {
+        let ss = (ctrl >> 3) & 3;
+        let vvv = (ctrl & 7) as usize;
+        let len = ss as usize + 1;
+        let raw = be(bytes(at, len)?) as usize;
+        let pointer = match ss {
+            0 => vvv << 8 | raw,
+            1 => (vvv << 16 | raw) + 2048,
+            2 => (vvv << 24 | raw) + 526_336,
+            _ => raw,
+        };
+        let (value, _) = decode(section, pointer)?;
+        return Ok((value, at + len));
+    }
+    if kind == 0 {
+        kind = 7 + *section.get(at).ok_or_else(eof)?;
+        at += 1;
+    }
+    let mut size = (ctrl & 0x1f) as usize;
+    if size >= 29 {
+        let len = size - 28;
+        let raw = be(bytes(at, len)?) as usize;
+        at += len;
+        size = match len {
+            1 => 29 + raw,
+            2 => 285 + raw,
+            _ => 65_821 + raw,
+        };
+    }
+    Ok(match kind {
+        2 => {
+            let s = std::str::from_utf8(bytes(at, size)?)?;
+            (Value::String(s.to_owned()), at + size)
+        }
+        3 => {
+            let raw: [u8; 8] = bytes(at, 8)?.try_into()?;
+            (Value::Double(f64::from_be_bytes(raw)), at + 8)
+        }
+        4 => (Value::Bytes(bytes(at, size)?.to_vec()), at + size),
+        5 | 6 | 9 | 10 => (Value::Uint(be(bytes(at, size)?)), at + size),
+        7 => {
+            let mut pairs = Vec::with_capacity(size);
+            for _ in 0..size {
+                let (key, next) = decode(section, at)?;
+                let Value::String(key) = key else {
+                    bail!("map key at {at} is not a string");
+                };
+                let (value, next) = decode(section, next)?;
+                pairs.push((key, value));
+                at = next;
+            }
+            (Value::Map(pairs), at)
+        }
+        8 => (Value::Int(be(bytes(at, size)?) as u32 as i32), at + size),
+        11 => {
+            let mut items = Vec::with_capacity(size);
+            for _ in 0..size {
+                let (value, next) = decode(section, at)?;
+                items.push(value);
+                at = next;
+            }
+            (Value::Array(items), at)
+        }
+        14 => (Value::Bool(size != 0), at),
+        15 => {
+            let raw: [u8; 4] = bytes(at, 4)?.try_into()?;
+            (Value::Double(f32::from_be_bytes(raw) as f64), at + 4)
+        }
+        kind => bail!("unsupported data type {kind} at {offset}"),
+    })
+}
+
+#[test]
+fn should_lookup_country() {
+    // IPv4 tree with 8 nodes mapping 1.0.0.0/8 to {country: {iso_code: "CN"}}
+    let node_count = 8usize;
+    let mut db = Vec::new();
+    for node in 0..node_count {
+        let (left, right) = match node {
+            7 => (node_count, node_count + DATA_SEPARATOR),
+            _ => (node + 1, node_count),
+        };
+        db.extend_from_slice(&(left as u32).to_be_bytes()[1..]);
+        db.extend_from_slice(&(right as u32).to_be_bytes()[1..]);
+    }
+    db.extend_from_slice(&[0; DATA_SEPARATOR]);
+    db.extend_from_slice(b"\xe1\x47country\xe1\x48iso_code\x42CN");
+    db.extend_from_slice(METADATA_MARKER);
+    db.extend_from_slice(
+        b"\xe3\x4anode_count\xc1\x08\x4brecord_size\xa1\x18\x4aip_version\xa1\x04",
+    );
+
+    let reader = Reader::new(db).unwrap();
+    let geo = reader.lookup("1.2.3.4".parse().unwrap()).unwrap();
+    assert_eq!(geo.country.as_deref(), Some("CN"));
+    assert!(reader.lookup("2.2.3.4".parse().unwrap()).is_none());
+    assert!(reader.lookup("::1".parse().unwrap()).is_none());
+
+    // a pointer to the string at offset 3
+    let section = b"\x20\x03\x00\x42CN";
+    assert_eq!(decode(section, 0).unwrap(), (Value::String("CN".into()), 2));
+}
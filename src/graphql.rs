@@ -0,0 +1,219 @@
+use openssl::sha::sha256;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::RedactConfig;
+
+/// 请求中的一个 GraphQL 操作，批量请求有多个
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Operation {
+    /// `query`、`mutation` 或 `subscription`；只有持久化查询的 hash 时为空
+    pub kind: Option<String>,
+    pub name: Option<String>,
+    /// query 文本的 SHA-256；持久化查询（APQ）取客户端给出的 hash
+    pub query_hash: Option<String>,
+    /// 按 `redact.json_fields` 处理过
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variables: Option<Value>,
+}
+
+/// GET 取 URL 参数，POST 取 JSON body（单个或批量），`application/graphql` 的 body 直接是 query；
+/// 截断的 body 解析不出操作
+pub fn operations(query: Option<&str>, body: &[u8], redact: &RedactConfig) -> Vec<Operation> {
+    if let Ok(value) = serde_json::from_slice::<Value>(body) {
+        return match value {
+            Value::Array(values) => values
+                .iter()
+                .filter_map(|value| operation(value, redact))
+                .collect(),
+            value => operation(&value, redact).into_iter().collect(),
+        };
+    }
+    let params: serde_json::Map<String, Value> =
+        form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .map(|(key, value)| {
+                // variables and extensions are JSON-encoded in the URL
+                let value = serde_json::from_str(&value)
+                    .ok()
+                    .filter(|_| key != "query" && key != "operationName")
+                    .unwrap_or(Value::String(value.into_owned()));
+                (key.into_owned(), value)
+            })
+            .collect();
+    if params.contains_key("query") || params.contains_key("extensions") {
+        return operation(&Value::Object(params), redact)
+            .into_iter()
+            .collect();
+    }
+    match std::str::from_utf8(body) {
+        Ok(text) if !text.trim().is_empty() => {
+            operation(&serde_json::json!({ "query": text }), redact)
+                .into_iter()
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn operation(value: &Value, redact: &RedactConfig) -> Option<Operation> {
+    let query = value.get("query").and_then(Value::as_str);
+    let persisted = value
+        .pointer("/extensions/persistedQuery/sha256Hash")
+        .and_then(Value::as_str);
+    if query.is_none() && persisted.is_none() {
+        return None;
+    }
+    let name = value
+        .get("operationName")
+        .and_then(Value::as_str)
+        .map(str::to_owned);
+    let definitions = query.map(definitions).unwrap_or_default();
+    let definition = match &name {
+        Some(name) => definitions
+            .iter()
+            .find(|(_, defined)| defined.as_deref() == Some(name.as_str())),
+        None => definitions.first(),
+    };
+    let variables = value
+        .get("variables")
+        .filter(|variables| !variables.is_null())
+        .map(|variables| {
+            let mut variables = variables.clone();
+            redact.json(&mut variables);
+            variables
+        });
+    Some(Operation {
+        kind: definition.map(|(kind, _)| kind.clone()),
+        name: name.or_else(|| definition.and_then(|(_, name)| name.clone())),
+        query_hash: query
+            .map(|query| hex(&sha256(query.as_bytes())))
+            .or(persisted.map(str::to_owned)),
+        variables,
+    })
+}
+
+/// 文档顶层的操作定义 (类型, 名字)，简写的 `{ ... }` 是匿名 query
+fn definitions(query: &str) -> Vec<(String, Option<String>)> {
+    let bytes = query.as_bytes();
+    let mut definitions = Vec::new();
+    let (mut braces, mut parens, mut i) = (0usize, 0usize, 0);
+    // the next top-level `{` belongs to a named definition or fragment
+    let mut pending = false;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'"' => {
+                let block = bytes[i..].starts_with(b"\"\"\"");
+                i += if block { 3 } else { 1 };
+                while i < bytes.len() {
+                    if block && bytes[i..].starts_with(b"\"\"\"") {
+                        i += 2;
+                        break;
+                    }
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    } else if !block && bytes[i] == b'"' {
+                        break;
+                    }
+                    i += 1;
+                }
+            }
+            b'(' => parens += 1,
+            b')' => parens = parens.saturating_sub(1),
+            b'{' if parens == 0 => {
+                if braces == 0 && !pending {
+                    definitions.push(("query".to_owned(), None));
+                }
+                pending = false;
+                braces += 1;
+            }
+            b'}' if parens == 0 => braces = braces.saturating_sub(1),
+            b if braces == 0 && parens == 0 && is_name_start(b) => {
+                let word = name(bytes, &mut i);
+                match word {
+                    "query" | "mutation" | "subscription" => {
+                        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                            i += 1;
+                        }
+                        let defined = (i < bytes.len() && is_name_start(bytes[i]))
+                            .then(|| name(bytes, &mut i).to_owned());
+                        definitions.push((word.to_owned(), defined));
+                        pending = true;
+                    }
+                    "fragment" => pending = true,
+                    _ => {}
+                }
+                continue;
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    definitions
+}
+
+fn is_name_start(b: u8) -> bool {
+    b == b'_' || b.is_ascii_alphabetic()
+}
+
+/// 从 `i` 读一个名字，`i` 移到名字之后
+fn name<'a>(bytes: &'a [u8], i: &mut usize) -> &'a str {
+    let start = *i;
+    while *i < bytes.len() && (bytes[*i] == b'_' || bytes[*i].is_ascii_alphanumeric()) {
+        *i += 1;
+    }
+    std::str::from_utf8(&bytes[start..*i]).unwrap_or_default()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[test]
+fn should_extract_graphql_operations() {
+    let redact: RedactConfig = serde_json::from_str(r#"{"json_fields":["password"]}"#).unwrap();
+    let query = r#"fragment F on User { id } # query Commented
+mutation Login($input: In = {a: "}"}) { login(input: $input) { ...F } }
+query Me { me { query } }"#;
+    let body = serde_json::json!({
+        "query": query,
+        "operationName": "Me",
+        "variables": { "user": "a", "password": "b" },
+    });
+    let operations = operations(None, body.to_string().as_bytes(), &redact);
+    assert_eq!(
+        operations,
+        [Operation {
+            kind: Some("query".to_owned()),
+            name: Some("Me".to_owned()),
+            query_hash: Some(hex(&sha256(query.as_bytes()))),
+            variables: Some(serde_json::json!({ "user": "a", "password": "[REDACTED]" })),
+        }]
+    );
+    assert_eq!(
+        definitions(query),
+        [
+            ("mutation".to_owned(), Some("Login".to_owned())),
+            ("query".to_owned(), Some("Me".to_owned()))
+        ]
+    );
+
+    let get = self::operations(
+        Some("extensions=%7B%22persistedQuery%22%3A%7B%22sha256Hash%22%3A%22abc%22%7D%7D&operationName=Feed"),
+        b"",
+        &redact,
+    );
+    assert_eq!(get[0].name.as_deref(), Some("Feed"));
+    assert_eq!(get[0].query_hash.as_deref(), Some("abc"));
+    assert_eq!(get[0].kind, None);
+
+    let raw = self::operations(None, b"{ viewer { login } }", &redact);
+    assert_eq!(
+        (raw[0].kind.as_deref(), raw[0].name.as_deref()),
+        (Some("query"), None)
+    );
+}
@@ -0,0 +1,346 @@
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use prost_reflect::{DescriptorPool, DynamicMessage};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::warn;
+
+/// 一次 gRPC 调用，记录在 flow 中
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct GrpcCall {
+    /// 如 `helloworld.Greeter`
+    pub service: String,
+    pub method: String,
+    /// `grpc-status`，流中断时为空
+    pub status: Option<u32>,
+    pub message: Option<String>,
+    pub request_messages: u64,
+    pub response_messages: u64,
+    /// 按 `grpc_descriptors` 解码的消息，被压缩或超过 `flow_body_limit` 的不解码
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub requests: Vec<Value>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub responses: Vec<Value>,
+}
+
+/// `application/grpc`、`application/grpc+proto`、`application/grpc-web` 等；
+/// base64 的 `grpc-web-text` 不解析
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc") && !v.contains("-text"))
+}
+
+/// `/package.Service/Method` -> (`package.Service`, `Method`)
+pub fn method(path: &str) -> Option<(String, String)> {
+    let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+    if service.is_empty() || method.is_empty() || method.contains('/') {
+        return None;
+    }
+    Some((service.to_owned(), method.to_owned()))
+}
+
+/// trailers 中的 `grpc-status` 与 `grpc-message`，只有头部的响应（trailers-only）取响应头
+pub fn status(headers: &HeaderMap) -> (Option<u32>, Option<String>) {
+    let status = headers
+        .get("grpc-status")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    let message = headers
+        .get("grpc-message")
+        .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned());
+    (status, message)
+}
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// 按 `grpc-encoding` 压缩
+    pub compressed: bool,
+    pub payload: Bytes,
+}
+
+/// 按长度前缀拆分 gRPC 消息；超过 `limit` 后只计数
+#[derive(Debug, Default)]
+pub struct Frames {
+    buf: BytesMut,
+    /// 当前被跳过的消息还剩的字节数
+    skip: usize,
+    limit: usize,
+    kept: usize,
+    pub count: u64,
+    pub messages: Vec<Message>,
+    /// grpc-web 在 body 末尾以 0x80 帧发送的 trailers
+    pub trailers: Option<HeaderMap>,
+}
+
+impl Frames {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.skip > 0 {
+                let n = self.skip.min(data.len());
+                self.skip -= n;
+                data = &data[n..];
+                continue;
+            }
+            let need = match self.len() {
+                Some(len) => 5 + len,
+                None => 5,
+            };
+            let take = (need - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            let Some(len) = self.len() else {
+                continue;
+            };
+            let trailer = self.buf[0] & 0x80 != 0;
+            if self.buf.len() == 5 && !trailer && self.kept + len > self.limit {
+                self.count += 1;
+                self.skip = len;
+                self.buf.clear();
+            } else if self.buf.len() == 5 + len {
+                let frame = self.buf.split().freeze();
+                self.frame(frame);
+            }
+        }
+    }
+
+    /// 帧头已完整时的消息长度
+    fn len(&self) -> Option<usize> {
+        let header = self.buf.get(..5)?;
+        Some(u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize)
+    }
+
+    fn frame(&mut self, frame: Bytes) {
+        let flag = frame[0];
+        let payload = frame.slice(5..);
+        if flag & 0x80 != 0 {
+            self.trailers = Some(web_trailers(&payload));
+            return;
+        }
+        self.count += 1;
+        self.kept += payload.len();
+        self.messages.push(Message {
+            compressed: flag & 0x01 != 0,
+            payload,
+        });
+    }
+}
+
+/// `name: value\r\n` 形式的 grpc-web trailers
+fn web_trailers(payload: &[u8]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for line in String::from_utf8_lossy(payload).split("\r\n") {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.trim().to_ascii_lowercase().as_bytes()),
+            HeaderValue::from_str(value.trim()),
+        ) {
+            headers.append(name, value);
+        }
+    }
+    headers
+}
+
+/// 按方法的输入（`request` 为真时）或输出类型解码，找不到方法或解码失败时为空
+pub fn decode(
+    pool: &DescriptorPool,
+    service: &str,
+    method: &str,
+    request: bool,
+    messages: &[Message],
+) -> Vec<Value> {
+    let Some(method) = pool
+        .get_service_by_name(service)
+        .and_then(|service| service.methods().find(|m| m.name() == method))
+    else {
+        return Vec::new();
+    };
+    let descriptor = if request {
+        method.input()
+    } else {
+        method.output()
+    };
+    messages
+        .iter()
+        .filter(|message| !message.compressed)
+        .filter_map(|message| {
+            let message =
+                DynamicMessage::decode(descriptor.clone(), message.payload.clone()).ok()?;
+            serde_json::to_value(&message).ok()
+        })
+        .collect()
+}
+
+/// 加载的路径与结果，加载失败时为 None
+type Loaded = (PathBuf, Option<DescriptorPool>);
+
+/// 加载过的 FileDescriptorSet，路径变化时重新加载
+#[derive(Clone, Default)]
+pub struct Descriptors {
+    inner: Arc<Mutex<Option<Loaded>>>,
+}
+
+impl Descriptors {
+    /// 加载失败时告警一次，之后不再尝试
+    pub fn pool(&self, path: &Path) -> Option<DescriptorPool> {
+        let mut cached = self.inner.lock().ok()?;
+        if cached.as_ref().map(|(loaded, _)| loaded.as_path()) != Some(path) {
+            let pool = std::fs::read(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(DescriptorPool::decode(bytes.as_slice())?))
+                .map_err(|e| warn!(path = %path.display(), "load gRPC descriptors fail: {e}"))
+                .ok();
+            *cached = Some((path.to_owned(), pool));
+        }
+        cached.as_ref()?.1.clone()
+    }
+}
+
+type OnDone = Box<dyn FnOnce(Frames, Option<HeaderMap>) + Send + Sync>;
+
+/// 原样转发 body，结束时交出拆分的消息与 trailers
+pub struct GrpcBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    frames: Frames,
+    trailers: Option<HeaderMap>,
+    on_done: Option<OnDone>,
+}
+
+impl GrpcBody {
+    pub fn new(
+        inner: BoxBody<Bytes, hyper::Error>,
+        limit: usize,
+        on_done: impl FnOnce(Frames, Option<HeaderMap>) + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            inner,
+            frames: Frames::new(limit),
+            trailers: None,
+            on_done: Some(Box::new(on_done)),
+        }
+    }
+
+    fn done(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done(std::mem::take(&mut self.frames), self.trailers.take());
+        }
+    }
+}
+
+impl Drop for GrpcBody {
+    fn drop(&mut self) {
+        self.done();
+    }
+}
+
+impl Body for GrpcBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.frames.push(data);
+                } else if let Some(trailers) = frame.trailers_ref() {
+                    self.trailers = Some(trailers.clone());
+                }
+            }
+            Poll::Ready(None) | Poll::Ready(Some(Err(_))) => self.done(),
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[test]
+fn should_decode_grpc_messages() {
+    use prost_reflect::prost::Message as _;
+    use prost_reflect::prost_types::{
+        field_descriptor_proto::Type, DescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+        FileDescriptorSet, MethodDescriptorProto, ServiceDescriptorProto,
+    };
+
+    let set = FileDescriptorSet {
+        file: vec![FileDescriptorProto {
+            name: Some("hello.proto".to_owned()),
+            package: Some("hello".to_owned()),
+            message_type: vec![DescriptorProto {
+                name: Some("HelloRequest".to_owned()),
+                field: vec![FieldDescriptorProto {
+                    name: Some("name".to_owned()),
+                    json_name: Some("name".to_owned()),
+                    number: Some(1),
+                    r#type: Some(Type::String as i32),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            service: vec![ServiceDescriptorProto {
+                name: Some("Greeter".to_owned()),
+                method: vec![MethodDescriptorProto {
+                    name: Some("SayHello".to_owned()),
+                    input_type: Some(".hello.HelloRequest".to_owned()),
+                    output_type: Some(".hello.HelloRequest".to_owned()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            syntax: Some("proto3".to_owned()),
+            ..Default::default()
+        }],
+    };
+    let pool = DescriptorPool::decode(set.encode_to_vec().as_slice()).unwrap();
+
+    assert_eq!(
+        method("/hello.Greeter/SayHello"),
+        Some(("hello.Greeter".to_owned(), "SayHello".to_owned()))
+    );
+    assert_eq!(method("/health"), None);
+
+    // field 1, length delimited "grpc"; split mid-header
+    let mut body = vec![0, 0, 0, 0, 6, 0x0a, 4, b'g', b'r', b'p', b'c'];
+    body.extend([0, 0, 0, 0, 6, 0x0a, 4, b'l', b'o', b'n', b'g']);
+    body.extend([0x80, 0, 0, 0, 31]);
+    body.extend(b"grpc-status: 5\r\ngrpc-message: x");
+    let mut frames = Frames::new(10);
+    frames.push(&body[..3]);
+    frames.push(&body[3..]);
+    assert_eq!(frames.count, 2);
+    assert_eq!(frames.messages.len(), 1);
+    let (status, message) = status(frames.trailers.as_ref().unwrap());
+    assert_eq!((status, message.as_deref()), (Some(5), Some("x")));
+
+    let decoded = decode(&pool, "hello.Greeter", "SayHello", true, &frames.messages);
+    assert_eq!(decoded, [serde_json::json!({ "name": "grpc" })]);
+    assert!(decode(&pool, "hello.Greeter", "Missing", true, &frames.messages).is_empty());
+}
@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use bytes::{Buf, Bytes};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{HeaderMap, HeaderValue, HOST};
+use hyper::{Request, Response, Uri, Version};
+use motore::Service;
+use quinn::crypto::rustls::{HandshakeData, QuicClientConfig, QuicServerConfig};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, error, info};
+
+use crate::flow::{self, Timings};
+use crate::metrics::Protocol;
+use crate::state::{ClientState, State};
+use crate::util;
+
+type SendRequest = h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>;
+
+/// HTTP/3 的 ALPN
+const ALPN: &[u8] = b"h3";
+
+/// 逐帧转发 body 的缓冲帧数
+const BODY_FRAMES: usize = 4;
+
+/// 连接专用的头，HTTP/3 中不允许出现
+const HOP_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// QUIC 监听端：按 SNI 签发证书，解密后的请求走与正向代理相同的 client 栈，上游仍按 `h3_hosts` 选择协议
+pub async fn serve<C>(state: State, addr: SocketAddr, client: C) -> Result<()>
+where
+    C: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        > + Clone
+        + Sync
+        + Send
+        + 'static,
+{
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(Signer {
+            state: state.clone(),
+        }));
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    let endpoint = quinn::Endpoint::server(config, addr)?;
+    info!("Listening on udp://{addr} (HTTP/3)");
+    while let Some(incoming) = endpoint.accept().await {
+        let (state, client) = (state.clone(), client.clone());
+        tokio::spawn(async move {
+            if let Err(e) = accept(incoming, state, client).await {
+                debug!("h3 connection failed: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn accept<C>(incoming: quinn::Incoming, state: State, client: C) -> Result<()>
+where
+    C: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        > + Clone
+        + Sync
+        + Send
+        + 'static,
+{
+    let conn = incoming.await?;
+    let sni = conn
+        .handshake_data()
+        .and_then(|data| data.downcast::<HandshakeData>().ok())
+        .and_then(|data| data.server_name);
+    let mut conn = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn)).await?;
+    while let Some(resolver) = conn.accept().await? {
+        let (state, client, sni) = (state.clone(), client.clone(), sni.clone());
+        tokio::spawn(async move {
+            let result = async {
+                let (req, stream) = resolver.resolve_request().await?;
+                respond(req, stream, sni, state, client).await
+            };
+            if let Err(e) = result.await {
+                error!("h3 request failed: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn respond<C>(
+    req: Request<()>,
+    stream: h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    sni: Option<String>,
+    state: State,
+    client: C,
+) -> Result<()>
+where
+    C: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        > + Clone
+        + Sync
+        + Send
+        + 'static,
+{
+    let (mut parts, ()) = req.into_parts();
+    let authority = parts
+        .uri
+        .authority()
+        .cloned()
+        .or_else(|| sni.as_deref().and_then(|sni| sni.parse().ok()))
+        .ok_or(anyhow!("request has no authority"))?;
+    let host = authority.host().trim_matches(['[', ']']).to_owned();
+    let port = authority.port_u16().unwrap_or(443);
+
+    let (mut send, mut recv) = stream.split();
+    let (tx, rx) = mpsc::channel(BODY_FRAMES);
+    tokio::spawn(async move {
+        while let Ok(Some(mut data)) = recv.recv_data().await {
+            let data = data.copy_to_bytes(data.remaining());
+            if tx.send(Ok(Frame::data(data))).await.is_err() {
+                return;
+            }
+        }
+        if let Ok(Some(trailers)) = recv.recv_trailers().await {
+            let _ = tx.send(Ok(Frame::trailers(trailers))).await;
+        }
+    });
+
+    // 上游按 HTTP/1.1 发送
+    let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+    parts.uri = path.parse()?;
+    parts.version = Version::HTTP_11;
+    parts
+        .headers
+        .insert(HOST, HeaderValue::from_str(authority.as_str())?);
+    let req = Request::from_parts(parts, StreamBody::new(ReceiverStream::new(rx)).boxed());
+
+    state.protocols().record(&host, Protocol::H3);
+    let mut client_state = ClientState {
+        id: flow::next_id(),
+        addr: format!("{}:{port}", authority.host()),
+        transcript: None,
+        parse: state.is_parse_for(&host),
+        sni: host,
+        is_secure: true,
+        timings: Default::default(),
+        shared: state,
+    };
+    let resp = client.call(&mut client_state, req).await?;
+
+    let (mut parts, mut body) = resp.into_parts();
+    strip_hop_headers(&mut parts.headers);
+    parts.version = Version::HTTP_3;
+    send.send_response(Response::from_parts(parts, ())).await?;
+    while let Some(frame) = body.frame().await {
+        match frame?.into_data() {
+            Ok(data) => send.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    return Ok(send.send_trailers(trailers).await?);
+                }
+            }
+        }
+    }
+    Ok(send.finish().await?)
+}
+
+fn strip_hop_headers(headers: &mut HeaderMap) {
+    for name in HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+/// 按 SNI 用 root CA 签发证书，没有 SNI 时用 `localhost`
+struct Signer {
+    state: State,
+}
+
+impl std::fmt::Debug for Signer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Signer")
+    }
+}
+
+impl ResolvesServerCert for Signer {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let host = hello.server_name().unwrap_or("localhost").to_owned();
+        let signed = self
+            .state
+            .get_signed_cert(host)
+            .inspect_err(|e| error!("sign certificate failed: {e}"))
+            .ok()?;
+        let cert = CertificateDer::from(signed.cert.to_der().ok()?);
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            signed.key.private_key_to_pkcs8().ok()?,
+        ));
+        let key = provider().key_provider.load_private_key(key).ok()?;
+        Some(Arc::new(CertifiedKey::new(vec![cert], key)))
+    }
+}
+
+/// 经 HTTP/3 发送请求，到源站的连接按 `host:port` 复用
+pub async fn send(
+    state: &mut ClientState,
+    req: Request<BoxBody<Bytes, hyper::Error>>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>> {
+    let mut sender = sender(state).await?;
+    let (mut parts, mut body) = req.into_parts();
+    let authority = parts
+        .headers
+        .remove(HOST)
+        .and_then(|host| host.to_str().ok().map(str::to_owned))
+        .unwrap_or_else(|| state.addr.clone());
+    let path = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+    parts.uri = format!("https://{authority}{path}").parse::<Uri>()?;
+    parts.version = Version::HTTP_3;
+    strip_hop_headers(&mut parts.headers);
+
+    let start = Instant::now();
+    let mut stream = sender.send_request(Request::from_parts(parts, ())).await?;
+    let mut trailers = false;
+    while let Some(frame) = body.frame().await {
+        match frame?.into_data() {
+            Ok(data) => stream.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(frame) = frame.into_trailers() {
+                    stream.send_trailers(frame).await?;
+                    trailers = true;
+                }
+            }
+        }
+    }
+    if !trailers {
+        stream.finish().await?;
+    }
+    let resp = stream.recv_response().await?;
+    state.timings.ttfb_ms = Timings::since(start);
+
+    let (tx, rx) = mpsc::channel(BODY_FRAMES);
+    tokio::spawn(async move {
+        loop {
+            match stream.recv_data().await {
+                Ok(Some(mut data)) => {
+                    let data = data.copy_to_bytes(data.remaining());
+                    if tx.send(Ok(Frame::data(data))).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!("h3 response body failed: {e}");
+                    return;
+                }
+            }
+        }
+        if let Ok(Some(trailers)) = stream.recv_trailers().await {
+            let _ = tx.send(Ok(Frame::trailers(trailers))).await;
+        }
+    });
+    let (mut parts, ()) = resp.into_parts();
+    // 返回给 HTTP/1.1 客户端
+    parts.version = Version::HTTP_11;
+    Ok(Response::from_parts(
+        parts,
+        StreamBody::new(ReceiverStream::new(rx)).boxed(),
+    ))
+}
+
+fn pool() -> &'static Mutex<HashMap<String, SendRequest>> {
+    static POOL: OnceLock<Mutex<HashMap<String, SendRequest>>> = OnceLock::new();
+    POOL.get_or_init(Mutex::default)
+}
+
+async fn sender(state: &mut ClientState) -> Result<SendRequest> {
+    if let Some(sender) = pool()
+        .lock()
+        .ok()
+        .and_then(|pool| pool.get(&state.addr).cloned())
+    {
+        return Ok(sender);
+    }
+    let dialer = state.shared.dialer();
+    let addrs = dialer.resolve(&state.addr, &mut state.timings).await?;
+    let addr = *addrs
+        .first()
+        .ok_or(anyhow!("{} has no address", state.addr))?;
+    let start = Instant::now();
+    let connecting = endpoint(addr)?.connect(addr, &state.sni)?;
+    let conn = util::timeout(dialer.config().timeouts.connect_secs, connecting).await??;
+    // QUIC 的握手包含 TLS
+    state.timings.tls_ms = Timings::since(start);
+    let (mut driver, sender) = h3::client::new(h3_quinn::Connection::new(conn)).await?;
+
+    let key = state.addr.clone();
+    tokio::spawn(async move {
+        let e = poll_fn(|cx| driver.poll_close(cx)).await;
+        debug!("h3 connection to {key} closed: {e}");
+        if let Ok(mut pool) = pool().lock() {
+            pool.remove(&key);
+        }
+    });
+    if let Ok(mut pool) = pool().lock() {
+        pool.insert(state.addr.clone(), sender.clone());
+    }
+    Ok(sender)
+}
+
+/// 每个地址族共用一个 UDP socket
+fn endpoint(addr: SocketAddr) -> Result<quinn::Endpoint> {
+    static V4: OnceLock<quinn::Endpoint> = OnceLock::new();
+    static V6: OnceLock<quinn::Endpoint> = OnceLock::new();
+    let (cell, bind) = match addr {
+        SocketAddr::V4(_) => (&V4, "0.0.0.0:0"),
+        SocketAddr::V6(_) => (&V6, "[::]:0"),
+    };
+    if let Some(endpoint) = cell.get() {
+        return Ok(endpoint.clone());
+    }
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NoVerifier(provider())))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let mut endpoint = quinn::Endpoint::client(bind.parse()?)?;
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(tls)?,
+    )));
+    Ok(cell.get_or_init(|| endpoint).clone())
+}
+
+/// 与 TCP 上游一致，不校验上游证书
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[tokio::test]
+async fn should_proxy_http3() {
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config = crate::config::Config {
+        echo_host: "echo.test".to_owned(),
+        host_overrides: [("echo.test".to_owned(), [127, 0, 0, 1].into())].into(),
+        ..Default::default()
+    };
+    let shared = State::new(config).await.unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    tokio::spawn(serve(shared.clone(), addr, crate::client::service()));
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    // the upstream side of one proxy talking to the listener of another
+    let mut state = ClientState {
+        id: flow::next_id(),
+        addr: format!("echo.test:{port}"),
+        sni: "echo.test".to_owned(),
+        is_secure: true,
+        parse: true,
+        transcript: None,
+        timings: Default::default(),
+        shared: shared.clone(),
+    };
+    let req = Request::post(format!("https://echo.test:{port}/echo"))
+        .header(HOST, format!("echo.test:{port}"))
+        .body(util::full("ping"))
+        .unwrap();
+    let resp = send(&mut state, req).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let body = resp.into_body().collect().await.unwrap().to_bytes();
+    let echo: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(echo["method"], "POST");
+    assert_eq!(echo["body"], "ping");
+    assert_eq!(echo["secure"], true);
+    assert_eq!(shared.protocols().snapshot()["echo.test"].h3, 1);
+}
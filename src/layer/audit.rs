@@ -0,0 +1,123 @@
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::{HeaderMap, Request, Response};
+use motore::{layer::Layer, service, Service};
+use tracing::warn;
+
+use crate::state::ClientState;
+
+/// 按 `audit_rules` 检查请求/响应头
+#[derive(Clone)]
+pub struct Audit<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Audit<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        mut req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.shared.config();
+        let rules: Vec<_> = config
+            .audit_rules
+            .iter()
+            .filter(|rule| rule.matches(&state.sni))
+            .collect();
+        if rules.is_empty() {
+            return self.inner.call(state, req).await;
+        }
+
+        for rule in &rules {
+            if let Some(allowed) = &rule.request_headers {
+                audit(
+                    &state.sni,
+                    "request",
+                    req.headers_mut(),
+                    allowed,
+                    rule.strip,
+                );
+            }
+        }
+        let mut resp = self.inner.call(state, req).await?;
+        for rule in &rules {
+            if let Some(allowed) = &rule.response_headers {
+                audit(
+                    &state.sni,
+                    "response",
+                    resp.headers_mut(),
+                    allowed,
+                    rule.strip,
+                );
+            }
+        }
+        Ok(resp)
+    }
+}
+
+fn audit(host: &str, direction: &str, headers: &mut HeaderMap, allowed: &[String], strip: bool) {
+    let outside: Vec<_> = headers
+        .keys()
+        .filter(|name| {
+            !allowed
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(name.as_str()))
+        })
+        .cloned()
+        .collect();
+    if outside.is_empty() {
+        return;
+    }
+    warn!(
+        target: "audit",
+        host,
+        direction,
+        stripped = strip,
+        "headers outside allowlist: {}",
+        outside.iter().map(|n| n.as_str()).collect::<Vec<_>>().join(", ")
+    );
+    if strip {
+        for name in outside {
+            headers.remove(name);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuditLayer;
+
+impl<S> Layer<S> for AuditLayer {
+    type Service = Audit<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Audit { inner }
+    }
+}
+
+#[test]
+fn should_strip_outside_allowlist() {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/json".parse().unwrap());
+    headers.insert("x-device-id", "abc".parse().unwrap());
+    audit(
+        "stats.example.com",
+        "request",
+        &mut headers,
+        &["Content-Type".to_owned()],
+        true,
+    );
+    assert!(headers.contains_key("content-type"));
+    assert!(!headers.contains_key("x-device-id"));
+}
@@ -0,0 +1,69 @@
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::header::AUTHORIZATION;
+use hyper::{Request, Response, StatusCode};
+use motore::{layer::Layer, service, Service};
+use tracing::warn;
+
+use crate::state::ClientState;
+
+/// 按 `auth_rules` 为发往上游的请求附加凭据；flow 与日志记录的是客户端原始的请求头
+#[derive(Clone)]
+pub struct Auth<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Auth<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.shared.config();
+        let rule = config
+            .auth_rules
+            .iter()
+            .find(|rule| rule.matches(&state.sni))
+            .filter(|rule| rule.overwrite || !req.headers().contains_key(AUTHORIZATION));
+        let Some(rule) = rule else {
+            return self.inner.call(state, req).await;
+        };
+        let mut req = req;
+        // forward without credentials, the upstream's 401 tells the rest
+        let tokens = state.shared.auth_tokens().clone();
+        match tokens.authorization(&state.shared, rule).await {
+            Ok(value) => {
+                req.headers_mut().insert(AUTHORIZATION, value);
+            }
+            Err(e) => warn!("auth: {e:#}"),
+        }
+        let resp = self.inner.call(state, req).await?;
+        if resp.status() == StatusCode::UNAUTHORIZED {
+            tokens.invalidate(rule);
+        }
+        Ok(resp)
+    }
+}
+
+#[derive(Clone)]
+pub struct AuthLayer;
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = Auth<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Auth { inner }
+    }
+}
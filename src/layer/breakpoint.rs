@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::header::EXPECT;
+use hyper::{Request, Response, StatusCode};
+use motore::{layer::Layer, service, Service};
+use tracing::info;
+
+use crate::breakpoint::{Decision, Paused, Phase};
+use crate::config::{BreakpointRule, RedactConfig};
+use crate::flow::{self, Flow};
+use crate::sse;
+use crate::state::ClientState;
+use crate::util;
+
+/// 命中 `breakpoints` 时缓冲整个 body 并暂停，等待管理接口放行或丢弃
+#[derive(Clone)]
+pub struct Breakpoint<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Breakpoint<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.shared.config();
+        if !state.parse || config.breakpoints.is_empty() {
+            return self.inner.call(state, req).await;
+        }
+        let timeout = Duration::from_secs(config.breakpoint_timeout_secs);
+        // edits need the real values, not the redacted ones
+        let raw = RedactConfig::default();
+        let mut seen = Flow {
+            addr: state.addr.clone(),
+            host: state.sni.clone(),
+            secure: state.is_secure,
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            request_headers: flow::headers(req.headers(), &raw),
+            ..Default::default()
+        };
+
+        let req = if matches(&config.breakpoints, Phase::Request, &seen) {
+            let (mut parts, body) = req.into_parts();
+            let collected = body.collect().await?;
+            let trailers = collected.trailers().cloned();
+            let mut body = collected.to_bytes();
+            let paused = Paused {
+                id: state.id,
+                phase: Phase::Request,
+                host: state.sni.clone(),
+                method: seen.method.clone(),
+                uri: seen.uri.clone(),
+                status: None,
+                headers: seen.request_headers.clone(),
+                body: body.clone(),
+            };
+            info!("request paused at breakpoint");
+            match state.shared.breakpoints().pause(paused, timeout).await {
+                Decision::Resume(edit) => edit.apply_request(&mut parts, &mut body),
+                Decision::Drop => return Ok(dropped()),
+            }
+            // the client already got its 100 when the body was read
+            parts.headers.remove(EXPECT);
+            Request::from_parts(parts, util::full_with_trailers(body, trailers))
+        } else {
+            req
+        };
+
+        let resp = self.inner.call(state, req).await?;
+        seen.status = Some(resp.status().as_u16());
+        seen.response_headers = flow::headers(resp.headers(), &raw);
+        if !matches(&config.breakpoints, Phase::Response, &seen) {
+            return Ok(resp);
+        }
+        if sse::is_event_stream(resp.headers()) {
+            info!("event stream never ends, not paused at breakpoint");
+            return Ok(resp);
+        }
+        let (mut parts, body) = resp.into_parts();
+        let collected = body.collect().await?;
+        let trailers = collected.trailers().cloned();
+        let mut body = collected.to_bytes();
+        let paused = Paused {
+            id: state.id,
+            phase: Phase::Response,
+            host: state.sni.clone(),
+            method: seen.method,
+            uri: seen.uri,
+            status: seen.status,
+            headers: seen.response_headers,
+            body: body.clone(),
+        };
+        info!("response paused at breakpoint");
+        match state.shared.breakpoints().pause(paused, timeout).await {
+            Decision::Resume(edit) => edit.apply_response(&mut parts, &mut body),
+            Decision::Drop => return Ok(dropped()),
+        }
+        Ok(Response::from_parts(
+            parts,
+            util::full_with_trailers(body, trailers),
+        ))
+    }
+}
+
+fn matches(rules: &[BreakpointRule], phase: Phase, flow: &Flow) -> bool {
+    rules.iter().any(|rule| {
+        let enabled = match phase {
+            Phase::Request => rule.request,
+            Phase::Response => rule.response,
+        };
+        enabled && rule.filter.matches(flow)
+    })
+}
+
+fn dropped() -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(util::full("dropped at breakpoint"));
+    *resp.status_mut() = StatusCode::BAD_GATEWAY;
+    resp
+}
+
+#[derive(Clone)]
+pub struct BreakpointLayer;
+
+impl<S> Layer<S> for BreakpointLayer {
+    type Service = Breakpoint<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Breakpoint { inner }
+    }
+}
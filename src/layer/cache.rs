@@ -0,0 +1,255 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{
+    HeaderName, HeaderValue, AGE, CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RANGE, VARY,
+};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use motore::{layer::Layer, service, Service};
+
+use crate::cache::{self, CacheControl, Entry, Meta};
+use crate::flow;
+use crate::metrics::Metrics;
+use crate::sse;
+use crate::state::ClientState;
+use crate::util;
+
+static X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+/// 304 中保留的头（RFC 9110 15.4.5）
+const NOT_MODIFIED_HEADERS: [HeaderName; 6] =
+    [CACHE_CONTROL, CONTENT_LOCATION, DATE, ETAG, EXPIRES, VARY];
+
+/// 按 RFC 9111 缓存 GET 响应，过期后带条件头向上游验证
+#[derive(Clone)]
+pub struct Cache<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Cache<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        mut req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.shared.config();
+        if !config.cache.enabled {
+            return self.inner.call(state, req).await;
+        }
+        let cache = state.shared.cache().clone();
+        let key = flow::url(&state.addr, state.is_secure, &req.uri().to_string());
+        match *req.method() {
+            Method::GET => {}
+            Method::HEAD | Method::OPTIONS | Method::TRACE => {
+                return self.inner.call(state, req).await;
+            }
+            _ => {
+                let resp = self.inner.call(state, req).await?;
+                if resp.status().is_success() || resp.status().is_redirection() {
+                    cache.remove(&key);
+                }
+                return Ok(resp);
+            }
+        }
+        let req_cc = CacheControl::request(req.headers());
+        if req_cc.no_store || req.headers().contains_key(RANGE) {
+            return self.inner.call(state, req).await;
+        }
+
+        let now = cache::now();
+        let req_headers = req.headers().clone();
+        let cached = cache
+            .get(&key)
+            .await
+            .filter(|entry| entry.meta.matches(&req_headers));
+        if let Some(entry) = &cached {
+            if entry.meta.is_fresh(now, &req_cc) {
+                Metrics::incr(&state.shared.metrics().cache_hits);
+                return Ok(serve(entry, &req_headers, now, "HIT"));
+            }
+        }
+        if req_cc.only_if_cached {
+            return Ok(gateway_timeout("not in cache"));
+        }
+
+        let validating = cached
+            .as_ref()
+            .is_some_and(|entry| entry.meta.conditional(req.headers_mut()));
+        let resp = self.inner.call(state, req).await?;
+        if let Some(mut entry) = cached.filter(|_| validating) {
+            if resp.status() == StatusCode::NOT_MODIFIED {
+                Metrics::incr(&state.shared.metrics().cache_revalidations);
+                entry.meta.refresh(resp.headers(), now);
+                cache.insert(entry.clone());
+                return Ok(serve(&entry, &req_headers, now, "REVALIDATED"));
+            }
+        }
+
+        Metrics::incr(&state.shared.metrics().cache_misses);
+        let (mut parts, body) = resp.into_parts();
+        parts
+            .headers
+            .insert(X_CACHE.clone(), HeaderValue::from_static("MISS"));
+        let Some(meta) = Meta::new(key, &req_headers, parts.status, &parts.headers, now)
+            .filter(|_| !sse::is_event_stream(&parts.headers))
+        else {
+            return Ok(Response::from_parts(parts, body));
+        };
+        // never polled by hyper
+        if body.is_end_stream() {
+            cache.insert(Entry::new(meta, Bytes::new()));
+            return Ok(Response::from_parts(parts, body));
+        }
+        let body = StoreBody {
+            inner: body,
+            buf: BytesMut::new(),
+            limit: config.cache.max_entry_bytes,
+            on_complete: Some(Box::new(move |body| cache.insert(Entry::new(meta, body)))),
+        };
+        Ok(Response::from_parts(parts, body.boxed()))
+    }
+}
+
+/// 新鲜的条目直接返回，客户端的条件请求满足时返回 304
+pub fn serve(
+    entry: &Entry,
+    req_headers: &HeaderMap,
+    now: u64,
+    label: &'static str,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut headers = entry.meta.header_map();
+    headers.insert(AGE, entry.meta.age(now).into());
+    headers.insert(X_CACHE.clone(), HeaderValue::from_static(label));
+    if is_not_modified(&headers, req_headers) {
+        let mut resp = Response::new(util::empty());
+        *resp.status_mut() = StatusCode::NOT_MODIFIED;
+        for name in NOT_MODIFIED_HEADERS.iter().chain([&AGE, &X_CACHE]) {
+            for value in headers.get_all(name) {
+                resp.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        return resp;
+    }
+    let mut resp = Response::new(util::full(entry.body.clone()));
+    *resp.status_mut() = StatusCode::from_u16(entry.meta.status).unwrap_or(StatusCode::OK);
+    *resp.headers_mut() = headers;
+    resp
+}
+
+/// `If-None-Match` 优先，用弱比较；没有时才看 `If-Modified-Since`
+fn is_not_modified(headers: &HeaderMap, req_headers: &HeaderMap) -> bool {
+    let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    if req_headers.contains_key(IF_NONE_MATCH) {
+        let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        return req_headers
+            .get_all(IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|tag| tag.trim() == "*" || weak(tag) == weak(etag));
+    }
+    match (
+        cache::header_time(headers, &LAST_MODIFIED),
+        cache::header_time(req_headers, &IF_MODIFIED_SINCE),
+    ) {
+        (Some(last_modified), Some(since)) => last_modified <= since,
+        _ => false,
+    }
+}
+
+pub fn gateway_timeout<T: Into<Bytes>>(reason: T) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(util::full(reason));
+    *resp.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+    resp
+}
+
+type OnComplete = Box<dyn FnOnce(Bytes) + Send + Sync>;
+
+/// 透传 body，完整读完且不超过 `limit` 时回调；中途出错、被丢弃或带 trailers 则不回调
+struct StoreBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    buf: BytesMut,
+    limit: usize,
+    on_complete: Option<OnComplete>,
+}
+
+impl StoreBody {
+    fn complete(&mut self) {
+        if let Some(on_complete) = self.on_complete.take() {
+            on_complete(self.buf.split().freeze());
+        }
+    }
+}
+
+impl Body for StoreBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    if self.buf.len() + data.len() > self.limit {
+                        self.on_complete = None;
+                        self.buf = BytesMut::new();
+                    } else if self.on_complete.is_some() {
+                        self.buf.extend_from_slice(data);
+                    }
+                } else if frame.is_trailers() {
+                    // entries have no place for trailers, a cached copy would lose them
+                    self.on_complete = None;
+                }
+                // hyper stops polling once the length is reached
+                if self.inner.is_end_stream() {
+                    self.complete();
+                }
+            }
+            Poll::Ready(None) => self.complete(),
+            Poll::Ready(Some(Err(_))) => self.on_complete = None,
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[derive(Clone)]
+pub struct CacheLayer;
+
+impl<S> Layer<S> for CacheLayer {
+    type Service = Cache<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Cache { inner }
+    }
+}
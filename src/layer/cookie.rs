@@ -0,0 +1,54 @@
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::{Request, Response};
+use motore::{layer::Layer, service, Service};
+
+use crate::state::ClientState;
+
+/// `cookie_jar` 开启时把经过的 `Cookie` 与 `Set-Cookie` 记到 cookie jar
+#[derive(Clone)]
+pub struct CookieRecord<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for CookieRecord<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.shared.config();
+        if !state.parse || !config.cookie_jar {
+            return self.inner.call(state, req).await;
+        }
+        let jar = state.shared.cookies().clone();
+        jar.record_sent(state.id, &state.sni, req.headers(), &config.redact);
+        let path = req.uri().path().to_owned();
+        let resp = self.inner.call(state, req).await?;
+        jar.record_set(state.id, &state.sni, &path, resp.headers(), &config.redact);
+        Ok(resp)
+    }
+}
+
+#[derive(Clone)]
+pub struct CookieLayer;
+
+impl<S> Layer<S> for CookieLayer {
+    type Service = CookieRecord<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        CookieRecord { inner }
+    }
+}
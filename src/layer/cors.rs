@@ -0,0 +1,162 @@
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    VARY,
+};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use motore::{layer::Layer, service, Service};
+
+use crate::config::CorsRule;
+use crate::state::ClientState;
+use crate::util;
+
+/// 按 `cors_rules` 放开跨域，供本地前端直接调用线上接口
+#[derive(Clone)]
+pub struct Cors<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Cors<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.shared.config();
+        let origin = req.headers().get(ORIGIN).cloned();
+        let rule = origin.as_ref().and_then(|origin| {
+            let origin = origin.to_str().ok()?;
+            config
+                .cors_rules
+                .iter()
+                .find(|rule| rule.matches(&state.sni) && rule.allows(origin))
+        });
+        let (Some(rule), Some(origin)) = (rule, origin) else {
+            return self.inner.call(state, req).await;
+        };
+
+        if req.method() == Method::OPTIONS
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return Ok(preflight(rule, origin, req.headers()));
+        }
+
+        let mut resp = self.inner.call(state, req).await?;
+        let exposed = if rule.expose_headers.is_empty() {
+            resp.headers()
+                .keys()
+                .map(|name| name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        } else {
+            rule.expose_headers.join(", ")
+        };
+        let headers = resp.headers_mut();
+        allow(rule, origin, headers);
+        if let Ok(exposed) = HeaderValue::from_str(&exposed) {
+            headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+        }
+        Ok(resp)
+    }
+}
+
+fn preflight(
+    rule: &CorsRule,
+    origin: HeaderValue,
+    req_headers: &HeaderMap,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(util::empty());
+    *resp.status_mut() = StatusCode::NO_CONTENT;
+    let headers = resp.headers_mut();
+    allow(rule, origin, headers);
+    let methods = configured(&rule.allow_methods)
+        .or_else(|| req_headers.get(ACCESS_CONTROL_REQUEST_METHOD).cloned());
+    if let Some(methods) = methods {
+        headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+    }
+    let allowed = configured(&rule.allow_headers)
+        .or_else(|| req_headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned());
+    if let Some(allowed) = allowed {
+        headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+    }
+    headers.insert(ACCESS_CONTROL_MAX_AGE, rule.max_age_secs.into());
+    resp
+}
+
+/// 为空时按请求回显
+fn configured(list: &[String]) -> Option<HeaderValue> {
+    if list.is_empty() {
+        return None;
+    }
+    HeaderValue::from_str(&list.join(", ")).ok()
+}
+
+fn allow(rule: &CorsRule, origin: HeaderValue, headers: &mut HeaderMap) {
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    if rule.allow_credentials {
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    } else {
+        headers.remove(ACCESS_CONTROL_ALLOW_CREDENTIALS);
+    }
+    headers.append(VARY, HeaderValue::from_static("Origin"));
+}
+
+#[derive(Clone)]
+pub struct CorsLayer;
+
+impl<S> Layer<S> for CorsLayer {
+    type Service = Cors<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Cors { inner }
+    }
+}
+
+#[test]
+fn should_answer_preflight() {
+    let origin = HeaderValue::from_static("http://localhost:3000");
+    let mut req_headers = HeaderMap::new();
+    req_headers.insert(ACCESS_CONTROL_REQUEST_METHOD, "PUT".parse().unwrap());
+    req_headers.insert(ACCESS_CONTROL_REQUEST_HEADERS, "x-token".parse().unwrap());
+
+    let permissive = CorsRule::default();
+    let resp = preflight(&permissive, origin.clone(), &req_headers);
+    let headers = resp.headers();
+    assert_eq!(
+        headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+        "http://localhost:3000"
+    );
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "PUT");
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "x-token");
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+
+    let configured = CorsRule {
+        allow_methods: ["GET".to_owned(), "POST".to_owned()].to_vec(),
+        allow_headers: ["content-type".to_owned()].to_vec(),
+        allow_credentials: false,
+        ..Default::default()
+    };
+    let resp = preflight(&configured, origin, &req_headers);
+    let headers = resp.headers();
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+    assert_eq!(headers[ACCESS_CONTROL_ALLOW_HEADERS], "content-type");
+    assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_CREDENTIALS));
+}
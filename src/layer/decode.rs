@@ -0,0 +1,204 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder, ZstdDecoder};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, BodyStream, StreamBody};
+use hyper::body::{Body, Frame};
+use hyper::header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH};
+use hyper::{Method, Request, Response, StatusCode};
+use motore::{layer::Layer, service, Service};
+use tokio::io::AsyncRead;
+use tokio_stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::error;
+
+use crate::config::ContentDecoding;
+use crate::state::ClientState;
+
+/// 按 `content_decoding` 解压响应，让 flow 与日志看到明文
+#[derive(Clone)]
+pub struct Decode<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Decode<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let mut req = req;
+        match state.shared.config().content_decoding {
+            ContentDecoding::Off => self.inner.call(state, req).await,
+            ContentDecoding::Identity => {
+                req.headers_mut()
+                    .insert(ACCEPT_ENCODING, HeaderValue::from_static("identity"));
+                self.inner.call(state, req).await
+            }
+            ContentDecoding::Decode => {
+                let head = req.method() == Method::HEAD;
+                let resp = self.inner.call(state, req).await?;
+                if head
+                    || matches!(
+                        resp.status(),
+                        StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+                    )
+                    || resp.body().is_end_stream()
+                {
+                    return Ok(resp);
+                }
+                let Some(coding) = resp
+                    .headers()
+                    .get(CONTENT_ENCODING)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(Coding::parse)
+                else {
+                    return Ok(resp);
+                };
+                let (mut parts, body) = resp.into_parts();
+                parts.headers.remove(CONTENT_ENCODING);
+                parts.headers.remove(CONTENT_LENGTH);
+                Ok(Response::from_parts(parts, decode(body, coding)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+}
+
+impl Coding {
+    /// 只处理单一编码，叠加多层的原样转发
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            "br" => Some(Self::Brotli),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// 流式解压，出错时记录日志并提前结束 body；trailers 在解压后的数据之后原样转发
+pub fn decode(body: BoxBody<Bytes, hyper::Error>, coding: Coding) -> BoxBody<Bytes, hyper::Error> {
+    let trailers = Arc::new(Mutex::new(None));
+    let seen = trailers.clone();
+    let frames = BodyStream::new(body).filter_map(move |frame| match frame {
+        Ok(frame) => match frame.into_data() {
+            Ok(data) => Some(Ok(data)),
+            Err(frame) => {
+                if let (Ok(trailers), Ok(mut seen)) = (frame.into_trailers(), seen.lock()) {
+                    *seen = Some(trailers);
+                }
+                None
+            }
+        },
+        Err(e) => Some(Err(io::Error::other(e))),
+    });
+    let reader = StreamReader::new(frames);
+    // HTTP deflate is zlib-wrapped (RFC 9110 8.4.1.2); multiple members keep reading the
+    // body to its end after the compressed stream, trailers come last
+    let decoder: Box<dyn AsyncRead + Send + Sync + Unpin> = match coding {
+        Coding::Gzip => {
+            let mut decoder = GzipDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Coding::Deflate => {
+            let mut decoder = ZlibDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Coding::Brotli => {
+            let mut decoder = BrotliDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+        Coding::Zstd => {
+            let mut decoder = ZstdDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(decoder)
+        }
+    };
+    let stream = ReaderStream::new(decoder).map_while(move |chunk| match chunk {
+        Ok(chunk) => Some(Ok::<_, hyper::Error>(Frame::data(chunk))),
+        Err(e) => {
+            error!("decode {coding:?} body failed: {e}");
+            None
+        }
+    });
+    let trailers = tokio_stream::once(()).filter_map(move |_| {
+        let trailers = trailers.lock().ok()?.take()?;
+        Some(Ok(Frame::trailers(trailers)))
+    });
+    StreamBody::new(stream.chain(trailers)).boxed()
+}
+
+#[derive(Clone)]
+pub struct DecodeLayer;
+
+impl<S> Layer<S> for DecodeLayer {
+    type Service = Decode<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Decode { inner }
+    }
+}
+
+#[tokio::test]
+async fn should_decode_gzip_body() {
+    use async_compression::tokio::bufread::GzipEncoder;
+    use tokio::io::AsyncReadExt;
+
+    let plain = "hello ".repeat(10_000);
+    let mut gzipped = Vec::new();
+    GzipEncoder::new(plain.as_bytes())
+        .read_to_end(&mut gzipped)
+        .await
+        .unwrap();
+    let decoded = decode(crate::util::full(gzipped), Coding::Gzip)
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    assert_eq!(decoded, plain.as_bytes());
+}
+
+#[tokio::test]
+async fn should_keep_trailers_after_decoding() {
+    use async_compression::tokio::bufread::GzipEncoder;
+    use hyper::header::HeaderMap;
+    use tokio::io::AsyncReadExt;
+
+    let mut gzipped = Vec::new();
+    GzipEncoder::new(&b"hello"[..])
+        .read_to_end(&mut gzipped)
+        .await
+        .unwrap();
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", HeaderValue::from_static("0"));
+    let body = crate::util::full_with_trailers(gzipped.into(), Some(trailers.clone()));
+    let collected = decode(body, Coding::Gzip).collect().await.unwrap();
+    assert_eq!(collected.trailers(), Some(&trailers));
+    assert_eq!(collected.to_bytes(), "hello");
+}
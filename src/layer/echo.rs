@@ -0,0 +1,149 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Request, Response, StatusCode};
+use motore::{layer::Layer, service, Service};
+use rand::RngCore;
+use serde_json::json;
+use tracing::info;
+
+use crate::state::ClientState;
+use crate::util;
+
+/// `/delay/{ms}` 的上限
+const MAX_DELAY_MS: u64 = 60_000;
+
+/// `/bytes/{n}` 的上限
+const MAX_BYTES: usize = 16 * 1024 * 1024;
+
+const INDEX: &str = "\
+GET /echo          the request as JSON, any method
+GET /status/{code} empty response with that status
+GET /delay/{ms}    respond after a delay, at most 60000
+GET /bytes/{n}     n random bytes, at most 16 MiB
+";
+
+/// 发往 `echo_host` 的请求由内置测试源站应答，不连接上游
+#[derive(Clone)]
+pub struct Echo<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Echo<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        if !state.shared.config().is_echo(&state.sni) {
+            return self.inner.call(state, req).await;
+        }
+        info!(path = req.uri().path(), "served by test origin");
+        let path = req.uri().path().to_owned();
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let resp = match segments.as_slice() {
+            [""] => text(StatusCode::OK, INDEX),
+            ["echo"] => echo(state, req).await?,
+            ["status", code] => {
+                match code.parse().ok().and_then(|c| StatusCode::from_u16(c).ok()) {
+                    Some(status) => {
+                        let mut resp = Response::new(util::empty());
+                        *resp.status_mut() = status;
+                        resp
+                    }
+                    None => text(StatusCode::BAD_REQUEST, "invalid status code\n"),
+                }
+            }
+            ["delay", ms] => match ms.parse::<u64>() {
+                Ok(ms) => {
+                    let ms = ms.min(MAX_DELAY_MS);
+                    tokio::time::sleep(Duration::from_millis(ms)).await;
+                    json_response(json!({ "delay_ms": ms }))
+                }
+                Err(_) => text(StatusCode::BAD_REQUEST, "invalid delay\n"),
+            },
+            ["bytes", n] => match n.parse::<usize>() {
+                Ok(n) => {
+                    let mut data = vec![0; n.min(MAX_BYTES)];
+                    rand::thread_rng().fill_bytes(&mut data);
+                    let mut resp = Response::new(util::full(data));
+                    resp.headers_mut()
+                        .insert(CONTENT_TYPE, "application/octet-stream".parse().unwrap());
+                    resp
+                }
+                Err(_) => text(StatusCode::BAD_REQUEST, "invalid length\n"),
+            },
+            _ => text(StatusCode::NOT_FOUND, "not found\n"),
+        };
+        Ok(resp)
+    }
+}
+
+/// 原样返回收到的请求，包括之前各层改写的结果
+async fn echo(
+    state: &ClientState,
+    req: Request<BoxBody<Bytes, hyper::Error>>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let (parts, body) = req.into_parts();
+    let body = body.collect().await?.to_bytes();
+    let headers: Vec<(&str, String)> = parts
+        .headers
+        .iter()
+        .map(|(k, v)| {
+            (
+                k.as_str(),
+                String::from_utf8_lossy(v.as_bytes()).into_owned(),
+            )
+        })
+        .collect();
+    Ok(json_response(json!({
+        "method": parts.method.as_str(),
+        "uri": parts.uri.to_string(),
+        "version": format!("{:?}", parts.version),
+        "host": parts.headers.get(HOST).and_then(|h| h.to_str().ok()).unwrap_or(&state.sni),
+        "secure": state.is_secure,
+        "headers": headers,
+        "body": String::from_utf8_lossy(&body),
+    })))
+}
+
+fn json_response(value: serde_json::Value) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(util::full(value.to_string()));
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    resp
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut resp = Response::new(util::full(body));
+    *resp.status_mut() = status;
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, "text/plain; charset=utf-8".parse().unwrap());
+    resp
+}
+
+#[derive(Clone)]
+pub struct EchoLayer;
+
+impl<S> Layer<S> for EchoLayer {
+    type Service = Echo<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Echo { inner }
+    }
+}
@@ -0,0 +1,67 @@
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::{Request, Response, StatusCode};
+use motore::{layer::Layer, service, Service};
+use tracing::info;
+
+use crate::flow;
+use crate::state::ClientState;
+use crate::util;
+
+/// 开启 `expect` 时记录每个请求，`block` 时拒绝不在允许列表中的请求
+#[derive(Clone)]
+pub struct Expect<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Expect<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let url = flow::url(&state.addr, state.is_secure, &req.uri().to_string());
+        let method = req.method().as_str();
+        if state.shared.expect(method, &url) {
+            return self.inner.call(state, req).await;
+        }
+        info!(method, url, "unexpected request");
+        let block = state
+            .shared
+            .config()
+            .expect
+            .as_ref()
+            .is_some_and(|expect| expect.block);
+        if !block {
+            return self.inner.call(state, req).await;
+        }
+        let mut resp = Response::new(util::full(format!(
+            "unexpected request {method} {url} is not in the expect allowlist"
+        )));
+        *resp.status_mut() = StatusCode::FORBIDDEN;
+        Ok(resp)
+    }
+}
+
+#[derive(Clone)]
+pub struct ExpectLayer;
+
+impl<S> Layer<S> for ExpectLayer {
+    type Service = Expect<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Expect { inner }
+    }
+}
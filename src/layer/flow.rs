@@ -0,0 +1,200 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::{Request, Response};
+use motore::{layer::Layer, service, Service};
+use tracing::{info, Span};
+use ulid::Ulid;
+
+use crate::filter::{self, Filter};
+use crate::flow::{self, CaptureBody, Captured, Flow, FlowStore};
+use crate::graphql;
+use crate::metrics::Metrics;
+use crate::state::ClientState;
+
+/// 把经过的请求记录到 flow store
+#[derive(Clone)]
+pub struct FlowRecord<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for FlowRecord<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        Metrics::incr(&state.shared.metrics().parsed_requests);
+        let flows = state.shared.flows().clone();
+        let config = state.shared.config();
+        let limit = config.flow_body_limit;
+        // redaction needs the whole body, nothing unredacted goes to disk
+        let spill_config = config
+            .flow_spill
+            .clone()
+            .filter(|_| !config.redact.redacts_bodies());
+        let id = state.id;
+        flows.insert(Flow {
+            id,
+            addr: state.addr.clone(),
+            host: state.sni.clone(),
+            secure: state.is_secure,
+            method: req.method().to_string(),
+            uri: config.redact.text(&req.uri().to_string()).into_owned(),
+            request_headers: flow::headers(req.headers(), &config.redact),
+            tls_fingerprint: state.shared.fingerprint(),
+            ..Default::default()
+        });
+
+        let start = Instant::now();
+        let request_size = Arc::new(AtomicU64::new(0));
+        let graphql = config
+            .is_graphql(&state.sni, req.uri().path())
+            .then(|| req.uri().query().map(str::to_owned));
+        let req = {
+            let flows = flows.clone();
+            let config = config.clone();
+            let request_size = request_size.clone();
+            let spill_config = spill_config.clone();
+            let span = Span::current();
+            req.map(move |body| {
+                CaptureBody::new(
+                    body,
+                    limit,
+                    move |Captured {
+                              body: buf,
+                              size,
+                              trailers,
+                              spill,
+                          }| {
+                        request_size.store(size, Ordering::Relaxed);
+                        let operations = graphql
+                            .map(|query| {
+                                graphql::operations(query.as_deref(), &buf, &config.redact)
+                            })
+                            .unwrap_or_default();
+                        for operation in &operations {
+                            span.in_scope(|| {
+                                info!(
+                                    kind = operation.kind,
+                                    query_hash = operation.query_hash,
+                                    variables = operation.variables.as_ref().map(|v| v.to_string()),
+                                    "graphql: {}",
+                                    operation.name.as_deref().unwrap_or("<anonymous>")
+                                )
+                            });
+                        }
+                        flows.update(id, |flow| {
+                            flow.graphql = operations;
+                            if let Some(trailers) = &trailers {
+                                flow.request_trailers = flow::headers(trailers, &config.redact);
+                            }
+                            flow.request_body = config.redact.body(buf);
+                            flow.request_size = size;
+                            flow.request_spill = spill;
+                        })
+                    },
+                )
+                .spill(spill_config.as_ref())
+                .boxed()
+            })
+        };
+
+        match self.inner.call(state, req).await {
+            Ok(resp) => {
+                let timings = state.timings;
+                let shared = state.shared.clone();
+                let host = state.sni.clone();
+                let failed = resp.status().is_server_error();
+                flows.update(id, |flow| {
+                    flow.status = Some(resp.status().as_u16());
+                    flow.timings = timings;
+                    flow.response_headers = flow::headers(resp.headers(), &config.redact);
+                });
+                Ok(resp.map(move |body| {
+                    CaptureBody::new(
+                        body,
+                        limit,
+                        move |Captured {
+                                  body: buf,
+                                  size,
+                                  trailers,
+                                  spill,
+                              }| {
+                            let duration_ms = start.elapsed().as_millis() as u64;
+                            if failed {
+                                shared.traffic().record_error(&host);
+                            } else {
+                                let up = request_size.load(Ordering::Relaxed);
+                                shared.record_traffic(&host, up, size, Some(duration_ms));
+                            }
+                            flows.update(id, |flow| {
+                                if let Some(trailers) = &trailers {
+                                    flow.response_trailers =
+                                        flow::headers(trailers, &config.redact);
+                                }
+                                flow.response_body = config.redact.body(buf);
+                                flow.response_size = size;
+                                flow.response_spill = spill;
+                                flow.duration_ms = Some(duration_ms);
+                                flow.complete = true;
+                            });
+                            discard_unmatched(&flows, id, config.filters.store.as_ref());
+                        },
+                    )
+                    .spill(spill_config.as_ref())
+                    .boxed()
+                }))
+            }
+            Err(e) => {
+                Metrics::incr(&state.shared.metrics().upstream_errors);
+                state.shared.traffic().record_error(&state.sni);
+                flows.update(id, |flow| {
+                    flow.timings = state.timings;
+                    flow.error = Some(e.to_string());
+                    flow.duration_ms = Some(start.elapsed().as_millis() as u64);
+                    flow.complete = true;
+                });
+                discard_unmatched(&flows, id, config.filters.store.as_ref());
+                Err(e)
+            }
+        }
+    }
+}
+
+/// 完整的 flow 才能按 body 与状态码求值，不匹配的在完成后移除
+fn discard_unmatched(flows: &FlowStore, id: Ulid, filter: Option<&Filter>) {
+    if flows
+        .get(id)
+        .is_some_and(|flow| !filter::allows(filter, &flow))
+    {
+        flows.remove(id);
+    }
+}
+
+#[derive(Clone)]
+pub struct FlowLayer;
+
+impl<S> Layer<S> for FlowLayer {
+    type Service = FlowRecord<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        FlowRecord { inner }
+    }
+}
@@ -0,0 +1,139 @@
+use std::net::IpAddr;
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::header::{HeaderName, HeaderValue, FORWARDED, VIA};
+use hyper::{HeaderMap, Request, Response, Version};
+use motore::{layer::Layer, service, Service};
+
+use crate::config::ForwardedPolicy;
+use crate::service::NAME;
+use crate::state::ClientState;
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_REAL_IP: HeaderName = HeaderName::from_static("x-real-ip");
+
+/// 按 `forwarded` 策略处理转发头
+#[derive(Clone)]
+pub struct Forwarded<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Forwarded<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let policy = state.shared.config().forwarded;
+        if policy == ForwardedPolicy::Passthrough {
+            return self.inner.call(state, req).await;
+        }
+        let client = state
+            .shared
+            .connection()
+            .and_then(|conn| conn.peer_addr)
+            .map(|addr| addr.ip());
+        let mut req = req;
+        let version = req.version();
+        rewrite(policy, req.headers_mut(), client, version);
+        let mut resp = self.inner.call(state, req).await?;
+        let version = resp.version();
+        match policy {
+            ForwardedPolicy::Append => append_via(resp.headers_mut(), version),
+            _ => {
+                resp.headers_mut().remove(VIA);
+            }
+        }
+        Ok(resp)
+    }
+}
+
+fn rewrite(
+    policy: ForwardedPolicy,
+    headers: &mut HeaderMap,
+    client: Option<IpAddr>,
+    version: Version,
+) {
+    match policy {
+        ForwardedPolicy::Passthrough => {}
+        ForwardedPolicy::Append => {
+            // unix socket / pipe clients have no address to add
+            if let Some(client) = client {
+                let forwarded_for = match headers.get(&X_FORWARDED_FOR) {
+                    Some(prev) => format!("{}, {client}", String::from_utf8_lossy(prev.as_bytes())),
+                    None => client.to_string(),
+                };
+                if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+                    headers.insert(&X_FORWARDED_FOR, value);
+                }
+            }
+            append_via(headers, version);
+        }
+        ForwardedPolicy::Strip => {
+            headers.remove(&X_FORWARDED_FOR);
+            headers.remove(&X_REAL_IP);
+            headers.remove(FORWARDED);
+            headers.remove(VIA);
+        }
+    }
+}
+
+/// RFC 9110 7.6.3
+fn append_via(headers: &mut HeaderMap, version: Version) {
+    let protocol = match version {
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        _ => "1.1",
+    };
+    if let Ok(value) = HeaderValue::from_str(&format!("{protocol} {NAME}")) {
+        headers.append(VIA, value);
+    }
+}
+
+#[derive(Clone)]
+pub struct ForwardedLayer;
+
+impl<S> Layer<S> for ForwardedLayer {
+    type Service = Forwarded<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Forwarded { inner }
+    }
+}
+
+#[test]
+fn should_rewrite_forwarded_headers() {
+    let client = Some("10.0.0.2".parse().unwrap());
+    let mut headers = HeaderMap::new();
+    headers.insert(&X_FORWARDED_FOR, "10.0.0.1".parse().unwrap());
+    rewrite(
+        ForwardedPolicy::Append,
+        &mut headers,
+        client,
+        Version::HTTP_11,
+    );
+    assert_eq!(headers[&X_FORWARDED_FOR], "10.0.0.1, 10.0.0.2");
+    assert_eq!(headers[VIA], "1.1 http-proxy-server");
+
+    rewrite(
+        ForwardedPolicy::Strip,
+        &mut headers,
+        client,
+        Version::HTTP_11,
+    );
+    assert!(!headers.contains_key(&X_FORWARDED_FOR));
+    assert!(!headers.contains_key(VIA));
+}
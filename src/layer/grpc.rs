@@ -0,0 +1,122 @@
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::{Request, Response};
+use motore::{layer::Layer, service, Service};
+use tracing::{info, Span};
+
+use crate::grpc::{self, GrpcBody, GrpcCall};
+use crate::state::ClientState;
+
+/// 记录 gRPC 调用的方法、状态码与（有描述符时）解码的消息，body 与 trailers 原样转发
+#[derive(Clone)]
+pub struct Grpc<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Grpc<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let Some((service, method)) =
+            grpc::method(req.uri().path()).filter(|_| state.parse && grpc::is_grpc(req.headers()))
+        else {
+            return self.inner.call(state, req).await;
+        };
+        let config = state.shared.config();
+        let limit = config.flow_body_limit;
+        let pool = config
+            .grpc_descriptors
+            .as_ref()
+            .and_then(|path| state.shared.grpc_descriptors().pool(path));
+        let (flows, id) = (state.shared.flows().clone(), state.id);
+        let call = Arc::new(Mutex::new(GrpcCall {
+            service,
+            method,
+            ..Default::default()
+        }));
+
+        let req = {
+            let (pool, flows, call) = (pool.clone(), flows.clone(), call.clone());
+            req.map(move |body| {
+                GrpcBody::new(body, limit, move |frames, _| {
+                    let Ok(mut call) = call.lock() else {
+                        return;
+                    };
+                    call.request_messages = frames.count;
+                    if let Some(pool) = &pool {
+                        call.requests =
+                            grpc::decode(pool, &call.service, &call.method, true, &frames.messages);
+                    }
+                    let call = call.clone();
+                    flows.update(id, |flow| flow.grpc = Some(call));
+                })
+                .boxed()
+            })
+        };
+
+        let resp = self.inner.call(state, req).await?;
+        let head = grpc::status(resp.headers());
+        let span = Span::current();
+        Ok(resp.map(move |body| {
+            GrpcBody::new(body, limit, move |frames, trailers| {
+                let Ok(mut call) = call.lock() else {
+                    return;
+                };
+                call.response_messages = frames.count;
+                if let Some(pool) = &pool {
+                    call.responses =
+                        grpc::decode(pool, &call.service, &call.method, false, &frames.messages);
+                }
+                (call.status, call.message) = match trailers.or(frames.trailers) {
+                    Some(trailers) => grpc::status(&trailers),
+                    None => head,
+                };
+                let _entered = span.enter();
+                info!(
+                    service = call.service,
+                    method = call.method,
+                    status = call.status,
+                    message = call.message,
+                    requests = ?call.requests,
+                    responses = ?call.responses,
+                    "grpc: {}/{} {} -> {} messages",
+                    call.service,
+                    call.method,
+                    call.request_messages,
+                    call.response_messages
+                );
+                let call = call.clone();
+                flows.update(id, |flow| flow.grpc = Some(call));
+            })
+            .boxed()
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcLayer;
+
+impl<S> Layer<S> for GrpcLayer {
+    type Service = Grpc<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Grpc { inner }
+    }
+}
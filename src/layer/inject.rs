@@ -0,0 +1,146 @@
+use bytes::{Bytes, BytesMut};
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::header::{
+    HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_SECURITY_POLICY,
+    CONTENT_TYPE, ETAG,
+};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use motore::{layer::Layer, service, Service};
+use tracing::debug;
+
+use crate::config::InjectPosition;
+use crate::layer::decode::{self, Coding};
+use crate::state::ClientState;
+use crate::util;
+
+static CONTENT_SECURITY_POLICY_REPORT_ONLY: HeaderName =
+    HeaderName::from_static("content-security-policy-report-only");
+
+/// 按 `inject_rules` 向页面插入片段；整个页面先读入内存
+#[derive(Clone)]
+pub struct Inject<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Inject<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.shared.config();
+        let Some(rule) = config
+            .inject_rules
+            .iter()
+            .find(|rule| rule.matches(&state.sni))
+            .filter(|_| req.method() != Method::HEAD)
+        else {
+            return self.inner.call(state, req).await;
+        };
+        let resp = self.inner.call(state, req).await?;
+        if resp.status() != StatusCode::OK || !is_html(resp.headers()) {
+            return Ok(resp);
+        }
+        let encoding = resp
+            .headers()
+            .get(CONTENT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| Coding::parse(v).ok_or(()));
+        let (mut parts, body) = resp.into_parts();
+        let body = match encoding {
+            None => body,
+            Some(Ok(coding)) => decode::decode(body, coding),
+            // layered or unknown codings can't be rewritten
+            Some(Err(())) => return Ok(Response::from_parts(parts, body)),
+        };
+        let collected = body.collect().await?;
+        let trailers = collected.trailers().cloned();
+        let page = inject(&collected.to_bytes(), &rule.snippet, rule.position);
+        debug!(size = page.len(), "injected snippet");
+
+        let headers = &mut parts.headers;
+        headers.remove(CONTENT_ENCODING);
+        // the page no longer matches the upstream's validator
+        headers.remove(ETAG);
+        if rule.strip_csp {
+            headers.remove(CONTENT_SECURITY_POLICY);
+            headers.remove(&CONTENT_SECURITY_POLICY_REPORT_ONLY);
+        }
+        if trailers.is_none() {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(page.len()));
+        } else {
+            headers.remove(CONTENT_LENGTH);
+        }
+        Ok(Response::from_parts(
+            parts,
+            util::full_with_trailers(page, trailers),
+        ))
+    }
+}
+
+fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/html"))
+}
+
+/// 插在第一个 `</head>` 或最后一个 `</body>` 之前，标签不区分大小写
+fn inject(page: &[u8], snippet: &str, position: InjectPosition) -> Bytes {
+    let matches = |tag: &'static [u8]| move |window: &[u8]| window.eq_ignore_ascii_case(tag);
+    let head = || page.windows(6).position(matches(b"</head"));
+    let body = || page.windows(6).rposition(matches(b"</body"));
+    let at = match position {
+        InjectPosition::Head => head().or_else(body),
+        InjectPosition::Body => body().or_else(head),
+    }
+    .unwrap_or(page.len());
+    let mut out = BytesMut::with_capacity(page.len() + snippet.len());
+    out.extend_from_slice(&page[..at]);
+    out.extend_from_slice(snippet.as_bytes());
+    out.extend_from_slice(&page[at..]);
+    out.freeze()
+}
+
+#[derive(Clone)]
+pub struct InjectLayer;
+
+impl<S> Layer<S> for InjectLayer {
+    type Service = Inject<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Inject { inner }
+    }
+}
+
+#[test]
+fn should_inject_snippet() {
+    let snippet = "<script src=\"/eruda.js\"></script>";
+    let page = b"<html><HEAD><title>t</title></HEAD><body>x</body></html>";
+    assert_eq!(
+        &inject(page, snippet, InjectPosition::Head)[..],
+        b"<html><HEAD><title>t</title><script src=\"/eruda.js\"></script></HEAD><body>x</body></html>"
+    );
+    assert_eq!(
+        &inject(page, snippet, InjectPosition::Body)[..],
+        b"<html><HEAD><title>t</title></HEAD><body>x<script src=\"/eruda.js\"></script></body></html>"
+    );
+    assert_eq!(
+        &inject(b"<p>fragment", snippet, InjectPosition::Head)[..],
+        b"<p>fragment<script src=\"/eruda.js\"></script>"
+    );
+}
@@ -1,22 +1,33 @@
+use std::fmt::Write;
+use std::time::Instant;
+
 use bytes::Bytes;
 use http_body_util::combinators::BoxBody;
-use hyper::{body::Incoming as IncomingBody, Request, Response};
+use http_body_util::BodyExt;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::{Request, Response};
 use motore::{layer::Layer, service, Service};
-use tracing::info;
+use tracing::{error, info, info_span, Instrument, Span};
 
+use crate::config::RedactConfig;
+use crate::filter;
+use crate::flow::{self, CaptureBody, Captured, Flow};
+use crate::sse::{self, LogEvents};
 use crate::state::ClientState;
 
+static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
 #[derive(Clone)]
 pub struct Log<S> {
     inner: S,
 }
 
 #[service]
-impl<S> Service<ClientState, Request<IncomingBody>> for Log<S>
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Log<S>
 where
     S: Service<
             ClientState,
-            Request<IncomingBody>,
+            Request<BoxBody<Bytes, hyper::Error>>,
             Response = Response<BoxBody<Bytes, hyper::Error>>,
             Error = hyper::Error,
         >
@@ -27,19 +38,192 @@ where
     async fn call(
         &self,
         state: &mut ClientState,
-        req: Request<IncomingBody>,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-        if state.parse {
-            info!("request: {req:?}");
+        let mut req = req;
+        if state.shared.config().inject_request_id {
+            if let Ok(id) = HeaderValue::from_str(&state.id.to_string()) {
+                req.headers_mut().entry(&X_REQUEST_ID).or_insert(id);
+            }
+        }
+        // every event of this flow carries its id
+        let span = info_span!("flow", id = %state.id);
+        async move {
+            let config = state.shared.config();
+            let method = req.method().clone();
+            let uri = config.redact.text(&req.uri().to_string()).into_owned();
+            let start = Instant::now();
+            let limit = config.log_body_limit;
+            let filter = config.filters.log.as_ref();
+            let mut seen = Flow {
+                addr: state.addr.clone(),
+                host: state.sni.clone(),
+                secure: state.is_secure,
+                method: method.to_string(),
+                uri: uri.clone(),
+                request_headers: flow::headers(req.headers(), &config.redact),
+                ..Default::default()
+            };
+            if state.parse && filter::allows(filter, &seen) {
+                info!(
+                    version = ?req.version(),
+                    headers = ?seen.request_headers,
+                    "request: {method} {uri}"
+                );
+                let (span, config) = (Span::current(), config.clone());
+                req = req.map(move |body| {
+                    CaptureBody::new(
+                        body,
+                        limit,
+                        move |Captured {
+                                  body: buf,
+                                  size,
+                                  trailers,
+                                  ..
+                              }| {
+                            span.in_scope(|| {
+                                log_body("request", &config.redact, buf, size, start);
+                                log_trailers("request", &config.redact, trailers);
+                            })
+                        },
+                    )
+                    .boxed()
+                });
+            }
+            let resp = self.inner.call(state, req).await;
+            let duration_ms = start.elapsed().as_millis() as u64;
+            match &resp {
+                Ok(resp) => {
+                    seen.status = Some(resp.status().as_u16());
+                    seen.response_headers = flow::headers(resp.headers(), &config.redact);
+                }
+                Err(e) => seen.error = Some(e.to_string()),
+            }
+            if !filter::allows(filter, &seen) {
+                return resp;
+            }
+            match &resp {
+                Ok(resp) => info!(
+                    host = %state.sni,
+                    %method,
+                    %uri,
+                    status = resp.status().as_u16(),
+                    duration_ms,
+                    timings = ?state.timings,
+                    "request completed"
+                ),
+                Err(e) => error!(
+                    host = %state.sni,
+                    %method,
+                    %uri,
+                    duration_ms,
+                    timings = ?state.timings,
+                    "request failed: {e}"
+                ),
+            }
+            let resp = resp?;
+            if !state.parse {
+                return Ok(resp);
+            }
+            info!(
+                version = ?resp.version(),
+                headers = ?seen.response_headers,
+                "response: {}",
+                resp.status()
+            );
+            let events = config.log_sse_events && sse::is_event_stream(resp.headers());
+            let span = Span::current();
+            Ok(resp.map(move |body| {
+                let body = if events {
+                    LogEvents::new(body, limit).boxed()
+                } else {
+                    body
+                };
+                CaptureBody::new(
+                    body,
+                    limit,
+                    move |Captured {
+                              body: buf,
+                              size,
+                              trailers,
+                              ..
+                          }| {
+                        span.in_scope(|| {
+                            log_body("response", &config.redact, buf, size, start);
+                            log_trailers("response", &config.redact, trailers);
+                        })
+                    },
+                )
+                .boxed()
+            }))
         }
-        let resp = self.inner.call(state, req).await;
-        if state.parse {
-            info!("response: {resp:?}");
+        .instrument(span)
+        .await
+    }
+}
+
+/// body 结束时记录大小、自请求开始的耗时与预览
+fn log_body(direction: &str, redact: &RedactConfig, buf: Bytes, size: u64, start: Instant) {
+    let elapsed_ms = start.elapsed().as_millis() as u64;
+    if buf.is_empty() {
+        info!(size, elapsed_ms, "{direction} body completed");
+        return;
+    }
+    let truncated = size > buf.len() as u64;
+    let buf = redact.body(buf);
+    info!(
+        size,
+        truncated,
+        elapsed_ms,
+        "{direction} body:\n{}",
+        preview(&buf)
+    );
+}
+
+fn log_trailers(direction: &str, redact: &RedactConfig, trailers: Option<HeaderMap>) {
+    if let Some(trailers) = trailers {
+        info!(trailers = ?flow::headers(&trailers, redact), "{direction} trailers");
+    }
+}
+
+/// 文本原样输出，二进制输出 hexdump
+fn preview(buf: &[u8]) -> String {
+    let text = match std::str::from_utf8(buf) {
+        Ok(text) => Some(text),
+        // cut in the middle of a multi-byte char by the limit
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&buf[..e.valid_up_to()]).ok(),
+        Err(_) => None,
+    };
+    match text {
+        Some(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => {
+            text.to_owned()
         }
-        resp
+        _ => hexdump(buf),
     }
 }
 
+fn hexdump(buf: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in buf.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", i * 16);
+        for byte in line {
+            let _ = write!(out, " {byte:02x}");
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        let _ = writeln!(out, "{:pad$}  |{ascii}|", "", pad = (16 - line.len()) * 3);
+    }
+    out
+}
+
 #[derive(Clone)]
 pub struct LogLayer;
 
@@ -50,3 +234,59 @@ impl<S> Layer<S> for LogLayer {
         Log { inner }
     }
 }
+
+#[test]
+fn should_preview_body() {
+    assert_eq!(preview("héllo\n".as_bytes()), "héllo\n");
+    // truncated inside `é`
+    assert_eq!(preview(&"hé".as_bytes()[..2]), "h");
+    assert_eq!(
+        preview(&[0x89, b'P', b'N', b'G', 0, 1]),
+        format!("00000000  89 50 4e 47 00 01{:32}|.PNG..|\n", "")
+    );
+}
+
+#[tokio::test]
+async fn should_inject_request_id() {
+    use crate::config::Config;
+
+    let echo = |inject_request_id, id: Option<&'static str>| async move {
+        let shared = crate::state::State::new(Config {
+            inject_request_id,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut state = ClientState {
+            id: flow::next_id(),
+            addr: "proxy.test:80".to_owned(),
+            sni: "proxy.test".to_owned(),
+            is_secure: false,
+            parse: true,
+            transcript: None,
+            timings: Default::default(),
+            shared,
+        };
+        let mut req = Request::get("http://proxy.test/echo");
+        if let Some(id) = id {
+            req = req.header(&X_REQUEST_ID, id);
+        }
+        let req = req.body(crate::util::empty()).unwrap();
+        let resp = crate::client::service()
+            .call(&mut state, req)
+            .await
+            .unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let echoed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let sent = echoed["headers"].as_array().unwrap().iter().find_map(|h| {
+            (h[0] == X_REQUEST_ID.as_str()).then(|| h[1].as_str().unwrap().to_owned())
+        });
+        (state.id.to_string(), sent)
+    };
+
+    let (id, sent) = echo(true, None).await;
+    assert_eq!(sent, Some(id));
+    // the client's own id is kept
+    assert_eq!(echo(true, Some("abc")).await.1.as_deref(), Some("abc"));
+    assert_eq!(echo(false, None).await.1, None);
+}
@@ -0,0 +1,227 @@
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::header::{EXPECT, UPGRADE};
+use hyper::{Request, Response};
+use motore::{layer::Layer, service, Service};
+use tracing::{debug, warn};
+use ulid::Ulid;
+
+use crate::client::{self, HttpClient};
+use crate::config::{MirrorRule, RedactConfig};
+use crate::flow::{self, Flow};
+use crate::metrics::Metrics;
+use crate::reverse::Target;
+use crate::state::{ClientState, State};
+use crate::util;
+
+/// 镜像出去的请求带上这个扩展，不再被镜像
+#[derive(Clone, Copy)]
+struct Mirrored;
+
+/// 命中 `mirrors` 时缓冲请求 body，复制一份异步发往镜像上游
+#[derive(Clone)]
+pub struct Mirror<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Mirror<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.shared.config();
+        let skip =
+            req.extensions().get::<Mirrored>().is_some() || req.headers().contains_key(UPGRADE);
+        if !state.parse || config.mirrors.is_empty() || skip {
+            return self.inner.call(state, req).await;
+        }
+        let seen = Flow {
+            addr: state.addr.clone(),
+            host: state.sni.clone(),
+            secure: state.is_secure,
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            request_headers: flow::headers(req.headers(), &RedactConfig::default()),
+            ..Default::default()
+        };
+        let Some(rule) = config
+            .mirrors
+            .iter()
+            .find(|rule| rule.filter.matches(&seen))
+        else {
+            return self.inner.call(state, req).await;
+        };
+        let target = match Target::parse(&rule.upstream, false) {
+            Ok(target) => target,
+            Err(e) => {
+                warn!(upstream = rule.upstream, "invalid mirror upstream: {e}");
+                return self.inner.call(state, req).await;
+            }
+        };
+
+        let (mut parts, body) = req.into_parts();
+        let collected = body.collect().await?;
+        let trailers = collected.trailers().cloned();
+        let body = collected.to_bytes();
+        // the client already got its 100 when the body was read
+        parts.headers.remove(EXPECT);
+        let mut copy = Request::from_parts(
+            parts.clone(),
+            util::full_with_trailers(body.clone(), trailers.clone()),
+        );
+        target.rewrite(&mut copy);
+        copy.extensions_mut().insert(Mirrored);
+        tokio::spawn(mirror(
+            state.id,
+            state.shared.clone(),
+            target,
+            rule.clone(),
+            copy,
+        ));
+
+        self.inner
+            .call(
+                state,
+                Request::from_parts(parts, util::full_with_trailers(body, trailers)),
+            )
+            .await
+    }
+}
+
+/// 响应读完后丢弃；`store` 时经过完整的 client 栈并记录为 flow
+async fn mirror(
+    origin: Ulid,
+    shared: State,
+    target: Target,
+    rule: MirrorRule,
+    req: Request<BoxBody<Bytes, hyper::Error>>,
+) {
+    Metrics::incr(&shared.metrics().mirrored_requests);
+    let mut state = ClientState {
+        id: flow::next_id(),
+        addr: target.addr,
+        sni: target.host,
+        is_secure: target.secure,
+        parse: rule.store,
+        transcript: None,
+        timings: Default::default(),
+        shared: shared.clone(),
+    };
+    let resp = if rule.store {
+        let resp = client::service().call(&mut state, req).await;
+        shared
+            .flows()
+            .update(state.id, |flow| flow.origin = Some(origin));
+        resp
+    } else {
+        HttpClient.call(&mut state, req).await
+    };
+    let drained = match resp {
+        Ok(resp) => resp.into_body().collect().await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    match drained {
+        Ok(()) => debug!(origin = %origin, upstream = rule.upstream, "mirrored"),
+        Err(e) => {
+            Metrics::incr(&shared.metrics().mirror_errors);
+            warn!(origin = %origin, upstream = rule.upstream, "mirror failed: {e}");
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MirrorLayer;
+
+impl<S> Layer<S> for MirrorLayer {
+    type Service = Mirror<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Mirror { inner }
+    }
+}
+
+#[tokio::test]
+async fn should_mirror_request_copy() {
+    use hyper::server::conn::http1::Builder as ServerBuilder;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use tokio::sync::mpsc;
+
+    use crate::config::Config;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mirror_addr = listener.local_addr().unwrap();
+    let (tx, mut rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+            let tx = tx.clone();
+            async move {
+                let (parts, body) = req.into_parts();
+                let body = body.collect().await?.to_bytes();
+                let _ = tx
+                    .send((parts.method, parts.uri.path().to_owned(), body))
+                    .await;
+                Ok::<_, hyper::Error>(Response::new(util::full("mirrored")))
+            }
+        });
+        let _ = ServerBuilder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    let shared = State::new(Config {
+        mirrors: [MirrorRule {
+            filter: Default::default(),
+            upstream: format!("http://{mirror_addr}/copy"),
+            store: true,
+        }]
+        .to_vec(),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let mut state = ClientState {
+        id: flow::next_id(),
+        addr: "proxy.test:80".to_owned(),
+        sni: "proxy.test".to_owned(),
+        is_secure: false,
+        parse: true,
+        transcript: None,
+        timings: Default::default(),
+        shared: shared.clone(),
+    };
+    let req = Request::post("http://proxy.test/echo")
+        .body(util::full("hello"))
+        .unwrap();
+    let resp = client::service().call(&mut state, req).await.unwrap();
+    let echoed = resp.into_body().collect().await.unwrap().to_bytes();
+    let echoed: serde_json::Value = serde_json::from_slice(&echoed).unwrap();
+    assert_eq!(echoed["body"], "hello");
+
+    let (method, path, body) = rx.recv().await.unwrap();
+    assert_eq!((method.as_str(), path.as_str()), ("POST", "/copy/echo"));
+    assert_eq!(body, "hello");
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    let stored = shared
+        .flows()
+        .list()
+        .into_iter()
+        .find(|flow| flow.origin == Some(state.id))
+        .unwrap();
+    assert_eq!(stored.status, Some(200));
+}
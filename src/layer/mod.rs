@@ -1 +1,20 @@
+pub mod audit;
+pub mod auth;
+pub mod breakpoint;
+pub mod cache;
+pub mod cookie;
+pub mod cors;
+pub mod decode;
+pub mod echo;
+pub mod expect;
+pub mod flow;
+pub mod forwarded;
+pub mod grpc;
+pub mod inject;
 pub mod log;
+pub mod mirror;
+pub mod offline;
+pub mod privacy;
+pub mod quota;
+pub mod split;
+pub mod user_agent;
@@ -0,0 +1,208 @@
+use std::fmt::Write;
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::header::{CONTENT_LENGTH, TRANSFER_ENCODING, UPGRADE};
+use hyper::{Request, Response, StatusCode};
+use motore::{layer::Layer, service, Service};
+use tracing::info;
+
+use crate::cache;
+use crate::flow::{self, Flow};
+use crate::layer::cache::{gateway_timeout, serve};
+use crate::replay;
+use crate::state::ClientState;
+
+/// `offline` 时不连接上游：依次使用缓存（不论是否过期）与 flow store 中录下的响应
+#[derive(Clone)]
+pub struct Offline<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Offline<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        if !state.shared.config().offline {
+            return self.inner.call(state, req).await;
+        }
+        let url = flow::url(&state.addr, state.is_secure, &req.uri().to_string());
+        let mut diagnostic = format!(
+            "offline: no cached or recorded response for {} {url}\n",
+            req.method()
+        );
+        if req.headers().contains_key(UPGRADE) {
+            diagnostic.push_str("protocol upgrades need a live upstream\n");
+            return Ok(gateway_timeout(diagnostic));
+        }
+
+        match state.shared.cache().get(&url).await {
+            Some(entry) if entry.meta.matches(req.headers()) => {
+                info!("offline: served from cache");
+                return Ok(serve(&entry, req.headers(), cache::now(), "OFFLINE"));
+            }
+            Some(_) => {
+                diagnostic.push_str("cache: stored variant does not match the Vary headers\n")
+            }
+            None => diagnostic.push_str("cache: miss\n"),
+        }
+
+        let flows: Vec<_> = state
+            .shared
+            .flows()
+            .list()
+            .into_iter()
+            .filter(|flow| flow.method == req.method().as_str() && flow.url() == url)
+            .collect();
+        let recorded = flows
+            .iter()
+            .rev()
+            .filter(|flow| flow.complete && flow.status.is_some())
+            .find_map(recorded_response);
+        if let Some(resp) = recorded {
+            info!("offline: served from recorded flow");
+            return Ok(resp);
+        }
+        let others = flows.iter().filter(|flow| flow.id != state.id).count();
+        let _ = match others {
+            0 => writeln!(diagnostic, "flows: none recorded"),
+            n => writeln!(
+                diagnostic,
+                "flows: {n} recorded, none complete with a full body"
+            ),
+        };
+        Ok(gateway_timeout(diagnostic))
+    }
+}
+
+/// 解码后的 body 与剩下的头一致，长度按保存的 body 重写；
+/// 超出 `flow_body_limit` 的 body 需要完整写入了临时文件
+fn recorded_response(flow: &Flow) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    let body = flow.full_response_body()?;
+    let mut headers = replay::headers(&flow.response_headers).ok()?;
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, flow.response_size.into());
+    let mut resp = Response::new(body);
+    *resp.status_mut() = StatusCode::from_u16(flow.status?).ok()?;
+    *resp.headers_mut() = headers;
+    Some(resp)
+}
+
+#[derive(Clone)]
+pub struct OfflineLayer;
+
+impl<S> Layer<S> for OfflineLayer {
+    type Service = Offline<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Offline { inner }
+    }
+}
+
+#[tokio::test]
+async fn should_serve_cached_and_recorded_offline() {
+    use http_body_util::BodyExt;
+    use hyper::header::CACHE_CONTROL;
+    use hyper::server::conn::http1::Builder as ServerBuilder;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+
+    use crate::config::{CacheConfig, Config};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let origin = tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|req: Request<hyper::body::Incoming>| async move {
+                let cache_control = match req.uri().path() {
+                    "/cached" => "max-age=60",
+                    _ => "no-store",
+                };
+                let mut resp = Response::new(crate::util::full(req.uri().path().to_owned()));
+                resp.headers_mut()
+                    .insert(CACHE_CONTROL, cache_control.parse().unwrap());
+                Ok::<_, hyper::Error>(resp)
+            });
+            tokio::spawn(ServerBuilder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+
+    let config = Config {
+        cache: CacheConfig {
+            enabled: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let shared = crate::state::State::new(config.clone()).await.unwrap();
+    let get = |path: &'static str| {
+        let (shared, addr) = (shared.clone(), addr.clone());
+        async move {
+            let mut state = ClientState {
+                id: flow::next_id(),
+                addr: addr.clone(),
+                sni: "127.0.0.1".to_owned(),
+                is_secure: false,
+                parse: true,
+                transcript: None,
+                timings: Default::default(),
+                shared,
+            };
+            let req = Request::get(format!("http://{addr}{path}"))
+                .body(crate::util::empty())
+                .unwrap();
+            let resp = crate::client::service()
+                .call(&mut state, req)
+                .await
+                .unwrap();
+            let status = resp.status();
+            let x_cache = resp
+                .headers()
+                .get("x-cache")
+                .map(|v| v.to_str().unwrap().to_owned());
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            (status, x_cache, String::from_utf8_lossy(&body).into_owned())
+        }
+    };
+
+    assert_eq!(get("/cached").await.0, StatusCode::OK);
+    assert_eq!(get("/recorded").await.0, StatusCode::OK);
+    // let the flows complete, then take the origin away
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    origin.abort();
+    shared
+        .set_config(Config {
+            offline: true,
+            ..config
+        })
+        .unwrap();
+
+    let (status, x_cache, body) = get("/cached").await;
+    assert_eq!(
+        (status, x_cache.as_deref()),
+        (StatusCode::OK, Some("OFFLINE"))
+    );
+    assert_eq!(body, "/cached");
+    let (status, x_cache, body) = get("/recorded").await;
+    // replays the headers recorded online
+    assert_eq!((status, x_cache.as_deref()), (StatusCode::OK, Some("MISS")));
+    assert_eq!(body, "/recorded");
+    let (status, _, body) = get("/missing").await;
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    assert!(body.contains("cache: miss") && body.contains("flows: none recorded"));
+}
@@ -0,0 +1,165 @@
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::header::REFERER;
+use hyper::{HeaderMap, Request, Response, Uri};
+use motore::{layer::Layer, service, Service};
+
+use crate::config::PrivacyConfig;
+use crate::state::ClientState;
+
+/// 没有 `Sec-CH-` 前缀的旧式 client hints
+const LEGACY_HINTS: [&str; 8] = [
+    "dpr",
+    "width",
+    "viewport-width",
+    "device-memory",
+    "rtt",
+    "downlink",
+    "ect",
+    "save-data",
+];
+
+/// 按 `privacy` 清理发往上游的请求；flow 与日志记录的是客户端原始的请求
+#[derive(Clone)]
+pub struct Privacy<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Privacy<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.shared.config();
+        let privacy = &config.privacy;
+        if !privacy.matches(&state.sni) {
+            return self.inner.call(state, req).await;
+        }
+        let mut req = req;
+        if let Some(uri) = strip_params(req.uri(), &privacy.params) {
+            *req.uri_mut() = uri;
+        }
+        let origin = origin(state.is_secure, &state.sni, &state.addr);
+        strip_headers(privacy, req.headers_mut(), &origin);
+        self.inner.call(state, req).await
+    }
+}
+
+/// 有参数被去掉时返回新的 URI，其余参数保持原样与顺序
+fn strip_params(uri: &Uri, params: &[String]) -> Option<Uri> {
+    let query = uri.query()?;
+    let tracked = |pair: &&str| {
+        let key = pair.split('=').next().unwrap_or_default();
+        params.iter().any(|param| match param.strip_suffix('*') {
+            Some(prefix) => key
+                .get(..prefix.len())
+                .is_some_and(|head| head.eq_ignore_ascii_case(prefix)),
+            None => key.eq_ignore_ascii_case(param),
+        })
+    };
+    let kept: Vec<&str> = query.split('&').filter(|pair| !tracked(pair)).collect();
+    if kept.len() == query.split('&').count() {
+        return None;
+    }
+    let mut path_and_query = uri.path().to_owned();
+    if !kept.is_empty() {
+        path_and_query.push('?');
+        path_and_query.push_str(&kept.join("&"));
+    }
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    Uri::from_parts(parts).ok()
+}
+
+/// 目标的 (scheme, host, port)
+fn origin(secure: bool, host: &str, addr: &str) -> (String, String, u16) {
+    let scheme = if secure { "https" } else { "http" };
+    let port = addr
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+        .unwrap_or(if secure { 443 } else { 80 });
+    (scheme.to_owned(), host.to_ascii_lowercase(), port)
+}
+
+fn strip_headers(privacy: &PrivacyConfig, headers: &mut HeaderMap, target: &(String, String, u16)) {
+    if privacy.strip_referer {
+        let same_origin = headers
+            .get(REFERER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<Uri>().ok())
+            .is_some_and(|referer| {
+                let secure = referer.scheme_str() == Some("https");
+                let host = referer.host().unwrap_or_default();
+                let port = referer.port_u16().unwrap_or(if secure { 443 } else { 80 });
+                origin(secure, host, &format!("{host}:{port}")) == *target
+            });
+        if !same_origin {
+            headers.remove(REFERER);
+        }
+    }
+    if privacy.strip_client_hints {
+        let hints: Vec<_> = headers
+            .keys()
+            .filter(|name| {
+                name.as_str().starts_with("sec-ch-") || LEGACY_HINTS.contains(&name.as_str())
+            })
+            .cloned()
+            .collect();
+        for name in hints {
+            headers.remove(name);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PrivacyLayer;
+
+impl<S> Layer<S> for PrivacyLayer {
+    type Service = Privacy<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Privacy { inner }
+    }
+}
+
+#[test]
+fn should_strip_tracking() {
+    let privacy = PrivacyConfig::default();
+    let uri: Uri = "http://a.com/p?id=1&utm_source=x&UTM_medium=y&fbclid=z&q=%20"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        strip_params(&uri, &privacy.params).unwrap(),
+        "http://a.com/p?id=1&q=%20"
+    );
+    let uri: Uri = "/p?gclid=1".parse().unwrap();
+    assert_eq!(strip_params(&uri, &privacy.params).unwrap(), "/p");
+    let uri: Uri = "/p?id=1".parse().unwrap();
+    assert!(strip_params(&uri, &privacy.params).is_none());
+
+    let target = origin(true, "a.com", "a.com:443");
+    let mut headers = HeaderMap::new();
+    headers.insert(REFERER, "https://a.com/home".parse().unwrap());
+    headers.insert("sec-ch-ua-platform", "\"Linux\"".parse().unwrap());
+    headers.insert("dpr", "2".parse().unwrap());
+    strip_headers(&privacy, &mut headers, &target);
+    assert_eq!(headers[REFERER], "https://a.com/home");
+    assert_eq!(headers.len(), 1);
+
+    headers.insert(REFERER, "https://b.com/".parse().unwrap());
+    strip_headers(&privacy, &mut headers, &target);
+    assert!(headers.is_empty());
+}
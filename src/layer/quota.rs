@@ -0,0 +1,66 @@
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Request, Response, StatusCode};
+use motore::{layer::Layer, service, Service};
+use tracing::info;
+
+use crate::quota;
+use crate::state::ClientState;
+use crate::util;
+
+/// 超出 `quota` 的额度时返回页面，不连接上游，也不计入 flow 与流量
+#[derive(Clone)]
+pub struct Quota<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Quota<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let Some(exceeded) = state.shared.quota_exceeded(&state.sni) else {
+            return self.inner.call(state, req).await;
+        };
+        info!(
+            subject = exceeded.subject,
+            used = exceeded.used,
+            limit = exceeded.limit,
+            "quota exceeded"
+        );
+        let config = state.shared.config();
+        let page = quota::page(config.quota.page.as_deref(), &exceeded);
+        let mut resp = Response::new(util::full(page));
+        *resp.status_mut() = StatusCode::FORBIDDEN;
+        resp.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        Ok(resp)
+    }
+}
+
+#[derive(Clone)]
+pub struct QuotaLayer;
+
+impl<S> Layer<S> for QuotaLayer {
+    type Service = Quota<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Quota { inner }
+    }
+}
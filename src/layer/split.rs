@@ -0,0 +1,169 @@
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::header::{HeaderValue, COOKIE, SET_COOKIE};
+use hyper::{HeaderMap, Request, Response};
+use motore::{layer::Layer, service, Service};
+use rand::Rng;
+use tracing::{info, warn};
+
+use crate::config::{RedactConfig, SplitRule, SplitTarget};
+use crate::flow::{self, Flow};
+use crate::reverse::Target;
+use crate::state::ClientState;
+
+/// 命中 `splits` 时按权重（或 sticky cookie）选一个上游，改写连接目标与 Host
+#[derive(Clone)]
+pub struct Split<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for Split<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        mut req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.shared.config();
+        if !state.parse || config.splits.is_empty() {
+            return self.inner.call(state, req).await;
+        }
+        let seen = Flow {
+            addr: state.addr.clone(),
+            host: state.sni.clone(),
+            secure: state.is_secure,
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            request_headers: flow::headers(req.headers(), &RedactConfig::default()),
+            ..Default::default()
+        };
+        let Some(rule) = config.splits.iter().find(|rule| rule.filter.matches(&seen)) else {
+            return self.inner.call(state, req).await;
+        };
+        let remembered = sticky(rule, req.headers());
+        let Some(index) = remembered.or_else(|| pick(&rule.targets)) else {
+            return self.inner.call(state, req).await;
+        };
+        let chosen = &rule.targets[index];
+        let target = match Target::parse(&chosen.upstream, chosen.preserve_host) {
+            Ok(target) => target,
+            Err(e) => {
+                warn!(upstream = chosen.upstream, "invalid split upstream: {e}");
+                return self.inner.call(state, req).await;
+            }
+        };
+
+        target.rewrite(&mut req);
+        info!(upstream = chosen.upstream, "split");
+        state.addr = target.addr;
+        state.sni = target.host;
+        state.is_secure = target.secure;
+        let uri = config.redact.text(&req.uri().to_string()).into_owned();
+        state.shared.flows().update(state.id, |flow| {
+            flow.addr = state.addr.clone();
+            flow.host = state.sni.clone();
+            flow.secure = state.is_secure;
+            flow.uri = uri;
+        });
+
+        let mut resp = self.inner.call(state, req).await?;
+        if let (Some(cookie), None) = (&rule.sticky_cookie, remembered) {
+            let value = format!("{cookie}={}; Path=/", label(chosen, index));
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                resp.headers_mut().append(SET_COOKIE, value);
+            }
+        }
+        Ok(resp)
+    }
+}
+
+fn label(target: &SplitTarget, index: usize) -> String {
+    if target.name.is_empty() {
+        index.to_string()
+    } else {
+        target.name.clone()
+    }
+}
+
+/// cookie 中记住的上游，已不在 `targets` 中时忽略
+fn sticky(rule: &SplitRule, headers: &HeaderMap) -> Option<usize> {
+    let name = rule.sticky_cookie.as_deref()?;
+    let value = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)?;
+    rule.targets
+        .iter()
+        .enumerate()
+        .position(|(index, target)| label(target, index) == value)
+}
+
+/// 按权重随机选择，权重全为 0 时返回 None
+fn pick(targets: &[SplitTarget]) -> Option<usize> {
+    let total: u64 = targets.iter().map(|target| target.weight as u64).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut roll = rand::thread_rng().gen_range(0..total);
+    targets.iter().position(|target| {
+        let weight = target.weight as u64;
+        if roll < weight {
+            return true;
+        }
+        roll -= weight;
+        false
+    })
+}
+
+#[derive(Clone)]
+pub struct SplitLayer;
+
+impl<S> Layer<S> for SplitLayer {
+    type Service = Split<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        Split { inner }
+    }
+}
+
+#[test]
+fn should_split_by_weight() {
+    let target = |name: &str, weight| SplitTarget {
+        name: name.to_owned(),
+        weight,
+        ..Default::default()
+    };
+    let rule = SplitRule {
+        targets: vec![target("stable", 9), target("", 1), target("off", 0)],
+        sticky_cookie: Some("canary".to_owned()),
+        ..Default::default()
+    };
+    let mut counts = [0; 3];
+    for _ in 0..1000 {
+        counts[pick(&rule.targets).unwrap()] += 1;
+    }
+    assert!(counts[0] > counts[1] && counts[1] > 0 && counts[2] == 0);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(COOKIE, HeaderValue::from_static("a=b; canary=1"));
+    assert_eq!(sticky(&rule, &headers), Some(1));
+    headers.insert(COOKIE, HeaderValue::from_static("canary=stable"));
+    assert_eq!(sticky(&rule, &headers), Some(0));
+    headers.insert(COOKIE, HeaderValue::from_static("canary=gone"));
+    assert_eq!(sticky(&rule, &headers), None);
+}
@@ -0,0 +1,98 @@
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::header::{HeaderValue, USER_AGENT};
+use hyper::{HeaderMap, Request, Response};
+use motore::{layer::Layer, service, Service};
+
+use crate::state::ClientState;
+
+/// 按 `user_agent_rules` 替换 User-Agent；flow 与日志记录的是客户端原始的请求头
+#[derive(Clone)]
+pub struct UserAgent<S> {
+    inner: S,
+}
+
+#[service]
+impl<S> Service<ClientState, Request<BoxBody<Bytes, hyper::Error>>> for UserAgent<S>
+where
+    S: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        >
+        + 'static
+        + Send
+        + Sync,
+{
+    async fn call(
+        &self,
+        state: &mut ClientState,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.shared.config();
+        let value = config
+            .user_agent_rules
+            .iter()
+            .find(|rule| rule.matches(&state.sni))
+            .and_then(|rule| rule.value())
+            .and_then(|value| HeaderValue::from_str(value).ok());
+        let mut req = req;
+        if let Some(value) = value {
+            replace(req.headers_mut(), value);
+        }
+        self.inner.call(state, req).await
+    }
+}
+
+/// `Sec-CH-UA*` 描述的是原来的浏览器，与替换后的 User-Agent 矛盾，一并去掉
+fn replace(headers: &mut HeaderMap, value: HeaderValue) {
+    headers.insert(USER_AGENT, value);
+    let hints: Vec<_> = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with("sec-ch-ua"))
+        .cloned()
+        .collect();
+    for name in hints {
+        headers.remove(name);
+    }
+}
+
+#[derive(Clone)]
+pub struct UserAgentLayer;
+
+impl<S> Layer<S> for UserAgentLayer {
+    type Service = UserAgent<S>;
+
+    fn layer(self, inner: S) -> Self::Service {
+        UserAgent { inner }
+    }
+}
+
+#[test]
+fn should_replace_user_agent() {
+    use crate::config::{UserAgentPreset, UserAgentRule};
+
+    let rule = UserAgentRule {
+        preset: Some(UserAgentPreset::Iphone),
+        ..Default::default()
+    };
+    assert!(rule.matches("m.example.com"));
+    let mut headers = HeaderMap::new();
+    headers.insert(USER_AGENT, "curl/8.0".parse().unwrap());
+    headers.insert("sec-ch-ua-mobile", "?0".parse().unwrap());
+    headers.insert("accept", "*/*".parse().unwrap());
+    let value = HeaderValue::from_str(rule.value().unwrap()).unwrap();
+    replace(&mut headers, value);
+    assert!(headers[USER_AGENT].to_str().unwrap().contains("iPhone"));
+    assert!(!headers.contains_key("sec-ch-ua-mobile"));
+    assert_eq!(headers.len(), 2);
+
+    let custom = UserAgentRule {
+        hosts: ["example.com".to_owned()].to_vec(),
+        user_agent: Some("test/1.0".to_owned()),
+        preset: Some(UserAgentPreset::Chrome),
+    };
+    assert_eq!(custom.value(), Some("test/1.0"));
+    assert!(!custom.matches("example.org"));
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::config::{LimitConfig, ShedMode};
+
+/// 超出后清理不再使用的 host 信号量
+const HOSTS_LIMIT: usize = 1024;
+
+/// 采样常驻内存的间隔
+const MEMORY_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// 达到减载阈值的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overload {
+    Connections(usize),
+    /// MB
+    Memory(u64),
+}
+
+impl fmt::Display for Overload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connections(count) => write!(f, "{count} active connections"),
+            Self::Memory(mb) => write!(f, "{mb} MB resident memory"),
+        }
+    }
+}
+
+/// 并发连接、隧道与请求的上限，修改后重启生效
+pub struct Limits {
+    config: LimitConfig,
+    connections: Option<Arc<Semaphore>>,
+    requests: Option<Arc<Semaphore>>,
+    per_host: Mutex<HashMap<String, Arc<Semaphore>>>,
+    waiting: AtomicUsize,
+    /// 最近一次采样的常驻内存，MB
+    resident_mb: AtomicU64,
+}
+
+/// 持有期间占用并发额度
+pub struct Permit {
+    _global: Option<OwnedSemaphorePermit>,
+    _host: Option<OwnedSemaphorePermit>,
+}
+
+impl Limits {
+    pub fn new(config: &LimitConfig) -> Self {
+        let semaphore = |max: usize| (max > 0).then(|| Arc::new(Semaphore::new(max)));
+        Self {
+            config: config.clone(),
+            connections: semaphore(config.max_connections),
+            requests: semaphore(config.max_requests),
+            per_host: Mutex::default(),
+            waiting: AtomicUsize::new(0),
+            resident_mb: AtomicU64::new(0),
+        }
+    }
+
+    pub fn shed_mode(&self) -> ShedMode {
+        self.config.shed
+    }
+
+    /// 活动连接或常驻内存达到减载阈值时返回原因
+    pub fn overloaded(&self, connections: usize) -> Option<Overload> {
+        let max = self.config.shed_connections;
+        if max > 0 && connections >= max {
+            return Some(Overload::Connections(connections));
+        }
+        let max = self.config.shed_memory_mb;
+        let resident = self.resident_mb.load(Ordering::Relaxed);
+        (max > 0 && resident >= max).then_some(Overload::Memory(resident))
+    }
+
+    /// 配置了 `shed_memory_mb` 时定期采样常驻内存
+    pub fn spawn_sampler(self: &Arc<Self>) {
+        if self.config.shed_memory_mb == 0 {
+            return;
+        }
+        if resident_mb().is_none() {
+            return warn!("shed_memory_mb ignored, resident memory is unavailable");
+        }
+        let limits = self.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(MEMORY_SAMPLE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Some(mb) = resident_mb() {
+                    limits.resident_mb.store(mb, Ordering::Relaxed);
+                }
+            }
+        });
+    }
+
+    /// 客户端连接不排队，超出时直接拒绝
+    pub fn try_connection(&self) -> Option<Permit> {
+        let global = match &self.connections {
+            Some(semaphore) => Some(semaphore.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(Permit {
+            _global: global,
+            _host: None,
+        })
+    }
+
+    /// 一个隧道或请求；排队的数量超过 `max_queue` 时返回空
+    pub async fn acquire(&self, host: &str) -> Option<Permit> {
+        let host_semaphore = self.host_semaphore(host);
+        if let Some(permit) = self.try_acquire(host_semaphore.as_ref()) {
+            return Some(permit);
+        }
+        if self.waiting.fetch_add(1, Ordering::Relaxed) >= self.config.max_queue {
+            self.waiting.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        let permit = self.acquire_wait(host_semaphore).await;
+        self.waiting.fetch_sub(1, Ordering::Relaxed);
+        permit
+    }
+
+    fn try_acquire(&self, host: Option<&Arc<Semaphore>>) -> Option<Permit> {
+        let global = match &self.requests {
+            Some(semaphore) => Some(semaphore.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        let host = match host {
+            Some(semaphore) => Some(semaphore.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(Permit {
+            _global: global,
+            _host: host,
+        })
+    }
+
+    async fn acquire_wait(&self, host: Option<Arc<Semaphore>>) -> Option<Permit> {
+        // per host first so a busy host doesn't hold global slots while waiting
+        let host = match host {
+            Some(semaphore) => Some(semaphore.acquire_owned().await.ok()?),
+            None => None,
+        };
+        let global = match &self.requests {
+            Some(semaphore) => Some(semaphore.clone().acquire_owned().await.ok()?),
+            None => None,
+        };
+        Some(Permit {
+            _global: global,
+            _host: host,
+        })
+    }
+
+    fn host_semaphore(&self, host: &str) -> Option<Arc<Semaphore>> {
+        if self.config.max_per_host == 0 {
+            return None;
+        }
+        let mut map = self.per_host.lock().ok()?;
+        if map.len() >= HOSTS_LIMIT {
+            map.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+        }
+        Some(
+            map.entry(host.to_owned())
+                .or_insert_with(|| Arc::new(Semaphore::new(self.config.max_per_host)))
+                .clone(),
+        )
+    }
+}
+
+/// `/proc/self/status` 中的 VmRSS
+#[cfg(target_os = "linux")]
+fn resident_mb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb / 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_mb() -> Option<u64> {
+    None
+}
+
+#[tokio::test]
+async fn should_limit_per_host() {
+    let limits = Limits::new(&LimitConfig {
+        max_per_host: 1,
+        max_queue: 0,
+        ..LimitConfig::default()
+    });
+    let permit = limits.acquire("a.com").await;
+    assert!(permit.is_some());
+    assert!(limits.acquire("a.com").await.is_none());
+    assert!(limits.acquire("b.com").await.is_some());
+    drop(permit);
+    assert!(limits.acquire("a.com").await.is_some());
+}
+
+#[test]
+fn should_shed_when_overloaded() {
+    let limits = Limits::new(&LimitConfig {
+        shed_connections: 100,
+        shed_memory_mb: 512,
+        ..LimitConfig::default()
+    });
+    assert_eq!(limits.overloaded(99), None);
+    assert_eq!(limits.overloaded(100), Some(Overload::Connections(100)));
+    limits.resident_mb.store(600, Ordering::Relaxed);
+    assert_eq!(limits.overloaded(1), Some(Overload::Memory(600)));
+    assert_eq!(Overload::Memory(600).to_string(), "600 MB resident memory");
+    #[cfg(target_os = "linux")]
+    assert!(resident_mb().is_some_and(|mb| mb > 0));
+
+    assert_eq!(
+        Limits::new(&LimitConfig::default()).overloaded(usize::MAX),
+        None
+    );
+}
@@ -0,0 +1,487 @@
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::Duration;
+
+use anyhow::Result;
+use hyper::server::conn::http1::Builder as ServerBuilder;
+use hyper_util::rt::TokioIo;
+use openssl::ssl::{NameType, SniError, Ssl, SslAcceptor, SslFiletype, SslMethod};
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::TcpSocket;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio_openssl::SslStream;
+use tracing::{error, info, warn};
+
+use crate::adapter::HyperAdapter;
+use crate::client;
+use crate::config::{Config, ListenerMode, ShedMode, SocketConfig};
+use crate::metrics::Metrics;
+use crate::proxy::Proxy;
+use crate::reverse::Reverse;
+use crate::state::State;
+use crate::supervisor;
+use crate::transparent;
+use crate::util;
+
+/// `Any` 用于在隧道中取回底层的 `TcpStream`
+pub trait Io: AsyncRead + AsyncWrite + Unpin + Send + Any {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Any> Io for T {}
+
+/// 客户端连接的来源
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+    /// 每个客户端占用一个管道实例，`server` 为等待下一个客户端的实例
+    #[cfg(windows)]
+    Pipe {
+        name: String,
+        server: NamedPipeServer,
+    },
+}
+
+impl Listener {
+    pub async fn bind_tcp(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self::Tcp(TcpListener::bind(addr).await?))
+    }
+
+    /// 多个 worker 以 SO_REUSEPORT 绑定同一地址，由内核分发连接
+    #[cfg(unix)]
+    pub fn bind_reuseport(addr: SocketAddr) -> io::Result<Self> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        socket.bind(addr)?;
+        Ok(Self::Tcp(socket.listen(1024)?))
+    }
+
+    /// 透明代理的监听端：设置 IP_TRANSPARENT 以接受 TPROXY 的连接，
+    /// 没有 CAP_NET_ADMIN 时只能接受 REDIRECT 的连接
+    #[cfg(target_os = "linux")]
+    pub fn bind_transparent(addr: SocketAddr) -> io::Result<Self> {
+        let socket = socket2::Socket::new(
+            socket2::Domain::for_address(addr),
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )?;
+        socket.set_reuse_address(true)?;
+        if let Err(e) = socket.set_ip_transparent(true) {
+            warn!("IP_TRANSPARENT on {addr} failed, TPROXY unavailable: {e}");
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        Ok(Self::Tcp(TcpListener::from_std(socket.into())?))
+    }
+
+    /// 移除残留的 socket 文件后绑定，路径上是其他文件时报错
+    #[cfg(unix)]
+    pub fn bind_unix(path: PathBuf) -> io::Result<Self> {
+        use std::os::unix::fs::FileTypeExt;
+
+        match std::fs::symlink_metadata(&path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(&path)?,
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Self::Unix(UnixListener::bind(&path)?, path))
+    }
+
+    /// `name` 形如 `\\.\pipe\http-proxy-server`
+    #[cfg(windows)]
+    pub fn bind_pipe(name: String) -> io::Result<Self> {
+        let server = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&name)?;
+        Ok(Self::Pipe { name, server })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Self::Unix(..) => None,
+            #[cfg(windows)]
+            Self::Pipe { .. } => None,
+        }
+    }
+
+    /// 非 TCP 连接没有对端地址
+    pub async fn accept(
+        &mut self,
+        socket: &SocketConfig,
+    ) -> io::Result<(Box<dyn Io>, Option<SocketAddr>)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                util::tune_socket(&stream, socket);
+                Ok((Box::new(stream), Some(peer_addr)))
+            }
+            #[cfg(unix)]
+            Self::Unix(listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), None))
+            }
+            #[cfg(windows)]
+            Self::Pipe { name, server } => {
+                server.connect().await?;
+                let next = ServerOptions::new().create(&*name)?;
+                Ok((Box::new(std::mem::replace(server, next)), None))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => write!(f, "tcp"),
+            },
+            #[cfg(unix)]
+            Self::Unix(_, path) => write!(f, "unix:{}", path.display()),
+            #[cfg(windows)]
+            Self::Pipe { name, .. } => write!(f, "pipe:{name}"),
+        }
+    }
+}
+
+/// accept 循环退出后重启前的等待，避免持续 panic 时空转
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// 过载期间检查是否恢复的间隔
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 看门狗：accept 循环返回或 panic 时在同一监听端上重新启动
+pub async fn supervise(
+    mut listener: Listener,
+    worker: usize,
+    state: State,
+    tls: Option<SslAcceptor>,
+) {
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("Listening on {scheme}://{listener} (worker {worker})");
+    loop {
+        match supervisor::catch(run(&mut listener, worker, &state, &tls)).await {
+            Ok(()) => error!("Accept loop on {listener} exited, restarting"),
+            Err(payload) => error!(
+                "Accept loop on {listener} panicked, restarting: {}",
+                supervisor::message(payload.as_ref())
+            ),
+        }
+        Metrics::incr(&state.metrics().listener_restarts);
+        tokio::time::sleep(RESTART_DELAY).await;
+    }
+}
+
+/// 一个监听端的 accept 循环，同一监听端可有多个 `worker`
+async fn run(listener: &mut Listener, worker: usize, state: &State, tls: &Option<SslAcceptor>) {
+    let label = format!("{listener}#{worker}");
+    loop {
+        if state.limits().shed_mode() == ShedMode::Pause {
+            pause_while_overloaded(state, &label).await;
+        }
+        let accepted = listener.accept(&state.config().socket).await;
+        if accepted.is_ok() {
+            state.accepts().record(&label);
+        }
+        match accepted {
+            Ok((_, Some(peer_addr))) if !state.config().is_client_allowed(peer_addr.ip()) => {
+                Metrics::incr(&state.metrics().denied_connections);
+                warn!("Denied connection from {peer_addr}");
+            }
+            Ok((stream, peer_addr)) => {
+                let Some(permit) = state.limits().try_connection() else {
+                    Metrics::incr(&state.metrics().limited_connections);
+                    warn!("Too many connections, dropping {peer_addr:?}");
+                    continue;
+                };
+                Metrics::incr(&state.metrics().connections_accepted);
+                let guard = state.connections().register(peer_addr);
+                let state = state.with_connection(guard.conn.clone());
+                let tls = tls.clone();
+
+                tokio::task::spawn(async move {
+                    let _guard = guard;
+                    let _permit = permit;
+                    supervisor::contain(&state, serve(stream, state.clone(), tls, peer_addr)).await;
+                });
+            }
+            Err(err) => error!("Failed to accept on {listener}: {err}"),
+        }
+    }
+}
+
+/// 过载期间不再 accept，新连接留在内核的 backlog 中
+async fn pause_while_overloaded(state: &State, label: &str) {
+    let Some(overload) = state.overloaded() else {
+        return;
+    };
+    Metrics::incr(&state.metrics().shed_pauses);
+    warn!("Pausing accept on {label}: {overload}");
+    while state.overloaded().is_some() {
+        tokio::time::sleep(PAUSE_CHECK_INTERVAL).await;
+    }
+    info!("Resuming accept on {label}");
+}
+
+/// 透明代理或（TLS 握手后）交给 hyper
+async fn serve(
+    stream: Box<dyn Io>,
+    state: State,
+    tls: Option<SslAcceptor>,
+    peer_addr: Option<SocketAddr>,
+) {
+    if state.mode() == ListenerMode::Transparent {
+        return transparent::serve(stream, state).await;
+    }
+    match tls {
+        Some(acceptor) => match tls_accept(
+            &acceptor,
+            stream,
+            state.config().timeouts.tls_handshake_secs,
+        )
+        .await
+        {
+            Ok(stream) => {
+                let sni = stream
+                    .ssl()
+                    .servername(NameType::HOST_NAME)
+                    .map(str::to_owned);
+                serve_connection(stream, state, sni).await
+            }
+            Err(e) => error!("TLS handshake with {peer_addr:?} failed: {e}"),
+        },
+        None => serve_connection(stream, state, None).await,
+    }
+}
+
+/// 配置了 `tls_cert_path` 时监听端本身走 TLS（secure web proxy）
+pub fn tls_acceptor(config: &Config) -> Result<Option<SslAcceptor>> {
+    let (Some(cert_path), Some(key_path)) = (&config.tls_cert_path, &config.tls_key_path) else {
+        return Ok(None);
+    };
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_certificate_chain_file(cert_path)?;
+    builder.set_private_key_file(key_path, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    builder.set_alpn_select_callback(|_, client| {
+        openssl::ssl::select_next_proto(b"\x08http/1.1", client)
+            .ok_or(openssl::ssl::AlpnError::NOACK)
+    });
+    Ok(Some(builder.build()))
+}
+
+/// 反向代理的 TLS 监听端：按 SNI 用 root CA 签发证书，没有 SNI 时用 `localhost`
+pub fn sni_acceptor(state: &State) -> Result<SslAcceptor> {
+    let fallback = state.get_signed_cert("localhost".to_owned())?;
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_certificate(&fallback.cert)?;
+    builder.set_private_key(&fallback.key)?;
+    let signer = state.clone();
+    builder.set_servername_callback(move |ssl, _| {
+        let Some(host) = ssl.servername(NameType::HOST_NAME).map(str::to_owned) else {
+            return Ok(());
+        };
+        let signed = signer.get_signed_cert(host).map_err(|e| {
+            error!("sign certificate failed: {e}");
+            SniError::ALERT_FATAL
+        })?;
+        ssl.set_certificate(&signed.cert)
+            .and_then(|_| ssl.set_private_key(&signed.key))
+            .map_err(|_| SniError::ALERT_FATAL)
+    });
+    builder.set_alpn_select_callback(|_, client| {
+        openssl::ssl::select_next_proto(b"\x08http/1.1", client)
+            .ok_or(openssl::ssl::AlpnError::NOACK)
+    });
+    Ok(builder.build())
+}
+
+pub async fn tls_accept<S>(
+    acceptor: &SslAcceptor,
+    stream: S,
+    handshake_secs: u64,
+) -> Result<SslStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = SslStream::new(Ssl::new(acceptor.context())?, stream)?;
+    util::timeout(handshake_secs, Pin::new(&mut stream).accept()).await??;
+    Ok(stream)
+}
+
+/// 在一个已接受的客户端连接上提供代理服务，`sni` 来自监听端的 TLS 握手
+pub async fn serve_connection<S>(stream: S, state: State, sni: Option<String>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let client = client::service();
+    let mut builder = ServerBuilder::new();
+    builder.preserve_header_case(true).title_case_headers(true);
+    // transparent connections never get here, see `run`
+    let served = match state.mode() {
+        ListenerMode::Reverse => {
+            builder
+                .serve_connection(
+                    TokioIo::new(stream),
+                    Reverse::new(client, sni).hyper(|req| (state, req)),
+                )
+                .with_upgrades()
+                .await
+        }
+        _ => {
+            builder
+                .serve_connection(
+                    TokioIo::new(stream),
+                    Proxy::new(client).hyper(|req| (state, req)),
+                )
+                .with_upgrades()
+                .await
+        }
+    };
+    if let Err(err) = served {
+        error!("Failed to serve connection: {err}");
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn should_proxy_over_unix_socket() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let path = std::env::temp_dir().join(format!(
+        "should_proxy_over_unix_{}.sock",
+        std::process::id()
+    ));
+    // left over from a crashed run
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(path.exists());
+    let listener = Listener::bind_unix(path.clone()).unwrap();
+    assert_eq!(listener.to_string(), format!("unix:{}", path.display()));
+    assert!(listener.local_addr().is_none());
+    let state = State::new(Config::default()).await.unwrap();
+    tokio::spawn(supervise(listener, 0, state, None));
+
+    let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+    stream
+        .write_all(b"GET http://proxy.test/status/204 HTTP/1.1\r\nHost: proxy.test\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).await.unwrap();
+    assert!(resp.starts_with("HTTP/1.1 204"), "{resp}");
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn should_apply_listener_auth_and_parse() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use crate::config::ListenerConfig;
+
+    let state = State::new(Config {
+        parse: true,
+        username: Some("user".to_owned()),
+        password: Some("secret".to_owned()),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let open = state.with_listener(ListenerConfig {
+        auth: false,
+        parse: Some(false),
+        ..Default::default()
+    });
+    assert!(state.requires_auth() && state.is_parse());
+    assert!(!open.requires_auth() && !open.is_parse());
+
+    let mut statuses = Vec::new();
+    for state in [state, open] {
+        let listener = Listener::bind_tcp("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(supervise(listener, 0, state, None));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET http://proxy.test/status/204 HTTP/1.1\r\nHost: proxy.test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        statuses.push(resp[9..12].to_owned());
+    }
+    assert_eq!(statuses, ["407", "204"]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn should_count_accepts_per_reuseport_worker() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    let state = State::new(Config::default()).await.unwrap();
+    let first = Listener::bind_reuseport("127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = first.local_addr().unwrap();
+    let second = Listener::bind_reuseport(addr).unwrap();
+    tokio::spawn(supervise(first, 0, state.clone(), None));
+    tokio::spawn(supervise(second, 1, state.clone(), None));
+
+    for _ in 0..16 {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET http://proxy.test/status/204 HTTP/1.1\r\nHost: proxy.test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.unwrap();
+        assert!(resp.starts_with("HTTP/1.1 204"), "{resp}");
+    }
+
+    let accepts = state.accepts().snapshot();
+    assert_eq!(accepts.values().sum::<u64>(), 16);
+    assert!(accepts
+        .keys()
+        .all(|label| *label == format!("{addr}#0") || *label == format!("{addr}#1")));
+}
+
+#[cfg(unix)]
+#[test]
+fn should_not_replace_regular_file_with_socket() {
+    let path = std::env::temp_dir().join(format!(
+        "should_not_replace_regular_file_{}.sock",
+        std::process::id()
+    ));
+    std::fs::write(&path, b"keep").unwrap();
+    let Err(err) = Listener::bind_unix(path.clone()) else {
+        panic!("bound over a regular file");
+    };
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(std::fs::read(&path).unwrap(), b"keep");
+    std::fs::remove_file(&path).unwrap();
+}
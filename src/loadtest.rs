@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use http_body_util::BodyExt;
+use motore::Service;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use ulid::Ulid;
+
+use crate::client::HttpClient;
+use crate::filter::{self, Filter};
+use crate::flow::{self, Flow};
+use crate::replay;
+use crate::reverse::Target;
+use crate::state::{ClientState, State};
+
+/// 最多报告的错误信息条数
+const MAX_ERRORS: usize = 10;
+
+/// 把记录的 flow 按原始间隔重放，直接发往上游，不经过规则也不记录为 flow
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct LoadTest {
+    /// 为空时取所有（命中 `filter` 的）flow
+    pub flows: Vec<Ulid>,
+    pub filter: Option<Filter>,
+    /// 原始间隔的加速倍数，0 表示不等待
+    pub rate: f64,
+    /// 同时进行的请求数
+    pub concurrency: usize,
+    /// 整段会话重复的次数
+    pub iterations: usize,
+    /// 按 host 改发到其他上游，如 `{"api.example.com": "http://127.0.0.1:8080"}`
+    pub remap: HashMap<String, String>,
+}
+
+impl Default for LoadTest {
+    fn default() -> Self {
+        Self {
+            flows: [].to_vec(),
+            filter: None,
+            rate: 1.0,
+            concurrency: 8,
+            iterations: 1,
+            remap: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Summary {
+    pub requests: usize,
+    pub errors: usize,
+    /// TCP 隧道与 body 被截断、无法重建的 flow
+    pub skipped: usize,
+    pub duration_ms: u64,
+    pub requests_per_sec: f64,
+    pub statuses: BTreeMap<u16, usize>,
+    pub latency: Latency,
+    /// 前几条错误
+    pub error_samples: Vec<String>,
+}
+
+/// 从发出请求到读完响应 body，单位毫秒
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Latency {
+    pub min: u64,
+    pub mean: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub max: u64,
+}
+
+impl Latency {
+    fn of(mut samples: Vec<u64>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+        samples.sort_unstable();
+        let at = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+        Self {
+            min: samples[0],
+            mean: samples.iter().sum::<u64>() / samples.len() as u64,
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: samples[samples.len() - 1],
+        }
+    }
+}
+
+/// 一次请求的结果：状态码或错误，以及耗时
+type Sample = (Result<u16, String>, u64);
+
+pub async fn run(state: &State, spec: LoadTest) -> Result<Summary> {
+    if spec.rate < 0.0 || !spec.rate.is_finite() {
+        return Err(anyhow!("rate must be a non-negative number"));
+    }
+    let mut flows = state.flows().list();
+    flows.retain(|flow| {
+        (spec.flows.is_empty() || spec.flows.contains(&flow.id))
+            && filter::allows(spec.filter.as_ref(), flow)
+    });
+    let total = flows.len();
+    flows.retain(|flow| replay::request(flow).is_ok());
+    let skipped = total - flows.len();
+    if flows.is_empty() {
+        return Err(anyhow!("no replayable flows selected"));
+    }
+    let mut remap = HashMap::new();
+    for (host, upstream) in &spec.remap {
+        remap.insert(host.as_str(), Arc::new(Target::parse(upstream, false)?));
+    }
+
+    // ULID 的时间戳即请求开始的时间
+    let first = flows[0].id.timestamp_ms();
+    let span = flows[flows.len() - 1].id.timestamp_ms() - first + 1;
+    let pace = |offset_ms: u64| {
+        if spec.rate == 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(offset_ms).div_f64(spec.rate)
+        }
+    };
+
+    let permits = Arc::new(Semaphore::new(spec.concurrency.max(1)));
+    let mut tasks = JoinSet::new();
+    let start = Instant::now();
+    for iteration in 0..spec.iterations as u64 {
+        for flow in &flows {
+            let offset = iteration * span + flow.id.timestamp_ms() - first;
+            tokio::time::sleep_until((start + pace(offset)).into()).await;
+            let permit = permits.clone().acquire_owned().await?;
+            let target = remap.get(flow.host.as_str()).cloned();
+            let (state, flow) = (state.clone(), flow.clone());
+            tasks.spawn(async move {
+                let sample = send(state, &flow, target.as_deref()).await;
+                drop(permit);
+                sample
+            });
+        }
+    }
+
+    let mut summary = Summary {
+        skipped,
+        ..Default::default()
+    };
+    let mut latencies = Vec::new();
+    while let Some(sample) = tasks.join_next().await {
+        let (result, elapsed) = sample?;
+        summary.requests += 1;
+        latencies.push(elapsed);
+        match result {
+            Ok(status) => *summary.statuses.entry(status).or_default() += 1,
+            Err(e) => {
+                summary.errors += 1;
+                if summary.error_samples.len() < MAX_ERRORS {
+                    summary.error_samples.push(e);
+                }
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    summary.duration_ms = elapsed.as_millis() as u64;
+    summary.requests_per_sec = summary.requests as f64 / elapsed.as_secs_f64().max(0.001);
+    summary.latency = Latency::of(latencies);
+    Ok(summary)
+}
+
+async fn send(state: State, flow: &Flow, target: Option<&Target>) -> Sample {
+    let start = Instant::now();
+    let result = async {
+        let mut req = replay::request(flow).map_err(|e| e.to_string())?;
+        let mut client_state = ClientState {
+            id: flow::next_id(),
+            addr: flow.addr.clone(),
+            sni: flow.host.clone(),
+            is_secure: flow.secure,
+            parse: false,
+            transcript: None,
+            timings: Default::default(),
+            shared: state,
+        };
+        if let Some(target) = target {
+            target.rewrite(&mut req);
+            client_state.addr = target.addr.clone();
+            client_state.sni = target.host.clone();
+            client_state.is_secure = target.secure;
+        }
+        let resp = HttpClient
+            .call(&mut client_state, req)
+            .await
+            .map_err(|e| e.to_string())?;
+        let status = resp.status().as_u16();
+        resp.into_body()
+            .collect()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(status)
+    }
+    .await;
+    (result, start.elapsed().as_millis() as u64)
+}
+
+#[test]
+fn should_summarize_latency() {
+    assert_eq!(Latency::of(vec![]), Latency::default());
+    let latency = Latency::of((1..=100).rev().collect());
+    assert_eq!(
+        latency,
+        Latency {
+            min: 1,
+            mean: 50,
+            p50: 51,
+            p90: 90,
+            p99: 99,
+            max: 100,
+        }
+    );
+}
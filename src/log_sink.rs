@@ -0,0 +1,553 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+
+use anyhow::Result;
+use time::{Date, OffsetDateTime, UtcOffset};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::{LogFileConfig, LogRotation, SyslogConfig, SyslogTransport};
+
+/// 发送线程积压的消息数，满了丢弃新的日志而不是阻塞
+const BACKLOG: usize = 1024;
+/// RFC 5424 的空值
+const NIL: &str = "-";
+
+/// RFC 5424 syslog 输出，格式化后的一行作为 MSG，发送在单独的线程中进行
+#[derive(Clone)]
+pub struct Syslog {
+    tx: SyncSender<String>,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    procid: String,
+}
+
+impl Syslog {
+    pub fn new(config: &SyslogConfig) -> Result<Self> {
+        let mut transport = Transport::connect(config.transport, config.addr())?;
+        let (tx, rx) = sync_channel(BACKLOG);
+        let kind = config.transport;
+        let addr = config.addr().to_owned();
+        std::thread::Builder::new()
+            .name("syslog".to_owned())
+            .spawn(move || transport.run(rx, kind, &addr))?;
+        Ok(Self {
+            tx,
+            facility: config.facility.code(),
+            hostname: config
+                .hostname
+                .clone()
+                .or_else(hostname)
+                .unwrap_or_else(|| NIL.to_owned()),
+            app_name: header_field(&config.app_name),
+            procid: std::process::id().to_string(),
+        })
+    }
+
+    fn message(&self, level: Level, msg: &str) -> String {
+        let now = OffsetDateTime::now_utc();
+        format!(
+            "<{}>1 {:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z {} {} {} - - {}",
+            self.facility * 8 + severity(level),
+            now.year(),
+            now.month() as u8,
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second(),
+            now.millisecond(),
+            self.hostname,
+            self.app_name,
+            self.procid,
+            msg.trim_end()
+        )
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = EventWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        EventWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        EventWriter::new(self, *meta.level())
+    }
+}
+
+/// 缓冲一条事件，drop 时发送
+pub struct EventWriter<'a> {
+    syslog: &'a Syslog,
+    level: Level,
+    buf: Vec<u8>,
+}
+
+impl<'a> EventWriter<'a> {
+    fn new(syslog: &'a Syslog, level: Level) -> Self {
+        Self {
+            syslog,
+            level,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Write for EventWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EventWriter<'_> {
+    fn drop(&mut self) {
+        if self.buf.is_empty() {
+            return;
+        }
+        let message = self
+            .syslog
+            .message(self.level, &String::from_utf8_lossy(&self.buf));
+        // dropped when the sender thread falls behind
+        let _ = self.syslog.tx.try_send(message);
+    }
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    Tcp(Option<TcpStream>),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+impl Transport {
+    fn connect(kind: SyslogTransport, addr: &str) -> io::Result<Self> {
+        Ok(match kind {
+            SyslogTransport::Udp => {
+                let socket = UdpSocket::bind(if addr.starts_with('[') {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                })?;
+                socket.connect(addr)?;
+                Self::Udp(socket)
+            }
+            SyslogTransport::Tcp => Self::Tcp(Some(TcpStream::connect(addr)?)),
+            #[cfg(unix)]
+            SyslogTransport::Unix => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(addr)?;
+                Self::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            SyslogTransport::Unix => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "syslog over UNIX sockets is only available on Unix",
+                ))
+            }
+        })
+    }
+
+    fn run(&mut self, rx: Receiver<String>, kind: SyslogTransport, addr: &str) {
+        for message in rx {
+            if let Err(e) = self.send(&message, addr) {
+                // tracing would feed back into this sink
+                eprintln!("send syslog to {addr} ({kind:?}) failed: {e}");
+            }
+        }
+    }
+
+    fn send(&mut self, message: &str, addr: &str) -> io::Result<()> {
+        match self {
+            Self::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+            Self::Tcp(stream) => {
+                let framed = format!("{} {message}", message.len());
+                if let Some(conn) = stream {
+                    if conn.write_all(framed.as_bytes()).is_ok() {
+                        return Ok(());
+                    }
+                }
+                // reconnect once, the message is dropped if that fails too
+                *stream = None;
+                let mut conn = TcpStream::connect(addr)?;
+                conn.write_all(framed.as_bytes())?;
+                *stream = Some(conn);
+                Ok(())
+            }
+            #[cfg(unix)]
+            Self::Unix(socket) => socket.send(message.as_bytes()).map(|_| ()),
+        }
+    }
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+/// HOSTNAME / APP-NAME 只允许可打印 ASCII，不能有空格
+fn header_field(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(48)
+        .collect();
+    if value.is_empty() {
+        NIL.to_owned()
+    } else {
+        value
+    }
+}
+
+fn hostname() -> Option<String> {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|name| header_field(name.trim()))
+        .filter(|name| name != NIL)
+}
+
+/// 按 `log_file` 轮转的日志文件，经 `non_blocking` 在单独的线程中写入
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    max_bytes: u64,
+    keep: usize,
+    offset: UtcOffset,
+    file: File,
+    size: u64,
+    /// 当前文件开始写入的本地日期
+    date: Date,
+}
+
+impl RotatingFile {
+    pub fn open(config: &LogFileConfig, offset: UtcOffset) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let metadata = file.metadata()?;
+        // an existing file continues from the day it was last written
+        let modified = metadata
+            .modified()
+            .map(OffsetDateTime::from)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc());
+        let rotating = Self {
+            path: config.path.clone(),
+            rotation: config.rotation,
+            max_bytes: config.max_size_mb.max(1) * 1024 * 1024,
+            keep: config.keep,
+            offset,
+            file,
+            size: metadata.len(),
+            date: modified.to_offset(offset).date(),
+        };
+        rotating.prune();
+        Ok(rotating)
+    }
+
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc().to_offset(self.offset)
+    }
+
+    fn should_rotate(&self, incoming: usize) -> bool {
+        match self.rotation {
+            LogRotation::Never => false,
+            LogRotation::Daily => self.size > 0 && self.now().date() != self.date,
+            LogRotation::Size => self.size > 0 && self.size + incoming as u64 > self.max_bytes,
+        }
+    }
+
+    /// 当前文件改名为 `<path>.<日期或时间>`，重新创建 `<path>`
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let now = self.now();
+        let stamp = match self.rotation {
+            LogRotation::Daily => self.date.to_string(),
+            _ => format!(
+                "{}-{:02}{:02}{:02}",
+                now.date(),
+                now.hour(),
+                now.minute(),
+                now.second()
+            ),
+        };
+        let mut target = rotated_name(&self.path, &stamp);
+        let mut n = 1;
+        while target.exists() {
+            target = rotated_name(&self.path, &format!("{stamp}.{n}"));
+            n += 1;
+        }
+        std::fs::rename(&self.path, &target)?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.date = now.date();
+        self.prune();
+        Ok(())
+    }
+
+    /// 删除超出 `keep` 的最旧的轮转文件
+    fn prune(&self) {
+        if self.keep == 0 {
+            return;
+        }
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let Some(name) = self.path.file_name().and_then(|name| name.to_str()) else {
+            return;
+        };
+        let prefix = format!("{name}.");
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        let mut rotated: Vec<_> = entries
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+            .collect();
+        rotated.sort_by(|a, b| b.cmp(a));
+        for (_, path) in rotated.into_iter().skip(self.keep) {
+            if let Err(e) = std::fs::remove_file(&path) {
+                eprintln!("remove old log {} failed: {e}", path.display());
+            }
+        }
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_rotate(buf.len()) {
+            // keep logging to the current file if the rename fails
+            if let Err(e) = self.rotate() {
+                eprintln!("rotate {} failed: {e}", self.path.display());
+            }
+        }
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn rotated_name(path: &Path, stamp: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{stamp}"));
+    PathBuf::from(name)
+}
+
+/// Windows 事件日志输出，未启用 `eventlog` feature 或不是 Windows 时返回错误
+pub use imp::EventLog;
+
+#[cfg(all(windows, feature = "eventlog"))]
+mod imp {
+    use std::ffi::c_void;
+    use std::io::{self, Write};
+
+    use anyhow::{bail, Result};
+    use tracing::{Level, Metadata};
+    use tracing_subscriber::fmt::MakeWriter;
+    use windows_sys::Win32::System::EventLog::{
+        DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+        EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+    };
+
+    use crate::config::EventLogConfig;
+
+    pub struct EventLog {
+        handle: *mut c_void,
+    }
+
+    // the handle is only passed to the thread-safe ReportEventW
+    unsafe impl Send for EventLog {}
+    unsafe impl Sync for EventLog {}
+
+    impl EventLog {
+        pub fn new(config: &EventLogConfig) -> Result<Self> {
+            let source = wide(&config.source);
+            let handle = unsafe { RegisterEventSourceW(std::ptr::null(), source.as_ptr()) };
+            if handle.is_null() {
+                bail!(
+                    "register event source {} failed: {}",
+                    config.source,
+                    io::Error::last_os_error()
+                );
+            }
+            Ok(Self { handle })
+        }
+
+        fn report(&self, level: Level, message: &str) {
+            let kind = match level {
+                Level::ERROR => EVENTLOG_ERROR_TYPE,
+                Level::WARN => EVENTLOG_WARNING_TYPE,
+                _ => EVENTLOG_INFORMATION_TYPE,
+            };
+            let message = wide(message.trim_end());
+            let strings = [message.as_ptr()];
+            unsafe {
+                ReportEventW(
+                    self.handle,
+                    kind,
+                    0,
+                    0,
+                    std::ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    std::ptr::null(),
+                );
+            }
+        }
+    }
+
+    impl Drop for EventLog {
+        fn drop(&mut self) {
+            unsafe { DeregisterEventSource(self.handle) };
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for EventLog {
+        type Writer = EventWriter<'a>;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            EventWriter {
+                log: self,
+                level: Level::INFO,
+                buf: Vec::new(),
+            }
+        }
+
+        fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+            EventWriter {
+                log: self,
+                level: *meta.level(),
+                buf: Vec::new(),
+            }
+        }
+    }
+
+    pub struct EventWriter<'a> {
+        log: &'a EventLog,
+        level: Level,
+        buf: Vec<u8>,
+    }
+
+    impl Write for EventWriter<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for EventWriter<'_> {
+        fn drop(&mut self) {
+            if !self.buf.is_empty() {
+                self.log
+                    .report(self.level, &String::from_utf8_lossy(&self.buf));
+            }
+        }
+    }
+
+    fn wide(s: &str) -> Vec<u16> {
+        s.encode_utf16().chain(Some(0)).collect()
+    }
+}
+
+#[cfg(not(all(windows, feature = "eventlog")))]
+mod imp {
+    use anyhow::{bail, Result};
+    use tracing_subscriber::fmt::MakeWriter;
+
+    use crate::config::EventLogConfig;
+
+    pub enum EventLog {}
+
+    impl EventLog {
+        pub fn new(_config: &EventLogConfig) -> Result<Self> {
+            bail!("the Windows Event Log needs a Windows build with the `eventlog` feature")
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for EventLog {
+        type Writer = std::io::Sink;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            match *self {}
+        }
+    }
+}
+
+#[test]
+fn should_format_rfc5424() {
+    let (tx, rx) = sync_channel(1);
+    let syslog = Syslog {
+        tx,
+        facility: 16,
+        hostname: "proxy".to_owned(),
+        app_name: header_field("http proxy"),
+        procid: "42".to_owned(),
+    };
+    drop(syslog.make_writer());
+    let mut writer = syslog.make_writer();
+    writer.level = Level::WARN;
+    writer.write_all(b"upstream timed out\n").unwrap();
+    drop(writer);
+    let message = rx.try_recv().unwrap();
+    // local0.warning
+    assert!(message.starts_with("<132>1 "), "{message}");
+    assert!(message.ends_with("Z proxy httpproxy 42 - - upstream timed out"));
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+fn should_rotate_by_size() {
+    let dir = std::env::temp_dir().join(format!("log-rotate-{}", ulid::Ulid::new()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = LogFileConfig {
+        path: dir.join("proxy.log"),
+        rotation: LogRotation::Size,
+        max_size_mb: 1,
+        keep: 2,
+    };
+    let mut file = RotatingFile::open(&config, UtcOffset::UTC).unwrap();
+    let line = vec![b'x'; 600 * 1024];
+    for _ in 0..5 {
+        file.write_all(&line).unwrap();
+    }
+    let mut names: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    // each rotation happens before the write that would exceed 1 MiB
+    assert_eq!(names.len(), 3, "{names:?}");
+    assert_eq!(names[0], "proxy.log");
+    assert!(names[1].starts_with("proxy.log."));
+    assert_eq!(std::fs::metadata(&config.path).unwrap().len(), 600 * 1024);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use serde::{Serialize, Serializer};
+use time::{macros::format_description, UtcOffset};
+use tokio::sync::broadcast;
+use tracing::field::{Field, Visit};
+use tracing::{error, info, Event, Level, Subscriber};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber::field::MakeExt;
+use tracing_subscriber::fmt::time::OffsetTime;
+use tracing_subscriber::fmt::{format, MakeWriter};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use crate::config::{Config, LogFormat};
+use crate::log_sink::{EventLog, RotatingFile, Syslog};
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+static EVENTS: OnceLock<broadcast::Sender<LogEvent>> = OnceLock::new();
+
+/// 通过 `log_filter` 的一条日志，推送给管理端口的 tail
+#[derive(Serialize, Debug, Clone)]
+pub struct LogEvent {
+    /// unix 毫秒
+    pub timestamp_ms: u64,
+    #[serde(serialize_with = "level_str")]
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
+fn level_str<S: Serializer>(level: &Level, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(level.as_str())
+}
+
+/// 初始化日志，返回的 guard 需持有至进程退出，否则文件日志会丢失
+pub fn init(config: &Config) -> Option<WorkerGuard> {
+    let offset = UtcOffset::current_local_offset().expect("Should get local offset!");
+    let timer = OffsetTime::new(
+        offset,
+        format_description!("[year]-[month]-[day] [hour]:[minute]:[second]"),
+    );
+    let json = config.log_format == LogFormat::Json;
+
+    let directives = config
+        .log_filter
+        .clone()
+        .unwrap_or_else(|| default_filter(config).to_owned());
+    let filter = EnvFilter::try_new(&directives).unwrap_or_else(|e| {
+        eprintln!("invalid log_filter {directives:?}: {e}");
+        EnvFilter::new(default_filter(config))
+    });
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = FILTER.set(handle);
+
+    // systemd captures stdout into the journal
+    let journal = std::env::var_os("JOURNAL_STREAM").is_some();
+    let file = if cfg!(not(debug_assertions)) && !journal {
+        RotatingFile::open(&config.log_file, offset)
+            .inspect_err(|e| {
+                eprintln!("open {} failed: {e}", config.log_file.path.display());
+            })
+            .ok()
+    } else {
+        None
+    };
+    let (layer, guard) = if let Some(file) = file {
+        let (non_blocking, guard) = tracing_appender::non_blocking(file);
+        let layer = fmt::layer()
+            .with_writer(non_blocking)
+            .with_timer(timer)
+            .with_ansi(false);
+        let layer = if json {
+            layer.json().boxed()
+        } else {
+            layer.boxed()
+        };
+        (layer, Some(guard))
+    } else {
+        let layer = fmt::layer().with_timer(timer);
+        let layer = if json {
+            layer.json().boxed()
+        } else {
+            layer.with_ansi(!journal).boxed()
+        };
+        (layer, None)
+    };
+
+    // sinks that fail to open are reported on stderr, logging is not up yet
+    let syslog = config.syslog.as_ref().and_then(|syslog| {
+        Syslog::new(syslog)
+            .inspect_err(|e| eprintln!("open syslog {} failed: {e}", syslog.addr()))
+            .ok()
+    });
+    let event_log = config.event_log.as_ref().and_then(|event_log| {
+        EventLog::new(event_log)
+            .inspect_err(|e| eprintln!("open event log failed: {e}"))
+            .ok()
+    });
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .with(syslog.map(|writer| sink(writer, json)))
+        .with(event_log.map(|writer| sink(writer, json)))
+        .with(Tap)
+        .init();
+    guard
+}
+
+/// syslog 与事件日志自带时间和级别
+fn sink<S, W>(writer: W, json: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = fmt::layer()
+        .with_writer(writer)
+        .with_ansi(false)
+        .without_time()
+        .with_level(false);
+    if json {
+        layer.json().boxed()
+    } else {
+        // span fields are cached per formatter type, the console's copy has ANSI colors
+        layer
+            .fmt_fields(
+                format::debug_fn(|writer, field, value| match field.name() {
+                    "message" => write!(writer, "{value:?}"),
+                    name => write!(writer, "{name}={value:?}"),
+                })
+                .delimited(" "),
+            )
+            .boxed()
+    }
+}
+
+fn default_filter(config: &Config) -> &'static str {
+    if cfg!(debug_assertions) || config.log_format == LogFormat::Json {
+        // structured request records are logged at INFO
+        "info"
+    } else {
+        "error"
+    }
+}
+
+fn events() -> &'static broadcast::Sender<LogEvent> {
+    EVENTS.get_or_init(|| broadcast::channel(1024).0)
+}
+
+/// 订阅之后的日志，仍受 `log_filter` 限制
+pub fn subscribe() -> broadcast::Receiver<LogEvent> {
+    events().subscribe()
+}
+
+/// 有订阅者时把日志转成 [`LogEvent`] 广播
+struct Tap;
+
+impl<S: Subscriber> Layer<S> for Tap {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let tx = events();
+        if tx.receiver_count() == 0 {
+            return;
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        let _ = tx.send(LogEvent {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+            level: *metadata.level(),
+            target: metadata.target().to_owned(),
+            message: fields.message,
+            fields: fields.fields,
+        });
+    }
+}
+
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: BTreeMap<String, String>,
+}
+
+impl Fields {
+    fn insert(&mut self, field: &Field, value: String) {
+        if field.name() == "message" {
+            self.message = value;
+        } else {
+            self.fields.insert(field.name().to_owned(), value);
+        }
+    }
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.insert(field, format!("{value:?}"));
+    }
+}
+
+/// 运行时替换过滤指令
+pub fn set_filter(directives: &str) -> Result<()> {
+    let filter = EnvFilter::try_new(directives)?;
+    FILTER
+        .get()
+        .ok_or(anyhow!("logging not initialized"))?
+        .reload(filter)?;
+    Ok(())
+}
+
+/// SIGHUP 重新读取配置中的 `log_filter`；控制台输入 `log <directives>` 直接切换
+pub fn spawn_reloader() {
+    #[cfg(unix)]
+    tokio::task::spawn(async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            return;
+        };
+        while hangup.recv().await.is_some() {
+            match Config::load().await.and_then(|config| config.resolve()) {
+                Ok(config) => {
+                    let directives = config
+                        .log_filter
+                        .clone()
+                        .unwrap_or_else(|| default_filter(&config).to_owned());
+                    apply(&directives);
+                }
+                Err(e) => error!("reload config failed: {e}"),
+            }
+        }
+    });
+
+    // a blocking stdin read can't be cancelled and would stall the runtime shutdown,
+    // a detached thread is simply abandoned at exit
+    let _ = std::thread::Builder::new()
+        .name("stdin-log-filter".to_owned())
+        .spawn(|| {
+            for line in std::io::stdin().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                if let Some(directives) = line.trim().strip_prefix("log ") {
+                    apply(directives.trim());
+                }
+            }
+        });
+}
+
+fn apply(directives: &str) {
+    match set_filter(directives) {
+        Ok(()) => info!("log filter set to {directives}"),
+        Err(e) => error!("set log filter {directives:?} failed: {e}"),
+    }
+}
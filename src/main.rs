@@ -1,81 +1,348 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 #![allow(clippy::manual_async_fn)]
+#![recursion_limit = "256"]
 
-use hyper::server::conn::http1::Builder as ServerBuilder;
-use hyper_util::rt::TokioIo;
-use motore::builder::ServiceBuilder;
-use time::{macros::format_description, UtcOffset};
-use tokio::net::TcpListener;
-use tracing::{error, info, Level};
-use tracing_subscriber::fmt::time::OffsetTime;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
 
-use crate::adapter::HyperAdapter;
-use crate::client::HttpClient;
-use crate::layer::log::LogLayer;
-use crate::proxy::Proxy;
+use clap::Parser;
+use tracing::{error, info, warn};
+
+use crate::ca::CA;
+use crate::cli::Cli;
+use crate::config::{Config, ListenerMode};
+use crate::listener::Listener;
 use crate::state::State;
+use crate::sysproxy::SystemProxy;
 
+mod acl;
 mod adapter;
+mod admin;
+mod alpn;
+mod auth;
+mod breakpoint;
 mod ca;
+mod cache;
+mod check;
+mod cli;
 mod client;
 mod config;
+mod cookie;
+mod dialer;
+mod diff;
+mod expect;
+mod export;
+mod filter;
+mod fingerprint;
+mod flow;
+mod geoip;
+mod graphql;
+mod grpc;
+#[cfg(feature = "http3")]
+mod h3;
 mod layer;
+mod limit;
+mod listener;
+mod loadtest;
+mod log_sink;
+mod logging;
+mod metrics;
+mod ntlm;
+mod pcap;
+mod pool;
+mod probe;
 mod proxy;
+mod quota;
+mod redact;
+mod replay;
+mod resolver;
+mod reverse;
+mod rotation;
+mod service;
+mod sniff;
+#[cfg(all(target_os = "linux", feature = "splice"))]
+mod splice;
+mod sse;
 mod state;
+mod stats;
+mod supervisor;
+mod sysproxy;
+mod tcp;
+mod transcript;
+mod transparent;
+mod tray;
+mod udp;
+mod upstream_cert;
+mod upstream_proxy;
 mod util;
+mod webhook;
+
+fn main() {
+    let cli = Cli::parse();
+    if let Some(command) = cli.service {
+        if let Err(e) = service::handle(command) {
+            eprintln!("service {command:?} failed: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(path) = cli.check {
+        std::process::exit(if check::run(&path) { 0 } else { 1 });
+    }
+    if let Some(path) = cli.import_ca {
+        if let Err(e) = import_ca(&path) {
+            eprintln!("import {} failed: {e}", path.display());
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(host) = cli.export_chain {
+        if let Err(e) = export_chain(&host, &cli.out) {
+            eprintln!("export chain of {host} failed: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(spec) = cli.load_test {
+        let result = std::fs::read(&spec)
+            .map_err(anyhow::Error::from)
+            .and_then(|body| export::print("/api/loadtest", Some(body.into())));
+        if let Err(e) = result {
+            eprintln!("load test {} failed: {e}", spec.display());
+            std::process::exit(1);
+        }
+        return;
+    }
+    let export = match (cli.curl, cli.postman, cli.openapi) {
+        (Some(id), _, _) => Some(format!("/api/flows/{id}/curl")),
+        (_, true, _) => Some("/api/export/postman".to_owned()),
+        (_, _, Some(host)) => Some(format!("/api/export/openapi/{host}")),
+        _ => None,
+    };
+    if let Some(path) = export {
+        if let Err(e) = export::print(&path, None) {
+            eprintln!("export {path} failed: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if !run(cli.profile, service::shutdown_signal()) {
+        std::process::exit(1);
+    }
+}
 
-#[tokio::main]
-async fn main() {
-    let offset = UtcOffset::current_local_offset().expect("Should get local offset!");
-    let timer = OffsetTime::new(
-        offset,
-        format_description!("[year]-[month]-[day] [hour]:[minute]:[second]"),
+/// 整条链一个文件，另外每个证书一个文件，0 为叶子证书
+fn export_chain(host: &str, dir: &Path) -> anyhow::Result<()> {
+    let chain = export::fetch(&format!("/api/upstream-certs/{host}/pem"), None)?;
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{host}.chain.pem"));
+    std::fs::write(&path, &chain)?;
+    println!("{}", path.display());
+    for (i, cert) in openssl::x509::X509::stack_from_pem(&chain)?
+        .iter()
+        .enumerate()
+    {
+        let path = dir.join(format!("{host}.{i}.pem"));
+        std::fs::write(&path, cert.to_pem()?)?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+/// PKCS#12 的口令与私钥加密保存的口令相同：`root_ca_key_encrypted` 或设置了
+/// `HTTP_PROXY_CA_PASSPHRASE` 时使用
+fn import_ca(source: &Path) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let config = runtime.block_on(Config::load())?;
+    let passphrase =
+        if config.root_ca_key_encrypted || std::env::var_os(ca::PASSPHRASE_ENV).is_some() {
+            Some(ca::passphrase()?)
+        } else {
+            None
+        };
+    let ca = runtime.block_on(CA::import(source, passphrase.as_deref()))?;
+    ca.verify()?;
+    let (cert, key) = (&config.root_ca_cert_path, &config.root_ca_key_path);
+    for path in [cert, key] {
+        if path.exists() {
+            let mut backup = path.clone().into_os_string();
+            backup.push(".bak");
+            std::fs::rename(path, backup)?;
+        }
+    }
+    let stored = match config.root_ca_key_encrypted {
+        true => passphrase.as_deref(),
+        false => None,
+    };
+    runtime.block_on(ca.save(cert, key, stored))?;
+    println!(
+        "imported root CA into {} and {}",
+        cert.display(),
+        key.display()
     );
-    if cfg!(not(debug_assertions)) {
-        let file_appender = tracing_appender::rolling::never(".", "proxy.log");
-        let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
-        tracing_subscriber::fmt()
-            .with_writer(non_blocking)
-            .with_timer(timer)
-            .with_ansi(false)
-            .with_max_level(Level::ERROR)
-            .init();
-    } else {
-        tracing_subscriber::fmt()
-            .with_timer(timer)
-            .with_ansi(true)
-            .with_max_level(Level::INFO)
-            .init();
+    Ok(())
+}
+
+/// 运行代理直到 `shutdown` 完成，`profile` 覆盖配置中的 `profile`；`expect` 未通过时返回 false
+pub fn run(profile: Option<String>, shutdown: impl Future<Output = ()>) -> bool {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Create runtime failed")
+        .block_on(serve(profile, shutdown))
+}
+
+/// 开启 `expect` 且有意外的请求时返回 false
+async fn serve(profile: Option<String>, shutdown: impl Future<Output = ()>) -> bool {
+    let mut config = Config::load().await.expect("Config load failed");
+    if profile.is_some() {
+        config.profile = profile;
     }
+    let _guard = logging::init(&config.resolve().expect("Apply profile failed"));
+    logging::spawn_reloader();
+    supervisor::install_hook();
 
-    let state = State::new().await.expect("State init failed");
+    let state = State::new(config).await.expect("State init failed");
+    webhook::spawn(state.clone());
+
+    if let Some(admin_addr) = state.config().admin_addr.clone() {
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            if let Err(e) = admin::serve(state, admin_addr).await {
+                error!("Admin server failed: {e}");
+            }
+        });
+    }
 
     let addr = state.local_addr().expect("Parse config address failed");
-    let listener = TcpListener::bind(addr)
-        .await
+    let mut listeners = bind_workers(addr, state.config().accept_workers).await;
+    let local_addr = listeners[0].0.local_addr();
+    #[cfg(unix)]
+    if let Some(path) = state.config().unix_path.clone() {
+        listeners.push((
+            Listener::bind_unix(path).expect("Create unix listener failed"),
+            0,
+        ));
+    }
+    #[cfg(windows)]
+    if let Some(name) = state.config().pipe_name.clone() {
+        listeners.push((
+            Listener::bind_pipe(name).expect("Create named pipe failed"),
+            0,
+        ));
+    }
+    let tls = listener::tls_acceptor(&state.config()).expect("Load listener certificate failed");
+    for (listener, worker) in listeners {
+        tokio::task::spawn(listener::supervise(
+            listener,
+            worker,
+            state.clone(),
+            tls.clone(),
+        ));
+    }
+    for config in state.config().listeners.clone() {
+        let addr = config.addr.parse().expect("Parse listener address failed");
+        let listener = match config.mode {
+            #[cfg(target_os = "linux")]
+            ListenerMode::Transparent => Listener::bind_transparent(addr),
+            _ => Listener::bind_tcp(addr).await,
+        }
         .expect("Create listener failed");
-    info!("Listening on http://{}", listener.local_addr().unwrap());
+        let tls = match (config.mode, config.tls) {
+            (ListenerMode::Reverse, true) => {
+                Some(listener::sni_acceptor(&state).expect("Create reverse TLS acceptor failed"))
+            }
+            (ListenerMode::Reverse | ListenerMode::Transparent, _) => None,
+            (ListenerMode::Forward, _) => tls.clone(),
+        };
+        let state = state.with_listener(config);
+        tokio::task::spawn(listener::supervise(listener, 0, state, tls));
+    }
+    if let Some(addr) = state.config().h3_addr.clone() {
+        let addr: SocketAddr = addr.parse().expect("Parse h3_addr failed");
+        #[cfg(feature = "http3")]
+        {
+            let state = state.clone();
+            tokio::task::spawn(async move {
+                if let Err(e) = h3::serve(state, addr, client::service()).await {
+                    error!("HTTP/3 listener failed: {e}");
+                }
+            });
+        }
+        #[cfg(not(feature = "http3"))]
+        warn!("h3_addr {addr} ignored, built without the http3 feature");
+    }
+    #[cfg(not(feature = "http3"))]
+    if !state.config().h3_hosts.is_empty() {
+        warn!("h3_hosts ignored, built without the http3 feature");
+    }
+    state.set_listening();
+    service::notify_ready();
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                let state = state.clone();
-                let io = TokioIo::new(stream);
+    let system_proxy = match local_addr {
+        Some(local_addr) if state.config().system_proxy => SystemProxy::enable(local_addr)
+            .inspect_err(|e| error!("Set system proxy failed: {e}"))
+            .ok(),
+        _ => None,
+    };
 
-                tokio::task::spawn(async move {
-                    let client = ServiceBuilder::new().layer(LogLayer).service(HttpClient);
-                    if let Err(err) = ServerBuilder::new()
-                        .preserve_header_case(true)
-                        .title_case_headers(true)
-                        .serve_connection(io, Proxy::new(client).hyper(|req| (state, req)))
-                        .with_upgrades()
-                        .await
-                    {
-                        error!("Failed to serve connection: {err}");
-                    }
-                });
-            }
-            Err(err) => error!("Failed to accept: {err}"),
+    let quit = tray::spawn(state.clone());
+    tokio::select! {
+        _ = shutdown => {}
+        _ = quit => {}
+    }
+    info!("Shutting down");
+    if !state.traffic().is_empty() {
+        info!("Traffic by host:\n{}", state.traffic().table());
+    }
+    if let Some(snapshots) = state.stats_snapshots() {
+        if let Err(e) = snapshots.write().await {
+            error!("write stats snapshot failed: {e}");
+        }
+    }
+    let config = state.config();
+    if let (true, Some(path)) = (config.quota.enabled, &config.quota.path) {
+        if let Err(e) = state.quotas().save(path).await {
+            error!("save quota usage to {} failed: {e}", path.display());
+        }
+    }
+    let passed = match &config.expect {
+        Some(expect) => state.expectations().finish(expect).await,
+        None => true,
+    };
+    if let Some(spill) = &config.flow_spill {
+        // flows still in the store keep their files until now
+        let _ = tokio::fs::remove_dir_all(spill.dir()).await;
+    }
+    service::notify_stopping();
+    if let Some(system_proxy) = system_proxy {
+        system_proxy.restore();
+    }
+    passed
+}
+
+/// `workers` 大于 1 时（仅 unix）以 SO_REUSEPORT 绑定多个监听端
+async fn bind_workers(addr: SocketAddr, workers: usize) -> Vec<(Listener, usize)> {
+    #[cfg(unix)]
+    if workers > 1 {
+        let first = Listener::bind_reuseport(addr).expect("Create listener failed");
+        // port 0 resolves on the first bind
+        let addr = first.local_addr().unwrap_or(addr);
+        let mut listeners = vec![(first, 0)];
+        for worker in 1..workers {
+            let listener = Listener::bind_reuseport(addr).expect("Create listener failed");
+            listeners.push((listener, worker));
         }
+        return listeners;
     }
+    if workers > 1 {
+        warn!("accept_workers requires SO_REUSEPORT, using a single accept loop");
+    }
+    let listener = Listener::bind_tcp(addr)
+        .await
+        .expect("Create listener failed");
+    vec![(listener, 0)]
 }
@@ -0,0 +1,363 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+/// 进程级计数器
+#[derive(Default)]
+pub struct Metrics {
+    pub connections_accepted: AtomicU64,
+    pub http_requests: AtomicU64,
+    pub connect_requests: AtomicU64,
+    pub parsed_requests: AtomicU64,
+    pub upstream_errors: AtomicU64,
+    pub tunnel_errors: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub denied_connections: AtomicU64,
+    pub denied_connect_ports: AtomicU64,
+    pub pooled_requests: AtomicU64,
+    pub dns_lookups: AtomicU64,
+    pub dns_cache_hits: AtomicU64,
+    pub dns_failures: AtomicU64,
+    pub upstream_retries: AtomicU64,
+    pub limited_connections: AtomicU64,
+    pub limited_requests: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    pub cache_revalidations: AtomicU64,
+    pub mirrored_requests: AtomicU64,
+    pub mirror_errors: AtomicU64,
+    /// 缓存的签发证书将过期而重新签发
+    pub certs_renewed: AtomicU64,
+    /// MITM 握手恢复了之前的会话
+    pub tls_resumed: AtomicU64,
+    /// 连接任务中捕获的 panic
+    pub panics: AtomicU64,
+    /// accept 循环被看门狗重启
+    pub listener_restarts: AtomicU64,
+    /// 过载时直接返回 503 的请求
+    pub shed_requests: AtomicU64,
+    /// 过载时暂停 accept 的次数
+    pub shed_pauses: AtomicU64,
+}
+
+impl Metrics {
+    pub fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HashMap<&'static str, u64> {
+        [
+            ("connections_accepted", &self.connections_accepted),
+            ("http_requests", &self.http_requests),
+            ("connect_requests", &self.connect_requests),
+            ("parsed_requests", &self.parsed_requests),
+            ("upstream_errors", &self.upstream_errors),
+            ("tunnel_errors", &self.tunnel_errors),
+            ("auth_failures", &self.auth_failures),
+            ("denied_connections", &self.denied_connections),
+            ("denied_connect_ports", &self.denied_connect_ports),
+            ("pooled_requests", &self.pooled_requests),
+            ("dns_lookups", &self.dns_lookups),
+            ("dns_cache_hits", &self.dns_cache_hits),
+            ("dns_failures", &self.dns_failures),
+            ("upstream_retries", &self.upstream_retries),
+            ("limited_connections", &self.limited_connections),
+            ("limited_requests", &self.limited_requests),
+            ("cache_hits", &self.cache_hits),
+            ("cache_misses", &self.cache_misses),
+            ("cache_revalidations", &self.cache_revalidations),
+            ("mirrored_requests", &self.mirrored_requests),
+            ("mirror_errors", &self.mirror_errors),
+            ("certs_renewed", &self.certs_renewed),
+            ("tls_resumed", &self.tls_resumed),
+            ("panics", &self.panics),
+            ("listener_restarts", &self.listener_restarts),
+            ("shed_requests", &self.shed_requests),
+            ("shed_pauses", &self.shed_pauses),
+        ]
+        .into_iter()
+        .map(|(name, counter)| (name, counter.load(Ordering::Relaxed)))
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Protocol {
+    H1,
+    H2,
+    #[cfg_attr(not(feature = "http3"), allow(dead_code))]
+    H3,
+    WebSocket,
+    /// RFC 9298 CONNECT-UDP
+    Udp,
+    /// 未解密，直接转发
+    Tunneled,
+    /// 与客户端 TLS 握手失败（通常是不信任根证书或证书固定）
+    MitmFailed,
+    /// 按 `sniff` 策略拒绝的隧道
+    Rejected,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ProtocolCounts {
+    pub h1: u64,
+    pub h2: u64,
+    pub h3: u64,
+    pub websocket: u64,
+    pub udp: u64,
+    pub tunneled: u64,
+    pub mitm_failed: u64,
+    pub rejected: u64,
+}
+
+/// 按 host 统计协议分布
+#[derive(Clone, Default)]
+pub struct ProtocolStats {
+    inner: Arc<Mutex<HashMap<String, ProtocolCounts>>>,
+}
+
+impl ProtocolStats {
+    pub fn record(&self, host: &str, protocol: Protocol) {
+        let Ok(mut map) = self.inner.lock() else {
+            return;
+        };
+        let counts = map.entry(host.to_owned()).or_default();
+        match protocol {
+            Protocol::H1 => counts.h1 += 1,
+            Protocol::H2 => counts.h2 += 1,
+            Protocol::H3 => counts.h3 += 1,
+            Protocol::WebSocket => counts.websocket += 1,
+            Protocol::Udp => counts.udp += 1,
+            Protocol::Tunneled => counts.tunneled += 1,
+            Protocol::MitmFailed => counts.mitm_failed += 1,
+            Protocol::Rejected => counts.rejected += 1,
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, ProtocolCounts> {
+        self.inner.lock().map(|map| map.clone()).unwrap_or_default()
+    }
+}
+
+/// 按 accept 循环统计接受的连接数，key 为 `监听端#worker`
+#[derive(Clone, Default)]
+pub struct AcceptStats {
+    inner: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl AcceptStats {
+    pub fn record(&self, worker: &str) {
+        if let Ok(mut map) = self.inner.lock() {
+            *map.entry(worker.to_owned()).or_default() += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, u64> {
+        self.inner.lock().map(|map| map.clone()).unwrap_or_default()
+    }
+}
+
+/// 每个 host 保留的最近延迟样本数
+const LATENCY_SAMPLES: usize = 1024;
+
+#[derive(Default)]
+struct HostTraffic {
+    requests: u64,
+    errors: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+    latencies_ms: VecDeque<u64>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct TrafficSummary {
+    pub requests: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub p50_ms: Option<u64>,
+    pub p95_ms: Option<u64>,
+}
+
+/// 按目标 host 统计请求数、流量、错误率与延迟，隧道按连接计
+#[derive(Clone, Default)]
+pub struct TrafficStats {
+    inner: Arc<Mutex<HashMap<String, HostTraffic>>>,
+}
+
+impl TrafficStats {
+    /// 隧道没有延迟样本
+    pub fn record(&self, host: &str, bytes_up: u64, bytes_down: u64, latency_ms: Option<u64>) {
+        let Ok(mut map) = self.inner.lock() else {
+            return;
+        };
+        let traffic = map.entry(host.to_owned()).or_default();
+        traffic.requests += 1;
+        traffic.bytes_up += bytes_up;
+        traffic.bytes_down += bytes_down;
+        if let Some(latency_ms) = latency_ms {
+            if traffic.latencies_ms.len() >= LATENCY_SAMPLES {
+                traffic.latencies_ms.pop_front();
+            }
+            traffic.latencies_ms.push_back(latency_ms);
+        }
+    }
+
+    pub fn record_error(&self, host: &str) {
+        if let Ok(mut map) = self.inner.lock() {
+            let traffic = map.entry(host.to_owned()).or_default();
+            traffic.requests += 1;
+            traffic.errors += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, TrafficSummary> {
+        let Ok(map) = self.inner.lock() else {
+            return HashMap::new();
+        };
+        map.iter()
+            .map(|(host, traffic)| {
+                let mut latencies: Vec<_> = traffic.latencies_ms.iter().copied().collect();
+                latencies.sort_unstable();
+                let percentile = |p: usize| {
+                    let last = latencies.len().checked_sub(1)?;
+                    latencies.get(last * p / 100).copied()
+                };
+                let summary = TrafficSummary {
+                    requests: traffic.requests,
+                    errors: traffic.errors,
+                    error_rate: traffic.errors as f64 / traffic.requests.max(1) as f64,
+                    bytes_up: traffic.bytes_up,
+                    bytes_down: traffic.bytes_down,
+                    p50_ms: percentile(50),
+                    p95_ms: percentile(95),
+                };
+                (host.clone(), summary)
+            })
+            .collect()
+    }
+
+    /// 按请求数降序的文本表格，退出时输出
+    pub fn table(&self) -> String {
+        let mut rows: Vec<_> = self.snapshot().into_iter().collect();
+        rows.sort_by(|a, b| b.1.requests.cmp(&a.1.requests).then(a.0.cmp(&b.0)));
+        let ms = |v: Option<u64>| v.map(|v| v.to_string()).unwrap_or("-".to_owned());
+        let mut table = format!(
+            "{:<40} {:>8} {:>7} {:>12} {:>12} {:>7} {:>7}\n",
+            "host", "requests", "errors", "bytes_up", "bytes_down", "p50_ms", "p95_ms"
+        );
+        for (host, s) in rows {
+            table.push_str(&format!(
+                "{:<40} {:>8} {:>6.1}% {:>12} {:>12} {:>7} {:>7}\n",
+                host,
+                s.requests,
+                s.error_rate * 100.0,
+                s.bytes_up,
+                s.bytes_down,
+                ms(s.p50_ms),
+                ms(s.p95_ms)
+            ));
+        }
+        table
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().map(|map| map.is_empty()).unwrap_or(true)
+    }
+}
+
+/// 一个已接受的客户端连接
+#[derive(Serialize, Debug)]
+pub struct Connection {
+    pub id: u64,
+    pub peer_addr: Option<SocketAddr>,
+    /// unix 秒
+    pub started_at: u64,
+    /// CONNECT / 请求的目标
+    pub targets: Mutex<Vec<String>>,
+}
+
+impl Connection {
+    pub fn add_target(&self, target: &str) {
+        if let Ok(mut targets) = self.targets.lock() {
+            if !targets.iter().any(|t| t == target) {
+                targets.push(target.to_owned());
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Connections {
+    inner: Arc<Mutex<HashMap<u64, Arc<Connection>>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Connections {
+    pub fn register(&self, peer_addr: Option<SocketAddr>) -> ConnectionGuard {
+        let conn = Arc::new(Connection {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            peer_addr,
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            targets: Mutex::new(Vec::new()),
+        });
+        if let Ok(mut map) = self.inner.lock() {
+            map.insert(conn.id, conn.clone());
+        }
+        ConnectionGuard {
+            conn,
+            connections: self.clone(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.inner.lock().map(|map| map.len()).unwrap_or_default()
+    }
+
+    pub fn list(&self) -> Vec<Arc<Connection>> {
+        let mut list: Vec<_> = self
+            .inner
+            .lock()
+            .map(|map| map.values().cloned().collect())
+            .unwrap_or_default();
+        list.sort_by_key(|conn| conn.id);
+        list
+    }
+}
+
+/// 连接结束时从登记表移除
+pub struct ConnectionGuard {
+    pub conn: Arc<Connection>,
+    connections: Connections,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut map) = self.connections.inner.lock() {
+            map.remove(&self.conn.id);
+        }
+    }
+}
+
+#[test]
+fn should_summarize_traffic() {
+    let stats = TrafficStats::default();
+    for latency in 1..=100 {
+        stats.record("example.com", 10, 100, Some(latency));
+    }
+    stats.record_error("example.com");
+    let summary = &stats.snapshot()["example.com"];
+    assert_eq!(summary.requests, 101);
+    assert_eq!(summary.bytes_down, 10_000);
+    assert_eq!(summary.p50_ms, Some(50));
+    assert_eq!(summary.p95_ms, Some(95));
+    assert!(stats.table().contains("example.com"));
+}
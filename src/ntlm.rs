@@ -0,0 +1,278 @@
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_OEM
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY;
+/// target info 中的 `MsvAvTimestamp`
+const AV_TIMESTAMP: u16 = 7;
+/// 1601-01-01 到 1970-01-01 的 100ns 数
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+/// NEGOTIATE_MESSAGE，不带域与工作站
+pub fn negotiate() -> Vec<u8> {
+    let mut msg = Vec::with_capacity(32);
+    msg.extend_from_slice(SIGNATURE);
+    msg.extend_from_slice(&1u32.to_le_bytes());
+    msg.extend_from_slice(&FLAGS.to_le_bytes());
+    msg.extend_from_slice(&[0; 16]);
+    msg
+}
+
+/// CHALLENGE_MESSAGE 中 NTLMv2 需要的部分
+pub struct Challenge {
+    server_challenge: [u8; 8],
+    target_info: Vec<u8>,
+}
+
+impl Challenge {
+    pub fn parse(msg: &[u8]) -> Option<Self> {
+        if msg.get(..8)? != SIGNATURE || u32_at(msg, 8)? != 2 {
+            return None;
+        }
+        let server_challenge = msg.get(24..32)?.try_into().ok()?;
+        let target_info = match (u16_at(msg, 40), u32_at(msg, 44)) {
+            (Some(len), Some(offset)) if len > 0 => {
+                let offset = offset as usize;
+                msg.get(offset..offset + len as usize)?.to_vec()
+            }
+            _ => Vec::new(),
+        };
+        Some(Self {
+            server_challenge,
+            target_info,
+        })
+    }
+
+    fn timestamp(&self) -> Option<[u8; 8]> {
+        let mut pairs = &self.target_info[..];
+        while pairs.len() >= 4 {
+            let id = u16::from_le_bytes([pairs[0], pairs[1]]);
+            let len = u16::from_le_bytes([pairs[2], pairs[3]]) as usize;
+            let value = pairs.get(4..4 + len)?;
+            if id == AV_TIMESTAMP {
+                return value.try_into().ok();
+            }
+            pairs = &pairs[4 + len..];
+        }
+        None
+    }
+}
+
+/// 应答 `challenge` 的 AUTHENTICATE_MESSAGE（NTLMv2，不带 MIC 与会话密钥）
+pub fn authenticate(
+    challenge: &Challenge,
+    username: &str,
+    password: &str,
+    domain: &str,
+    workstation: &str,
+) -> Result<Vec<u8>, ErrorStack> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let now = FILETIME_UNIX_EPOCH + now.as_nanos() as u64 / 100;
+    let client_challenge: [u8; 8] = rand::random();
+    let key = ntowf_v2(username, password, domain)?;
+    // MS-NLMP 3.1.5.1.2: with a server timestamp the LM response is Z(24)
+    let (timestamp, lm) = match challenge.timestamp() {
+        Some(timestamp) => (timestamp, vec![0; 24]),
+        None => (
+            now.to_le_bytes(),
+            lm_v2(&key, &challenge.server_challenge, &client_challenge)?,
+        ),
+    };
+    let nt = nt_v2(&key, challenge, &client_challenge, &timestamp)?;
+
+    // header order: LM, NT, domain, user, workstation, session key
+    let fields = [
+        lm,
+        nt,
+        utf16(domain),
+        utf16(username),
+        utf16(workstation),
+        Vec::new(),
+    ];
+    // payload order: domain, user, workstation, LM, NT
+    let order = [2, 3, 4, 0, 1, 5];
+    let mut offsets = [0u32; 6];
+    let mut offset = 64;
+    for i in order {
+        offsets[i] = offset;
+        offset += fields[i].len() as u32;
+    }
+    let mut msg = Vec::with_capacity(offset as usize);
+    msg.extend_from_slice(SIGNATURE);
+    msg.extend_from_slice(&3u32.to_le_bytes());
+    for (field, offset) in fields.iter().zip(offsets) {
+        let len = field.len() as u16;
+        msg.extend_from_slice(&len.to_le_bytes());
+        msg.extend_from_slice(&len.to_le_bytes());
+        msg.extend_from_slice(&offset.to_le_bytes());
+    }
+    msg.extend_from_slice(&FLAGS.to_le_bytes());
+    for i in order {
+        msg.extend_from_slice(&fields[i]);
+    }
+    Ok(msg)
+}
+
+fn ntowf_v2(username: &str, password: &str, domain: &str) -> Result<[u8; 16], ErrorStack> {
+    let identity = utf16(&format!("{}{domain}", username.to_uppercase()));
+    hmac_md5(&md4(&utf16(password)), &[&identity])
+}
+
+fn lm_v2(
+    key: &[u8; 16],
+    server_challenge: &[u8; 8],
+    client_challenge: &[u8; 8],
+) -> Result<Vec<u8>, ErrorStack> {
+    let mut lm = hmac_md5(key, &[server_challenge, client_challenge])?.to_vec();
+    lm.extend_from_slice(client_challenge);
+    Ok(lm)
+}
+
+/// NTProofStr 加上 temp blob
+fn nt_v2(
+    key: &[u8; 16],
+    challenge: &Challenge,
+    client_challenge: &[u8; 8],
+    timestamp: &[u8; 8],
+) -> Result<Vec<u8>, ErrorStack> {
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend_from_slice(timestamp);
+    blob.extend_from_slice(client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&challenge.target_info);
+    blob.extend_from_slice(&[0; 4]);
+    let mut nt = hmac_md5(key, &[&challenge.server_challenge, &blob])?.to_vec();
+    nt.extend_from_slice(&blob);
+    Ok(nt)
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> Result<[u8; 16], ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::md5(), &key)?;
+    for part in parts {
+        signer.update(part)?;
+    }
+    let mut out = [0; 16];
+    signer.sign(&mut out)?;
+    Ok(out)
+}
+
+/// OpenSSL 3 只在 legacy provider 中提供 MD4，这里自行实现（RFC 1320）
+fn md4(input: &[u8]) -> [u8; 16] {
+    let mut msg = input.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(input.len() as u64).wrapping_mul(8).to_le_bytes());
+
+    let f = |x: u32, y: u32, z: u32| (x & y) | (!x & z);
+    let g = |x: u32, y: u32, z: u32| (x & y) | (x & z) | (y & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let mut state: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+    for block in msg.chunks(64) {
+        let x: Vec<u32> = block
+            .chunks(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        let step = |op: &dyn Fn(u32, u32, u32) -> u32,
+                    v: u32,
+                    p: u32,
+                    q: u32,
+                    r: u32,
+                    word: u32,
+                    shift: u32| {
+            v.wrapping_add(op(p, q, r))
+                .wrapping_add(word)
+                .rotate_left(shift)
+        };
+        for i in [0, 4, 8, 12] {
+            a = step(&f, a, b, c, d, x[i], 3);
+            d = step(&f, d, a, b, c, x[i + 1], 7);
+            c = step(&f, c, d, a, b, x[i + 2], 11);
+            b = step(&f, b, c, d, a, x[i + 3], 19);
+        }
+        for i in [0, 1, 2, 3] {
+            a = step(&g, a, b, c, d, x[i].wrapping_add(0x5a82_7999), 3);
+            d = step(&g, d, a, b, c, x[i + 4].wrapping_add(0x5a82_7999), 5);
+            c = step(&g, c, d, a, b, x[i + 8].wrapping_add(0x5a82_7999), 9);
+            b = step(&g, b, c, d, a, x[i + 12].wrapping_add(0x5a82_7999), 13);
+        }
+        for i in [0, 2, 1, 3] {
+            a = step(&h, a, b, c, d, x[i].wrapping_add(0x6ed9_eba1), 3);
+            d = step(&h, d, a, b, c, x[i + 8].wrapping_add(0x6ed9_eba1), 9);
+            c = step(&h, c, d, a, b, x[i + 4].wrapping_add(0x6ed9_eba1), 11);
+            b = step(&h, b, c, d, a, x[i + 12].wrapping_add(0x6ed9_eba1), 15);
+        }
+        for (word, add) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+    let mut out = [0; 16];
+    for (chunk, word) in out.chunks_mut(4).zip(state) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+fn utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn u16_at(msg: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(msg.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn u32_at(msg: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(msg.get(at..at + 4)?.try_into().ok()?))
+}
+
+#[test]
+fn should_answer_ntlm_challenge() {
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    assert_eq!(hex(&md4(b"")), "31d6cfe0d16ae931b73c59d7e0c089c0");
+    assert_eq!(hex(&md4(b"abc")), "a448017aaf21d8525fc10ae87aa6729d");
+
+    // MS-NLMP 4.2.4
+    let key = ntowf_v2("User", "Password", "Domain").unwrap();
+    assert_eq!(hex(&key), "0c868a403bfd7a93a3001ef22ef02e3f");
+    let server_challenge = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+    let client_challenge = [0xaa; 8];
+    let lm = lm_v2(&key, &server_challenge, &client_challenge).unwrap();
+    assert_eq!(hex(&lm), "86c35097ac9cec102554764a57cccc19aaaaaaaaaaaaaaaa");
+    let mut msg = b"NTLMSSP\0\x02\0\0\0".to_vec();
+    msg.extend_from_slice(&[0; 12]);
+    msg.extend_from_slice(&server_challenge);
+    msg.extend_from_slice(&[0; 8]);
+    let target_info = b"\x02\0\x0c\0D\0o\0m\0a\0i\0n\0\x01\0\x0c\0S\0e\0r\0v\0e\0r\0\0\0\0\0";
+    msg.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+    msg.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+    msg.extend_from_slice(&48u32.to_le_bytes());
+    msg.extend_from_slice(target_info);
+    let challenge = Challenge::parse(&msg).unwrap();
+    assert!(challenge.timestamp().is_none());
+    let nt = nt_v2(&key, &challenge, &client_challenge, &[0; 8]).unwrap();
+    assert_eq!(hex(&nt[..16]), "68cd0ab851e51c96aabc927bebef6a1c");
+
+    let msg = authenticate(&challenge, "User", "Password", "Domain", "COMPUTER").unwrap();
+    assert_eq!(u32_at(&msg, 8), Some(3));
+    // the domain is the first payload field
+    assert_eq!(u32_at(&msg, 32), Some(64));
+    assert_eq!(&msg[64..76], &utf16("Domain")[..]);
+}
@@ -0,0 +1,369 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
+use tracing::error;
+
+use crate::transcript::poll_reserve;
+
+/// 每个 flow 的客户端地址从 10.0.0.1 开始递增，服务端固定为 192.0.2.1:80，
+/// 让 Wireshark 按 HTTP 解析解密后的内容
+const SERVER: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80);
+const LINKTYPE_RAW: u16 = 101;
+/// 单个合成 TCP 段的最大负载
+const SEGMENT: usize = 16 * 1024;
+const BUFFERED_BLOCKS: usize = 64;
+
+const SYN: u8 = 0x02;
+const ACK: u8 = 0x10;
+const PSH: u8 = 0x08;
+const FIN: u8 = 0x01;
+
+static FLOWS: AtomicU32 = AtomicU32::new(0);
+
+/// 解密流量的 pcapng 文件，所有 flow 共用
+#[derive(Clone)]
+pub struct Pcap {
+    tx: mpsc::Sender<Bytes>,
+}
+
+impl Pcap {
+    pub async fn create(path: &Path) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path).await?);
+        file.write_all(&section_header()).await?;
+        file.write_all(&interface_description()).await?;
+        file.flush().await?;
+        let (tx, mut rx) = mpsc::channel::<Bytes>(BUFFERED_BLOCKS);
+        let path = path.display().to_string();
+        tokio::task::spawn(async move {
+            while let Some(blocks) = rx.recv().await {
+                if let Err(e) = file.write_all(&blocks).await {
+                    error!("write pcap {path} failed: {e}");
+                    return;
+                }
+                // readable while running
+                if rx.is_empty() {
+                    let _ = file.flush().await;
+                }
+            }
+            let _ = file.flush().await;
+        });
+        Ok(Self { tx })
+    }
+
+    /// 写入握手后开始记录，`inner` 为客户端一侧解密后的流
+    pub async fn record<S>(&self, inner: S) -> PcapStream<S> {
+        let n = FLOWS.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut flow = TcpFlow {
+            client: SocketAddrV4::new(
+                Ipv4Addr::from(0x0a00_0000 + (n & 0x00ff_ffff)),
+                1024 + (n % 64_000) as u16,
+            ),
+            server: SERVER,
+            client_seq: 0,
+            server_seq: 0,
+        };
+        if self.tx.send(flow.handshake()).await.is_err() {
+            return PcapStream::passthrough(inner);
+        }
+        PcapStream {
+            inner,
+            tx: Some(PollSender::new(self.tx.clone())),
+            flow,
+        }
+    }
+}
+
+/// 按需包装，`pcap` 为空时不记录
+pub async fn record<S>(pcap: Option<&Pcap>, inner: S) -> PcapStream<S> {
+    match pcap {
+        Some(pcap) => pcap.record(inner).await,
+        None => PcapStream::passthrough(inner),
+    }
+}
+
+/// 透传的流，读到的字节记为客户端发往服务端，写出的记为服务端发往客户端
+pub struct PcapStream<S> {
+    inner: S,
+    tx: Option<PollSender<Bytes>>,
+    flow: TcpFlow,
+}
+
+impl<S> PcapStream<S> {
+    fn passthrough(inner: S) -> Self {
+        Self {
+            inner,
+            tx: None,
+            flow: TcpFlow {
+                client: SERVER,
+                server: SERVER,
+                client_seq: 0,
+                server_seq: 0,
+            },
+        }
+    }
+
+    fn send(&mut self, to_server: bool, data: &[u8]) {
+        if let Some(sender) = &mut self.tx {
+            if data.is_empty() {
+                sender.abort_send();
+            } else if sender.send_item(self.flow.data(to_server, data)).is_err() {
+                self.tx = None;
+            }
+        }
+    }
+}
+
+impl<S> Drop for PcapStream<S> {
+    fn drop(&mut self) {
+        // best effort, the channel may be full
+        if let Some(sender) = self.tx.as_ref().and_then(|tx| tx.get_ref()) {
+            let _ = sender.try_send(self.flow.close());
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PcapStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(poll_reserve(&mut this.tx, cx));
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        match &poll {
+            Poll::Ready(Ok(())) => this.send(true, &buf.filled()[before..]),
+            Poll::Ready(Err(_)) => this.send(true, &[]),
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PcapStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(poll_reserve(&mut this.tx, cx));
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        match &poll {
+            Poll::Ready(Ok(n)) => this.send(false, &buf[..*n]),
+            Poll::Ready(Err(_)) => this.send(false, &[]),
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// 合成的 TCP 连接状态，序号从 0 开始
+struct TcpFlow {
+    client: SocketAddrV4,
+    server: SocketAddrV4,
+    client_seq: u32,
+    server_seq: u32,
+}
+
+impl TcpFlow {
+    fn handshake(&mut self) -> Bytes {
+        let mut blocks = BytesMut::new();
+        self.segment(&mut blocks, true, SYN, &[]);
+        self.segment(&mut blocks, false, SYN | ACK, &[]);
+        self.segment(&mut blocks, true, ACK, &[]);
+        blocks.freeze()
+    }
+
+    fn data(&mut self, to_server: bool, data: &[u8]) -> Bytes {
+        let mut blocks = BytesMut::new();
+        for chunk in data.chunks(SEGMENT) {
+            self.segment(&mut blocks, to_server, PSH | ACK, chunk);
+        }
+        blocks.freeze()
+    }
+
+    fn close(&mut self) -> Bytes {
+        let mut blocks = BytesMut::new();
+        self.segment(&mut blocks, true, FIN | ACK, &[]);
+        self.segment(&mut blocks, false, FIN | ACK, &[]);
+        self.segment(&mut blocks, true, ACK, &[]);
+        blocks.freeze()
+    }
+
+    fn segment(&mut self, blocks: &mut BytesMut, to_server: bool, flags: u8, payload: &[u8]) {
+        let (src, dst, seq, ack) = if to_server {
+            (self.client, self.server, self.client_seq, self.server_seq)
+        } else {
+            (self.server, self.client, self.server_seq, self.client_seq)
+        };
+        // SYN and FIN take one sequence number
+        let advance = payload.len() as u32 + u32::from(flags & (SYN | FIN) != 0);
+        let seq_after = seq.wrapping_add(advance);
+        if to_server {
+            self.client_seq = seq_after;
+        } else {
+            self.server_seq = seq_after;
+        }
+        let ack = if flags & ACK != 0 { ack } else { 0 };
+        enhanced_packet(blocks, &packet(src, dst, seq, ack, flags, payload));
+    }
+}
+
+/// IPv4 + TCP，无选项
+fn packet(
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let tcp_len = 20 + payload.len();
+    let mut tcp = Vec::with_capacity(tcp_len);
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.extend_from_slice(&[5 << 4, flags]);
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+    let mut pseudo = Vec::with_capacity(12);
+    pseudo.extend_from_slice(&src.ip().octets());
+    pseudo.extend_from_slice(&dst.ip().octets());
+    pseudo.extend_from_slice(&[0, 6]);
+    pseudo.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+    let sum = checksum(&[&pseudo, &tcp]);
+    tcp[16..18].copy_from_slice(&sum.to_be_bytes());
+
+    let mut ip = Vec::with_capacity(20 + tcp_len);
+    ip.extend_from_slice(&[0x45, 0]);
+    ip.extend_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+    ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+    ip.extend_from_slice(&src.ip().octets());
+    ip.extend_from_slice(&dst.ip().octets());
+    let sum = checksum(&[&ip]);
+    ip[10..12].copy_from_slice(&sum.to_be_bytes());
+    ip.extend_from_slice(&tcp);
+    ip
+}
+
+/// RFC 1071，除最后一段外长度均为偶数
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match word {
+                [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+                [hi] => u16::from_be_bytes([*hi, 0]),
+                _ => 0,
+            };
+            sum += u32::from(word);
+        }
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn section_header() -> Vec<u8> {
+    let mut block = Vec::with_capacity(28);
+    block.put_u32_le(0x0a0d_0d0a);
+    block.put_u32_le(28);
+    block.put_u32_le(0x1a2b_3c4d);
+    block.put_u16_le(1);
+    block.put_u16_le(0);
+    // section length unknown
+    block.put_i64_le(-1);
+    block.put_u32_le(28);
+    block
+}
+
+fn interface_description() -> Vec<u8> {
+    let mut block = Vec::with_capacity(20);
+    block.put_u32_le(1);
+    block.put_u32_le(20);
+    block.put_u16_le(LINKTYPE_RAW);
+    block.put_u16_le(0);
+    block.put_u32_le(0);
+    block.put_u32_le(20);
+    block
+}
+
+/// 时间戳为默认的微秒精度
+fn enhanced_packet(blocks: &mut BytesMut, packet: &[u8]) {
+    let padded = packet.len().next_multiple_of(4);
+    let len = (32 + padded) as u32;
+    let micros = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default();
+    blocks.put_u32_le(6);
+    blocks.put_u32_le(len);
+    blocks.put_u32_le(0);
+    blocks.put_u32_le((micros >> 32) as u32);
+    blocks.put_u32_le(micros as u32);
+    blocks.put_u32_le(packet.len() as u32);
+    blocks.put_u32_le(packet.len() as u32);
+    blocks.put_slice(packet);
+    blocks.put_bytes(0, padded - packet.len());
+    blocks.put_u32_le(len);
+}
+
+#[tokio::test]
+async fn should_write_pcapng() {
+    use tokio::io::AsyncReadExt;
+
+    let path = std::env::temp_dir().join(format!("pcap-test-{}.pcapng", std::process::id()));
+    let pcap = Pcap::create(&path).await.unwrap();
+    let (client, mut server) = tokio::io::duplex(1024);
+    let mut stream = pcap.record(client).await;
+    server.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+    let mut buf = [0; 18];
+    stream.read_exact(&mut buf).await.unwrap();
+    stream
+        .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+        .await
+        .unwrap();
+    drop(stream);
+    drop(pcap);
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let file = tokio::fs::read(&path).await.unwrap();
+    let _ = tokio::fs::remove_file(&path).await;
+    assert_eq!(file[..4], [0x0a, 0x0d, 0x0d, 0x0a]);
+    let mut offset = 28 + 20;
+    let mut packets = 0;
+    while offset < file.len() {
+        let len = u32::from_le_bytes(file[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let ip = &file[offset + 28..offset + 28 + 20];
+        assert_eq!(checksum(&[ip]), 0);
+        offset += len;
+        packets += 1;
+    }
+    assert_eq!(offset, file.len());
+    // handshake, request, response, close
+    assert_eq!(packets, 3 + 1 + 1 + 3);
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use hyper::client::conn::http1::SendRequest;
+use tracing::debug;
+
+use crate::config::PoolConfig;
+
+pub type Sender = SendRequest<BoxBody<Bytes, hyper::Error>>;
+
+/// 上游连接按 `(addr, sni, secure)` 复用
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PoolKey {
+    pub addr: String,
+    pub sni: String,
+    pub secure: bool,
+}
+
+struct Idle {
+    sender: Sender,
+    since: Instant,
+}
+
+/// 空闲的 http1 连接，取出时检查是否仍可用
+#[derive(Clone, Default)]
+pub struct Pool {
+    inner: Arc<Mutex<HashMap<PoolKey, Vec<Idle>>>>,
+}
+
+impl Pool {
+    pub fn take(&self, key: &PoolKey, config: &PoolConfig) -> Option<Sender> {
+        let mut map = self.inner.lock().ok()?;
+        let idle = map.get_mut(key)?;
+        let timeout = Duration::from_secs(config.idle_timeout_secs);
+        // most recently used first
+        while let Some(Idle { sender, since }) = idle.pop() {
+            if since.elapsed() < timeout && !sender.is_closed() && sender.is_ready() {
+                debug!(addr = key.addr, "reuse pooled connection");
+                return Some(sender);
+            }
+        }
+        map.remove(key);
+        None
+    }
+
+    /// 当前请求的响应读完、连接重新可用后放回
+    pub fn put_when_ready(&self, key: PoolKey, mut sender: Sender, config: &PoolConfig) {
+        let pool = self.clone();
+        let config = config.clone();
+        tokio::task::spawn(async move {
+            if sender.ready().await.is_err() {
+                return;
+            }
+            let Ok(mut map) = pool.inner.lock() else {
+                return;
+            };
+            let timeout = Duration::from_secs(config.idle_timeout_secs);
+            map.retain(|_, idle| {
+                idle.retain(|i| i.since.elapsed() < timeout && !i.sender.is_closed());
+                !idle.is_empty()
+            });
+            let idle = map.entry(key).or_default();
+            if idle.len() < config.max_idle_per_host {
+                idle.push(Idle {
+                    sender,
+                    since: Instant::now(),
+                });
+            }
+        });
+    }
+}
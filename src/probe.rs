@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use http_body_util::Empty;
+use hyper::{Method, Request};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, error, info};
+
+use crate::config::{ProbeConfig, ProbeKind};
+use crate::dialer::{split_host, Dialer};
+use crate::util::create_ssl_connection;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Health {
+    pub reachable: bool,
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// 源站探测结果，key 为 `host:port`
+#[derive(Clone, Default)]
+pub struct HealthMap {
+    inner: Arc<Mutex<HashMap<String, Health>>>,
+}
+
+impl HealthMap {
+    pub fn is_reachable(&self, addr: &str) -> bool {
+        self.inner
+            .lock()
+            .map(|map| map.get(addr).is_none_or(|h| h.reachable))
+            .unwrap_or(true)
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, Health> {
+        self.inner.lock().map(|map| map.clone()).unwrap_or_default()
+    }
+
+    fn update(&self, addr: &str, health: Health) {
+        let Ok(mut map) = self.inner.lock() else {
+            return;
+        };
+        debug!(addr, latency_ms = ?health.latency_ms, "probed");
+        let was_reachable = map.get(addr).is_none_or(|h| h.reachable);
+        match (was_reachable, health.reachable) {
+            (true, false) => error!(
+                "origin {addr} became unreachable: {}",
+                health.error.as_deref().unwrap_or_default()
+            ),
+            (false, true) => info!("origin {addr} is reachable again"),
+            _ => {}
+        }
+        map.insert(addr.to_owned(), health);
+    }
+}
+
+pub fn spawn(config: ProbeConfig, dialer: Dialer, health: HealthMap) {
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs.max(1)));
+        loop {
+            interval.tick().await;
+            for addr in &config.hosts {
+                let start = Instant::now();
+                let result = tokio::time::timeout(
+                    Duration::from_secs(config.timeout_secs),
+                    probe(&dialer, addr, config.kind),
+                )
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("timed out")));
+                let (latency_ms, error) = match result {
+                    Ok(()) => (Some(start.elapsed().as_millis() as u64), None),
+                    Err(e) => (None, Some(e.to_string())),
+                };
+                health.update(
+                    addr,
+                    Health {
+                        reachable: error.is_none(),
+                        latency_ms,
+                        error,
+                    },
+                );
+            }
+        }
+    });
+}
+
+async fn probe(dialer: &Dialer, addr: &str, kind: ProbeKind) -> Result<()> {
+    let host = split_host(addr);
+    match kind {
+        ProbeKind::Tcp => {
+            dialer.connect(addr).await?;
+        }
+        ProbeKind::Tls => {
+            create_ssl_connection(dialer, addr, host).await?;
+        }
+        ProbeKind::Http if addr.ends_with(":443") => {
+            let stream = create_ssl_connection(dialer, addr, host).await?;
+            head(stream, host).await?;
+        }
+        ProbeKind::Http => {
+            let stream = dialer.connect(addr).await?;
+            head(stream, host).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn head<T>(stream: T, host: &str) -> Result<()>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::task::spawn(conn);
+    let req = Request::builder()
+        .method(Method::HEAD)
+        .uri("/")
+        .header(hyper::header::HOST, host)
+        .body(Empty::<bytes::Bytes>::new())?;
+    sender.send_request(req).await?;
+    Ok(())
+}
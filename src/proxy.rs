@@ -3,18 +3,33 @@ use std::pin::Pin;
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::header::{
+    HeaderMap, HeaderValue, CONNECTION, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, RETRY_AFTER,
+    UPGRADE,
+};
 use hyper::server::conn::http1::Builder as ServerBuilder;
 use hyper::{body::Incoming as IncomingBody, Request, Response};
 use hyper::{Method, StatusCode};
 use hyper_util::rt::TokioIo;
 use motore::{service, Service};
-use tokio::io;
-use tokio::net::TcpStream;
-use tracing::{debug, error, info};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{debug, error, info, info_span, Instrument};
 
 use crate::adapter::HyperAdapter;
+use crate::alpn;
+use crate::config::{Config, ShedMode, TunnelPolicy};
+use crate::fingerprint::Fingerprint;
+use crate::flow;
+use crate::metrics::{Metrics, Protocol};
+use crate::pcap;
+use crate::sniff::{self, Detected, Rewind};
 use crate::state::{ClientState, State};
-use crate::util::{self, create_ssl_connection, host_addr};
+use crate::supervisor;
+use crate::tcp;
+use crate::transcript::{self, Transcript};
+use crate::udp;
+use crate::util::{self, create_ssl_connection, create_ssl_connection_alpn, host_addr};
 
 #[derive(Clone)]
 pub struct Proxy<C> {
@@ -32,7 +47,7 @@ impl<C> Service<State, Request<IncomingBody>> for Proxy<C>
 where
     C: Service<
             ClientState,
-            Request<IncomingBody>,
+            Request<BoxBody<Bytes, hyper::Error>>,
             Response = Response<BoxBody<Bytes, hyper::Error>>,
             Error = hyper::Error,
         > + Clone
@@ -46,27 +61,116 @@ where
         state: &mut State,
         req: Request<IncomingBody>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let config = state.config();
+        if state.requires_auth() && !is_authorized(&config, req.headers()) {
+            Metrics::incr(&state.metrics().auth_failures);
+            let uri = config.redact.text(&req.uri().to_string()).into_owned();
+            info!(uri, "proxy authentication required");
+            let mut resp = Response::new(util::empty());
+            *resp.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+            resp.headers_mut().insert(
+                PROXY_AUTHENTICATE,
+                "Basic realm=\"http-proxy-server\"".parse().unwrap(),
+            );
+            return Ok(resp);
+        }
+        let mut req = req;
+        req.headers_mut().remove(PROXY_AUTHORIZATION);
+
+        if req.method() != Method::CONNECT {
+            if let Some(resp) = shed(state) {
+                return Ok(resp);
+            }
+        }
+
+        if let Some((host, port)) = udp::target(&req) {
+            return Ok(connect_udp(req, state, host, port).await);
+        }
+
+        if let Some((addr, _)) = host_addr(req.uri()).filter(|(addr, _)| !state.is_reachable(addr))
+        {
+            let mut resp = Response::new(util::full(format!("origin {addr} is unreachable")));
+            *resp.status_mut() = StatusCode::BAD_GATEWAY;
+            return Ok(resp);
+        }
+
+        if let (Some(conn), Some(authority)) = (state.connection(), req.uri().authority()) {
+            conn.add_target(authority.as_str());
+        }
+
+        let target = req.uri().host().unwrap_or_default().to_owned();
+        let Some(permit) = state.limits().acquire(&target).await else {
+            Metrics::incr(&state.metrics().limited_requests);
+            let mut resp = Response::new(util::full("too many concurrent requests"));
+            *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            return Ok(resp);
+        };
+
         if Method::CONNECT == req.method() {
+            Metrics::incr(&state.metrics().connect_requests);
+            let port = req.uri().port_u16().unwrap_or(443);
+            if !config.is_connect_port_allowed(port) {
+                Metrics::incr(&state.metrics().denied_connect_ports);
+                info!(%target, port, "CONNECT port denied");
+                let mut resp =
+                    Response::new(util::full(format!("CONNECT to port {port} is not allowed")));
+                *resp.status_mut() = StatusCode::FORBIDDEN;
+                return Ok(resp);
+            }
+            // parsed tunnels are checked and get the quota page per request instead,
+            // decrypted but relayed ones never meet the client layers
+            let parsed = state.is_proxy(&target) && state.is_parse_for(&target);
+            if !parsed {
+                let authority = format!("{target}:{port}");
+                if let Some(resp) = refuse_tunnel(state, "CONNECT", &target, &authority) {
+                    return Ok(resp);
+                }
+            }
+            if config.offline && !parsed {
+                let mut resp = Response::new(util::full(format!(
+                    "offline: {target} is neither intercepted nor parsed, tunnels need a live upstream"
+                )));
+                *resp.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+                return Ok(resp);
+            }
             let state = state.clone();
             let client = self.client.clone();
-            // https
-            tokio::task::spawn(async move {
-                let _ = upgrade_https(req, state, client)
-                    .await
-                    .inspect_err(|e| error!("upgrade https fail: {e}"));
-            });
+            // https, parsed requests inside get their own flow span
+            let span = info_span!("tunnel", id = %flow::next_id(), %target);
+            tokio::task::spawn(
+                async move {
+                    let _permit = permit;
+                    let tunnel = async {
+                        if let Err(e) = upgrade_https(req, state.clone(), client).await {
+                            Metrics::incr(&state.metrics().tunnel_errors);
+                            state.traffic().record_error(&target);
+                            error!("upgrade https fail: {e}");
+                        }
+                    };
+                    supervisor::contain(&state, tunnel).await;
+                }
+                .instrument(span),
+            );
 
             Ok(Response::new(util::empty()))
         } else {
             // http
+            Metrics::incr(&state.metrics().http_requests);
             if let Some((addr, host)) = host_addr(req.uri()) {
+                state.protocols().record(&host, request_protocol(&req));
                 let mut state = ClientState {
+                    id: flow::next_id(),
                     addr,
+                    transcript: state.transcript(&host).await,
+                    parse: state.is_parse_for(&host),
                     sni: host,
                     is_secure: false,
-                    parse: state.is_parse(),
+                    timings: Default::default(),
+                    shared: state.clone(),
                 };
-                self.client.call(&mut state, req).await
+                let resp = self.client.call(&mut state, req.map(|b| b.boxed())).await;
+                drop(permit);
+                resp
             } else {
                 let mut resp = Response::new(util::full("HTTP must be to socket address"));
                 *resp.status_mut() = StatusCode::NOT_ACCEPTABLE;
@@ -76,11 +180,26 @@ where
     }
 }
 
+/// `shed` 为 `reject` 且过载时，明文 HTTP 请求直接返回 503
+pub fn shed(state: &State) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    if state.limits().shed_mode() != ShedMode::Reject {
+        return None;
+    }
+    let overload = state.overloaded()?;
+    Metrics::incr(&state.metrics().shed_requests);
+    debug!("shedding request: {overload}");
+    let mut resp = Response::new(util::full(format!("overloaded: {overload}")));
+    *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    resp.headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+    Some(resp)
+}
+
 async fn upgrade_https<C>(req: Request<IncomingBody>, state: State, client: C) -> Result<()>
 where
     C: Service<
             ClientState,
-            Request<IncomingBody>,
+            Request<BoxBody<Bytes, hyper::Error>>,
             Response = Response<BoxBody<Bytes, hyper::Error>>,
             Error = hyper::Error,
         > + Clone
@@ -91,45 +210,676 @@ where
 {
     let (addr, host) = host_addr(req.uri()).ok_or(anyhow!("CONNECT must be to socket address"))?;
     let upgraded = hyper::upgrade::on(req).await?;
-    let mut upgraded = TokioIo::new(upgraded);
+    let transcript = state.transcript(&host).await;
 
-    if state.is_proxy(&host) {
-        let mut input = state.wrap_ssl_stream(upgraded, host.clone())?;
-        Pin::new(&mut input).accept().await?;
+    #[cfg(all(target_os = "linux", feature = "splice"))]
+    if transcript.is_none() && !state.config().sniff.is_needed(state.is_proxy(&host)) {
+        state.protocols().record(&host, Protocol::Tunneled);
+        let server = state.dialer().connect(&addr).await?;
+        let config = state.config();
+        let (from_client, from_server) = crate::splice::tunnel(
+            upgraded,
+            server,
+            config.timeouts.tunnel_idle_secs,
+            config.socket.copy_buffer_size,
+        )
+        .await?;
+        state.record_traffic(&host, from_client, from_server, None);
+        return Ok(());
+    }
 
-        debug!("accept success");
+    tunnel(
+        TokioIo::new(upgraded),
+        addr,
+        host,
+        transcript,
+        state,
+        client,
+    )
+    .await
+}
 
-        let sni = state.get_sni(&host);
+/// 已建立的客户端连接：需要时先嗅探开头的字节，再按 `sniff` 的策略解密、解析、转发或拒绝
+pub async fn tunnel<S, C>(
+    stream: S,
+    addr: String,
+    host: String,
+    transcript: Option<Transcript>,
+    state: State,
+    client: C,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    C: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        > + Clone
+        + Sync
+        + Send
+        + Unpin
+        + 'static,
+{
+    let mut upgraded = transcript::record(transcript.as_ref(), stream, "raw").await;
+    let config = state.config();
+    let is_proxy = state.is_proxy(&host);
+    let (detected, upgraded) = if config.sniff.is_needed(is_proxy) {
+        let hello = sniff::read_hello(&mut upgraded).await?;
+        (Some(sniff::detect(&hello)), Rewind::new(hello, upgraded))
+    } else {
+        (None, Rewind::new(Bytes::new(), upgraded))
+    };
+    let policy = detected.as_ref().map_or(TunnelPolicy::Tunnel, |detected| {
+        config.sniff.policy(detected)
+    });
+    debug!(?detected, ?policy, "sniffed");
 
-        if state.is_parse() {
-            // use hyper parse http
-            let input = TokioIo::new(input);
+    match (policy, &detected) {
+        (TunnelPolicy::Reject, Some(detected)) => {
+            info!(protocol = detected.name(), "tunnel rejected");
+            state.protocols().record(&host, Protocol::Rejected);
+            Ok(())
+        }
+        (TunnelPolicy::Intercept, Some(Detected::Tls(hello))) if is_proxy => {
+            let state = state.with_fingerprint(Fingerprint::of(hello));
+            mitm(upgraded, addr, host, transcript, state, client).await
+        }
+        (TunnelPolicy::Intercept, Some(Detected::Http)) if state.is_parse_for(&host) => {
+            state.protocols().record(&host, Protocol::H1);
             let state = ClientState {
+                id: flow::next_id(),
                 addr,
-                sni: sni.to_owned(),
-                is_secure: true,
+                sni: host,
+                is_secure: false,
                 parse: true,
+                transcript,
+                timings: Default::default(),
+                shared: state,
             };
             ServerBuilder::new()
-                .serve_connection(input, client.hyper(|req| (state, req)))
-                .without_shutdown()
+                .serve_connection(
+                    TokioIo::new(upgraded),
+                    client.hyper(|req: Request<IncomingBody>| {
+                        let state = ClientState {
+                            id: flow::next_id(),
+                            ..state
+                        };
+                        (state, req.map(|b| b.boxed()))
+                    }),
+                )
+                .with_upgrades()
                 .await?;
-        } else {
-            let mut output = create_ssl_connection(&addr, sni).await?;
-
-            debug!("connect success");
+            Ok(())
+        }
+        (TunnelPolicy::Intercept, Some(detected @ (Detected::Ssh | Detected::Other))) => {
+            tcp::relay(upgraded, &addr, &host, detected.name(), &state).await
+        }
+        _ => relay(upgraded, &addr, &host, &state).await,
+    }
+}
 
-            let (from_client, from_server) =
-                io::copy_bidirectional(&mut input, &mut output).await?;
-            info!("client wrote {from_client} bytes and received {from_server} bytes");
+/// 以 root CA 签发的证书与客户端握手，再按 `parse` 解析请求或与上游 TLS 对转
+async fn mitm<S, C>(
+    upgraded: S,
+    addr: String,
+    host: String,
+    transcript: Option<Transcript>,
+    state: State,
+    client: C,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    C: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        > + Clone
+        + Sync
+        + Send
+        + Unpin
+        + 'static,
+{
+    let sni = state.get_sni(&host);
+    let parse = state.is_parse_for(&host);
+    // 原样对转时先了解源站选择的协议，向客户端通告同样的协议
+    let (alpn, learned) = if parse {
+        (Some(alpn::HTTP1.to_vec()), None)
+    } else {
+        match state.alpn().get(&addr) {
+            Some(selected) => (selected.as_deref().map(alpn::wire), None),
+            None => {
+                let output =
+                    create_ssl_connection_alpn(&state.dialer(), &addr, &sni, alpn::OFFER).await?;
+                let selected = output.ssl().selected_alpn_protocol();
+                state.alpn().record(&addr, selected);
+                (selected.map(alpn::wire), Some(output))
+            }
         }
+    };
+    let mut input = state.wrap_ssl_stream(upgraded, host.clone(), alpn.as_deref())?;
+    let handshake_secs = state.config().timeouts.tls_handshake_secs;
+    let accepted: Result<()> = async {
+        util::timeout(handshake_secs, Pin::new(&mut input).accept()).await??;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = accepted {
+        state.protocols().record(&host, Protocol::MitmFailed);
+        return Err(e);
+    }
+
+    debug!(resumed = input.ssl().session_reused(), "accept success");
+    if input.ssl().session_reused() {
+        Metrics::incr(&state.metrics().tls_resumed);
+    }
+
+    let chosen = input.ssl().selected_alpn_protocol().map(<[u8]>::to_vec);
+    let protocol = match chosen.as_deref() {
+        Some(b"h2") => Protocol::H2,
+        _ => Protocol::H1,
+    };
+
+    let input = transcript::record(transcript.as_ref(), input, "tls").await;
+    let mut input = pcap::record(state.pcap(), input).await;
+
+    if parse {
+        // use hyper parse http
+        let input = TokioIo::new(input);
+        let protocols = state.protocols().clone();
+        let state = ClientState {
+            id: flow::next_id(),
+            addr,
+            sni: sni.clone(),
+            is_secure: true,
+            parse: true,
+            transcript,
+            timings: Default::default(),
+            shared: state.clone(),
+        };
+        ServerBuilder::new()
+            .serve_connection(
+                input,
+                client.hyper(|req: Request<IncomingBody>| {
+                    protocols.record(&state.sni, request_protocol(&req));
+                    // one flow per request on the same connection
+                    let state = ClientState {
+                        id: flow::next_id(),
+                        ..state
+                    };
+                    (state, req.map(|b| b.boxed()))
+                }),
+            )
+            .without_shutdown()
+            .await?;
     } else {
-        // Connect to remote server
-        let mut server = TcpStream::connect(addr).await?;
+        state.protocols().record(&host, protocol);
+        // 客户端没有选择源站的协议时按客户端的选择重新连接
+        let mut output = match learned {
+            Some(output) if output.ssl().selected_alpn_protocol() == chosen.as_deref() => output,
+            _ => match &chosen {
+                Some(chosen) => {
+                    create_ssl_connection_alpn(&state.dialer(), &addr, &sni, &alpn::wire(chosen))
+                        .await?
+                }
+                None => create_ssl_connection(&state.dialer(), &addr, &sni).await?,
+            },
+        };
+        let warn_days = state.config().upstream_cert_warn_days;
+        state
+            .upstream_certs()
+            .record(&host, output.ssl(), warn_days);
+
+        debug!("connect success");
+
+        let config = state.config();
+        let (from_client, from_server) = util::copy_bidirectional_idle(
+            &mut input,
+            &mut output,
+            config.timeouts.tunnel_idle_secs,
+            config.socket.copy_buffer_size,
+        )
+        .await?;
+        state.record_traffic(&host, from_client, from_server, None);
+    }
+    Ok(())
+}
+
+/// 解析目标后以 101 升级，在升级后的连接上转发 UDP 报文
+async fn connect_udp<B: Send + 'static>(
+    req: Request<B>,
+    state: &State,
+    host: String,
+    port: u16,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let config = state.config();
+    Metrics::incr(&state.metrics().connect_requests);
+    if !config.is_connect_port_allowed(port) {
+        Metrics::incr(&state.metrics().denied_connect_ports);
+        info!(target = %host, port, "CONNECT-UDP port denied");
+        let mut resp = Response::new(util::full(format!(
+            "CONNECT-UDP to port {port} is not allowed"
+        )));
+        *resp.status_mut() = StatusCode::FORBIDDEN;
+        return resp;
+    }
+    let authority = if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    };
+    if let Some(resp) = refuse_tunnel(state, "CONNECT-UDP", &host, &authority) {
+        return resp;
+    }
+    if config.offline {
+        let mut resp = Response::new(util::full(format!(
+            "offline: UDP tunnels to {authority} need a live upstream"
+        )));
+        *resp.status_mut() = StatusCode::GATEWAY_TIMEOUT;
+        return resp;
+    }
+    let addr = match state
+        .dialer()
+        .resolve(&authority, &mut Default::default())
+        .await
+    {
+        Ok(addrs) if !addrs.is_empty() => addrs[0],
+        result => {
+            let reason = result
+                .err()
+                .map_or("no address".to_owned(), |e| e.to_string());
+            let mut resp = Response::new(util::full(format!("resolve {authority} fail: {reason}")));
+            *resp.status_mut() = StatusCode::BAD_GATEWAY;
+            return resp;
+        }
+    };
+    // a direct UDP relay would bypass the proxy the rules require
+    if let Some(proxy) = state.dialer().upstream_proxy(&host, addr) {
+        info!(target = %authority, upstream = proxy.addr, "CONNECT-UDP via upstream refused");
+        let mut resp = Response::new(util::full(format!(
+            "{authority} is routed through upstream proxy {}, which can't relay UDP",
+            proxy.addr
+        )));
+        *resp.status_mut() = StatusCode::BAD_GATEWAY;
+        return resp;
+    }
+
+    if let Some(conn) = state.connection() {
+        conn.add_target(&authority);
+    }
+    let Some(permit) = state.limits().acquire(&host).await else {
+        Metrics::incr(&state.metrics().limited_requests);
+        let mut resp = Response::new(util::full("too many concurrent requests"));
+        *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        return resp;
+    };
 
-        // Proxying data
-        let (from_client, from_server) = io::copy_bidirectional(&mut upgraded, &mut server).await?;
-        info!("client wrote {from_client} bytes and received {from_server} bytes");
+    let state = state.clone();
+    let span = info_span!("udp", id = %flow::next_id(), target = %authority);
+    tokio::task::spawn(
+        async move {
+            let _permit = permit;
+            state.protocols().record(&host, Protocol::Udp);
+            let tunnel = async {
+                let result = async {
+                    let upgraded = hyper::upgrade::on(req).await?;
+                    let idle_secs = state.config().timeouts.tunnel_idle_secs;
+                    Ok::<_, anyhow::Error>(
+                        udp::relay(TokioIo::new(upgraded), addr, idle_secs).await?,
+                    )
+                };
+                match result.await {
+                    Ok((from_client, from_server)) => {
+                        state.record_traffic(&host, from_client, from_server, None)
+                    }
+                    Err(e) => {
+                        Metrics::incr(&state.metrics().tunnel_errors);
+                        state.traffic().record_error(&host);
+                        error!("connect-udp fail: {e}");
+                    }
+                }
+            };
+            supervisor::contain(&state, tunnel).await;
+        }
+        .instrument(span),
+    );
+
+    let mut resp = Response::new(util::empty());
+    *resp.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = resp.headers_mut();
+    headers.insert(UPGRADE, "connect-udp".parse().unwrap());
+    headers.insert(CONNECTION, "Upgrade".parse().unwrap());
+    headers.insert("capsule-protocol", "?1".parse().unwrap());
+    resp
+}
+
+/// 不解析的隧道：`expect` 阻止的目标与超出配额时返回拒绝的响应
+pub fn refuse_tunnel(
+    state: &State,
+    method: &str,
+    host: &str,
+    authority: &str,
+) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+    let block = state
+        .config()
+        .expect
+        .as_ref()
+        .is_some_and(|expect| expect.block);
+    if !state.expect(method, authority) {
+        info!(authority, "unexpected tunnel");
+        if block {
+            let mut resp = Response::new(util::full(format!(
+                "unexpected tunnel to {authority} is not in the expect allowlist"
+            )));
+            *resp.status_mut() = StatusCode::FORBIDDEN;
+            return Some(resp);
+        }
     }
+    let exceeded = state.quota_exceeded(host)?;
+    info!(subject = exceeded.subject, "quota exceeded");
+    let mut resp = Response::new(util::full(format!(
+        "data quota of {} exceeded: {} of {} bytes",
+        exceeded.subject, exceeded.used, exceeded.limit
+    )));
+    *resp.status_mut() = StatusCode::FORBIDDEN;
+    Some(resp)
+}
+
+/// 原样转发到 `addr`
+async fn relay<S>(mut upgraded: S, addr: &str, host: &str, state: &State) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    state.protocols().record(host, Protocol::Tunneled);
+    let mut server = state.dialer().connect(addr).await?;
+
+    let config = state.config();
+    let (from_client, from_server) = util::copy_bidirectional_idle(
+        &mut upgraded,
+        &mut server,
+        config.timeouts.tunnel_idle_secs,
+        config.socket.copy_buffer_size,
+    )
+    .await?;
+    state.record_traffic(host, from_client, from_server, None);
     Ok(())
 }
+
+/// 未配置用户名时不需要认证
+fn is_authorized(config: &Config, headers: &HeaderMap) -> bool {
+    let Some(username) = &config.username else {
+        return true;
+    };
+    let expected = format!(
+        "{username}:{}",
+        config.password.as_deref().unwrap_or_default()
+    );
+    headers
+        .get(PROXY_AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| openssl::base64::decode_block(v.trim()).ok())
+        .is_some_and(|credentials| {
+            credentials.len() == expected.len()
+                && openssl::memcmp::eq(&credentials, expected.as_bytes())
+        })
+}
+
+/// 解析的请求按请求计数，隧道与 MITM 失败按连接计数
+pub fn request_protocol<B>(req: &Request<B>) -> Protocol {
+    let websocket = req
+        .headers()
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    if websocket {
+        Protocol::WebSocket
+    } else {
+        Protocol::H1
+    }
+}
+
+#[test]
+fn should_authorize() {
+    let mut config = Config::default();
+    let mut headers = HeaderMap::new();
+    assert!(is_authorized(&config, &headers));
+
+    config.username = Some("user".to_owned());
+    config.password = Some("pass".to_owned());
+    assert!(!is_authorized(&config, &headers));
+
+    let basic = |credentials: &str| {
+        format!(
+            "Basic {}",
+            openssl::base64::encode_block(credentials.as_bytes())
+        )
+        .parse()
+        .unwrap()
+    };
+    headers.insert(PROXY_AUTHORIZATION, basic("user:wrong"));
+    assert!(!is_authorized(&config, &headers));
+    headers.insert(PROXY_AUTHORIZATION, basic("user:pass"));
+    assert!(is_authorized(&config, &headers));
+}
+
+#[tokio::test]
+async fn should_check_udp_tunnels() {
+    let udp = |port: u16| {
+        Request::get(format!("/.well-known/masque/udp/127.0.0.1/{port}/"))
+            .header(UPGRADE, "connect-udp")
+            .body(())
+            .unwrap()
+    };
+    let config = Config {
+        allow_connect_ports: ["53".parse().unwrap(), "443".parse().unwrap()].to_vec(),
+        expect: Some(crate::config::ExpectConfig {
+            allow: ["CONNECT-UDP 127.0.0.1:53".to_owned()].to_vec(),
+            block: true,
+            ..Default::default()
+        }),
+        offline: true,
+        upstream_proxies: [crate::config::UpstreamProxy {
+            addr: "127.0.0.1:1080".to_owned(),
+            kind: crate::config::UpstreamKind::Socks5,
+            hosts: ["localhost".to_owned()].to_vec(),
+            ..Default::default()
+        }]
+        .to_vec(),
+        ..Default::default()
+    };
+    let state = State::new(config.clone()).await.unwrap();
+    let status = |req: Request<()>| async {
+        let (host, port) = udp::target(&req).unwrap();
+        connect_udp(req, &state, host, port).await.status()
+    };
+    assert_eq!(status(udp(443)).await, StatusCode::FORBIDDEN);
+    assert!(state
+        .expectations()
+        .report()
+        .unexpected
+        .iter()
+        .any(|request| request.url == "127.0.0.1:443"));
+    // allowed by expect, but offline
+    assert_eq!(status(udp(53)).await, StatusCode::GATEWAY_TIMEOUT);
+
+    let state = State::new(Config {
+        expect: None,
+        offline: false,
+        ..config
+    })
+    .await
+    .unwrap();
+    let to = |host: &str| {
+        Request::get(format!("/.well-known/masque/udp/{host}/53/"))
+            .header(UPGRADE, "connect-udp")
+            .body(())
+            .unwrap()
+    };
+    let status = |req: Request<()>| async {
+        let (host, port) = udp::target(&req).unwrap();
+        connect_udp(req, &state, host, port).await.status()
+    };
+    // SOCKS routes the host, a direct relay would leak around it
+    assert_eq!(status(to("localhost")).await, StatusCode::BAD_GATEWAY);
+    assert_eq!(
+        status(to("127.0.0.1")).await,
+        StatusCode::SWITCHING_PROTOCOLS
+    );
+}
+
+/// 嗅探出的协议按 `sniff` 的策略拒绝、解析或原样转发
+#[tokio::test]
+async fn should_apply_sniffed_protocol_policy() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::{SniffConfig, TunnelPolicy};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin = listener.local_addr().unwrap().to_string();
+    let upstream = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        received
+    });
+
+    let state = State::new(Config {
+        parse: true,
+        sniff: SniffConfig {
+            http: TunnelPolicy::Intercept,
+            ssh: TunnelPolicy::Reject,
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    // hyper closes without answering once the client half-closes
+    let open = |addr: &str, host: &str, data: &'static [u8], half_close: bool| {
+        let (mut client, stream) = tokio::io::duplex(1024);
+        let tunnel = tunnel(
+            stream,
+            addr.to_owned(),
+            host.to_owned(),
+            None,
+            state.clone(),
+            crate::client::service(),
+        );
+        async move {
+            let tunnel = tokio::spawn(tunnel);
+            client.write_all(data).await.unwrap();
+            if half_close {
+                client.shutdown().await.unwrap();
+            }
+            let mut resp = Vec::new();
+            client.read_to_end(&mut resp).await.unwrap();
+            tunnel.await.unwrap().unwrap();
+            String::from_utf8(resp).unwrap()
+        }
+    };
+
+    // rejected before reaching any upstream
+    assert_eq!(
+        open(&origin, "127.0.0.1", b"SSH-2.0-test\r\n", false).await,
+        ""
+    );
+    let protocols = state.protocols().snapshot();
+    assert_eq!(protocols["127.0.0.1"].rejected, 1);
+
+    // parsed and answered by the built-in echo origin
+    let resp = open(
+        "proxy.test:80",
+        "proxy.test",
+        b"GET /status/204 HTTP/1.1\r\nHost: proxy.test\r\nConnection: close\r\n\r\n",
+        false,
+    )
+    .await;
+    assert!(resp.starts_with("HTTP/1.1 204"), "{resp}");
+    assert_eq!(state.protocols().snapshot()["proxy.test"].h1, 1);
+
+    // anything else is relayed as is
+    open(&origin, "127.0.0.1", b"\x00\x01binary", true).await;
+    assert_eq!(upstream.await.unwrap(), b"\x00\x01binary");
+}
+
+/// 解密但不解析的隧道不会经过 `ExpectLayer`，CONNECT 时就要检查
+#[tokio::test]
+async fn should_expect_unparsed_tunnels() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::listener::{self, Listener};
+
+    let state = State::new(Config {
+        intercept: true,
+        proxy_hosts: ["example.com".to_owned()].to_vec(),
+        parse: false,
+        expect: Some(crate::config::ExpectConfig {
+            block: true,
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let listener = Listener::bind_tcp("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(listener::supervise(listener, 0, state.clone(), None));
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = vec![0; 12];
+    stream.read_exact(&mut resp).await.unwrap();
+    assert_eq!(resp, b"HTTP/1.1 403");
+    assert!(state
+        .expectations()
+        .report()
+        .unexpected
+        .iter()
+        .any(|request| request.url == "example.com:443"));
+}
+
+/// 解密但不解析的隧道不会经过 `QuotaLayer`，超出配额时 CONNECT 就被拒绝
+#[tokio::test]
+async fn should_refuse_unparsed_tunnels_over_quota() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::listener::{self, Listener};
+
+    let state = State::new(Config {
+        intercept: true,
+        proxy_hosts: ["example.com".to_owned()].to_vec(),
+        parse: false,
+        quota: crate::config::QuotaConfig {
+            enabled: true,
+            client_bytes: 10,
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    state
+        .quotas()
+        .record(Some("127.0.0.1".parse().unwrap()), "other.com", 10);
+    let listener = Listener::bind_tcp("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(listener::supervise(listener, 0, state, None));
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+        .await
+        .unwrap();
+    let mut resp = vec![0; 12];
+    stream.read_exact(&mut resp).await.unwrap();
+    assert_eq!(resp, b"HTTP/1.1 403");
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::config::QuotaConfig;
+
+const PAGE: &str = include_str!("quota.html");
+
+/// 累计的字节数（上下行合计）
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Usage {
+    /// 客户端 IP -> 字节数
+    pub clients: HashMap<String, u64>,
+    /// host -> 字节数
+    pub hosts: HashMap<String, u64>,
+}
+
+/// 超出的额度
+#[derive(Debug, PartialEq)]
+pub struct Exceeded {
+    /// 如 `client 192.168.1.2` 或 `host example.com`
+    pub subject: String,
+    pub used: u64,
+    pub limit: u64,
+}
+
+/// `quota` 的计数，设置 `path` 时启动时载入、定期与退出时写回
+#[derive(Clone, Default)]
+pub struct Quotas {
+    usage: Arc<Mutex<Usage>>,
+    dirty: Arc<AtomicBool>,
+}
+
+impl Quotas {
+    /// 文件不存在时从零开始
+    pub async fn load(path: &Path) -> Result<Self> {
+        let usage = if path.exists() {
+            serde_json::from_slice(&tokio::fs::read(path).await?)?
+        } else {
+            Usage::default()
+        };
+        Ok(Self {
+            usage: Arc::new(Mutex::new(usage)),
+            dirty: Arc::default(),
+        })
+    }
+
+    pub fn record(&self, client: Option<IpAddr>, host: &str, bytes: u64) {
+        if bytes == 0 {
+            return;
+        }
+        let Ok(mut usage) = self.usage.lock() else {
+            return;
+        };
+        if let Some(client) = client {
+            *usage.clients.entry(client.to_string()).or_default() += bytes;
+        }
+        *usage.hosts.entry(host.to_owned()).or_default() += bytes;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// 先检查客户端再检查 host
+    pub fn exceeded(
+        &self,
+        config: &QuotaConfig,
+        client: Option<IpAddr>,
+        host: &str,
+    ) -> Option<Exceeded> {
+        let usage = self.usage.lock().ok()?;
+        let client = client.map(|ip| ip.to_string());
+        let checks = [
+            client.as_deref().map(|ip| {
+                let used = usage.clients.get(ip).copied().unwrap_or_default();
+                (format!("client {ip}"), used, config.client_limit(ip))
+            }),
+            Some((
+                format!("host {host}"),
+                usage.hosts.get(host).copied().unwrap_or_default(),
+                config.host_limit(host),
+            )),
+        ];
+        checks
+            .into_iter()
+            .flatten()
+            .find(|(_, used, limit)| *limit > 0 && used >= limit)
+            .map(|(subject, used, limit)| Exceeded {
+                subject,
+                used,
+                limit,
+            })
+    }
+
+    pub fn snapshot(&self) -> Usage {
+        self.usage
+            .lock()
+            .map(|usage| usage.clone())
+            .unwrap_or_default()
+    }
+
+    /// 返回清除的计数数量
+    pub fn reset(&self) -> usize {
+        let Ok(mut usage) = self.usage.lock() else {
+            return 0;
+        };
+        let count = usage.clients.len() + usage.hosts.len();
+        *usage = Usage::default();
+        self.dirty.store(true, Ordering::Relaxed);
+        count
+    }
+
+    /// 有变化时写回，先写临时文件再改名
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let json = serde_json::to_vec_pretty(&self.snapshot())?;
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, json).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    pub fn spawn_saver(&self, path: &Path, interval_secs: u64) {
+        let quotas = self.clone();
+        let path = path.to_owned();
+        let period = Duration::from_secs(interval_secs.max(1));
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if let Err(e) = quotas.save(&path).await {
+                    error!("save quota usage to {} failed: {e}", path.display());
+                }
+            }
+        });
+    }
+}
+
+/// 超出额度时的页面，`custom` 读取失败时使用内置页面
+pub fn page(custom: Option<&Path>, exceeded: &Exceeded) -> String {
+    let template = custom
+        .and_then(|path| {
+            std::fs::read_to_string(path)
+                .inspect_err(|e| error!("read quota page {} failed: {e}", path.display()))
+                .ok()
+        })
+        .unwrap_or_else(|| PAGE.to_owned());
+    template
+        .replace("{{subject}}", &escape(&exceeded.subject))
+        .replace("{{used}}", &exceeded.used.to_string())
+        .replace("{{limit}}", &exceeded.limit.to_string())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[test]
+fn should_enforce_quota() {
+    let config = QuotaConfig {
+        enabled: true,
+        client_bytes: 100,
+        hosts: [("example.com".to_owned(), 50)].into(),
+        ..Default::default()
+    };
+    let quotas = Quotas::default();
+    let client: IpAddr = "192.168.1.2".parse().unwrap();
+    quotas.record(Some(client), "a.example.com", 40);
+    assert!(quotas
+        .exceeded(&config, Some(client), "a.example.com")
+        .is_none());
+    quotas.record(Some(client), "a.example.com", 10);
+    let exceeded = quotas
+        .exceeded(&config, Some(client), "a.example.com")
+        .unwrap();
+    assert_eq!(exceeded.subject, "host a.example.com");
+    // other hosts only count against the client
+    assert!(quotas
+        .exceeded(&config, Some(client), "other.org")
+        .is_none());
+    quotas.record(Some(client), "other.org", 50);
+    let exceeded = quotas.exceeded(&config, Some(client), "other.org").unwrap();
+    assert_eq!(exceeded.subject, "client 192.168.1.2");
+    assert!(page(None, &exceeded).contains("client 192.168.1.2"));
+
+    assert_eq!(quotas.reset(), 3);
+    assert!(quotas
+        .exceeded(&config, Some(client), "other.org")
+        .is_none());
+}
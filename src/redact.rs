@@ -0,0 +1,117 @@
+use std::borrow::Cow;
+
+use bytes::Bytes;
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::RedactConfig;
+
+pub const MASK: &str = "[REDACTED]";
+
+/// 配置里的正则，加载配置时编译
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pattern(Regex);
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Regex::new(&value).map(Self)
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(value: Pattern) -> Self {
+        value.0.as_str().to_owned()
+    }
+}
+
+impl RedactConfig {
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty() && self.json_fields.is_empty() && self.patterns.is_empty()
+    }
+
+    /// `body` 会改写内容
+    pub fn redacts_bodies(&self) -> bool {
+        !self.json_fields.is_empty() || !self.patterns.is_empty()
+    }
+
+    pub fn header<'a>(&self, name: &str, value: &'a str) -> Cow<'a, str> {
+        if self.headers.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+            Cow::Borrowed(MASK)
+        } else {
+            self.text(value)
+        }
+    }
+
+    /// 只应用 `patterns`，用于 URI 等文本
+    pub fn text<'a>(&self, value: &'a str) -> Cow<'a, str> {
+        if self.patterns.is_empty() {
+            return Cow::Borrowed(value);
+        }
+        let redacted = self
+            .patterns
+            .iter()
+            .fold(value.as_bytes().to_vec(), |text, p| {
+                p.0.replace_all(&text, MASK.as_bytes()).into_owned()
+            });
+        Cow::Owned(String::from_utf8_lossy(&redacted).into_owned())
+    }
+
+    /// JSON body 按字段名替换值，再应用 `patterns`；截断的 JSON 只应用 `patterns`
+    pub fn body(&self, body: Bytes) -> Bytes {
+        if self.is_empty() || body.is_empty() {
+            return body;
+        }
+        let mut body = body;
+        if !self.json_fields.is_empty() {
+            if let Ok(mut value) = serde_json::from_slice::<Value>(&body) {
+                self.json(&mut value);
+                if let Ok(json) = serde_json::to_vec(&value) {
+                    body = json.into();
+                }
+            }
+        }
+        for pattern in &self.patterns {
+            if let Cow::Owned(replaced) = pattern.0.replace_all(&body, MASK.as_bytes()) {
+                body = replaced.into();
+            }
+        }
+        body
+    }
+
+    /// 按 `json_fields` 替换任意层级的字段值
+    pub fn json(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter_mut() {
+                    if self.json_fields.iter().any(|f| f.eq_ignore_ascii_case(key)) {
+                        *value = Value::String(MASK.to_owned());
+                    } else {
+                        self.json(value);
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|value| self.json(value)),
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn should_redact() {
+    let config: RedactConfig = serde_json::from_str(
+        r#"{"headers":["Authorization"],"json_fields":["password"],"patterns":["token=[^&]+"]}"#,
+    )
+    .unwrap();
+    assert_eq!(config.header("authorization", "Bearer x"), MASK);
+    assert_eq!(config.header("accept", "*/*"), "*/*");
+    assert_eq!(config.text("/a?token=abc&b=1"), "/a?[REDACTED]&b=1");
+    assert_eq!(
+        config.body(Bytes::from(r#"{"user":{"name":"a","password":"b"}}"#)),
+        r#"{"user":{"name":"a","password":"[REDACTED]"}}"#
+    );
+    assert!(serde_json::from_str::<RedactConfig>(r#"{"patterns":["("]}"#).is_err());
+}
@@ -0,0 +1,240 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::{HeaderMap, Request};
+use motore::Service;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::client;
+use crate::flow::{self, Flow};
+use crate::redact::MASK;
+use crate::state::{ClientState, State};
+
+/// 重放结果，重放本身也会作为新的 flow 记录
+#[derive(Serialize, Debug)]
+pub struct Replayed {
+    pub flow: Ulid,
+    pub replay: Ulid,
+    pub status: u16,
+    pub duration_ms: u64,
+    #[serde(skip)]
+    pub body: Bytes,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Assertion {
+    StatusEquals(u16),
+    BodyContains(String),
+    LatencyUnderMs(u64),
+}
+
+impl Assertion {
+    fn check(&self, replayed: &Replayed) -> Option<String> {
+        match self {
+            Self::StatusEquals(status) if replayed.status != *status => {
+                Some(format!("status {} != {status}", replayed.status))
+            }
+            Self::BodyContains(text)
+                if !String::from_utf8_lossy(&replayed.body).contains(text.as_str()) =>
+            {
+                Some(format!("body does not contain {text:?}"))
+            }
+            Self::LatencyUnderMs(limit) if replayed.duration_ms >= *limit => {
+                Some(format!("latency {}ms >= {limit}ms", replayed.duration_ms))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Batch {
+    pub flows: Vec<Ulid>,
+    #[serde(default)]
+    pub assertions: Vec<Assertion>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Outcome {
+    pub flow: Ulid,
+    pub passed: bool,
+    pub failures: Vec<String>,
+    pub result: Option<Replayed>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub passed: usize,
+    pub failed: usize,
+    pub outcomes: Vec<Outcome>,
+}
+
+pub async fn replay(state: &State, flow: &Flow) -> Result<Replayed> {
+    let req = request(flow)?;
+    let mut client_state = ClientState {
+        id: flow::next_id(),
+        addr: flow.addr.clone(),
+        sni: flow.host.clone(),
+        is_secure: flow.secure,
+        parse: true,
+        transcript: None,
+        timings: Default::default(),
+        shared: state.clone(),
+    };
+    let start = Instant::now();
+    let resp = client::service().call(&mut client_state, req).await?;
+    state
+        .flows()
+        .update(client_state.id, |replay| replay.origin = Some(flow.id));
+    let status = resp.status().as_u16();
+    let body = resp.into_body().collect().await?.to_bytes();
+    // the whole response, not just its headers
+    let duration_ms = duration_ms(start.elapsed());
+    Ok(Replayed {
+        flow: flow.id,
+        replay: client_state.id,
+        status,
+        duration_ms,
+        body,
+    })
+}
+
+/// 按记录重建请求，TCP 隧道、body 被截断与记录时被 `redact` 遮盖的请求无法重建
+pub fn request(flow: &Flow) -> Result<Request<BoxBody<Bytes, hyper::Error>>> {
+    if flow.is_tcp() {
+        return Err(anyhow!("{} is a TCP tunnel, not a request", flow.id));
+    }
+    if let Some(part) = redacted(flow) {
+        return Err(anyhow!(
+            "{part} of {} was redacted when recorded, replaying it would send {MASK}",
+            flow.id
+        ));
+    }
+    let Some(body) = flow.full_request_body() else {
+        return Err(anyhow!(
+            "request body of {} was truncated by flow_body_limit",
+            flow.id
+        ));
+    };
+    let mut req = Request::builder()
+        .method(flow.method.as_str())
+        .uri(flow.uri.as_str())
+        .body(body)?;
+    *req.headers_mut() = headers(&flow.request_headers)?;
+    Ok(req)
+}
+
+fn redacted(flow: &Flow) -> Option<String> {
+    if flow.uri.contains(MASK) {
+        return Some("URI".to_owned());
+    }
+    if let Some((name, _)) = flow
+        .request_headers
+        .iter()
+        .find(|(_, value)| value.contains(MASK))
+    {
+        return Some(format!("header {name}"));
+    }
+    flow.request_body
+        .windows(MASK.len())
+        .any(|window| window == MASK.as_bytes())
+        .then(|| "request body".to_owned())
+}
+
+/// 依次重放并检查断言
+pub async fn run_batch(state: &State, batch: Batch) -> Report {
+    let mut outcomes = Vec::with_capacity(batch.flows.len());
+    for id in batch.flows {
+        let result = match state.flows().get(id) {
+            Some(flow) => replay(state, &flow).await,
+            None => Err(anyhow!("flow {id} not found")),
+        };
+        let outcome = match result {
+            Ok(replayed) => {
+                let failures: Vec<_> = batch
+                    .assertions
+                    .iter()
+                    .filter_map(|assertion| assertion.check(&replayed))
+                    .collect();
+                Outcome {
+                    flow: id,
+                    passed: failures.is_empty(),
+                    failures,
+                    result: Some(replayed),
+                }
+            }
+            Err(e) => Outcome {
+                flow: id,
+                passed: false,
+                failures: vec![e.to_string()],
+                result: None,
+            },
+        };
+        outcomes.push(outcome);
+    }
+    let passed = outcomes.iter().filter(|o| o.passed).count();
+    Report {
+        passed,
+        failed: outcomes.len() - passed,
+        outcomes,
+    }
+}
+
+pub fn headers(list: &[(String, String)]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in list {
+        map.append(
+            hyper::header::HeaderName::from_bytes(name.as_bytes())?,
+            value.parse()?,
+        );
+    }
+    Ok(map)
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+#[test]
+fn should_check_assertions() {
+    let replayed = Replayed {
+        flow: Ulid::nil(),
+        replay: Ulid::nil(),
+        status: 500,
+        duration_ms: 20,
+        body: Bytes::from_static(b"internal error"),
+    };
+    assert!(Assertion::StatusEquals(500).check(&replayed).is_none());
+    assert!(Assertion::StatusEquals(200).check(&replayed).is_some());
+    assert!(Assertion::BodyContains("error".into())
+        .check(&replayed)
+        .is_none());
+    assert!(Assertion::LatencyUnderMs(10).check(&replayed).is_some());
+}
+
+#[test]
+fn should_refuse_redacted_flows() {
+    let flow = Flow {
+        method: "GET".to_owned(),
+        uri: "http://example.com/".to_owned(),
+        request_headers: [("authorization".to_owned(), "Bearer t0ken".to_owned())].to_vec(),
+        ..Default::default()
+    };
+    assert!(request(&flow).is_ok());
+    let masked = Flow {
+        request_headers: [("authorization".to_owned(), MASK.to_owned())].to_vec(),
+        ..flow.clone()
+    };
+    let e = request(&masked).unwrap_err().to_string();
+    assert!(e.contains("header authorization"), "{e}");
+    let masked = Flow {
+        uri: format!("http://example.com/?token={MASK}"),
+        ..flow
+    };
+    assert!(request(&masked).is_err());
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::TokioAsyncResolver;
+use tracing::{debug, error};
+
+use crate::config::{DnsConfig, DnsProtocol};
+use crate::metrics::Metrics;
+
+/// 缓存的条目数上限，超出时先清理过期条目
+const CACHE_LIMIT: usize = 4096;
+
+struct Entry {
+    result: Result<Vec<IpAddr>, String>,
+    expires: Instant,
+}
+
+/// 异步 DNS 解析，带正/负缓存，所有出站连接共用
+#[derive(Clone)]
+pub struct Resolver {
+    inner: TokioAsyncResolver,
+    cache: Arc<Mutex<HashMap<String, Entry>>>,
+    config: DnsConfig,
+    metrics: Arc<Metrics>,
+}
+
+impl Resolver {
+    /// `nameservers` 为空时使用系统配置；DoT/DoH 缺少 `tls_name` 时报错
+    pub fn new(config: &DnsConfig, metrics: Arc<Metrics>) -> anyhow::Result<Self> {
+        config
+            .check()
+            .map_err(|e| anyhow::anyhow!("dns.tls_name: {e}"))?;
+        let mut opts = ResolverOpts::default();
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        // cached here, with our own TTL bounds
+        opts.cache_size = 0;
+        let inner = if config.nameservers.is_empty() {
+            match hickory_resolver::system_conf::read_system_conf() {
+                Ok((resolver_config, _)) => TokioAsyncResolver::tokio(resolver_config, opts),
+                Err(e) => {
+                    error!("Read system DNS config failed, using defaults: {e}");
+                    TokioAsyncResolver::tokio(ResolverConfig::default(), opts)
+                }
+            }
+        } else {
+            let (protocols, port): (&[Protocol], u16) = match config.protocol {
+                DnsProtocol::Udp => (&[Protocol::Udp, Protocol::Tcp], 53),
+                DnsProtocol::Tls => (&[Protocol::Tls], 853),
+                DnsProtocol::Https => (&[Protocol::Https], 443),
+            };
+            let mut resolver_config = ResolverConfig::new();
+            for nameserver in &config.nameservers {
+                let Some(addr) = parse_nameserver(nameserver, port) else {
+                    error!("Invalid nameserver `{nameserver}`");
+                    continue;
+                };
+                for protocol in protocols {
+                    let mut nameserver = NameServerConfig::new(addr, *protocol);
+                    nameserver.tls_dns_name = config.tls_name.clone();
+                    resolver_config.add_name_server(nameserver);
+                }
+            }
+            TokioAsyncResolver::tokio(resolver_config, opts)
+        };
+        Ok(Self {
+            inner,
+            cache: Arc::default(),
+            config: config.clone(),
+            metrics,
+        })
+    }
+
+    pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![ip]);
+        }
+        Metrics::incr(&self.metrics.dns_lookups);
+        if let Some(result) = self.cached(host) {
+            Metrics::incr(&self.metrics.dns_cache_hits);
+            return result.map_err(|e| Error::new(ErrorKind::NotFound, e));
+        }
+
+        let (result, ttl) = match self.inner.lookup_ip(host).await {
+            Ok(lookup) => {
+                let ttl = lookup
+                    .valid_until()
+                    .saturating_duration_since(Instant::now())
+                    .min(Duration::from_secs(self.config.max_ttl_secs));
+                (Ok(lookup.iter().collect::<Vec<_>>()), ttl)
+            }
+            Err(e) => {
+                Metrics::incr(&self.metrics.dns_failures);
+                (
+                    Err(format!("resolve {host} failed: {e}")),
+                    Duration::from_secs(self.config.negative_ttl_secs),
+                )
+            }
+        };
+        debug!(host, ?result, ?ttl, "resolved");
+        self.store(host, result.clone(), ttl);
+        result.map_err(|e| Error::new(ErrorKind::NotFound, e))
+    }
+
+    fn cached(&self, host: &str) -> Option<Result<Vec<IpAddr>, String>> {
+        let cache = self.cache.lock().ok()?;
+        cache
+            .get(host)
+            .filter(|entry| entry.expires > Instant::now())
+            .map(|entry| entry.result.clone())
+    }
+
+    fn store(&self, host: &str, result: Result<Vec<IpAddr>, String>, ttl: Duration) {
+        if ttl.is_zero() {
+            return;
+        }
+        let Ok(mut cache) = self.cache.lock() else {
+            return;
+        };
+        if cache.len() >= CACHE_LIMIT {
+            let now = Instant::now();
+            cache.retain(|_, entry| entry.expires > now);
+            if cache.len() >= CACHE_LIMIT {
+                cache.clear();
+            }
+        }
+        cache.insert(
+            host.to_owned(),
+            Entry {
+                result,
+                expires: Instant::now() + ttl,
+            },
+        );
+    }
+}
+
+/// `1.1.1.1` 或 `1.1.1.1:53`，省略端口时使用协议的默认端口
+pub fn parse_nameserver(nameserver: &str, port: u16) -> Option<SocketAddr> {
+    nameserver.parse().ok().or_else(|| {
+        nameserver
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| (ip, port).into())
+    })
+}
+
+#[tokio::test]
+async fn should_cache_answers_and_failures() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::net::UdpSocket;
+
+    // answers `found.test` with 10.0.0.1 and everything else with NXDOMAIN
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let nameserver = socket.local_addr().unwrap();
+    let queries = Arc::new(AtomicUsize::new(0));
+    let counter = queries.clone();
+    tokio::spawn(async move {
+        let mut buf = [0; 512];
+        loop {
+            let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+            counter.fetch_add(1, Ordering::Relaxed);
+            let query = &buf[..n];
+            let mut end = 12;
+            while query[end] != 0 {
+                end += query[end] as usize + 1;
+            }
+            let name = &query[12..end];
+            let qtype = u16::from_be_bytes([query[end + 1], query[end + 2]]);
+            let question = &query[12..end + 5];
+            let found = name == b"\x05found\x04test";
+            let answer = found && qtype == 1;
+            let mut resp = query[..2].to_vec();
+            resp.extend_from_slice(if found { &[0x81, 0x80] } else { &[0x81, 0x83] });
+            resp.extend_from_slice(&[0, 1, 0, answer as u8, 0, 0, 0, 0]);
+            resp.extend_from_slice(question);
+            if answer {
+                resp.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1]);
+            }
+            socket.send_to(&resp, peer).await.unwrap();
+        }
+    });
+
+    let config = DnsConfig {
+        nameservers: [nameserver.to_string()].to_vec(),
+        ..Default::default()
+    };
+    let metrics = Arc::new(Metrics::default());
+    let resolver = Resolver::new(&config, metrics.clone()).unwrap();
+
+    let ips = resolver.resolve("found.test").await.unwrap();
+    assert_eq!(ips, ["10.0.0.1".parse::<IpAddr>().unwrap()]);
+    let err = resolver.resolve("missing.test").await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    let sent = queries.load(Ordering::Relaxed);
+
+    assert_eq!(resolver.resolve("found.test").await.unwrap(), ips);
+    assert!(resolver.resolve("missing.test").await.is_err());
+    assert_eq!(queries.load(Ordering::Relaxed), sent);
+    assert_eq!(metrics.dns_lookups.load(Ordering::Relaxed), 4);
+    assert_eq!(metrics.dns_cache_hits.load(Ordering::Relaxed), 2);
+    assert_eq!(metrics.dns_failures.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::BodyExt;
+use hyper::header::{HeaderValue, HOST};
+use hyper::http::uri::Scheme;
+use hyper::{body::Incoming as IncomingBody, Request, Response, StatusCode, Uri};
+use motore::{service, Service};
+use tracing::info;
+
+use crate::config::Backend;
+use crate::flow;
+use crate::metrics::Metrics;
+use crate::proxy::{self, request_protocol};
+use crate::state::{ClientState, State};
+use crate::util::{self, request_host};
+
+/// 反向代理：按 SNI 或 Host 选择 `backends`，请求走与正向代理相同的 client 栈
+#[derive(Clone)]
+pub struct Reverse<C> {
+    client: C,
+    /// TLS 监听端握手时的 SNI
+    sni: Option<String>,
+}
+
+impl<C> Reverse<C> {
+    pub fn new(client: C, sni: Option<String>) -> Self {
+        Self { client, sni }
+    }
+}
+
+#[service]
+impl<C> Service<State, Request<IncomingBody>> for Reverse<C>
+where
+    C: Service<
+            ClientState,
+            Request<BoxBody<Bytes, hyper::Error>>,
+            Response = Response<BoxBody<Bytes, hyper::Error>>,
+            Error = hyper::Error,
+        > + Clone
+        + Sync
+        + Send
+        + 'static,
+{
+    async fn call(
+        &self,
+        state: &mut State,
+        req: Request<IncomingBody>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        if let Some(resp) = proxy::shed(state) {
+            return Ok(resp);
+        }
+        let config = state.config();
+        let host = self.sni.clone().or_else(|| request_host(&req));
+        let host = host.unwrap_or_default();
+        let Some(target) = config
+            .backend(&host)
+            .and_then(|backend| Target::new(backend).ok())
+        else {
+            info!(host, "no backend");
+            let mut resp = Response::new(util::full(format!("no backend for host {host}")));
+            *resp.status_mut() = StatusCode::BAD_GATEWAY;
+            return Ok(resp);
+        };
+        let mut req = req;
+        target.rewrite(&mut req);
+
+        if !state.is_reachable(&target.addr) {
+            let addr = target.addr;
+            let mut resp = Response::new(util::full(format!("origin {addr} is unreachable")));
+            *resp.status_mut() = StatusCode::BAD_GATEWAY;
+            return Ok(resp);
+        }
+        if let Some(conn) = state.connection() {
+            conn.add_target(&target.authority);
+        }
+        let Some(permit) = state.limits().acquire(&target.host).await else {
+            Metrics::incr(&state.metrics().limited_requests);
+            let mut resp = Response::new(util::full("too many concurrent requests"));
+            *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            return Ok(resp);
+        };
+
+        Metrics::incr(&state.metrics().http_requests);
+        state
+            .protocols()
+            .record(&target.host, request_protocol(&req));
+        let mut client_state = ClientState {
+            id: flow::next_id(),
+            addr: target.addr,
+            transcript: state.transcript(&target.host).await,
+            parse: state.is_parse_for(&target.host),
+            sni: target.host,
+            is_secure: target.secure,
+            timings: Default::default(),
+            shared: state.clone(),
+        };
+        let resp = self
+            .client
+            .call(&mut client_state, req.map(|b| b.boxed()))
+            .await;
+        drop(permit);
+        resp
+    }
+}
+
+/// 由 `http://host:port/prefix` 形式的上游地址解析出的连接目标
+#[derive(Debug)]
+pub struct Target {
+    /// 用于连接，总是带端口
+    pub addr: String,
+    /// 上游 TLS 的 SNI
+    pub host: String,
+    /// 改写的 Host 头
+    pub authority: String,
+    pub secure: bool,
+    /// 不以 `/` 结尾
+    prefix: String,
+    preserve_host: bool,
+}
+
+impl Target {
+    fn new(backend: &Backend) -> Result<Self> {
+        Self::parse(&backend.upstream, backend.preserve_host)
+    }
+
+    pub fn parse(upstream: &str, preserve_host: bool) -> Result<Self> {
+        let uri: Uri = upstream.parse()?;
+        let secure = match uri.scheme() {
+            Some(scheme) if *scheme == Scheme::HTTPS => true,
+            Some(scheme) if *scheme == Scheme::HTTP => false,
+            _ => return Err(anyhow!("upstream must be an http or https URL")),
+        };
+        let authority = uri
+            .authority()
+            .ok_or(anyhow!("upstream has no host"))?
+            .clone();
+        let port = authority
+            .port_u16()
+            .unwrap_or(if secure { 443 } else { 80 });
+        let host = authority.host().to_owned();
+        Ok(Self {
+            addr: format!("{host}:{port}"),
+            host: host.trim_matches(['[', ']']).to_owned(),
+            authority: authority.to_string(),
+            secure,
+            prefix: uri.path().trim_end_matches('/').to_owned(),
+            preserve_host,
+        })
+    }
+
+    /// 转为 origin-form 并加上 path 前缀
+    pub fn rewrite<B>(&self, req: &mut Request<B>) {
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        if let Ok(uri) = format!("{}{path}", self.prefix).parse() {
+            *req.uri_mut() = uri;
+        }
+        if !self.preserve_host {
+            if let Ok(value) = HeaderValue::from_str(&self.authority) {
+                req.headers_mut().insert(HOST, value);
+            }
+        }
+    }
+}
+
+#[test]
+fn should_route_to_backend() {
+    let config = crate::config::Config {
+        backends: vec![
+            Backend {
+                hosts: vec![],
+                upstream: "http://127.0.0.1:3000".to_owned(),
+                preserve_host: false,
+            },
+            Backend {
+                hosts: vec!["api.test".to_owned()],
+                upstream: "https://api.internal/v1/".to_owned(),
+                preserve_host: false,
+            },
+        ],
+        ..Default::default()
+    };
+    let backend = config.backend("www.test").unwrap();
+    assert_eq!(backend.upstream, "http://127.0.0.1:3000");
+
+    let target = Target::new(config.backend("v2.api.test").unwrap()).unwrap();
+    assert_eq!(target.addr, "api.internal:443");
+    assert!(target.secure);
+    let mut req = Request::get("/users?page=2")
+        .header(HOST, "v2.api.test")
+        .body(())
+        .unwrap();
+    target.rewrite(&mut req);
+    assert_eq!(req.uri(), "/v1/users?page=2");
+    assert_eq!(req.headers()[HOST], "api.internal");
+}
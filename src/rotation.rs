@@ -0,0 +1,178 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use tokio::fs;
+use tracing::info;
+
+use crate::ca::{self, CA};
+
+/// 写在 `*.next` 证书 PEM 之前的说明行，解析 PEM 时会被忽略
+const RETIRE_AFTER: &str = "Retire-After: ";
+
+/// 进行中的 root CA 轮换：新 CA 保存为 `*.next`，`cutoff` 前仍用旧 CA 签发，
+/// 两个证书都可下载，客户端有时间信任新的 CA
+#[derive(Clone)]
+pub struct Rotation {
+    pub next: Arc<CA>,
+    pub cutoff: SystemTime,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub active: CaInfo,
+    pub next: Option<CaInfo>,
+    /// unix 秒
+    pub cutoff: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CaInfo {
+    pub subject: String,
+    pub not_after: String,
+    pub sha256: String,
+}
+
+impl CaInfo {
+    pub fn of(ca: &CA) -> Self {
+        let subject = ca
+            .cert
+            .subject_name()
+            .entries()
+            .filter_map(|entry| entry.data().as_utf8().ok().map(|data| data.to_string()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        Self {
+            subject,
+            not_after: ca.cert.not_after().to_string(),
+            sha256: ca.fingerprint(),
+        }
+    }
+}
+
+impl Status {
+    pub fn new(active: &CA, rotation: Option<&Rotation>) -> Self {
+        Self {
+            active: CaInfo::of(active),
+            next: rotation.map(|rotation| CaInfo::of(&rotation.next)),
+            cutoff: rotation.map(|rotation| unix_secs(rotation.cutoff)),
+        }
+    }
+}
+
+impl Rotation {
+    /// 生成新 CA 并保存为 `*.next`
+    pub async fn start(
+        cert_path: &Path,
+        key_path: &Path,
+        passphrase: Option<&[u8]>,
+        overlap: Duration,
+    ) -> Result<Self> {
+        let next = CA::generate().await?;
+        let cutoff = SystemTime::now() + overlap;
+        let mut pem = format!("{RETIRE_AFTER}{}\n", unix_secs(cutoff)).into_bytes();
+        pem.extend(next.cert.to_pem()?);
+        fs::write(next_path(cert_path), pem).await?;
+        ca::write_key(&next_path(key_path), &next.key, passphrase).await?;
+        info!(
+            "Rotating root CA, the new one takes over at {}",
+            httpdate::fmt_http_date(cutoff)
+        );
+        Ok(Self {
+            next: Arc::new(next),
+            cutoff,
+        })
+    }
+
+    /// 启动时恢复上次未完成的轮换
+    pub async fn resume(
+        cert_path: &Path,
+        key_path: &Path,
+        passphrase: Option<&[u8]>,
+    ) -> Result<Option<Self>> {
+        let (next_cert, next_key) = (next_path(cert_path), next_path(key_path));
+        let Ok(pem) = fs::read_to_string(&next_cert).await else {
+            return Ok(None);
+        };
+        let cutoff = pem
+            .lines()
+            .find_map(|line| line.strip_prefix(RETIRE_AFTER))
+            .and_then(|secs| secs.trim().parse().ok())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+            .ok_or(anyhow!("{} has no {RETIRE_AFTER}line", next_cert.display()))?;
+        let next = CA::load(&next_cert, &next_key, passphrase).await?;
+        Ok(Some(Self {
+            next: Arc::new(next),
+            cutoff,
+        }))
+    }
+
+    /// 新 CA 替换旧的：旧证书保留为 `*.retired`，旧私钥被覆盖
+    pub async fn complete(
+        &self,
+        cert_path: &Path,
+        key_path: &Path,
+        passphrase: Option<&[u8]>,
+    ) -> Result<()> {
+        if fs::try_exists(cert_path).await? {
+            fs::rename(cert_path, with_suffix(cert_path, ".retired")).await?;
+        }
+        self.next.save(cert_path, key_path, passphrase).await?;
+        Self::cancel(cert_path, key_path).await?;
+        info!("Root CA rotated, now {}", self.next.fingerprint());
+        Ok(())
+    }
+
+    /// 删除 `*.next`
+    pub async fn cancel(cert_path: &Path, key_path: &Path) -> Result<()> {
+        for path in [next_path(cert_path), next_path(key_path)] {
+            if fs::try_exists(&path).await? {
+                fs::remove_file(path).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn next_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".next")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[tokio::test]
+async fn should_rotate_root_ca() {
+    let dir = std::env::temp_dir().join(format!("proxy-ca-rotation-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert, key) = (dir.join("ca.crt"), dir.join("ca.key.pem"));
+    let old = CA::load_or_create(&cert, &key, None).await.unwrap();
+
+    assert!(Rotation::resume(&cert, &key, None).await.unwrap().is_none());
+    let rotation = Rotation::start(&cert, &key, None, Duration::from_secs(60))
+        .await
+        .unwrap();
+    let resumed = Rotation::resume(&cert, &key, None).await.unwrap().unwrap();
+    assert_eq!(resumed.next.cert, rotation.next.cert);
+    assert_eq!(unix_secs(resumed.cutoff), unix_secs(rotation.cutoff));
+
+    rotation.complete(&cert, &key, None).await.unwrap();
+    let current = CA::load(&cert, &key, None).await.unwrap();
+    assert_eq!(current.cert, rotation.next.cert);
+    let retired = std::fs::read(with_suffix(&cert, ".retired")).unwrap();
+    assert_eq!(retired, old.cert.to_pem().unwrap());
+    assert!(!next_path(&cert).exists() && !next_path(&key).exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,300 @@
+use anyhow::Result;
+
+use crate::cli::ServiceCommand;
+
+pub const NAME: &str = "http-proxy-server";
+
+pub fn handle(command: ServiceCommand) -> Result<()> {
+    // services start outside the install directory, config/CA paths are relative to the binary
+    if command == ServiceCommand::Run {
+        if let Some(dir) = std::env::current_exe()?.parent() {
+            std::env::set_current_dir(dir)?;
+        }
+    }
+    match command {
+        ServiceCommand::Install => imp::install(),
+        ServiceCommand::Uninstall => imp::uninstall(),
+        ServiceCommand::Run => imp::run(),
+    }
+}
+
+/// Ctrl-C，unix 下还包括 SIGTERM（systemd stop）
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = term.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// `crate::run` 返回 false（`expect` 未通过）时以错误结束，进程非零退出
+fn exit_status(passed: bool) -> Result<()> {
+    if passed {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "unexpected requests, see the expect report"
+        ))
+    }
+}
+
+/// 通知服务管理器已就绪，非 systemd 下无操作
+pub fn notify_ready() {
+    imp::notify("READY=1");
+}
+
+pub fn notify_stopping() {
+    imp::notify("STOPPING=1");
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::path::Path;
+    use std::process::Command;
+
+    use anyhow::{anyhow, Result};
+
+    use super::{shutdown_signal, NAME};
+
+    fn unit_path() -> String {
+        format!("/etc/systemd/system/{NAME}.service")
+    }
+
+    pub fn install() -> Result<()> {
+        let exe = std::env::current_exe()?;
+        let dir = exe
+            .parent()
+            .ok_or(anyhow!("executable has no parent directory"))?;
+        let unit = format!(
+            "[Unit]\n\
+             Description=HTTP(S) MITM proxy server\n\
+             After=network-online.target\n\
+             Wants=network-online.target\n\
+             \n\
+             [Service]\n\
+             Type=notify\n\
+             ExecStart={} --service run\n\
+             WorkingDirectory={}\n\
+             Restart=on-failure\n\
+             RestartSec=5\n\
+             \n\
+             [Install]\n\
+             WantedBy=multi-user.target\n",
+            exe.display(),
+            dir.display()
+        );
+        std::fs::write(unit_path(), unit)?;
+        systemctl(&["daemon-reload"])?;
+        systemctl(&["enable", "--now", NAME])
+    }
+
+    pub fn uninstall() -> Result<()> {
+        systemctl(&["disable", "--now", NAME])?;
+        if Path::new(&unit_path()).exists() {
+            std::fs::remove_file(unit_path())?;
+        }
+        systemctl(&["daemon-reload"])
+    }
+
+    pub fn run() -> Result<()> {
+        super::exit_status(crate::run(None, shutdown_signal()))
+    }
+
+    fn systemctl(args: &[&str]) -> Result<()> {
+        let status = Command::new("systemctl").args(args).status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow!("systemctl {} failed: {status}", args.join(" ")))
+        }
+    }
+
+    /// sd_notify(3)
+    pub fn notify(message: &str) {
+        let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+        let Ok(sock) = std::os::unix::net::UnixDatagram::unbound() else {
+            return;
+        };
+        let socket = socket.to_string_lossy();
+        let result = match socket.strip_prefix('@') {
+            // abstract namespace
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())
+                    .and_then(|addr| sock.send_to_addr(message.as_bytes(), &addr))
+            }
+            None => sock.send_to(message.as_bytes(), socket.as_ref()),
+        };
+        if let Err(e) = result {
+            tracing::error!("sd_notify failed: {e}");
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::ffi::OsString;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use anyhow::Result;
+    use tracing::error;
+    use windows_service::service::{
+        ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept,
+        ServiceErrorControl, ServiceExitCode, ServiceFailureActions, ServiceFailureResetPeriod,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    use super::NAME;
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    pub fn install() -> Result<()> {
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )?;
+        let info = ServiceInfo {
+            name: NAME.into(),
+            display_name: "HTTP Proxy Server".into(),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe()?,
+            launch_arguments: vec!["--service".into(), "run".into()],
+            dependencies: vec![],
+            account_name: None,
+            account_password: None,
+        };
+        let service =
+            manager.create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)?;
+        service.set_description("HTTP(S) MITM proxy server")?;
+        service.update_failure_actions(ServiceFailureActions {
+            reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(86400)),
+            reboot_msg: None,
+            command: None,
+            actions: Some(vec![ServiceAction {
+                action_type: ServiceActionType::Restart,
+                delay: Duration::from_secs(5),
+            }]),
+        })?;
+        service.start::<&str>(&[])?;
+        Ok(())
+    }
+
+    pub fn uninstall() -> Result<()> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let service = manager.open_service(
+            NAME,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )?;
+        if service.query_status()?.current_state != ServiceState::Stopped {
+            service.stop()?;
+        }
+        service.delete()?;
+        Ok(())
+    }
+
+    pub fn run() -> Result<()> {
+        service_dispatcher::start(NAME, ffi_service_main)?;
+        super::exit_status(!FAILED.load(Ordering::Relaxed))
+    }
+
+    /// 服务以 `ServiceSpecific` 退出码停止，`run` 在 dispatcher 返回后同样报错
+    static FAILED: AtomicBool = AtomicBool::new(false);
+
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(e) = run_service() {
+            error!("Service failed: {e}");
+        }
+    }
+
+    fn run_service() -> Result<()> {
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let tx = Mutex::new(Some(tx));
+        let status_handle =
+            service_control_handler::register(NAME, move |control| match control {
+                ServiceControl::Stop | ServiceControl::Shutdown => {
+                    if let Some(tx) = tx.lock().ok().and_then(|mut tx| tx.take()) {
+                        let _ = tx.send(());
+                    }
+                    ServiceControlHandlerResult::NoError
+                }
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                _ => ServiceControlHandlerResult::NotImplemented,
+            })?;
+
+        let status = |current_state, controls_accepted| ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        };
+        status_handle.set_service_status(status(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+        ))?;
+        let passed = crate::run(None, async {
+            let _ = rx.await;
+        });
+        FAILED.store(!passed, Ordering::Relaxed);
+        let exit_code = if passed {
+            ServiceExitCode::Win32(0)
+        } else {
+            ServiceExitCode::ServiceSpecific(1)
+        };
+        status_handle.set_service_status(ServiceStatus {
+            exit_code,
+            ..status(ServiceState::Stopped, ServiceControlAccept::empty())
+        })?;
+        Ok(())
+    }
+
+    pub fn notify(_message: &str) {}
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod imp {
+    use anyhow::{anyhow, Result};
+
+    use super::shutdown_signal;
+
+    pub fn install() -> Result<()> {
+        Err(anyhow!("service install is not supported on this platform"))
+    }
+
+    pub fn uninstall() -> Result<()> {
+        Err(anyhow!(
+            "service uninstall is not supported on this platform"
+        ))
+    }
+
+    pub fn run() -> Result<()> {
+        super::exit_status(crate::run(None, shutdown_signal()))
+    }
+
+    pub fn notify(_message: &str) {}
+}
+
+#[test]
+fn should_fail_run_when_expect_fails() {
+    assert!(exit_status(true).is_ok());
+    assert!(exit_status(false).is_err());
+}
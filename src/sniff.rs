@@ -0,0 +1,269 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+/// 服务端先发言的协议（SMTP 等）等不到客户端数据，超时后按原始 TCP 转发
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// TLS 记录的最大长度加上头
+const MAX_RECORD: usize = 5 + 16384 + 2048;
+
+/// 读取连接开头的数据：TLS 时读完第一个记录，否则只读一次；超时返回已读到的部分
+pub async fn read_hello<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Bytes> {
+    let mut buf = BytesMut::with_capacity(1024);
+    let read = async {
+        stream.read_buf(&mut buf).await?;
+        if buf.first() != Some(&0x16) {
+            return Ok(());
+        }
+        while buf.len() < 5 || buf.len() < record_len(&buf) {
+            if buf.len() >= MAX_RECORD || stream.read_buf(&mut buf).await? == 0 {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(())
+    };
+    // on timeout whatever was read is still replayed
+    if let Ok(read) = tokio::time::timeout(SNIFF_TIMEOUT, read).await {
+        read?;
+    }
+    Ok(buf.freeze())
+}
+
+fn record_len(buf: &[u8]) -> usize {
+    5 + u16::from_be_bytes([buf[3], buf[4]]) as usize
+}
+
+/// ClientHello 中与路由及指纹有关的字段，列表都保持原顺序
+#[derive(Debug, Default, PartialEq)]
+pub struct ClientHello {
+    pub server_name: Option<String>,
+    pub alpn: Vec<String>,
+    /// legacy_version
+    pub version: u16,
+    pub ciphers: Vec<u16>,
+    /// 扩展类型
+    pub extensions: Vec<u16>,
+    pub groups: Vec<u16>,
+    pub point_formats: Vec<u8>,
+    pub signature_algorithms: Vec<u16>,
+    pub supported_versions: Vec<u16>,
+}
+
+/// 解析第一个 TLS 记录中的 ClientHello，不是 ClientHello 时返回 None
+pub fn client_hello(data: &[u8]) -> Option<ClientHello> {
+    let mut record = data;
+    if record.len() < 5 || record[0] != 0x16 {
+        return None;
+    }
+    let len = record_len(record).min(record.len());
+    record = &record[5..len];
+    // handshake type + length, client version, random
+    if record.first() != Some(&0x01) || record.len() < 4 + 2 + 32 {
+        return None;
+    }
+    let mut parsed = ClientHello {
+        version: u16::from_be_bytes([record[4], record[5]]),
+        ..Default::default()
+    };
+    let mut hello = &record[4 + 2 + 32..];
+    skip(&mut hello, 1)?; // session id
+    parsed.ciphers = u16s(take(&mut hello, 2)?);
+    skip(&mut hello, 1)?; // compression methods
+    let mut extensions = take(&mut hello, 2)?;
+
+    while extensions.len() >= 4 {
+        let kind = extensions.get_u16();
+        parsed.extensions.push(kind);
+        let mut data = take(&mut extensions, 2)?;
+        match kind {
+            // server_name
+            0x0000 => {
+                let mut list = take(&mut data, 2)?;
+                while list.len() >= 3 {
+                    let name_type = list.get_u8();
+                    let name = take(&mut list, 2)?;
+                    if name_type == 0 {
+                        parsed.server_name = std::str::from_utf8(name).ok().map(str::to_owned);
+                    }
+                }
+            }
+            // application_layer_protocol_negotiation
+            0x0010 => {
+                let mut list = take(&mut data, 2)?;
+                while !list.is_empty() {
+                    let proto = take(&mut list, 1)?;
+                    parsed
+                        .alpn
+                        .push(String::from_utf8_lossy(proto).into_owned());
+                }
+            }
+            // supported_groups
+            0x000a => parsed.groups = u16s(take(&mut data, 2)?),
+            // ec_point_formats
+            0x000b => parsed.point_formats = take(&mut data, 1)?.to_vec(),
+            // signature_algorithms
+            0x000d => parsed.signature_algorithms = u16s(take(&mut data, 2)?),
+            // supported_versions
+            0x002b => parsed.supported_versions = u16s(take(&mut data, 1)?),
+            _ => {}
+        }
+    }
+    Some(parsed)
+}
+
+/// 读取长度为 `width` 字节的前缀，返回其后的内容
+fn take<'a>(buf: &mut &'a [u8], width: usize) -> Option<&'a [u8]> {
+    if buf.len() < width {
+        return None;
+    }
+    let len = match width {
+        1 => buf.get_u8() as usize,
+        _ => buf.get_u16() as usize,
+    };
+    if buf.len() < len {
+        return None;
+    }
+    let (value, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(value)
+}
+
+fn u16s(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect()
+}
+
+fn skip(buf: &mut &[u8], width: usize) -> Option<()> {
+    take(buf, width).map(|_| ())
+}
+
+/// 按连接开头的字节识别的协议
+#[derive(Debug, PartialEq)]
+pub enum Detected {
+    Tls(ClientHello),
+    Http,
+    Ssh,
+    /// 包括客户端没有先发数据的协议
+    Other,
+}
+
+impl Detected {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Tls(_) => "tls",
+            Self::Http => "http",
+            Self::Ssh => "ssh",
+            Self::Other => "other",
+        }
+    }
+}
+
+pub fn detect(data: &[u8]) -> Detected {
+    if let Some(hello) = client_hello(data) {
+        Detected::Tls(hello)
+    } else if is_http(data) {
+        Detected::Http
+    } else if data.starts_with(b"SSH-") {
+        Detected::Ssh
+    } else {
+        Detected::Other
+    }
+}
+
+/// 以 HTTP/1 请求行开头
+pub fn is_http(data: &[u8]) -> bool {
+    const METHODS: [&[u8]; 9] = [
+        b"GET ",
+        b"POST ",
+        b"PUT ",
+        b"HEAD ",
+        b"DELETE ",
+        b"OPTIONS ",
+        b"PATCH ",
+        b"CONNECT ",
+        b"TRACE ",
+    ];
+    METHODS.iter().any(|method| data.starts_with(method))
+}
+
+/// 先读出已嗅探的数据，再读底层连接
+pub struct Rewind<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Bytes, inner: S) -> Self {
+        Self { prefix, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let len = self.prefix.len().min(buf.remaining());
+            let prefix = self.prefix.split_to(len);
+            buf.put_slice(&prefix);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn should_parse_client_hello() {
+    let ssl = openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls())
+        .unwrap()
+        .build();
+    let mut config = ssl.configure().unwrap();
+    config.set_alpn_protos(b"\x02h2\x08http/1.1").unwrap();
+    let ssl = config.into_ssl("example.com").unwrap();
+
+    // the ClientHello is written on the first handshake attempt
+    let (client, mut server) = std::os::unix::net::UnixStream::pair().unwrap();
+    client.set_nonblocking(true).unwrap();
+    let _ = ssl.connect(client);
+    let mut data = vec![0; MAX_RECORD];
+    let n = std::io::Read::read(&mut server, &mut data).unwrap();
+
+    let hello = client_hello(&data[..n]).unwrap();
+    assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+    assert_eq!(hello.alpn, ["h2", "http/1.1"]);
+    assert_eq!(hello.version, 0x0303);
+    assert!(hello.supported_versions.contains(&0x0304));
+    assert!(!hello.ciphers.is_empty() && !hello.groups.is_empty());
+    assert_eq!(hello.extensions.first(), Some(&0x0000));
+    assert!(matches!(detect(&data[..n]), Detected::Tls(_)));
+    assert_eq!(detect(b"GET / HTTP/1.1\r\n"), Detected::Http);
+    assert_eq!(detect(b"SSH-2.0-OpenSSH_9.6\r\n"), Detected::Ssh);
+    assert_eq!(detect(b""), Detected::Other);
+}
@@ -0,0 +1,190 @@
+use std::any::Any;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncWriteExt, Interest};
+use tokio::net::TcpStream;
+
+use crate::listener::Io;
+use crate::util::{self, Activity};
+
+/// 管道容量，也是单次 splice 的长度
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// 未解密的隧道：客户端为 TCP 时在内核中经 pipe 转发（splice(2)），否则回退到普通拷贝
+pub async fn tunnel(
+    upgraded: Upgraded,
+    mut server: TcpStream,
+    idle_secs: u64,
+    buffer_size: usize,
+) -> io::Result<(u64, u64)> {
+    let parts = match upgraded.downcast::<TokioIo<Box<dyn Io>>>() {
+        Ok(parts) => parts,
+        Err(upgraded) => {
+            let mut upgraded = TokioIo::new(upgraded);
+            return util::copy_bidirectional_idle(
+                &mut upgraded,
+                &mut server,
+                idle_secs,
+                buffer_size,
+            )
+            .await;
+        }
+    };
+    // bytes hyper read past the CONNECT request
+    server.write_all(&parts.read_buf).await?;
+    let mut client = parts.io.into_inner();
+    let (from_client, from_server) = match (&*client as &dyn Any).downcast_ref::<TcpStream>() {
+        Some(tcp) => {
+            let activity = Activity::new();
+            let copy = async {
+                tokio::try_join!(
+                    splice_one(tcp, &server, &activity),
+                    splice_one(&server, tcp, &activity)
+                )
+            };
+            activity.guard(idle_secs, copy).await?
+        }
+        None => {
+            util::copy_bidirectional_idle(&mut client, &mut server, idle_secs, buffer_size).await?
+        }
+    };
+    Ok((from_client + parts.read_buf.len() as u64, from_server))
+}
+
+/// 单向转发直到 `from` 读到 EOF，然后关闭 `to` 的写端
+async fn splice_one(from: &TcpStream, to: &TcpStream, activity: &Activity) -> io::Result<u64> {
+    let (pipe_read, pipe_write) = pipe()?;
+    let mut total = 0;
+    loop {
+        let n = loop {
+            from.readable().await?;
+            match from.try_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe_write.as_raw_fd(), PIPE_SIZE)
+            }) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        if n == 0 {
+            // SAFETY: `to` outlives the call
+            unsafe { libc::shutdown(to.as_raw_fd(), libc::SHUT_WR) };
+            return Ok(total);
+        }
+        activity.touch();
+
+        // drain the pipe so the next read always has room
+        let mut pending = n;
+        while pending > 0 {
+            to.writable().await?;
+            match to.try_io(Interest::WRITABLE, || {
+                splice(pipe_read.as_raw_fd(), to.as_raw_fd(), pending)
+            }) {
+                Ok(written) => pending -= written,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        total += n as u64;
+        activity.touch();
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: both fds are open for the duration of the call, offsets are null for pipes/sockets
+    let n = unsafe {
+        libc::splice(
+            fd_in,
+            std::ptr::null_mut(),
+            fd_out,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: pipe2 succeeded, both fds are new and owned here
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+#[tokio::test]
+async fn should_splice_tunnel_both_ways() {
+    use hyper::server::conn::http1::Builder as ServerBuilder;
+    use hyper::service::service_fn;
+    use hyper::{Method, Response};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    const UPLOAD: usize = 300 * 1024;
+    const DOWNLOAD: usize = 200 * 1024;
+
+    // reads until the client half-closes, then answers
+    let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let origin_addr = origin.local_addr().unwrap();
+    let origin = tokio::spawn(async move {
+        let (mut stream, _) = origin.accept().await.unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        stream.write_all(&vec![b'd'; DOWNLOAD]).await.unwrap();
+        received.len()
+    });
+
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (stream, _) = proxy.accept().await.unwrap();
+        let io: Box<dyn Io> = Box::new(stream);
+        let tx = std::sync::Mutex::new(Some(tx));
+        let service = service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+            assert_eq!(req.method(), Method::CONNECT);
+            let tx = tx.lock().unwrap().take().unwrap();
+            tokio::spawn(async move {
+                let upgraded = hyper::upgrade::on(req).await.unwrap();
+                let server = TcpStream::connect(origin_addr).await.unwrap();
+                let _ = tx.send(tunnel(upgraded, server, 30, 8192).await);
+            });
+            async { Ok::<_, hyper::Error>(Response::new(util::empty())) }
+        });
+        let _ = ServerBuilder::new()
+            .serve_connection(TokioIo::new(io), service)
+            .with_upgrades()
+            .await;
+    });
+
+    let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+    client
+        .write_all(
+            format!("CONNECT {origin_addr} HTTP/1.1\r\nHost: {origin_addr}\r\n\r\n").as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(client.read_u8().await.unwrap());
+    }
+    assert!(head.starts_with(b"HTTP/1.1 200"));
+    client.write_all(&vec![b'u'; UPLOAD]).await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut downloaded = Vec::new();
+    client.read_to_end(&mut downloaded).await.unwrap();
+
+    assert_eq!(origin.await.unwrap(), UPLOAD);
+    assert_eq!(downloaded.len(), DOWNLOAD);
+    assert_eq!(rx.await.unwrap().unwrap(), (UPLOAD as u64, DOWNLOAD as u64));
+}
@@ -0,0 +1,170 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes, BytesMut};
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Body, Frame, SizeHint};
+use hyper::header::{HeaderMap, CONTENT_TYPE};
+use tracing::{info, Span};
+
+/// 单个事件超过时不再等待结尾，直接丢弃已缓冲的部分
+const MAX_EVENT: usize = 64 * 1024;
+
+/// `text/event-stream` 的响应没有尽头，不能收集完整的 body（缓存、断点）
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(';')
+                .next()
+                .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("text/event-stream"))
+        })
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Event {
+    pub event: Option<String>,
+    pub id: Option<String>,
+    /// 多行 `data` 以换行连接
+    pub data: String,
+}
+
+/// 按空行切分事件，跨 chunk 的事件先缓冲
+#[derive(Default)]
+pub struct Events {
+    buf: BytesMut,
+}
+
+impl Events {
+    pub fn push(&mut self, data: &[u8]) -> Vec<Event> {
+        self.buf.extend_from_slice(data);
+        let mut events = Vec::new();
+        while let Some((end, separator)) = boundary(&self.buf) {
+            let block = self.buf.split_to(end);
+            self.buf.advance(separator);
+            if let Some(event) = parse(&String::from_utf8_lossy(&block)) {
+                events.push(event);
+            }
+        }
+        if self.buf.len() > MAX_EVENT {
+            self.buf.clear();
+        }
+        events
+    }
+}
+
+/// 第一个空行的位置与分隔符长度，兼容 `\r\n`
+fn boundary(buf: &[u8]) -> Option<(usize, usize)> {
+    [&b"\r\n\r\n"[..], b"\n\n", b"\r\r"]
+        .iter()
+        .filter_map(|separator| {
+            buf.windows(separator.len())
+                .position(|window| window == *separator)
+                .map(|end| (end, separator.len()))
+        })
+        .min()
+}
+
+/// 只有注释（`:` 开头，常用作心跳）的块不是事件
+fn parse(block: &str) -> Option<Event> {
+    let mut event = Event::default();
+    let mut data = Vec::new();
+    for line in block.lines() {
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => event.event = Some(value.to_owned()),
+            "id" => event.id = Some(value.to_owned()),
+            "data" => data.push(value),
+            _ => {}
+        }
+    }
+    if data.is_empty() && event.event.is_none() {
+        return None;
+    }
+    event.data = data.join("\n");
+    Some(event)
+}
+
+/// 原样转发，每收到一个完整事件记录一条日志
+pub struct LogEvents {
+    inner: BoxBody<Bytes, hyper::Error>,
+    events: Events,
+    span: Span,
+    /// 每条 data 预览的字节数
+    limit: usize,
+}
+
+impl LogEvents {
+    pub fn new(inner: BoxBody<Bytes, hyper::Error>, limit: usize) -> Self {
+        Self {
+            inner,
+            events: Events::default(),
+            span: Span::current(),
+            limit,
+        }
+    }
+}
+
+impl Body for LogEvents {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                let events = self.events.push(data);
+                let _entered = self.span.enter();
+                for event in events {
+                    let mut end = event.data.len().min(self.limit);
+                    while !event.data.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    info!(
+                        event = event.event,
+                        id = event.id,
+                        size = event.data.len(),
+                        "sse: {}",
+                        &event.data[..end]
+                    );
+                }
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[test]
+fn should_split_events() {
+    let mut events = Events::default();
+    assert!(events
+        .push(b": ping\n\nid: 1\nevent: tick\ndata: a")
+        .is_empty());
+    assert_eq!(
+        events.push(b"\r\ndata:b\r\n\r\ndata: c\n\n"),
+        [
+            Event {
+                event: Some("tick".to_owned()),
+                id: Some("1".to_owned()),
+                data: "a\nb".to_owned(),
+            },
+            Event {
+                data: "c".to_owned(),
+                ..Default::default()
+            },
+        ]
+    );
+}
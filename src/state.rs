@@ -1,95 +1,808 @@
 use anyhow::{anyhow, Result};
-use cached::{cached_result, Cached, SizedCache};
-use hyper::upgrade::Upgraded;
-use hyper_util::rt::TokioIo;
-use openssl::ssl::{Ssl, SslAcceptor, SslMethod};
-use std::{net::SocketAddr, sync::Arc};
+use cached::{Cached, SizedCache};
+use openssl::ssl::{Ssl, SslAcceptor, SslMethod, SslSessionCacheMode};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_openssl::SslStream;
+use tracing::{error, info, warn};
+use ulid::Ulid;
 
-use crate::{ca::CA, config::Config};
+use crate::alpn::{self, AlpnCache};
+use crate::auth::Tokens;
+use crate::breakpoint::Breakpoints;
+use crate::cache::Cache;
+use crate::config::{Config, Depth, ListenerConfig, ListenerMode};
+use crate::cookie::CookieJar;
+use crate::expect::Expectations;
+use crate::fingerprint::Fingerprint;
+use crate::flow::{FlowStore, Timings};
+use crate::geoip::GeoIp;
+use crate::grpc::Descriptors;
+use crate::limit::{Limits, Overload};
+use crate::metrics::{AcceptStats, Connection, Connections, Metrics, ProtocolStats, TrafficStats};
+use crate::pcap::Pcap;
+use crate::pool::Pool;
+use crate::probe::{self, HealthMap};
+use crate::quota::{Exceeded, Quotas};
+use crate::resolver::Resolver;
+use crate::rotation::{self, Rotation};
+use crate::stats::Snapshots;
+use crate::upstream_cert::UpstreamCerts;
+use crate::{
+    ca::{self, CA},
+    dialer::Dialer,
+    transcript::Transcript,
+};
 
-cached_result! {
-    SIGNED_CA: SizedCache<String, CA> = SizedCache::with_size(50);
-    fn get_cached_cert(host: String) -> Result<CA, String> = {
-        let mut cache = SIGNED_CA.lock().map_err(|e| e.to_string())?;
-        cache.cache_get(&host).cloned().ok_or("had not cache".to_string())
-    }
+/// 签发证书有效期 365 天，剩余不足 30 天时重新签发
+const RENEW_BEFORE_DAYS: u32 = 30;
+
+/// 后台检查签发证书有效期的间隔
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// 签发证书缓存；panic 后仍可使用，不因锁中毒而失效
+static SIGNED_CA: LazyLock<Mutex<SizedCache<String, CA>>> =
+    LazyLock::new(|| Mutex::new(SizedCache::with_size(50)));
+
+fn signed_ca() -> MutexGuard<'static, SizedCache<String, CA>> {
+    SIGNED_CA.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn get_cached_cert(host: &String) -> Option<CA> {
+    signed_ca().cache_get(host).cloned()
 }
 
 #[derive(Clone)]
 pub struct ClientState {
+    /// flow id
+    pub id: Ulid,
     pub addr: String,
     // http will be host
     pub sni: String,
     pub is_secure: bool,
     pub parse: bool,
+    pub transcript: Option<Transcript>,
+    /// 由 HttpClient 填写
+    pub timings: Timings,
+    pub shared: State,
 }
 
 #[derive(Clone)]
 pub struct State {
-    config: Arc<Config>,
-    root_ca: Arc<CA>,
+    config: Arc<RwLock<Configs>>,
+    /// 轮换完成时替换
+    root_ca: Arc<RwLock<Arc<CA>>>,
+    rotation: Arc<RwLock<Option<Rotation>>>,
+    /// 私钥加密保存时的口令，轮换时写入新私钥
+    passphrase: Option<Arc<Vec<u8>>>,
+    /// `root_cas` 按名字加载的 CA
+    root_cas: Arc<HashMap<String, Arc<CA>>>,
+    health: HealthMap,
+    flows: FlowStore,
+    breakpoints: Breakpoints,
+    cache: Cache,
+    metrics: Arc<Metrics>,
+    connections: Connections,
+    protocols: ProtocolStats,
+    traffic: TrafficStats,
+    accepts: AcceptStats,
+    pool: Pool,
+    /// MITM 共用的 context，会话缓存与 ticket key 在连接间共享，浏览器重连时可恢复会话
+    acceptor: SslAcceptor,
+    upstream_certs: UpstreamCerts,
+    grpc_descriptors: Descriptors,
+    cookies: CookieJar,
+    quotas: Quotas,
+    stats_snapshots: Option<Snapshots>,
+    expectations: Expectations,
+    auth_tokens: Tokens,
+    alpn: AlpnCache,
+    resolver: Resolver,
+    geoip: GeoIp,
+    limits: Arc<Limits>,
+    pcap: Option<Pcap>,
+    /// 当前服务的客户端连接，仅在连接内的副本上有值
+    connection: Option<Arc<Connection>>,
+    /// 被解密的 TLS 客户端的指纹，仅在 MITM 连接内的副本上有值
+    fingerprint: Option<Arc<Fingerprint>>,
+    /// 接受该连接的额外监听端，主监听端为空
+    listener: Option<Arc<ListenerConfig>>,
+    /// 所有监听端都已绑定
+    listening: Arc<AtomicBool>,
+}
+
+/// 一起替换，读到的基础配置与生效配置总是对应的
+struct Configs {
+    /// 配置文件中的内容，管理接口在此之上修改
+    base: Arc<Config>,
+    /// 合并了 `profile` 之后实际使用的配置
+    effective: Arc<Config>,
+}
+
+/// `/readyz` 的检查结果
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub listening: bool,
+    /// root CA 的问题，正常时为空
+    pub ca: Option<String>,
+    pub config: Vec<String>,
 }
 
 impl State {
-    pub async fn new() -> Result<Self> {
-        let config = Arc::new(Config::load().await?);
-        let root_ca = Arc::new(
-            CA::load_or_create(&config.root_ca_cert_path, &config.root_ca_key_path).await?,
-        );
-        Ok(Self { config, root_ca })
+    pub async fn new(base: Config) -> Result<Self> {
+        let config = Arc::new(base.resolve()?);
+        let base = Arc::new(base);
+        let passphrase = if config.root_ca_key_encrypted {
+            Some(tokio::task::spawn_blocking(ca::passphrase).await??)
+        } else {
+            None
+        };
+        let root_ca = Arc::new(RwLock::new(Arc::new(
+            CA::load_or_create(
+                &config.root_ca_cert_path,
+                &config.root_ca_key_path,
+                passphrase.as_deref(),
+            )
+            .await?,
+        )));
+        let rotation = Rotation::resume(
+            &config.root_ca_cert_path,
+            &config.root_ca_key_path,
+            passphrase.as_deref(),
+        )
+        .await?;
+        let mut root_cas = HashMap::new();
+        for root in &config.root_cas {
+            let ca = CA::load(&root.cert_path, &root.key_path, passphrase.as_deref())
+                .await
+                .map_err(|e| anyhow!("load root CA `{}` failed: {e}", root.name))?;
+            root_cas.insert(root.name.clone(), Arc::new(ca));
+        }
+        let metrics = Arc::<Metrics>::default();
+        let resolver = Resolver::new(&config.dns, metrics.clone())?;
+        let geoip = GeoIp::default();
+        let health = HealthMap::default();
+        if let Some(probe) = &config.probe {
+            let dialer = Dialer::new(config.clone(), resolver.clone(), geoip.clone());
+            probe::spawn(probe.clone(), dialer, health.clone());
+        }
+        let flows = FlowStore::new(config.flow_capacity);
+        let cache = Cache::new(&config.cache).await?;
+        let traffic = TrafficStats::default();
+        let stats_snapshots = config.stats_snapshot.as_ref().map(|snapshot| {
+            let snapshots = Snapshots::new(snapshot, traffic.clone());
+            snapshots.spawn(snapshot.interval_secs);
+            snapshots
+        });
+        let quotas = match (&config.quota.path, config.quota.enabled) {
+            (Some(path), true) => {
+                let quotas = Quotas::load(path).await?;
+                quotas.spawn_saver(path, config.quota.save_interval_secs);
+                quotas
+            }
+            _ => Quotas::default(),
+        };
+        let limits = Arc::new(Limits::new(&config.limits));
+        limits.spawn_sampler();
+        let pcap = match &config.pcap_path {
+            Some(path) => Some(Pcap::create(path).await?),
+            None => None,
+        };
+        let state = Self {
+            config: Arc::new(RwLock::new(Configs {
+                base,
+                effective: config,
+            })),
+            root_ca,
+            rotation: Arc::default(),
+            passphrase: passphrase.map(Arc::new),
+            root_cas: Arc::new(root_cas),
+            health,
+            flows,
+            breakpoints: Breakpoints::default(),
+            cache,
+            metrics,
+            connections: Connections::default(),
+            protocols: ProtocolStats::default(),
+            traffic,
+            accepts: AcceptStats::default(),
+            pool: Pool::default(),
+            acceptor: mitm_acceptor()?,
+            upstream_certs: UpstreamCerts::default(),
+            grpc_descriptors: Descriptors::default(),
+            cookies: CookieJar::default(),
+            quotas,
+            stats_snapshots,
+            expectations: Expectations::default(),
+            auth_tokens: Tokens::default(),
+            alpn: AlpnCache::default(),
+            resolver,
+            geoip,
+            limits,
+            pcap,
+            connection: None,
+            fingerprint: None,
+            listener: None,
+            listening: Arc::default(),
+        };
+        if let Some(rotation) = rotation {
+            state.schedule_rotation(rotation);
+        }
+        state.spawn_cert_renewal();
+        Ok(state)
+    }
+
+    /// 当前生效配置的快照
+    pub fn config(&self) -> Arc<Config> {
+        match self.config.read() {
+            Ok(configs) => configs.effective.clone(),
+            Err(e) => e.into_inner().effective.clone(),
+        }
+    }
+
+    /// 未合并 profile 的配置，保存与修改都以它为准
+    pub fn base_config(&self) -> Arc<Config> {
+        match self.config.read() {
+            Ok(configs) => configs.base.clone(),
+            Err(e) => e.into_inner().base.clone(),
+        }
+    }
+
+    /// 运行时替换基础配置并重新合并 profile，已建立的连接不受影响
+    pub fn set_config(&self, base: Config) -> Result<()> {
+        let configs = Configs {
+            effective: Arc::new(base.resolve()?),
+            base: Arc::new(base),
+        };
+        configs
+            .effective
+            .dns
+            .check()
+            .map_err(|e| anyhow!("dns.tls_name: {e}"))?;
+        // 签发所用的 CA 可能变化
+        if configs.effective.root_cas != self.config().root_cas {
+            self.purge_signed(None);
+        }
+        match self.config.write() {
+            Ok(mut current) => *current = configs,
+            Err(e) => *e.into_inner() = configs,
+        }
+        Ok(())
+    }
+
+    pub fn flows(&self) -> &FlowStore {
+        &self.flows
+    }
+
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    pub fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    pub fn health(&self) -> &HealthMap {
+        &self.health
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn protocols(&self) -> &ProtocolStats {
+        &self.protocols
+    }
+
+    pub fn traffic(&self) -> &TrafficStats {
+        &self.traffic
+    }
+
+    /// 计入按 host 的统计，开启 `quota` 时同时计入客户端与 host 的额度
+    pub fn record_traffic(
+        &self,
+        host: &str,
+        bytes_up: u64,
+        bytes_down: u64,
+        latency_ms: Option<u64>,
+    ) {
+        self.traffic.record(host, bytes_up, bytes_down, latency_ms);
+        if self.config().quota.enabled {
+            self.quotas
+                .record(self.client_ip(), host, bytes_up + bytes_down);
+        }
+    }
+
+    pub fn stats_snapshots(&self) -> Option<&Snapshots> {
+        self.stats_snapshots.as_ref()
+    }
+
+    pub fn quotas(&self) -> &Quotas {
+        &self.quotas
+    }
+
+    /// 开启 `quota` 时检查当前客户端与 `host` 的额度
+    pub fn quota_exceeded(&self, host: &str) -> Option<Exceeded> {
+        let config = self.config();
+        if !config.quota.enabled {
+            return None;
+        }
+        self.quotas.exceeded(&config.quota, self.client_ip(), host)
+    }
+
+    pub fn expectations(&self) -> &Expectations {
+        &self.expectations
+    }
+
+    /// 开启 `expect` 时记录请求，返回是否在允许列表中；未开启时总是允许
+    pub fn expect(&self, method: &str, url: &str) -> bool {
+        match &self.config().expect {
+            Some(expect) => self.expectations.check(expect, method, url),
+            None => true,
+        }
+    }
+
+    /// 经 unix socket 等连接时为空
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.connection()?.peer_addr.map(|addr| addr.ip())
+    }
+
+    pub fn accepts(&self) -> &AcceptStats {
+        &self.accepts
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// 按当前活动连接数判断是否需要减载
+    pub fn overloaded(&self) -> Option<Overload> {
+        self.limits.overloaded(self.connections.count())
+    }
+
+    pub fn pcap(&self) -> Option<&Pcap> {
+        self.pcap.as_ref()
+    }
+
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    pub fn grpc_descriptors(&self) -> &Descriptors {
+        &self.grpc_descriptors
+    }
+
+    pub fn cookies(&self) -> &CookieJar {
+        &self.cookies
+    }
+
+    pub fn auth_tokens(&self) -> &Tokens {
+        &self.auth_tokens
+    }
+
+    pub fn upstream_certs(&self) -> &UpstreamCerts {
+        &self.upstream_certs
+    }
+
+    pub fn alpn(&self) -> &AlpnCache {
+        &self.alpn
+    }
+
+    pub fn connections(&self) -> &Connections {
+        &self.connections
+    }
+
+    /// 供单个连接使用的副本
+    pub fn with_connection(&self, connection: Arc<Connection>) -> Self {
+        Self {
+            connection: Some(connection),
+            ..self.clone()
+        }
+    }
+
+    pub fn connection(&self) -> Option<&Connection> {
+        self.connection.as_deref()
+    }
+
+    /// 供单个 MITM 连接使用的副本
+    pub fn with_fingerprint(&self, fingerprint: Fingerprint) -> Self {
+        Self {
+            fingerprint: Some(Arc::new(fingerprint)),
+            ..self.clone()
+        }
+    }
+
+    pub fn fingerprint(&self) -> Option<Arc<Fingerprint>> {
+        self.fingerprint.clone()
+    }
+
+    /// 供额外监听端使用的副本
+    pub fn with_listener(&self, listener: ListenerConfig) -> Self {
+        Self {
+            listener: Some(Arc::new(listener)),
+            ..self.clone()
+        }
+    }
+
+    pub fn requires_auth(&self) -> bool {
+        self.listener.as_ref().is_none_or(|l| l.auth)
+    }
+
+    pub fn listener(&self) -> Option<&ListenerConfig> {
+        self.listener.as_deref()
+    }
+
+    pub fn mode(&self) -> ListenerMode {
+        self.listener().map(|l| l.mode).unwrap_or_default()
+    }
+
+    pub fn set_listening(&self) {
+        self.listening.store(true, Ordering::Relaxed);
+    }
+
+    pub fn readiness(&self) -> Readiness {
+        let listening = self.listening.load(Ordering::Relaxed);
+        let mut problems: Vec<String> = self
+            .root_ca()
+            .verify()
+            .err()
+            .map(|e| e.to_string())
+            .into_iter()
+            .collect();
+        for (name, ca) in self.root_cas.iter() {
+            if let Err(e) = ca.verify() {
+                problems.push(format!("{name}: {e}"));
+            }
+        }
+        let ca = (!problems.is_empty()).then(|| problems.join("; "));
+        let config = self.config().validate();
+        Readiness {
+            ready: listening && ca.is_none() && config.is_empty(),
+            listening,
+            ca,
+            config,
+        }
+    }
+
+    pub fn signed_hosts(&self) -> Vec<String> {
+        signed_ca().key_order().cloned().collect()
+    }
+
+    /// 清除签发证书缓存，`host` 为空时全部清除，返回清除数量
+    pub fn purge_signed(&self, host: Option<&str>) -> usize {
+        let mut cache = signed_ca();
+        match host {
+            Some(host) => cache.cache_remove(&host.to_owned()).map_or(0, |_| 1),
+            None => {
+                let size = cache.cache_size();
+                cache.cache_clear();
+                size
+            }
+        }
+    }
+
+    /// 熔断：探测为不可达的源站直接拒绝
+    pub fn is_reachable(&self, addr: &str) -> bool {
+        match &self.config().probe {
+            Some(probe) if probe.circuit_breaker => self.health.is_reachable(addr),
+            _ => true,
+        }
     }
 
     pub fn local_addr(&self) -> Result<SocketAddr> {
-        self.config.local_addr()
+        self.config().local_addr()
     }
 
     pub fn is_proxy(&self, host: &str) -> bool {
-        self.config.is_proxy(host)
+        self.config().is_proxy(host)
+    }
+
+    pub fn dialer(&self) -> Dialer {
+        Dialer::new(self.config(), self.resolver.clone(), self.geoip.clone())
     }
 
     pub fn is_parse(&self) -> bool {
-        self.config.parse
+        self.listener
+            .as_ref()
+            .and_then(|l| l.parse)
+            .unwrap_or(self.config().parse)
     }
 
-    pub fn get_sni<'a>(&'a self, host: &'a str) -> &str {
-        if self.config.sni.is_empty() {
-            host
+    /// 内置测试源站总是解析，`depth_hosts` 中的规则优先于监听端与全局的 `parse`
+    pub fn is_parse_for(&self, host: &str) -> bool {
+        let config = self.config();
+        if config.is_echo(host) {
+            return true;
+        }
+        match config.depth(host) {
+            Some(depth) => depth == Depth::Parse,
+            None => self.is_parse(),
+        }
+    }
+
+    pub async fn transcript(&self, host: &str) -> Option<Transcript> {
+        let config = self.config();
+        if !config.is_transcript(host) {
+            return None;
+        }
+        Transcript::new(&config.transcript_dir, host)
+            .await
+            .inspect_err(|e| error!("create transcript failed: {e}"))
+            .ok()
+    }
+
+    pub fn get_sni(&self, host: &str) -> String {
+        let config = self.config();
+        if config.sni.is_empty() {
+            host.to_owned()
         } else {
-            &self.config.sni
+            config.sni.clone()
         }
     }
 
-    pub fn get_signed_cert(&self, host: String) -> Result<CA> {
-        match get_cached_cert(host.clone()) {
-            Ok(ca) => Ok(ca),
-            Err(_) => match self.root_ca.sign(host.clone()) {
-                Ok(ca) => match SIGNED_CA.lock() {
-                    Ok(mut cache) => {
-                        cache.cache_set(host, ca.clone());
-                        Ok(ca)
-                    }
-                    Err(e) => Err(anyhow!("{e}")),
-                },
-                Err(e) => Err(anyhow!("{e}")),
+    /// 默认的 root CA，轮换完成前为旧的
+    pub fn root_ca(&self) -> Arc<CA> {
+        match self.root_ca.read() {
+            Ok(ca) => ca.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    fn rotation(&self) -> Option<Rotation> {
+        match self.rotation.read() {
+            Ok(rotation) => rotation.clone(),
+            Err(e) => e.into_inner().clone(),
+        }
+    }
+
+    fn set_rotation(&self, rotation: Option<Rotation>) {
+        match self.rotation.write() {
+            Ok(mut current) => *current = rotation,
+            Err(e) => *e.into_inner() = rotation,
+        }
+    }
+
+    pub fn rotation_status(&self) -> rotation::Status {
+        rotation::Status::new(&self.root_ca(), self.rotation().as_ref())
+    }
+
+    /// 供客户端下载的证书：当前的 root CA，轮换中时还有新的
+    pub fn root_ca_pem(&self) -> Result<Vec<u8>> {
+        let mut pem = self.root_ca().cert.to_pem()?;
+        if let Some(rotation) = self.rotation() {
+            pem.extend(rotation.next.cert.to_pem()?);
+        }
+        Ok(pem)
+    }
+
+    /// 生成新的 root CA，`overlap` 后替换当前的
+    pub async fn rotate_ca(&self, overlap: Duration) -> Result<rotation::Status> {
+        if self.rotation().is_some() {
+            return Err(anyhow!("a root CA rotation is already in progress"));
+        }
+        let config = self.config();
+        let rotation = Rotation::start(
+            &config.root_ca_cert_path,
+            &config.root_ca_key_path,
+            self.passphrase.as_deref().map(Vec::as_slice),
+            overlap,
+        )
+        .await?;
+        self.schedule_rotation(rotation);
+        Ok(self.rotation_status())
+    }
+
+    /// 放弃进行中的轮换，返回是否有轮换
+    pub async fn cancel_rotation(&self) -> Result<bool> {
+        if self.rotation().is_none() {
+            return Ok(false);
+        }
+        self.set_rotation(None);
+        let config = self.config();
+        Rotation::cancel(&config.root_ca_cert_path, &config.root_ca_key_path).await?;
+        Ok(true)
+    }
+
+    fn schedule_rotation(&self, rotation: Rotation) {
+        self.set_rotation(Some(rotation.clone()));
+        let state = self.clone();
+        tokio::task::spawn(async move {
+            let wait = rotation
+                .cutoff
+                .duration_since(SystemTime::now())
+                .unwrap_or_default();
+            tokio::time::sleep(wait).await;
+            // 期间被取消或重新开始
+            let current = state
+                .rotation()
+                .is_some_and(|current| Arc::ptr_eq(&current.next, &rotation.next));
+            if !current {
+                return;
+            }
+            let config = state.config();
+            let passphrase = state.passphrase.as_deref().map(Vec::as_slice);
+            if let Err(e) = rotation
+                .complete(
+                    &config.root_ca_cert_path,
+                    &config.root_ca_key_path,
+                    passphrase,
+                )
+                .await
+            {
+                error!("Complete root CA rotation failed: {e}");
+                return;
+            }
+            match state.root_ca.write() {
+                Ok(mut ca) => *ca = rotation.next.clone(),
+                Err(e) => *e.into_inner() = rotation.next.clone(),
+            }
+            state.set_rotation(None);
+            // 旧 CA 签发的证书不再使用
+            state.purge_signed(None);
+        });
+    }
+
+    /// 签发 `host` 证书的 CA：`root_cas` 中匹配的，否则为默认的 root CA
+    pub fn root_ca_for(&self, host: &str) -> Arc<CA> {
+        let config = self.config();
+        match config.root_ca(host) {
+            Some(root) => match self.root_cas.get(&root.name) {
+                Some(ca) => ca.clone(),
+                None => {
+                    warn!(
+                        name = root.name,
+                        "root CA added after start, restart to load it"
+                    );
+                    self.root_ca()
+                }
             },
+            None => self.root_ca(),
         }
     }
 
-    pub fn wrap_ssl_stream(
+    pub fn get_signed_cert(&self, host: String) -> Result<CA> {
+        if let Some(ca) = get_cached_cert(&host) {
+            if !ca.expires_within(RENEW_BEFORE_DAYS) {
+                return Ok(ca);
+            }
+            info!(host, "Renewing expiring certificate");
+            Metrics::incr(&self.metrics.certs_renewed);
+        }
+        self.sign_cert(host)
+    }
+
+    fn sign_cert(&self, host: String) -> Result<CA> {
+        match self.root_ca_for(&host).sign(host.clone()) {
+            Ok(ca) => {
+                signed_ca().cache_set(host, ca.clone());
+                Ok(ca)
+            }
+            Err(e) => Err(anyhow!("{e}")),
+        }
+    }
+
+    /// 定期重新签发缓存中将过期的证书，避免在握手时才发现
+    fn spawn_cert_renewal(&self) {
+        let state = self.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval(RENEW_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let state = state.clone();
+                let renewed = tokio::task::spawn_blocking(move || state.renew_expiring())
+                    .await
+                    .unwrap_or_default();
+                if renewed > 0 {
+                    info!("Renewed {renewed} expiring certificates");
+                }
+            }
+        });
+    }
+
+    fn renew_expiring(&self) -> usize {
+        let mut renewed = 0;
+        for host in self.signed_hosts() {
+            let expiring =
+                get_cached_cert(&host).is_some_and(|ca| ca.expires_within(RENEW_BEFORE_DAYS));
+            if !expiring {
+                continue;
+            }
+            match self.sign_cert(host.clone()) {
+                Ok(_) => {
+                    Metrics::incr(&self.metrics.certs_renewed);
+                    renewed += 1;
+                }
+                Err(e) => error!(host, "Renew certificate failed: {e}"),
+            }
+        }
+        renewed
+    }
+
+    /// `alpn` 为向客户端通告的协议（wire 格式），为空时不协商
+    pub fn wrap_ssl_stream<S>(
         &self,
-        upgraded: TokioIo<Upgraded>,
+        upgraded: S,
         host: String,
-    ) -> Result<SslStream<TokioIo<Upgraded>>> {
+        alpn: Option<&[u8]>,
+    ) -> Result<SslStream<S>>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
         let signed_ca = Self::get_signed_cert(self, host)?;
 
-        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
-        builder.set_certificate(&signed_ca.cert)?;
-        builder.set_private_key(&signed_ca.key)?;
-        let acceptor = builder.build();
-
-        let server_ssl = Ssl::new(acceptor.context())?;
+        let mut server_ssl = Ssl::new(self.acceptor.context())?;
+        server_ssl.set_certificate(&signed_ca.cert)?;
+        server_ssl.set_private_key(&signed_ca.key)?;
+        if let Some(alpn) = alpn {
+            server_ssl.set_ex_data(alpn::index(), alpn.to_vec());
+        }
         let input = SslStream::new(server_ssl, upgraded)?;
         Ok(input)
     }
 }
+
+/// 证书按连接设置，context 只保存会话
+fn mitm_acceptor() -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_session_id_context(b"http-proxy-server")?;
+    builder.set_session_cache_mode(SslSessionCacheMode::SERVER);
+    builder.set_alpn_select_callback(alpn::select);
+    Ok(builder.build())
+}
+
+#[tokio::test]
+async fn should_resume_mitm_session() {
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use std::pin::Pin;
+
+    let root = CA::generate().await.unwrap();
+    let leaf = root.sign("localhost".to_owned()).unwrap();
+    let acceptor = mitm_acceptor().unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ssl = Ssl::new(acceptor.context()).unwrap();
+            ssl.set_certificate(&leaf.cert).unwrap();
+            ssl.set_private_key(&leaf.key).unwrap();
+            let mut stream = SslStream::new(ssl, stream).unwrap();
+            if Pin::new(&mut stream).accept().await.is_ok() {
+                // TLS 1.3 tickets are sent after the handshake
+                let _ = tokio::io::AsyncWriteExt::write_all(&mut stream, b"x").await;
+                let _ = tokio::io::AsyncWriteExt::shutdown(&mut stream).await;
+            }
+        }
+    });
+
+    let connector = SslConnector::builder(SslMethod::tls()).unwrap().build();
+    let mut session: Option<openssl::ssl::SslSession> = None;
+    for resumed in [false, true] {
+        let mut ssl = connector
+            .configure()
+            .unwrap()
+            .into_ssl("localhost")
+            .unwrap();
+        ssl.set_verify(SslVerifyMode::NONE);
+        if let Some(session) = &session {
+            unsafe { ssl.set_session(session).unwrap() };
+        }
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = SslStream::new(ssl, stream).unwrap();
+        Pin::new(&mut stream).connect().await.unwrap();
+        let mut byte = [0; 1];
+        tokio::io::AsyncReadExt::read_exact(&mut stream, &mut byte)
+            .await
+            .unwrap();
+        assert_eq!(stream.ssl().session_reused(), resumed);
+        session = stream.ssl().session().map(|session| session.to_owned());
+        // sessions of connections dropped without close_notify are not resumable
+        let _ = tokio::io::AsyncWriteExt::shutdown(&mut stream).await;
+    }
+}
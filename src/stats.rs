@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use tokio::io::AsyncWriteExt;
+use tracing::error;
+
+use crate::config::StatsSnapshotConfig;
+use crate::metrics::{TrafficStats, TrafficSummary};
+
+const HEADER: &str = "timestamp,host,requests,errors,bytes_up,bytes_down\n";
+
+/// 按 `stats_snapshot` 追加流量增量；timestamp 为 unix 秒，每行是上次写入以来的变化
+#[derive(Clone)]
+pub struct Snapshots {
+    traffic: TrafficStats,
+    path: PathBuf,
+    last: Arc<Mutex<HashMap<String, TrafficSummary>>>,
+}
+
+impl Snapshots {
+    pub fn new(config: &StatsSnapshotConfig, traffic: TrafficStats) -> Self {
+        Self {
+            traffic,
+            path: config.path.clone(),
+            last: Arc::default(),
+        }
+    }
+
+    pub fn spawn(&self, interval_secs: u64) {
+        let snapshots = self.clone();
+        let period = Duration::from_secs(interval_secs.max(1));
+        tokio::task::spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                if let Err(e) = snapshots.write().await {
+                    error!(
+                        "write stats snapshot to {} failed: {e}",
+                        snapshots.path.display()
+                    );
+                }
+            }
+        });
+    }
+
+    /// 返回写入的行数
+    pub async fn write(&self) -> Result<usize> {
+        let now = self.traffic.snapshot();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let (rows, count) = {
+            let mut last = self.last.lock().map_err(|e| anyhow!("{e}"))?;
+            let rows = rows(&last, &now, timestamp);
+            *last = now;
+            rows
+        };
+        if count == 0 {
+            return Ok(0);
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        if file.metadata().await?.len() == 0 {
+            file.write_all(HEADER.as_bytes()).await?;
+        }
+        file.write_all(rows.as_bytes()).await?;
+        Ok(count)
+    }
+}
+
+/// 有变化的 host 各一行，按 host 排序
+fn rows(
+    last: &HashMap<String, TrafficSummary>,
+    now: &HashMap<String, TrafficSummary>,
+    timestamp: u64,
+) -> (String, usize) {
+    let mut hosts: Vec<_> = now.keys().collect();
+    hosts.sort();
+    let mut out = String::new();
+    let mut count = 0;
+    for host in hosts {
+        let current = &now[host];
+        let delta = |f: fn(&TrafficSummary) -> u64| {
+            f(current).saturating_sub(last.get(host).map(f).unwrap_or_default())
+        };
+        let requests = delta(|s| s.requests);
+        if requests == 0 {
+            continue;
+        }
+        out.push_str(&format!(
+            "{timestamp},{},{requests},{},{},{}\n",
+            csv_field(host),
+            delta(|s| s.errors),
+            delta(|s| s.bytes_up),
+            delta(|s| s.bytes_down)
+        ));
+        count += 1;
+    }
+    (out, count)
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[test]
+fn should_write_deltas() {
+    let traffic = TrafficStats::default();
+    traffic.record("a.com", 10, 100, Some(5));
+    traffic.record_error("b.com");
+    let first = traffic.snapshot();
+    let (csv, count) = rows(&HashMap::new(), &first, 1);
+    assert_eq!(count, 2);
+    assert_eq!(csv, "1,a.com,1,0,10,100\n1,b.com,1,1,0,0\n");
+
+    traffic.record("a.com", 1, 2, Some(5));
+    let (csv, count) = rows(&first, &traffic.snapshot(), 2);
+    assert_eq!(count, 1);
+    assert_eq!(csv, "2,a.com,1,0,1,2\n");
+    assert_eq!(csv_field("a,b"), "\"a,b\"");
+}
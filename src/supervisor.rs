@@ -0,0 +1,76 @@
+use std::any::Any;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::task::Poll;
+
+use tracing::error;
+
+use crate::metrics::Metrics;
+use crate::state::State;
+
+/// panic 时记录日志而不是打印到 stderr，
+/// 在 tokio 任务中发生时带上当前 span，即 flow / tunnel 的 id
+pub fn install_hook() {
+    panic::set_hook(Box::new(|info| {
+        let location = info
+            .location()
+            .map(|location| location.to_string())
+            .unwrap_or_default();
+        error!(target: "panic", location, "{}", message(info.payload()));
+    }));
+}
+
+/// 轮询 `fut` 并捕获其中的 panic
+pub async fn catch<F: Future>(fut: F) -> Result<F::Output, Box<dyn Any + Send>> {
+    let mut fut = Box::pin(fut);
+    std::future::poll_fn(|cx| {
+        panic::catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(cx)))
+            .map_or_else(|payload| Poll::Ready(Err(payload)), |poll| poll.map(Ok))
+    })
+    .await
+}
+
+pub fn message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+/// 连接任务的 panic 只结束该连接，记录连接信息并计数
+pub async fn contain<F: Future<Output = ()>>(state: &State, fut: F) {
+    let Err(payload) = catch(fut).await else {
+        return;
+    };
+    Metrics::incr(&state.metrics().panics);
+    let message = message(payload.as_ref());
+    match state.connection() {
+        Some(conn) => {
+            let targets = conn
+                .targets
+                .lock()
+                .map(|targets| targets.join(","))
+                .unwrap_or_default();
+            error!(
+                conn = conn.id,
+                peer = ?conn.peer_addr,
+                targets,
+                "connection task panicked: {message}"
+            );
+        }
+        None => error!("connection task panicked: {message}"),
+    }
+}
+
+#[tokio::test]
+async fn should_catch_panics() {
+    assert_eq!(catch(async { 1 }).await.unwrap(), 1);
+    let payload = catch(async {
+        tokio::task::yield_now().await;
+        panic!("boom {}", 42);
+    })
+    .await
+    .unwrap_err();
+    assert_eq!(message(payload.as_ref()), "boom 42");
+}
@@ -0,0 +1,290 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::Command;
+
+use anyhow::{anyhow, Result};
+use tracing::{error, info};
+
+/// 启动时设置的系统代理，`restore` 恢复为启动前的配置
+pub struct SystemProxy {
+    restore: Vec<Vec<String>>,
+}
+
+impl SystemProxy {
+    /// 未指定地址（0.0.0.0）时指向本机；失败时撤销已做的修改
+    pub fn enable(addr: SocketAddr) -> Result<Self> {
+        let server = proxy_server(addr);
+        let mut proxy = Self {
+            restore: Vec::new(),
+        };
+        if let Err(e) = imp::enable(server, &mut proxy.restore) {
+            proxy.restore();
+            return Err(e);
+        }
+        info!("System proxy set to {server}");
+        Ok(proxy)
+    }
+
+    pub fn restore(self) {
+        for args in self.restore {
+            if let Err(e) = run(&args) {
+                error!("restore system proxy failed: {e}");
+            }
+        }
+        info!("System proxy restored");
+    }
+}
+
+fn run<S: AsRef<str>>(args: &[S]) -> Result<String> {
+    let (program, args) = args.split_first().ok_or(anyhow!("empty command"))?;
+    let output = Command::new(program.as_ref())
+        .args(args.iter().map(|a| a.as_ref()))
+        .output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(anyhow!(
+            "{} failed: {}",
+            program.as_ref(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+fn cmd(args: &[&str]) -> Vec<String> {
+    args.iter().map(|a| a.to_string()).collect()
+}
+
+/// 未指定地址时指向本机；显示为 `host:port`，IPv6 带方括号
+fn proxy_server(addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())
+    } else {
+        addr
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::net::SocketAddr;
+
+    use anyhow::Result;
+    use tracing::warn;
+
+    use super::{cmd, run};
+
+    const KEY: &str = r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings";
+
+    fn query(name: &str) -> Option<(String, String)> {
+        let output = run(&["reg", "query", KEY, "/v", name]).ok()?;
+        // "    ProxyEnable    REG_DWORD    0x1"
+        output.lines().find_map(|line| {
+            let mut parts = line.split_whitespace();
+            (parts.next()? == name).then_some(())?;
+            let kind = parts.next()?.to_owned();
+            Some((kind, parts.collect::<Vec<_>>().join(" ")))
+        })
+    }
+
+    fn restore_value(name: &str) -> Vec<String> {
+        match query(name) {
+            Some((kind, value)) => {
+                let value = match value.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).unwrap_or_default().to_string(),
+                    None => value,
+                };
+                cmd(&[
+                    "reg", "add", KEY, "/v", name, "/t", &kind, "/d", &value, "/f",
+                ])
+            }
+            None => cmd(&["reg", "delete", KEY, "/v", name, "/f"]),
+        }
+    }
+
+    fn set_commands(server: &str) -> Vec<Vec<String>> {
+        [
+            ("ProxyEnable", "REG_DWORD", "1"),
+            ("ProxyServer", "REG_SZ", server),
+            ("ProxyOverride", "REG_SZ", "<local>"),
+        ]
+        .into_iter()
+        .map(|(name, kind, value)| {
+            cmd(&["reg", "add", KEY, "/v", name, "/t", kind, "/d", value, "/f"])
+        })
+        .collect()
+    }
+
+    /// 先记下原值，`restore` 在失败时也由调用方执行
+    pub fn enable(server: SocketAddr, restore: &mut Vec<Vec<String>>) -> Result<()> {
+        restore.extend(["ProxyEnable", "ProxyServer", "ProxyOverride"].map(restore_value));
+        let server = server.to_string();
+        for args in set_commands(&server) {
+            run(&args)?;
+        }
+
+        // WinHTTP needs an elevated process
+        match run(&["netsh", "winhttp", "set", "proxy", &server, "<local>"]) {
+            Ok(_) => restore.push(cmd(&["netsh", "winhttp", "reset", "proxy"])),
+            Err(e) => warn!("set WinHTTP proxy failed: {e}"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use std::net::SocketAddr;
+
+    use anyhow::Result;
+
+    use super::{cmd, run};
+
+    fn services() -> Result<Vec<String>> {
+        let output = run(&["networksetup", "-listallnetworkservices"])?;
+        // first line is a notice, disabled services start with '*'
+        Ok(output
+            .lines()
+            .skip(1)
+            .filter(|line| !line.is_empty() && !line.starts_with('*'))
+            .map(|line| line.to_owned())
+            .collect())
+    }
+
+    /// `networksetup -getwebproxy` output: Enabled / Server / Port
+    fn restore_proxy(service: &str, kind: &str) -> Result<Vec<Vec<String>>> {
+        let output = run(&["networksetup", &format!("-get{kind}"), service])?;
+        let field = |name: &str| {
+            output.lines().find_map(|line| {
+                line.strip_prefix(name)
+                    .and_then(|v| v.strip_prefix(": "))
+                    .map(|v| v.trim().to_owned())
+            })
+        };
+        let enabled = field("Enabled").is_some_and(|v| v == "Yes");
+        let server = field("Server").unwrap_or_default();
+        let port = field("Port").unwrap_or_default();
+        let mut restore = Vec::new();
+        if !server.is_empty() {
+            restore.push(cmd(&[
+                "networksetup",
+                &format!("-set{kind}"),
+                service,
+                &server,
+                &port,
+            ]));
+        }
+        let state = if enabled { "on" } else { "off" };
+        restore.push(cmd(&[
+            "networksetup",
+            &format!("-set{kind}state"),
+            service,
+            state,
+        ]));
+        Ok(restore)
+    }
+
+    fn set_command(service: &str, kind: &str, server: SocketAddr) -> Vec<String> {
+        cmd(&[
+            "networksetup",
+            &format!("-set{kind}"),
+            service,
+            &server.ip().to_string(),
+            &server.port().to_string(),
+        ])
+    }
+
+    /// 逐个服务记下原值再修改，`restore` 在失败时也由调用方执行
+    pub fn enable(server: SocketAddr, restore: &mut Vec<Vec<String>>) -> Result<()> {
+        for service in services()? {
+            for kind in ["webproxy", "securewebproxy"] {
+                restore.extend(restore_proxy(&service, kind)?);
+                run(&set_command(&service, kind, server))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+mod imp {
+    use std::net::SocketAddr;
+
+    use anyhow::Result;
+
+    use super::{cmd, run};
+
+    fn get(schema: &str, key: &str) -> Result<Vec<String>> {
+        let value = run(&["gsettings", "get", schema, key])?;
+        Ok(cmd(&["gsettings", "set", schema, key, value.trim()]))
+    }
+
+    const SCHEMAS: [&str; 2] = [
+        "org.gnome.system.proxy.http",
+        "org.gnome.system.proxy.https",
+    ];
+
+    pub(super) fn set_commands(server: SocketAddr) -> Vec<Vec<String>> {
+        let (host, port) = (server.ip().to_string(), server.port().to_string());
+        let mut commands = Vec::new();
+        for schema in SCHEMAS {
+            commands.push(cmd(&["gsettings", "set", schema, "host", &host]));
+            commands.push(cmd(&["gsettings", "set", schema, "port", &port]));
+        }
+        // mode last so that host/port are in place before it takes effect
+        commands.push(cmd(&[
+            "gsettings",
+            "set",
+            "org.gnome.system.proxy",
+            "mode",
+            "manual",
+        ]));
+        commands
+    }
+
+    /// GNOME 桌面，其他桌面环境需手动设置；先记下所有原值，`restore` 在失败时也由调用方执行
+    pub fn enable(server: SocketAddr, restore: &mut Vec<Vec<String>>) -> Result<()> {
+        // mode first when restoring, the proxy is off before host/port change back
+        restore.push(get("org.gnome.system.proxy", "mode")?);
+        for schema in SCHEMAS {
+            restore.push(get(schema, "host")?);
+            restore.push(get(schema, "port")?);
+        }
+        for args in set_commands(server) {
+            run(&args)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn should_build_proxy_commands() {
+    let server = |addr: &str| proxy_server(addr.parse().unwrap());
+    assert_eq!(server("0.0.0.0:8080").to_string(), "127.0.0.1:8080");
+    assert_eq!(server("[::]:8080").to_string(), "127.0.0.1:8080");
+    assert_eq!(server("[fd00::1]:8080").to_string(), "[fd00::1]:8080");
+
+    #[cfg(not(any(windows, target_os = "macos")))]
+    {
+        let commands = imp::set_commands(server("[fd00::1]:8080"));
+        assert_eq!(
+            commands[0],
+            [
+                "gsettings",
+                "set",
+                "org.gnome.system.proxy.http",
+                "host",
+                "fd00::1"
+            ]
+        );
+        assert_eq!(
+            commands[3],
+            [
+                "gsettings",
+                "set",
+                "org.gnome.system.proxy.https",
+                "port",
+                "8080"
+            ]
+        );
+        assert_eq!(commands.last().unwrap()[4], "manual");
+    }
+}
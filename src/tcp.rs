@@ -0,0 +1,197 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::info;
+
+use crate::filter;
+use crate::flow::{self, Chunk, Direction, Flow};
+use crate::metrics::Protocol;
+use crate::state::State;
+use crate::util;
+
+/// 把非 HTTP 隧道记录为 method 为 `TCP` 的 flow：上行计入 request，下行计入 response，
+/// `tcp_capture` 时按读写记录原始字节，总量受 `flow_body_limit` 限制
+pub async fn relay<S>(
+    client: S,
+    addr: &str,
+    host: &str,
+    protocol: &str,
+    state: &State,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    state.protocols().record(host, Protocol::Tunneled);
+    let config = state.config();
+    let flows = state.flows();
+    let id = flow::next_id();
+    flows.insert(Flow {
+        id,
+        addr: addr.to_owned(),
+        host: host.to_owned(),
+        method: "TCP".to_owned(),
+        uri: format!("tcp://{addr}"),
+        ..Default::default()
+    });
+    info!(%id, protocol, "tcp tunnel opened");
+
+    let mut client = Recorder {
+        inner: client,
+        start: Instant::now(),
+        chunks: Vec::new(),
+        budget: if config.tcp_capture {
+            config.flow_body_limit
+        } else {
+            0
+        },
+    };
+    let copied = async {
+        let mut server = state.dialer().connect(addr).await?;
+        let copied = util::copy_bidirectional_idle(
+            &mut client,
+            &mut server,
+            config.timeouts.tunnel_idle_secs,
+            config.socket.copy_buffer_size,
+        )
+        .await?;
+        Ok::<_, anyhow::Error>(copied)
+    }
+    .await;
+
+    let duration_ms = Some(client.start.elapsed().as_millis() as u64);
+    let chunks = client.chunks;
+    flows.update(id, |flow| {
+        match &copied {
+            Ok((up, down)) => {
+                flow.request_size = *up;
+                flow.response_size = *down;
+            }
+            Err(e) => flow.error = Some(e.to_string()),
+        }
+        flow.duration_ms = duration_ms;
+        flow.chunks = chunks;
+        flow.complete = true;
+    });
+    if let Some(flow) = flows.get(id) {
+        if !filter::allows(config.filters.store.as_ref(), &flow) {
+            flows.remove(id);
+        }
+    }
+    let (up, down) = copied?;
+    info!(%id, up, down, "tcp tunnel closed");
+    state.record_traffic(host, up, down, None);
+    Ok(())
+}
+
+/// 包装客户端一侧：读到的是上行，写出的是下行
+struct Recorder<S> {
+    inner: S,
+    start: Instant,
+    chunks: Vec<Chunk>,
+    /// 还能记录的字节数
+    budget: usize,
+}
+
+impl<S> Recorder<S> {
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        if self.budget == 0 || data.is_empty() {
+            return;
+        }
+        let data = &data[..data.len().min(self.budget)];
+        self.budget -= data.len();
+        self.chunks.push(Chunk {
+            direction,
+            offset_ms: self.start.elapsed().as_millis() as u64,
+            data: Bytes::copy_from_slice(data),
+        });
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorder<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            self.record(Direction::Up, &buf.filled()[filled..]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorder<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.record(Direction::Down, &buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn should_record_tcp_flow() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::config::Config;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut ping = [0; 4];
+        stream.read_exact(&mut ping).await.unwrap();
+        stream.write_all(b"pong").await.unwrap();
+    });
+
+    let state = State::new(Config {
+        tcp_capture: true,
+        flow_body_limit: 6,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let (mut client, stream) = tokio::io::duplex(64);
+    client.write_all(b"ping").await.unwrap();
+    client.shutdown().await.unwrap();
+    relay(stream, &addr, "127.0.0.1", "other", &state)
+        .await
+        .unwrap();
+    let mut pong = Vec::new();
+    client.read_to_end(&mut pong).await.unwrap();
+    assert_eq!(pong, b"pong");
+
+    let flow = state.flows().list().pop().unwrap();
+    assert!(flow.is_tcp() && flow.complete);
+    assert_eq!((flow.request_size, flow.response_size), (4, 4));
+    // capture stops at `flow_body_limit`
+    let chunks: Vec<_> = flow
+        .chunks
+        .iter()
+        .map(|chunk| (chunk.direction, &chunk.data[..]))
+        .collect();
+    assert_eq!(
+        chunks,
+        [(Direction::Up, &b"ping"[..]), (Direction::Down, &b"po"[..])]
+    );
+}
@@ -0,0 +1,211 @@
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
+use tracing::error;
+
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// 每个记录文件最多缓冲的块数，写盘跟不上时对连接施加背压
+const BUFFERED_CHUNKS: usize = 64;
+
+/// 透传的流，启用时把读写的原始字节分别记录到 `.read` / `.write` 文件
+pub struct TranscriptStream<S> {
+    inner: S,
+    read_tx: Option<PollSender<Bytes>>,
+    write_tx: Option<PollSender<Bytes>>,
+}
+
+/// 一次连接的记录前缀，同一连接的 pre-TLS / post-TLS 记录共用
+#[derive(Clone)]
+pub struct Transcript {
+    prefix: String,
+}
+
+impl Transcript {
+    pub async fn new(dir: &Path, host: &str) -> Result<Self> {
+        fs::create_dir_all(dir).await?;
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+        let host: String = host
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let prefix = dir
+            .join(format!("{millis}-{seq}-{host}"))
+            .to_string_lossy()
+            .into_owned();
+        Ok(Self { prefix })
+    }
+
+    /// 以 `tag` 区分记录的位置，如 `raw`、`tls`、`upstream`
+    /// 记录文件创建失败时不影响连接，仅打印错误并透传
+    pub async fn record<S>(&self, inner: S, tag: &str) -> TranscriptStream<S> {
+        match tokio::try_join!(
+            spawn_writer(format!("{}.{tag}.read", self.prefix)),
+            spawn_writer(format!("{}.{tag}.write", self.prefix))
+        ) {
+            Ok((read_tx, write_tx)) => TranscriptStream {
+                inner,
+                read_tx: Some(read_tx),
+                write_tx: Some(write_tx),
+            },
+            Err(e) => {
+                error!("create transcript {}.{tag} failed: {e}", self.prefix);
+                TranscriptStream::passthrough(inner)
+            }
+        }
+    }
+}
+
+impl<S> TranscriptStream<S> {
+    /// 不记录
+    pub fn passthrough(inner: S) -> Self {
+        Self {
+            inner,
+            read_tx: None,
+            write_tx: None,
+        }
+    }
+}
+
+/// 按需包装，`transcript` 为空时不记录
+pub async fn record<S>(
+    transcript: Option<&Transcript>,
+    inner: S,
+    tag: &str,
+) -> TranscriptStream<S> {
+    match transcript {
+        Some(transcript) => transcript.record(inner, tag).await,
+        None => TranscriptStream::passthrough(inner),
+    }
+}
+
+async fn spawn_writer(path: String) -> Result<PollSender<Bytes>> {
+    let mut file = File::create(&path).await?;
+    let (tx, mut rx) = mpsc::channel::<Bytes>(BUFFERED_CHUNKS);
+    tokio::task::spawn(async move {
+        while let Some(chunk) = rx.recv().await {
+            if let Err(e) = file.write_all(&chunk).await {
+                error!("write transcript {path} failed: {e}");
+                return;
+            }
+        }
+        let _ = file.flush().await;
+    });
+    Ok(PollSender::new(tx))
+}
+
+/// 先占到发送位置再读写，写盘出错时停止记录
+pub fn poll_reserve(tx: &mut Option<PollSender<Bytes>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(sender) = tx {
+        if ready!(sender.poll_reserve(cx)).is_err() {
+            *tx = None;
+        }
+    }
+    Poll::Ready(())
+}
+
+fn send(tx: &mut Option<PollSender<Bytes>>, chunk: &[u8]) {
+    if let Some(sender) = tx {
+        if chunk.is_empty() {
+            sender.abort_send();
+        } else if sender.send_item(Bytes::copy_from_slice(chunk)).is_err() {
+            *tx = None;
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TranscriptStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(poll_reserve(&mut this.read_tx, cx));
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        match &poll {
+            Poll::Ready(Ok(())) => send(&mut this.read_tx, &buf.filled()[before..]),
+            Poll::Ready(Err(_)) => send(&mut this.read_tx, &[]),
+            // keep the reserved slot for the next poll
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TranscriptStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(poll_reserve(&mut this.write_tx, cx));
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        match &poll {
+            Poll::Ready(Ok(n)) => send(&mut this.write_tx, &buf[..*n]),
+            Poll::Ready(Err(_)) => send(&mut this.write_tx, &[]),
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn should_record_both_directions() {
+    use tokio::io::AsyncReadExt;
+
+    let dir = std::env::temp_dir().join(format!("should_record_transcript_{}", std::process::id()));
+    let transcript = Transcript::new(&dir, "example.com:443").await.unwrap();
+    let (client, mut server) = tokio::io::duplex(64);
+    let mut stream = transcript.record(client, "raw").await;
+    stream.write_all(b"ping").await.unwrap();
+    server.write_all(b"pong!").await.unwrap();
+    let mut buf = [0; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    drop(stream);
+
+    let read = format!("{}.raw.read", transcript.prefix);
+    let write = format!("{}.raw.write", transcript.prefix);
+    assert!(read.contains("example.com_443"));
+    // the writers flush in the background once the stream is gone
+    for _ in 0..50 {
+        if fs::read(&read).await.unwrap() == b"pong!" && fs::read(&write).await.unwrap() == b"ping"
+        {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(fs::read(&read).await.unwrap(), b"pong!");
+    assert_eq!(fs::read(&write).await.unwrap(), b"ping");
+    fs::remove_dir_all(&dir).await.unwrap();
+}
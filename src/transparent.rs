@@ -0,0 +1,124 @@
+use std::any::Any;
+use std::net::SocketAddr;
+
+use anyhow::{anyhow, Result};
+use http_body_util::BodyExt;
+use hyper::body::Incoming as IncomingBody;
+use hyper::server::conn::http1::Builder as ServerBuilder;
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+#[cfg(target_os = "linux")]
+use socket2::SockRef;
+use tokio::net::TcpStream;
+use tracing::{error, info_span, warn, Instrument};
+
+use crate::adapter::HyperAdapter;
+use crate::client;
+use crate::flow;
+use crate::listener::Io;
+use crate::metrics::Metrics;
+use crate::proxy::{self, request_protocol};
+use crate::sniff::{self, Rewind};
+use crate::state::{ClientState, State};
+use crate::util::request_host;
+
+/// 透明代理：iptables/nftables 重定向来的连接没有 CONNECT，
+/// 目标取自 SO_ORIGINAL_DST（REDIRECT）或本地地址（TPROXY），主机名取自 SNI 或 Host
+pub async fn serve(stream: Box<dyn Io>, state: State) {
+    let stream: Box<dyn Any> = stream;
+    let Ok(stream) = stream.downcast::<TcpStream>() else {
+        error!("transparent mode needs a TCP listener");
+        return;
+    };
+    let listen_port = state
+        .listener()
+        .and_then(|l| l.addr.parse::<SocketAddr>().ok())
+        .map(|addr| addr.port());
+    let dst = match original_dst(&stream, listen_port) {
+        Ok(dst) => dst,
+        Err(e) => {
+            warn!("transparent: {e}");
+            return;
+        }
+    };
+    let span = info_span!("transparent", id = %flow::next_id(), %dst);
+    if let Err(e) = intercept(*stream, dst, state.clone())
+        .instrument(span)
+        .await
+    {
+        Metrics::incr(&state.metrics().tunnel_errors);
+        error!("transparent {dst} fail: {e}");
+    }
+}
+
+fn original_dst(stream: &TcpStream, listen_port: Option<u16>) -> Result<SocketAddr> {
+    let local = stream.local_addr()?;
+    #[cfg(target_os = "linux")]
+    {
+        let socket = SockRef::from(stream);
+        let redirected = socket
+            .original_dst()
+            .or_else(|_| socket.original_dst_ipv6());
+        if let Some(dst) = redirected.ok().and_then(|addr| addr.as_socket()) {
+            if dst != local {
+                return Ok(dst);
+            }
+        }
+    }
+    // TPROXY keeps the original destination as the local address
+    if Some(local.port()) == listen_port {
+        return Err(anyhow!("connection to {local} was not redirected"));
+    }
+    Ok(local)
+}
+
+/// 明文 HTTP 直接解析，其余与 CONNECT 隧道相同
+async fn intercept(mut stream: TcpStream, dst: SocketAddr, state: State) -> Result<()> {
+    let hello = sniff::read_hello(&mut stream).await?;
+    let addr = dst.to_string();
+    let client = client::service();
+    if !sniff::is_http(&hello) {
+        let host = sniff::client_hello(&hello)
+            .and_then(|client_hello| client_hello.server_name)
+            .unwrap_or_else(|| dst.ip().to_string());
+        let authority = format!("{host}:{}", dst.port());
+        if let Some(conn) = state.connection() {
+            conn.add_target(&authority);
+        }
+        // same checks as CONNECT, without a response to send the refusal is a close
+        let parsed = state.is_proxy(&host) && state.is_parse_for(&host);
+        if !parsed && proxy::refuse_tunnel(&state, "CONNECT", &host, &authority).is_some() {
+            return Ok(());
+        }
+        let transcript = state.transcript(&host).await;
+        let stream = Rewind::new(hello, stream);
+        return proxy::tunnel(stream, addr, host, transcript, state, client).await;
+    }
+
+    let stream = TokioIo::new(Rewind::new(hello, stream));
+    ServerBuilder::new()
+        .preserve_header_case(true)
+        .title_case_headers(true)
+        .serve_connection(
+            stream,
+            client.hyper(|req: Request<IncomingBody>| {
+                let host = request_host(&req).unwrap_or_else(|| dst.ip().to_string());
+                Metrics::incr(&state.metrics().http_requests);
+                state.protocols().record(&host, request_protocol(&req));
+                let client_state = ClientState {
+                    id: flow::next_id(),
+                    addr: addr.clone(),
+                    parse: state.is_parse_for(&host),
+                    sni: host,
+                    is_secure: false,
+                    transcript: None,
+                    timings: Default::default(),
+                    shared: state.clone(),
+                };
+                (client_state, req.map(|b| b.boxed()))
+            }),
+        )
+        .with_upgrades()
+        .await?;
+    Ok(())
+}
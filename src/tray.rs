@@ -0,0 +1,123 @@
+use std::future::Future;
+
+use crate::state::State;
+
+/// 托盘图标，返回的 future 在选择“退出”时完成；未启用时永不完成
+pub fn spawn(state: State) -> impl Future<Output = ()> {
+    imp::spawn(state)
+}
+
+#[cfg(all(windows, feature = "tray"))]
+mod imp {
+    use std::future::Future;
+
+    use tokio::sync::oneshot;
+    use tracing::error;
+    use tray_icon::menu::{CheckMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem};
+    use tray_icon::{Icon, TrayIconBuilder};
+    use windows_sys::Win32::UI::WindowsAndMessaging::{
+        DispatchMessageW, GetMessageW, TranslateMessage, MSG,
+    };
+
+    use crate::config::Config;
+    use crate::state::State;
+
+    pub fn spawn(state: State) -> impl Future<Output = ()> {
+        let (tx, rx) = oneshot::channel();
+        let handle = tokio::runtime::Handle::current();
+        std::thread::spawn(move || {
+            if let Err(e) = run(state, handle) {
+                error!("Tray icon failed: {e}");
+                return;
+            }
+            let _ = tx.send(());
+        });
+        async move {
+            // the sender is dropped without sending if the tray fails to start
+            if rx.await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// 托盘必须在创建它的线程上处理窗口消息
+    fn run(state: State, handle: tokio::runtime::Handle) -> anyhow::Result<()> {
+        let config = state.config();
+        let intercept = CheckMenuItem::new("Intercept HTTPS", true, config.intercept, None);
+        let parse = CheckMenuItem::new("Parse HTTP", true, config.parse, None);
+        let open_log = MenuItem::new("Open log", true, None);
+        let quit = MenuItem::new("Quit", true, None);
+        let menu = Menu::new();
+        menu.append_items(&[
+            &intercept,
+            &parse,
+            &PredefinedMenuItem::separator(),
+            &open_log,
+            &quit,
+        ])?;
+
+        let _tray = TrayIconBuilder::new()
+            .with_menu(Box::new(menu))
+            .with_tooltip("http-proxy-server")
+            .with_icon(icon()?)
+            .build()?;
+
+        let mut msg: MSG = unsafe { std::mem::zeroed() };
+        while unsafe { GetMessageW(&mut msg, std::ptr::null_mut(), 0, 0) } > 0 {
+            unsafe {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+            while let Ok(event) = MenuEvent::receiver().try_recv() {
+                if &event.id == intercept.id() {
+                    update(&state, &handle, |config| {
+                        config.intercept = intercept.is_checked()
+                    });
+                } else if &event.id == parse.id() {
+                    update(&state, &handle, |config| config.parse = parse.is_checked());
+                } else if &event.id == open_log.id() {
+                    if let Err(e) = std::process::Command::new("explorer")
+                        .arg("proxy.log")
+                        .spawn()
+                    {
+                        error!("Open log failed: {e}");
+                    }
+                } else if &event.id == quit.id() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn update(state: &State, handle: &tokio::runtime::Handle, f: impl FnOnce(&mut Config)) {
+        let mut config = (*state.base_config()).clone();
+        f(&mut config);
+        if let Err(e) = state.set_config(config.clone()) {
+            error!("Apply config failed: {e}");
+            return;
+        }
+        handle.spawn(async move {
+            if let Err(e) = config.save().await {
+                error!("Save config failed: {e}");
+            }
+        });
+    }
+
+    /// 16x16 纯色图标
+    fn icon() -> anyhow::Result<Icon> {
+        let rgba = [0x1e, 0x88, 0xe5, 0xff].repeat(16 * 16);
+        Ok(Icon::from_rgba(rgba, 16, 16)?)
+    }
+}
+
+#[cfg(not(all(windows, feature = "tray")))]
+mod imp {
+    use std::future::Future;
+
+    use crate::state::State;
+
+    pub fn spawn(_state: State) -> impl Future<Output = ()> {
+        std::future::pending()
+    }
+}
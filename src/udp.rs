@@ -0,0 +1,202 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hyper::header::UPGRADE;
+use hyper::Request;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::UdpSocket;
+
+use crate::util::Activity;
+
+/// RFC 9298 默认 URI 模板 `/.well-known/masque/udp/{target_host}/{target_port}/` 的前缀
+const WELL_KNOWN: &str = "/.well-known/masque/udp/";
+
+/// RFC 9297 的 DATAGRAM capsule
+const DATAGRAM: u64 = 0x00;
+
+/// 单个 UDP 报文的上限，更长的 capsule 视为错误
+const MAX_DATAGRAM: usize = 65535;
+
+/// `Upgrade: connect-udp` 请求的目标 `(host, port)`；WebSocket 的 extended CONNECT（RFC 8441）
+/// 需要 h2 的 `:protocol`，监听端只有 HTTP/1.1，仍走 CONNECT 隧道或 `Upgrade: websocket`
+pub fn target<B>(req: &Request<B>) -> Option<(String, u16)> {
+    let upgrade = req.headers().get(UPGRADE)?.to_str().ok()?;
+    if !upgrade.eq_ignore_ascii_case("connect-udp") {
+        return None;
+    }
+    let rest = req.uri().path().strip_prefix(WELL_KNOWN)?;
+    let mut segments = rest.trim_end_matches('/').split('/');
+    let host = percent_decode(segments.next()?)?;
+    let port = segments.next()?.parse().ok()?;
+    if host.is_empty() || segments.next().is_some() {
+        return None;
+    }
+    Some((host, port))
+}
+
+/// IPv6 的 `:` 在模板中编码为 `%3A`
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut iter = text.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// 在升级后的连接上收发 DATAGRAM capsule，与 `addr` 交换 UDP 报文，直到客户端关闭或空闲超时；
+/// 返回 (客户端发出的, 上游发回的) UDP 负载字节数
+pub async fn relay<S>(stream: S, addr: SocketAddr, idle_secs: u64) -> std::io::Result<(u64, u64)>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let bind: SocketAddr = match addr {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+
+    let (reader, mut writer) = tokio::io::split(stream);
+    let activity = Activity::new();
+    let (sent, received) = (AtomicU64::new(0), AtomicU64::new(0));
+    let up = async {
+        let mut reader = BufReader::new(reader);
+        while let Some((kind, payload)) = read_capsule(&mut reader).await? {
+            activity.touch();
+            if kind != DATAGRAM {
+                continue;
+            }
+            let mut payload = &payload[..];
+            // only context 0 carries UDP payloads
+            if read_varint_slice(&mut payload)? == 0 {
+                socket.send(payload).await?;
+                sent.fetch_add(payload.len() as u64, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    };
+    let down = async {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            activity.touch();
+            writer.write_all(&datagram(&buf[..n])).await?;
+            writer.flush().await?;
+            received.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    };
+    // UDP has no end, the flow ends when the client closes its stream
+    activity
+        .guard(idle_secs, async {
+            tokio::select! {
+                result = up => result,
+                result = down => result,
+            }
+        })
+        .await?;
+    Ok((sent.into_inner(), received.into_inner()))
+}
+
+/// 上下文 0 的 DATAGRAM capsule
+fn datagram(payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(payload.len() + 10);
+    put_varint(&mut buf, DATAGRAM);
+    put_varint(&mut buf, payload.len() as u64 + 1);
+    put_varint(&mut buf, 0);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+/// `(type, value)`，流在 capsule 之间结束时为 None
+async fn read_capsule<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Option<(u64, Vec<u8>)>> {
+    let first = match reader.read_u8().await {
+        Ok(first) => first,
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let kind = read_varint(reader, first).await?;
+    let first = reader.read_u8().await?;
+    let len = read_varint(reader, first).await? as usize;
+    if len > MAX_DATAGRAM + 8 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("capsule of {len} bytes"),
+        ));
+    }
+    let mut value = vec![0; len];
+    reader.read_exact(&mut value).await?;
+    Ok(Some((kind, value)))
+}
+
+/// QUIC 变长整数（RFC 9000 16 节），前两位为长度
+async fn read_varint<R: AsyncRead + Unpin>(reader: &mut R, first: u8) -> std::io::Result<u64> {
+    let len = 1 << (first >> 6);
+    let mut value = (first & 0x3f) as u64;
+    for _ in 1..len {
+        value = (value << 8) | reader.read_u8().await? as u64;
+    }
+    Ok(value)
+}
+
+fn read_varint_slice(buf: &mut &[u8]) -> std::io::Result<u64> {
+    let truncated = || Error::new(ErrorKind::InvalidData, "truncated varint");
+    let first = *buf.first().ok_or_else(truncated)?;
+    let len = 1 << (first >> 6);
+    if buf.len() < len {
+        return Err(truncated());
+    }
+    let mut value = (first & 0x3f) as u64;
+    for b in &buf[1..len] {
+        value = (value << 8) | *b as u64;
+    }
+    buf.advance(len);
+    Ok(value)
+}
+
+fn put_varint(buf: &mut BytesMut, value: u64) {
+    match value {
+        0..=0x3f => buf.put_u8(value as u8),
+        0x40..=0x3fff => buf.put_u16(0x4000 | value as u16),
+        0x4000..=0x3fff_ffff => buf.put_u32(0x8000_0000 | value as u32),
+        _ => buf.put_u64(0xc000_0000_0000_0000 | value),
+    }
+}
+
+#[tokio::test]
+async fn should_relay_udp_datagrams() {
+    let req = Request::get("/.well-known/masque/udp/2001%3Adb8%3A%3A1/443/")
+        .header(UPGRADE, "connect-udp")
+        .body(())
+        .unwrap();
+    assert_eq!(target(&req), Some(("2001:db8::1".to_owned(), 443)));
+
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 1500];
+        let (n, peer) = echo.recv_from(&mut buf).await.unwrap();
+        echo.send_to(&buf[..n], peer).await.unwrap();
+    });
+
+    let (mut client, proxy) = tokio::io::duplex(4096);
+    let relay = tokio::spawn(relay(proxy, addr, 0));
+    // an unknown capsule is skipped
+    client.write_all(&[0x17, 0x01, 0xff]).await.unwrap();
+    client.write_all(&datagram(b"ping")).await.unwrap();
+    let mut reader = BufReader::new(&mut client);
+    let (kind, value) = read_capsule(&mut reader).await.unwrap().unwrap();
+    assert_eq!((kind, &value[..]), (DATAGRAM, &b"\0ping"[..]));
+    drop(reader);
+    drop(client);
+    assert_eq!(relay.await.unwrap().unwrap(), (4, 4));
+}
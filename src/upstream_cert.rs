@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::ssl::SslRef;
+use openssl::x509::{X509NameRef, X509Ref, X509VerifyResult, X509};
+use serde::Serialize;
+use tracing::warn;
+
+/// 上游证书摘要，记录在 flow 与 `/api/upstream-certs` 中
+#[derive(Serialize, Debug, Clone)]
+pub struct CertInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub sans: Vec<String>,
+    pub not_before: String,
+    pub not_after: String,
+    /// 距过期的天数，已过期时为负
+    pub days_left: i32,
+    /// 上游发送的证书链，从叶子证书开始
+    pub chain: Vec<String>,
+    /// 证书链可由系统 CA 验证且与 SNI 匹配；不影响连接，上游证书不做校验
+    pub verified: bool,
+    pub verify_error: Option<String>,
+}
+
+impl CertInfo {
+    pub fn of(ssl: &SslRef) -> Option<Self> {
+        let leaf = ssl.peer_certificate()?;
+        let chain = ssl
+            .peer_cert_chain()
+            .map(|chain| chain.iter().map(|cert| name(cert.subject_name())).collect())
+            .unwrap_or_default();
+        let sans = leaf
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| {
+                        name.dnsname()
+                            .map(str::to_owned)
+                            .or_else(|| name.ipaddress().and_then(ip))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let result = ssl.verify_result();
+        Some(Self {
+            subject: name(leaf.subject_name()),
+            issuer: name(leaf.issuer_name()),
+            sans,
+            not_before: leaf.not_before().to_string(),
+            not_after: leaf.not_after().to_string(),
+            days_left: days_left(leaf.not_after()),
+            chain,
+            verified: result == X509VerifyResult::OK,
+            verify_error: (result != X509VerifyResult::OK)
+                .then(|| result.error_string().to_owned()),
+        })
+    }
+}
+
+/// 如 `CN=example.com, O=Example`
+fn name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|value| value.to_string())
+                .unwrap_or_default();
+            format!("{key}={value}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn ip(octets: &[u8]) -> Option<String> {
+    let ip = match octets.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(octets).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(octets).ok()?),
+        _ => return None,
+    };
+    Some(ip.to_string())
+}
+
+fn days_left(not_after: &Asn1TimeRef) -> i32 {
+    Asn1Time::days_from_now(0)
+        .and_then(|now| now.diff(not_after))
+        .map_or(0, |diff| diff.days)
+}
+
+struct Observed {
+    info: Arc<CertInfo>,
+    /// 原始证书链，导出为 PEM
+    chain: Vec<X509>,
+}
+
+/// 按 host 保存最近一次握手看到的上游证书，复用连接时也能取到
+#[derive(Clone, Default)]
+pub struct UpstreamCerts {
+    inner: Arc<Mutex<HashMap<String, Observed>>>,
+}
+
+impl UpstreamCerts {
+    /// `warn_days` 内过期时告警，同一证书只告警一次
+    pub fn record(
+        &self,
+        host: &str,
+        ssl: &SslRef,
+        warn_days: Option<u32>,
+    ) -> Option<Arc<CertInfo>> {
+        let info = Arc::new(CertInfo::of(ssl)?);
+        let chain = ssl
+            .peer_cert_chain()
+            .map(|chain| chain.iter().map(X509Ref::to_owned).collect())
+            .unwrap_or_default();
+        let Ok(mut map) = self.inner.lock() else {
+            return Some(info);
+        };
+        let seen = map
+            .get(host)
+            .is_some_and(|observed| observed.info.not_after == info.not_after);
+        if let Some(days) = warn_days {
+            if !seen && info.days_left <= days as i32 {
+                warn!(
+                    host,
+                    not_after = info.not_after,
+                    "upstream certificate expires in {} days",
+                    info.days_left
+                );
+            }
+        }
+        map.insert(
+            host.to_owned(),
+            Observed {
+                info: info.clone(),
+                chain,
+            },
+        );
+        Some(info)
+    }
+
+    pub fn get(&self, host: &str) -> Option<Arc<CertInfo>> {
+        let map = self.inner.lock().ok()?;
+        map.get(host).map(|observed| observed.info.clone())
+    }
+
+    /// 证书链的 PEM，从叶子证书开始
+    pub fn chain_pem(&self, host: &str) -> Option<Vec<u8>> {
+        let map = self.inner.lock().ok()?;
+        let mut pem = Vec::new();
+        for cert in &map.get(host)?.chain {
+            pem.extend(cert.to_pem().ok()?);
+        }
+        Some(pem)
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, Arc<CertInfo>> {
+        self.inner
+            .lock()
+            .map(|map| {
+                map.iter()
+                    .map(|(host, observed)| (host.clone(), observed.info.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[tokio::test]
+async fn should_inspect_upstream_certificate() {
+    use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
+    use std::pin::Pin;
+    use tokio_openssl::SslStream;
+
+    let root = crate::ca::CA::generate().await.unwrap();
+    let leaf = root.sign("localhost".to_owned()).unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        builder.set_certificate(&leaf.cert).unwrap();
+        builder.set_private_key(&leaf.key).unwrap();
+        let acceptor = builder.build();
+        let (stream, _) = listener.accept().await.unwrap();
+        let ssl = Ssl::new(acceptor.context()).unwrap();
+        let mut stream = SslStream::new(ssl, stream).unwrap();
+        let _ = Pin::new(&mut stream).accept().await;
+    });
+
+    let mut ssl = SslConnector::builder(SslMethod::tls())
+        .unwrap()
+        .build()
+        .configure()
+        .unwrap()
+        .into_ssl("localhost")
+        .unwrap();
+    ssl.set_verify(SslVerifyMode::NONE);
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut stream = SslStream::new(ssl, stream).unwrap();
+    Pin::new(&mut stream).connect().await.unwrap();
+
+    let certs = UpstreamCerts::default();
+    let info = certs.record("localhost", stream.ssl(), Some(30)).unwrap();
+    assert!(info.subject.contains("CN=localhost"));
+    assert!(info.issuer.contains("CN=thlstsul.github.io"));
+    assert_eq!(info.sans, ["localhost"]);
+    assert!((363..=365).contains(&info.days_left));
+    assert!(!info.verified && info.verify_error.is_some());
+    assert!(certs.get("localhost").is_some());
+    let pem = certs.chain_pem("localhost").unwrap();
+    assert_eq!(X509::stack_from_pem(&pem).unwrap().len(), 1);
+}
+
+/// 导出的 PEM 按上游发送的顺序包含整条链
+#[tokio::test]
+async fn should_export_chain_pem() {
+    use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
+    use std::pin::Pin;
+    use tokio_openssl::SslStream;
+
+    let root = crate::ca::CA::generate().await.unwrap();
+    let leaf = root.sign("localhost".to_owned()).unwrap();
+    let root_cert = root.cert.clone();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        builder.set_certificate(&leaf.cert).unwrap();
+        builder.set_private_key(&leaf.key).unwrap();
+        builder.add_extra_chain_cert(root_cert).unwrap();
+        let acceptor = builder.build();
+        let (stream, _) = listener.accept().await.unwrap();
+        let ssl = Ssl::new(acceptor.context()).unwrap();
+        let mut stream = SslStream::new(ssl, stream).unwrap();
+        let _ = Pin::new(&mut stream).accept().await;
+    });
+
+    let mut ssl = SslConnector::builder(SslMethod::tls())
+        .unwrap()
+        .build()
+        .configure()
+        .unwrap()
+        .into_ssl("localhost")
+        .unwrap();
+    ssl.set_verify(SslVerifyMode::NONE);
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut stream = SslStream::new(ssl, stream).unwrap();
+    Pin::new(&mut stream).connect().await.unwrap();
+
+    let certs = UpstreamCerts::default();
+    let info = certs.record("localhost", stream.ssl(), None).unwrap();
+    assert_eq!(info.chain.len(), 2);
+    let pem = certs.chain_pem("localhost").unwrap();
+    let chain = X509::stack_from_pem(&pem).unwrap();
+    let subjects: Vec<_> = chain.iter().map(|cert| name(cert.subject_name())).collect();
+    assert_eq!(subjects, info.chain);
+    assert!(subjects[0].contains("CN=localhost"));
+    assert_eq!(chain[1].to_der().unwrap(), root.cert.to_der().unwrap());
+    assert!(certs.chain_pem("example.com").is_none());
+}
@@ -0,0 +1,357 @@
+use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
+
+use openssl::base64;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::config::{ProxyAuthScheme, UpstreamProxy};
+use crate::dialer::split_host;
+use crate::ntlm;
+
+/// 响应头的上限
+const MAX_HEAD: usize = 16 * 1024;
+
+/// 上游代理对 CONNECT 的响应
+struct Head {
+    status: u16,
+    challenges: Vec<String>,
+    content_length: usize,
+    close: bool,
+}
+
+/// 在已连上 `proxy` 的 `stream` 上建立到 `addr` 的 CONNECT 隧道并完成认证
+pub async fn tunnel<S>(stream: &mut S, proxy: &UpstreamProxy, addr: &str) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Some(username) = proxy.username.as_deref() else {
+        return finish(proxy, addr, connect(stream, addr, None).await?);
+    };
+    let password = proxy.password.as_deref().unwrap_or_default();
+    let scheme = match proxy.auth {
+        ProxyAuthScheme::Basic => {
+            let credentials = base64::encode_block(format!("{username}:{password}").as_bytes());
+            let head = connect(stream, addr, Some(&format!("Basic {credentials}"))).await?;
+            return finish(proxy, addr, head);
+        }
+        ProxyAuthScheme::Ntlm => "NTLM",
+        ProxyAuthScheme::Negotiate => "Negotiate",
+    };
+    let negotiate = base64::encode_block(&ntlm::negotiate());
+    let head = connect(stream, addr, Some(&format!("{scheme} {negotiate}"))).await?;
+    if head.status != 407 {
+        return finish(proxy, addr, head);
+    }
+    let token = head.challenges.iter().find_map(|challenge| {
+        let (name, token) = challenge.split_once(' ')?;
+        name.eq_ignore_ascii_case(scheme).then(|| token.trim())
+    });
+    let challenge = token
+        .and_then(|token| base64::decode_block(token).ok())
+        .and_then(|msg| ntlm::Challenge::parse(&msg))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                format!("upstream proxy {} sent no {scheme} challenge", proxy.addr),
+            )
+        })?;
+    if head.close {
+        // the challenge is bound to this connection
+        return Err(Error::new(
+            ErrorKind::ConnectionAborted,
+            format!("upstream proxy {} closed during {scheme}", proxy.addr),
+        ));
+    }
+    let (domain, username) = match username.split_once('\\') {
+        Some((domain, username)) => (domain, username),
+        None => (proxy.domain.as_deref().unwrap_or_default(), username),
+    };
+    let workstation = proxy.workstation.as_deref().unwrap_or_default();
+    let authenticate = ntlm::authenticate(&challenge, username, password, domain, workstation)
+        .map_err(Error::other)?;
+    let authenticate = base64::encode_block(&authenticate);
+    let head = connect(stream, addr, Some(&format!("{scheme} {authenticate}"))).await?;
+    finish(proxy, addr, head)
+}
+
+fn finish(proxy: &UpstreamProxy, addr: &str, head: Head) -> Result<(), Error> {
+    match head.status {
+        200..=299 => {
+            debug!("tunnel to {addr} via {}", proxy.addr);
+            Ok(())
+        }
+        407 => Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("upstream proxy {} rejected credentials", proxy.addr),
+        )),
+        status => Err(Error::new(
+            ErrorKind::ConnectionRefused,
+            format!(
+                "upstream proxy {} answered CONNECT {addr} with {status}",
+                proxy.addr
+            ),
+        )),
+    }
+}
+
+/// SOCKS5（RFC 1928）的 CONNECT；`addr` 的 host 为 IP 时按地址发送，否则发送域名由上游解析
+pub async fn socks5<S>(stream: &mut S, proxy: &UpstreamProxy, addr: &str) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let host = split_host(addr);
+    let port: u16 = addr
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("no port in {addr}")))?;
+    let invalid = || Error::new(ErrorKind::InvalidData, "invalid SOCKS5 reply");
+    let too_long = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "SOCKS5 fields are at most 255 bytes",
+        )
+    };
+
+    let method = if proxy.username.is_some() { 2 } else { 0 };
+    stream.write_all(&[5, 1, method]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    match reply {
+        [5, 0] => {}
+        [5, 2] if method == 2 => {
+            let username = proxy.username.as_deref().unwrap_or_default();
+            let password = proxy.password.as_deref().unwrap_or_default();
+            let mut auth = vec![1];
+            for field in [username, password] {
+                auth.push(u8::try_from(field.len()).map_err(|_| too_long())?);
+                auth.extend_from_slice(field.as_bytes());
+            }
+            stream.write_all(&auth).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("upstream proxy {} rejected credentials", proxy.addr),
+                ));
+            }
+        }
+        [5, 0xff] => {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "upstream proxy {} accepts no offered auth method",
+                    proxy.addr
+                ),
+            ))
+        }
+        _ => return Err(invalid()),
+    }
+
+    let mut req = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            req.push(1);
+            req.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            req.push(4);
+            req.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            req.push(3);
+            req.push(u8::try_from(host.len()).map_err(|_| too_long())?);
+            req.extend_from_slice(host.as_bytes());
+        }
+    }
+    req.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&req).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        return Err(invalid());
+    }
+    if reply[1] != 0 {
+        let kind = match reply[1] {
+            5 => ErrorKind::ConnectionRefused,
+            2 => ErrorKind::PermissionDenied,
+            _ => ErrorKind::Other,
+        };
+        let reason = match reply[1] {
+            1 => "general failure",
+            2 => "not allowed by ruleset",
+            3 => "network unreachable",
+            4 => "host unreachable",
+            5 => "connection refused",
+            6 => "TTL expired",
+            7 => "command not supported",
+            8 => "address type not supported",
+            _ => "unknown error",
+        };
+        return Err(Error::new(
+            kind,
+            format!(
+                "upstream proxy {} failed to connect {addr}: {reason}",
+                proxy.addr
+            ),
+        ));
+    }
+    // the bound address is of no use here
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(invalid()),
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound).await?;
+    debug!("socks5 to {addr} via {}", proxy.addr);
+    Ok(())
+}
+
+/// 发送 CONNECT，读完响应头；非 2xx 的响应体按 `Content-Length` 丢弃，连接留给下一轮认证
+async fn connect<S>(stream: &mut S, addr: &str, authorization: Option<&str>) -> Result<Head, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut req =
+        format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\nProxy-Connection: Keep-Alive\r\n");
+    if let Some(authorization) = authorization {
+        req.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+    }
+    req.push_str("\r\n");
+    stream.write_all(req.as_bytes()).await?;
+
+    // byte by byte so nothing past the head, i.e. tunnelled data, is consumed
+    let mut raw = Vec::with_capacity(256);
+    while !raw.ends_with(b"\r\n\r\n") {
+        if raw.len() >= MAX_HEAD {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "upstream proxy head too large",
+            ));
+        }
+        raw.push(stream.read_u8().await?);
+    }
+    let head = parse(&raw)?;
+    if !(200..300).contains(&head.status) && head.content_length > 0 {
+        let mut body = stream.take(head.content_length as u64);
+        tokio::io::copy(&mut body, &mut tokio::io::sink()).await?;
+    }
+    Ok(head)
+}
+
+fn parse(raw: &[u8]) -> Result<Head, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "invalid upstream proxy response");
+    let text = std::str::from_utf8(raw).map_err(|_| invalid())?;
+    let mut lines = text.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(invalid)?;
+    let mut head = Head {
+        status,
+        challenges: Vec::new(),
+        content_length: 0,
+        close: false,
+    };
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "proxy-authenticate" => head.challenges.push(value.to_owned()),
+            "content-length" => head.content_length = value.parse().map_err(|_| invalid())?,
+            "connection" | "proxy-connection" => {
+                head.close |= value.eq_ignore_ascii_case("close");
+            }
+            _ => {}
+        }
+    }
+    Ok(head)
+}
+
+#[tokio::test]
+async fn should_authenticate_to_upstream_proxy() {
+    let proxy = UpstreamProxy {
+        addr: "proxy:3128".to_owned(),
+        username: Some("CORP\\alice".to_owned()),
+        password: Some("secret".to_owned()),
+        auth: ProxyAuthScheme::Ntlm,
+        ..Default::default()
+    };
+    let (mut client, mut server) = tokio::io::duplex(4096);
+    let upstream = tokio::spawn(async move {
+        let mut buf = vec![0; 4096];
+        let n = server.read(&mut buf).await.unwrap();
+        let req = String::from_utf8_lossy(&buf[..n]).into_owned();
+        assert!(req.starts_with("CONNECT example.com:443 HTTP/1.1\r\n"));
+        assert!(req.contains("Proxy-Authorization: NTLM TlRMTVNTUA"));
+        let mut challenge = b"NTLMSSP\0\x02\0\0\0".to_vec();
+        challenge.extend_from_slice(&[0; 36]);
+        let challenge = base64::encode_block(&challenge);
+        let resp = format!(
+            "HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: NTLM {challenge}\r\nContent-Length: 4\r\n\r\ndeny"
+        );
+        server.write_all(resp.as_bytes()).await.unwrap();
+        let n = server.read(&mut buf).await.unwrap();
+        let req = String::from_utf8_lossy(&buf[..n]).into_owned();
+        let token = req
+            .lines()
+            .find_map(|line| line.strip_prefix("Proxy-Authorization: NTLM "))
+            .unwrap();
+        let msg = base64::decode_block(token).unwrap();
+        assert_eq!(msg[8], 3);
+        server
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+    });
+    tunnel(&mut client, &proxy, "example.com:443")
+        .await
+        .unwrap();
+    upstream.await.unwrap();
+
+    let head = parse(
+        b"HTTP/1.1 407 x\r\nProxy-Authenticate: Basic realm=\"p\"\r\nConnection: close\r\n\r\n",
+    )
+    .unwrap();
+    assert_eq!(head.status, 407);
+    assert!(head.close);
+    assert_eq!(head.challenges, ["Basic realm=\"p\""]);
+}
+
+#[tokio::test]
+async fn should_connect_through_socks5() {
+    let proxy = UpstreamProxy {
+        addr: "127.0.0.1:1080".to_owned(),
+        username: Some("u".to_owned()),
+        password: Some("p".to_owned()),
+        ..Default::default()
+    };
+    let (mut client, mut server) = tokio::io::duplex(4096);
+    let upstream = tokio::spawn(async move {
+        let mut buf = [0; 64];
+        server.read_exact(&mut buf[..3]).await.unwrap();
+        assert_eq!(&buf[..3], [5, 1, 2]);
+        server.write_all(&[5, 2]).await.unwrap();
+        server.read_exact(&mut buf[..5]).await.unwrap();
+        assert_eq!(&buf[..5], [1, 1, b'u', 1, b'p']);
+        server.write_all(&[1, 0]).await.unwrap();
+        server.read_exact(&mut buf[..18]).await.unwrap();
+        assert_eq!(&buf[..5], [5, 1, 0, 3, 11]);
+        assert_eq!(&buf[5..18], b"example.com\x01\xbb");
+        server
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0x04, 0x38])
+            .await
+            .unwrap();
+        server.write_all(b"tunnelled").await.unwrap();
+    });
+    socks5(&mut client, &proxy, "example.com:443")
+        .await
+        .unwrap();
+    let mut rest = [0; 9];
+    client.read_exact(&mut rest).await.unwrap();
+    assert_eq!(&rest, b"tunnelled");
+    upstream.await.unwrap();
+}
@@ -1,31 +1,310 @@
+use std::future::Future;
+use std::io;
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
-use http::uri::Scheme;
-use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
-use hyper::Uri;
-use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use http::uri::{Authority, Scheme};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full, StreamBody};
+use hyper::body::Frame;
+use hyper::header::{HeaderMap, HOST};
+use hyper::{Request, Uri};
+use openssl::ssl::{
+    SslConnector, SslConnectorBuilder, SslMethod, SslVerifyMode, SslVersion, StatusType,
+};
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
 use tokio_openssl::SslStream;
+use tracing::error;
 
-pub async fn create_ssl_connection(addr: &str, sni: &str) -> Result<SslStream<TcpStream>> {
-    let output = TcpStream::connect(addr).await?;
-    let mut client_ssl = SslConnector::builder(SslMethod::tls())?
-        .build()
-        .configure()?
-        .verify_hostname(false)
-        .into_ssl(sni)?;
-    // TODO 客户端校验证书（store: Microsoft.pem）
+use crate::config::{SocketConfig, TlsProfile};
+use crate::dialer::Dialer;
+use crate::flow::Timings;
+
+pub async fn create_ssl_connection(
+    dialer: &Dialer,
+    addr: &str,
+    sni: &str,
+) -> Result<SslStream<TcpStream>> {
+    create_ssl_connection_timed(dialer, addr, sni, &mut Timings::default()).await
+}
+
+/// 同 `create_ssl_connection`，在 ALPN 中提供 `alpn`（wire 格式）
+pub async fn create_ssl_connection_alpn(
+    dialer: &Dialer,
+    addr: &str,
+    sni: &str,
+    alpn: &[u8],
+) -> Result<SslStream<TcpStream>> {
+    connect_ssl(dialer, addr, sni, Some(alpn), &mut Timings::default()).await
+}
+
+/// 同 `create_ssl_connection`，记录解析、建连与握手耗时
+pub async fn create_ssl_connection_timed(
+    dialer: &Dialer,
+    addr: &str,
+    sni: &str,
+    timings: &mut Timings,
+) -> Result<SslStream<TcpStream>> {
+    connect_ssl(dialer, addr, sni, None, timings).await
+}
+
+async fn connect_ssl(
+    dialer: &Dialer,
+    addr: &str,
+    sni: &str,
+    alpn: Option<&[u8]>,
+    timings: &mut Timings,
+) -> Result<SslStream<TcpStream>> {
+    let output = dialer.connect_timed(addr, timings).await?;
+    let start = Instant::now();
+    let handshake_secs = dialer.config().timeouts.tls_handshake_secs;
+    let profile = dialer.config().upstream_tls_profile;
+    let mut client_ssl = connector(profile)?.configure()?.into_ssl(sni)?;
+    if profile != TlsProfile::Openssl {
+        // 浏览器都会请求 OCSP stapling
+        client_ssl.set_status_type(StatusType::OCSP)?;
+    }
+    if let Some(alpn) = alpn {
+        client_ssl.set_alpn_protos(alpn)?;
+    }
+    // 只记录验证结果（见 `upstream_cert`），不因此断开
     client_ssl.set_verify(SslVerifyMode::NONE);
     let mut output = SslStream::new(client_ssl, output)?;
-    Pin::new(&mut output)
-        .connect()
-        .await
+    timeout(handshake_secs, Pin::new(&mut output).connect())
+        .await?
         .map_err(|e| anyhow!("ssl客户端连接异常:{}", e))?;
+    timings.tls_ms = Timings::since(start);
     Ok(output)
 }
 
+/// 常见发行版的 CA bundle，未设置 `SSL_CERT_FILE` 时使用
+const CA_BUNDLES: [&str; 3] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+/// 上游 TLS 每种 profile 共用一个 context，CA bundle 只加载一次
+fn connector(profile: TlsProfile) -> Result<SslConnector> {
+    static CONNECTORS: [OnceLock<SslConnector>; 3] =
+        [OnceLock::new(), OnceLock::new(), OnceLock::new()];
+    let cell = &CONNECTORS[profile as usize];
+    if let Some(connector) = cell.get() {
+        return Ok(connector.clone());
+    }
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    if std::env::var_os("SSL_CERT_FILE").is_none() {
+        if let Some(bundle) = CA_BUNDLES.iter().find(|path| Path::new(path).exists()) {
+            builder.set_ca_file(bundle)?;
+        }
+    }
+    shape(&mut builder, profile)?;
+    Ok(cell.get_or_init(|| builder.build()).clone())
+}
+
+/// 按 profile 调整 ClientHello；上游只走 HTTP/1.1，ALPN 不提供 h2
+fn shape(builder: &mut SslConnectorBuilder, profile: TlsProfile) -> Result<()> {
+    let (ciphersuites, ciphers, groups, sigalgs) = match profile {
+        TlsProfile::Openssl => return Ok(()),
+        TlsProfile::Chrome => (
+            "TLS_AES_128_GCM_SHA256:TLS_AES_256_GCM_SHA384:TLS_CHACHA20_POLY1305_SHA256",
+            "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+             ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+             ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+             ECDHE-RSA-AES128-SHA:ECDHE-RSA-AES256-SHA:\
+             AES128-GCM-SHA256:AES256-GCM-SHA384:AES128-SHA:AES256-SHA",
+            "X25519:P-256:P-384",
+            "ecdsa_secp256r1_sha256:rsa_pss_rsae_sha256:rsa_pkcs1_sha256:\
+             ecdsa_secp384r1_sha384:rsa_pss_rsae_sha384:rsa_pkcs1_sha384:\
+             rsa_pss_rsae_sha512:rsa_pkcs1_sha512",
+        ),
+        TlsProfile::Firefox => (
+            "TLS_AES_128_GCM_SHA256:TLS_CHACHA20_POLY1305_SHA256:TLS_AES_256_GCM_SHA384",
+            "ECDHE-ECDSA-AES128-GCM-SHA256:ECDHE-RSA-AES128-GCM-SHA256:\
+             ECDHE-ECDSA-CHACHA20-POLY1305:ECDHE-RSA-CHACHA20-POLY1305:\
+             ECDHE-ECDSA-AES256-GCM-SHA384:ECDHE-RSA-AES256-GCM-SHA384:\
+             ECDHE-ECDSA-AES256-SHA:ECDHE-ECDSA-AES128-SHA:\
+             ECDHE-RSA-AES128-SHA:ECDHE-RSA-AES256-SHA:\
+             AES128-GCM-SHA256:AES256-GCM-SHA384:AES128-SHA:AES256-SHA",
+            "X25519:P-256:P-384:P-521:ffdhe2048:ffdhe3072",
+            "ecdsa_secp256r1_sha256:ecdsa_secp384r1_sha384:ecdsa_secp521r1_sha512:\
+             rsa_pss_rsae_sha256:rsa_pss_rsae_sha384:rsa_pss_rsae_sha512:\
+             rsa_pkcs1_sha256:rsa_pkcs1_sha384:rsa_pkcs1_sha512",
+        ),
+    };
+    builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+    builder.set_ciphersuites(ciphersuites)?;
+    builder.set_cipher_list(ciphers)?;
+    builder.set_groups_list(groups)?;
+    builder.set_sigalgs_list(sigalgs)?;
+    builder.set_alpn_protos(b"\x08http/1.1")?;
+    Ok(())
+}
+
+/// `secs` 为 0 时不限制，超时返回 `TimedOut`
+pub async fn timeout<F: Future>(secs: u64, future: F) -> io::Result<F::Output> {
+    if secs == 0 {
+        return Ok(future.await);
+    }
+    tokio::time::timeout(Duration::from_secs(secs), future)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("timed out after {secs}s")))
+}
+
+pub fn is_timeout(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::TimedOut)
+}
+
+/// 同 `io::copy_bidirectional`，双向都空闲超过 `idle_secs` 时返回 `TimedOut`
+pub async fn copy_bidirectional_idle<A, B>(
+    a: &mut A,
+    b: &mut B,
+    idle_secs: u64,
+    buffer_size: usize,
+) -> io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let activity = Activity::new();
+    let mut a = Tracked {
+        inner: a,
+        activity: &activity,
+    };
+    let mut b = Tracked {
+        inner: b,
+        activity: &activity,
+    };
+    activity
+        .guard(
+            idle_secs,
+            tokio::io::copy_bidirectional_with_sizes(&mut a, &mut b, buffer_size, buffer_size),
+        )
+        .await
+}
+
+/// 最近一次读写的时间（相对创建时的毫秒数）
+pub struct Activity {
+    start: Instant,
+    last: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    pub fn touch(&self) {
+        self.last
+            .store(self.start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// `future` 完成前空闲超过 `idle_secs` 时返回 `TimedOut`，为 0 时不限制
+    pub async fn guard<T>(
+        &self,
+        idle_secs: u64,
+        future: impl Future<Output = io::Result<T>>,
+    ) -> io::Result<T> {
+        if idle_secs == 0 {
+            return future.await;
+        }
+        let idle = Duration::from_secs(idle_secs);
+        tokio::pin!(future);
+        loop {
+            let active = self.last.load(Ordering::Relaxed);
+            let deadline = self.start + Duration::from_millis(active) + idle;
+            tokio::select! {
+                result = &mut future => return result,
+                _ = tokio::time::sleep_until(deadline.into()) => {
+                    // otherwise there was activity meanwhile, wait for the new deadline
+                    if self.last.load(Ordering::Relaxed) == active {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("idle for {idle_secs}s"),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 读写时记录活动
+struct Tracked<'a, S> {
+    inner: &'a mut S,
+    activity: &'a Activity,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let poll = Pin::new(&mut *self.inner).poll_read(cx, buf);
+        if poll.is_ready() {
+            self.activity.touch();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut *self.inner).poll_write(cx, buf);
+        if poll.is_ready() {
+            self.activity.touch();
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.inner).poll_shutdown(cx)
+    }
+}
+
+/// 按配置设置 socket 参数，失败只记录日志
+pub fn tune_socket(stream: &TcpStream, config: &SocketConfig) {
+    let socket = SockRef::from(stream);
+    let mut result = socket.set_nodelay(config.nodelay);
+    if let Some(secs) = config.keepalive_secs {
+        let mut keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+        if let Some(interval) = config.keepalive_interval_secs {
+            keepalive = keepalive.with_interval(Duration::from_secs(interval));
+        }
+        result = result.and(socket.set_tcp_keepalive(&keepalive));
+    }
+    if let Some(size) = config.send_buffer_size {
+        result = result.and(socket.set_send_buffer_size(size));
+    }
+    if let Some(size) = config.recv_buffer_size {
+        result = result.and(socket.set_recv_buffer_size(size));
+    }
+    if let Err(e) = result {
+        error!("Set socket options failed: {e}");
+    }
+}
+
 pub fn host_addr(uri: &Uri) -> Option<(String, String)> {
     uri.authority()
         .map(|auth| {
@@ -39,6 +318,16 @@ pub fn host_addr(uri: &Uri) -> Option<(String, String)> {
         .zip(uri.host().map(|host| host.to_string()))
 }
 
+/// Host 头去掉端口，没有时取绝对 URI 的主机
+pub fn request_host<B>(req: &Request<B>) -> Option<String> {
+    req.headers()
+        .get(HOST)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<Authority>().ok())
+        .map(|authority| authority.host().to_owned())
+        .or_else(|| req.uri().host().map(str::to_owned))
+}
+
 pub fn empty() -> BoxBody<Bytes, hyper::Error> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
@@ -50,3 +339,115 @@ pub fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
         .map_err(|never| match never {})
         .boxed()
 }
+
+/// 没有 trailers 时同 `full`
+pub fn full_with_trailers(
+    chunk: Bytes,
+    trailers: Option<HeaderMap>,
+) -> BoxBody<Bytes, hyper::Error> {
+    let Some(trailers) = trailers else {
+        return full(chunk);
+    };
+    let frames = [Frame::data(chunk), Frame::trailers(trailers)].map(Ok);
+    StreamBody::new(tokio_stream::iter(frames)).boxed()
+}
+
+#[tokio::test]
+async fn should_close_idle_tunnel() {
+    let (mut client, mut a) = tokio::io::duplex(64);
+    let (mut b, mut server) = tokio::io::duplex(64);
+    let copy =
+        tokio::spawn(async move { copy_bidirectional_idle(&mut a, &mut b, 1, 8 * 1024).await });
+    tokio::io::AsyncWriteExt::write_all(&mut client, b"ping")
+        .await
+        .unwrap();
+    let mut buf = [0; 4];
+    tokio::io::AsyncReadExt::read_exact(&mut server, &mut buf)
+        .await
+        .unwrap();
+    let err = copy.await.unwrap().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+async fn should_tune_socket() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let config = SocketConfig {
+        nodelay: true,
+        keepalive_secs: Some(30),
+        keepalive_interval_secs: Some(5),
+        send_buffer_size: Some(64 * 1024),
+        recv_buffer_size: Some(64 * 1024),
+        ..Default::default()
+    };
+    tune_socket(&stream, &config);
+    let socket = SockRef::from(&stream);
+    assert!(socket.nodelay().unwrap());
+    assert!(socket.keepalive().unwrap());
+    #[cfg(target_os = "linux")]
+    {
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+    }
+    // the kernel may round the buffers up
+    assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+    assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+}
+
+#[tokio::test]
+async fn should_copy_with_small_buffer() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut client, mut a) = tokio::io::duplex(64);
+    let (mut b, mut server) = tokio::io::duplex(64);
+    let copy = tokio::spawn(async move { copy_bidirectional_idle(&mut a, &mut b, 5, 16).await });
+    let data = vec![b'x'; 4096];
+    let sent = data.clone();
+    let writer = tokio::spawn(async move {
+        client.write_all(&sent).await.unwrap();
+        client.shutdown().await.unwrap();
+        client
+    });
+    let mut received = Vec::new();
+    server.read_to_end(&mut received).await.unwrap();
+    let _client = writer.await.unwrap();
+    assert_eq!(received, data);
+    server.shutdown().await.unwrap();
+    assert_eq!(copy.await.unwrap().unwrap(), (4096, 0));
+}
+
+#[cfg(unix)]
+#[test]
+fn should_shape_client_hello() {
+    use crate::fingerprint::Fingerprint;
+
+    let hello = |profile| {
+        let ssl = connector(profile)
+            .unwrap()
+            .configure()
+            .unwrap()
+            .into_ssl("example.com")
+            .unwrap();
+        let (client, mut server) = std::os::unix::net::UnixStream::pair().unwrap();
+        client.set_nonblocking(true).unwrap();
+        let _ = ssl.connect(client);
+        let mut data = vec![0; 8192];
+        let n = std::io::Read::read(&mut server, &mut data).unwrap();
+        crate::sniff::client_hello(&data[..n]).unwrap()
+    };
+    let chrome = hello(TlsProfile::Chrome);
+    assert_eq!(chrome.ciphers[..3], [0x1301, 0x1302, 0x1303]);
+    assert_eq!(chrome.groups, [0x001d, 0x0017, 0x0018]);
+    assert_eq!(chrome.alpn, ["http/1.1"]);
+    assert_eq!(
+        hello(TlsProfile::Firefox).ciphers[..3],
+        [0x1301, 0x1303, 0x1302]
+    );
+    assert_ne!(
+        Fingerprint::of(&chrome).ja4,
+        Fingerprint::of(&hello(TlsProfile::Openssl)).ja4
+    );
+}
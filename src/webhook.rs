@@ -0,0 +1,274 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Request, StatusCode, Uri};
+use motore::Service;
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+use ulid::Ulid;
+
+use crate::client::HttpClient;
+use crate::config::WebhookConfig;
+use crate::flow::{self, Flow, Summary};
+use crate::reverse::Target;
+use crate::state::{ClientState, State};
+use crate::util;
+
+const CA_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+const CA_NOTIFY_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+/// 已通知的 flow，之后的更新不再通知
+const RECENT: usize = 1024;
+
+#[derive(Serialize, Debug)]
+struct Payload {
+    /// Slack 显示的文本
+    text: String,
+    /// `flow` 或 `ca_expiry`
+    event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flow: Option<Summary>,
+    /// 上次通知以来因限流没有发送的匹配数
+    suppressed: u64,
+}
+
+/// 每个 webhook 的限流状态
+#[derive(Default)]
+struct Limiter {
+    last: Option<Instant>,
+    suppressed: u64,
+    ca_notified: Option<Instant>,
+}
+
+impl Limiter {
+    /// 可以发送时返回之前被抑制的数量
+    fn admit(&mut self, interval: Duration, now: Instant) -> Option<u64> {
+        if self.last.is_some_and(|last| now < last + interval) {
+            self.suppressed += 1;
+            return None;
+        }
+        self.last = Some(now);
+        Some(std::mem::take(&mut self.suppressed))
+    }
+}
+
+/// 按 url 区分的限流状态，配置变化后仍然沿用
+#[derive(Default)]
+struct Notifier {
+    limiters: HashMap<String, Limiter>,
+    recent: VecDeque<Ulid>,
+}
+
+impl Notifier {
+    fn flow(&mut self, state: &State, flow: &Flow) {
+        if !(flow.complete || flow.error.is_some()) || self.recent.contains(&flow.id) {
+            return;
+        }
+        let config = state.config();
+        let hooks = config
+            .webhooks
+            .iter()
+            .filter(|hook| hook.filter.as_ref().is_some_and(|f| f.matches(flow)));
+        let mut matched = false;
+        for hook in hooks {
+            matched = true;
+            let limiter = self.limiters.entry(hook.url.clone()).or_default();
+            let interval = Duration::from_secs(hook.min_interval_secs);
+            let Some(suppressed) = limiter.admit(interval, Instant::now()) else {
+                continue;
+            };
+            let summary = Summary::from(flow);
+            let payload = Payload {
+                text: flow_text(&summary, suppressed),
+                event: "flow",
+                flow: Some(summary),
+                suppressed,
+            };
+            deliver(state, hook, payload);
+        }
+        if matched {
+            if self.recent.len() == RECENT {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(flow.id);
+        }
+    }
+
+    fn ca(&mut self, state: &State) {
+        let config = state.config();
+        let ca = state.root_ca();
+        for hook in &config.webhooks {
+            let Some(days) = hook.ca_expiry_days else {
+                continue;
+            };
+            if !ca.expires_within(days) {
+                continue;
+            }
+            let limiter = self.limiters.entry(hook.url.clone()).or_default();
+            let now = Instant::now();
+            if limiter
+                .ca_notified
+                .is_some_and(|last| now < last + CA_NOTIFY_INTERVAL)
+            {
+                continue;
+            }
+            limiter.ca_notified = Some(now);
+            let payload = Payload {
+                text: format!("Root CA expires at {}", ca.cert.not_after()),
+                event: "ca_expiry",
+                flow: None,
+                suppressed: 0,
+            };
+            deliver(state, hook, payload);
+        }
+    }
+}
+
+/// 按 `webhooks` 监听结束的 flow 并定期检查根 CA，配置修改即时生效
+pub fn spawn(state: State) {
+    tokio::task::spawn(async move {
+        let mut flows = state.flows().subscribe();
+        let mut ca_check = tokio::time::interval(CA_CHECK_INTERVAL);
+        let mut notifier = Notifier::default();
+        loop {
+            tokio::select! {
+                flow = flows.recv() => match flow {
+                    Ok(flow) => notifier.flow(&state, &flow),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("webhook notifier skipped {skipped} flow updates")
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = ca_check.tick() => notifier.ca(&state),
+            }
+        }
+    });
+}
+
+fn flow_text(summary: &Summary, suppressed: u64) -> String {
+    let outcome = match (&summary.error, summary.status) {
+        (Some(error), _) => format!("failed ({error})"),
+        (None, Some(status)) => status.to_string(),
+        (None, None) => "no response".to_owned(),
+    };
+    let mut text = format!("{outcome} {} {}", summary.method, summary.url);
+    if let Some(ms) = summary.duration_ms {
+        text.push_str(&format!(" in {ms} ms"));
+    }
+    if suppressed > 0 {
+        text.push_str(&format!(
+            " (+{suppressed} more since the last notification)"
+        ));
+    }
+    text
+}
+
+/// 在后台发送，失败时按 `retries` 重试
+fn deliver(state: &State, hook: &WebhookConfig, payload: Payload) {
+    let state = state.clone();
+    let hook = hook.clone();
+    tokio::task::spawn(async move {
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(e) => return warn!("encode webhook payload failed: {e}"),
+        };
+        let mut attempt = 0;
+        loop {
+            let result = util::timeout(hook.timeout_secs, post(&state, &hook.url, body.clone()))
+                .await
+                .unwrap_or_else(|e| Err(Failure::Retry(e.into())));
+            let retry = match result {
+                Ok(()) => return debug!(url = hook.url, "webhook delivered"),
+                Err(Failure::Retry(e)) => e,
+                Err(Failure::Fatal(e)) => return warn!(url = hook.url, "webhook rejected: {e}"),
+            };
+            if attempt >= hook.retries {
+                return warn!(
+                    url = hook.url,
+                    attempts = attempt + 1,
+                    "webhook failed: {retry}"
+                );
+            }
+            tokio::time::sleep(Duration::from_secs(1 << attempt.min(6))).await;
+            attempt += 1;
+        }
+    });
+}
+
+enum Failure {
+    Retry(anyhow::Error),
+    /// 其余 4xx，重试也不会成功
+    Fatal(anyhow::Error),
+}
+
+async fn post(state: &State, url: &str, body: Vec<u8>) -> Result<(), Failure> {
+    let (status, body) = send(state, url, body).await.map_err(Failure::Retry)?;
+    if status.is_success() {
+        return Ok(());
+    }
+    let e = anyhow!("{status}: {}", String::from_utf8_lossy(&body));
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        Err(Failure::Retry(e))
+    } else {
+        Err(Failure::Fatal(e))
+    }
+}
+
+/// 经由上游连接池发送，不记录为 flow
+async fn send(state: &State, url: &str, body: Vec<u8>) -> Result<(StatusCode, Bytes)> {
+    let target = Target::parse(url, false)?;
+    let uri: Uri = url.parse()?;
+    let req = Request::post(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(HOST, &target.authority)
+        .header(CONTENT_TYPE, "application/json")
+        .body(util::full(body))?;
+    let mut client = ClientState {
+        id: flow::next_id(),
+        addr: target.addr,
+        sni: target.host,
+        is_secure: target.secure,
+        parse: false,
+        transcript: None,
+        timings: Default::default(),
+        shared: state.clone(),
+    };
+    let resp = HttpClient.call(&mut client, req).await?;
+    let status = resp.status();
+    let body = resp.into_body().collect().await?.to_bytes();
+    Ok((status, body))
+}
+
+#[test]
+fn should_rate_limit_notifications() {
+    let mut limiter = Limiter::default();
+    let interval = Duration::from_secs(60);
+    let start = Instant::now();
+    assert_eq!(limiter.admit(interval, start), Some(0));
+    assert_eq!(
+        limiter.admit(interval, start + Duration::from_secs(1)),
+        None
+    );
+    assert_eq!(
+        limiter.admit(interval, start + Duration::from_secs(2)),
+        None
+    );
+    assert_eq!(limiter.admit(interval, start + interval), Some(2));
+
+    let summary = Summary::from(&Flow {
+        addr: "example.com:443".to_owned(),
+        secure: true,
+        method: "GET".to_owned(),
+        uri: "/api".to_owned(),
+        status: Some(503),
+        duration_ms: Some(12),
+        ..Default::default()
+    });
+    assert_eq!(
+        flow_text(&summary, 2),
+        "503 GET https://example.com/api in 12 ms (+2 more since the last notification)"
+    );
+}